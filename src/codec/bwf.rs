@@ -0,0 +1,330 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Broadcast Wave chunks: bext (EBU Tech 3285), cart (AES46) and iXML.
+// The bext time reference is lifted out into Metadata::time_reference so that
+// stages which move the start of the audio can keep it correct.
+
+use std::convert::TryInto;
+
+const BEXT_FIXED_LEN : usize = 602;
+const CART_FIXED_LEN : usize = 2048;
+
+// bext loudness fields are hundredths of a unit; 0x7fff marks a value as unset
+const LOUDNESS_UNSET : i16 = 0x7fff;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bext {
+	pub description : String,
+	pub originator : String,
+	pub originator_reference : String,
+	// yyyy:mm:dd and hh:mm:ss
+	pub origination_date : String,
+	pub origination_time : String,
+	pub version : u16,
+	pub umid : Vec<u8>,
+	pub loudness_value : Option<f64>,
+	pub loudness_range : Option<f64>,
+	pub max_true_peak_level : Option<f64>,
+	pub max_momentary_loudness : Option<f64>,
+	pub max_short_term_loudness : Option<f64>,
+	pub coding_history : String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cart {
+	pub version : String,
+	pub title : String,
+	pub artist : String,
+	pub cut_id : String,
+	pub client_id : String,
+	pub category : String,
+	pub classification : String,
+	pub out_cue : String,
+	pub start_date : String,
+	pub start_time : String,
+	pub end_date : String,
+	pub end_time : String,
+	pub producer_app_id : String,
+	pub producer_app_version : String,
+	pub user_def : String,
+	pub level_reference : i32,
+	// Usage FOURCC and sample position, up to eight of them
+	pub post_timers : Vec<([u8; 4], u32)>,
+	pub url : String,
+	pub tag_text : String,
+}
+
+// Sequential reader over the fixed-width fields of a chunk
+struct Fields<'a> {
+	data : &'a [u8],
+	pos : usize,
+}
+
+impl<'a> Fields<'a> {
+	fn bytes(&mut self, len: usize) -> &'a [u8] {
+		let start = self.pos.min(self.data.len());
+		let end = (self.pos + len).min(self.data.len());
+		self.pos += len;
+		&self.data[start..end]
+	}
+
+	fn text(&mut self, len: usize) -> String {
+		let bytes = self.bytes(len);
+		let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+		bytes[..end].iter().map(|&b| b as char).collect()
+	}
+
+	fn u16(&mut self) -> u16 {
+		self.bytes(2).try_into().map(u16::from_le_bytes).unwrap_or(0)
+	}
+
+	fn u32(&mut self) -> u32 {
+		self.bytes(4).try_into().map(u32::from_le_bytes).unwrap_or(0)
+	}
+
+	fn rest(&mut self) -> String {
+		let len = self.data.len().saturating_sub(self.pos);
+		self.text(len)
+	}
+}
+
+fn push_text(out: &mut Vec<u8>, text: &str, len: usize) {
+	let mut bytes : Vec<u8> = text.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }).collect();
+	bytes.resize(len, 0);
+	out.extend_from_slice(&bytes);
+}
+
+fn loudness(raw: u16, version: u16) -> Option<f64> {
+	if version < 2 || raw as i16 == LOUDNESS_UNSET {
+		None
+	} else {
+		Some(raw as i16 as f64 / 100.0)
+	}
+}
+
+fn push_loudness(out: &mut Vec<u8>, value: Option<f64>) {
+	let raw = match value {
+		Some(v) => (v * 100.0).round() as i16,
+		None => LOUDNESS_UNSET,
+	};
+	out.extend_from_slice(&raw.to_le_bytes());
+}
+
+impl Bext {
+	// Returns the chunk along with its time reference, in samples since midnight
+	pub fn parse(data: &[u8]) -> (Bext, u64) {
+		let mut f = Fields { data, pos: 0 };
+		let description = f.text(256);
+		let originator = f.text(32);
+		let originator_reference = f.text(32);
+		let origination_date = f.text(10);
+		let origination_time = f.text(8);
+		let time_reference = f.u32() as u64 | (f.u32() as u64) << 32;
+		let version = f.u16();
+		let umid = f.bytes(64).to_vec();
+		let loudness_value = loudness(f.u16(), version);
+		let loudness_range = loudness(f.u16(), version);
+		let max_true_peak_level = loudness(f.u16(), version);
+		let max_momentary_loudness = loudness(f.u16(), version);
+		let max_short_term_loudness = loudness(f.u16(), version);
+		f.bytes(180);
+		let coding_history = f.rest();
+
+		let bext = Bext {
+			description,
+			originator,
+			originator_reference,
+			origination_date,
+			origination_time,
+			version,
+			umid,
+			loudness_value,
+			loudness_range,
+			max_true_peak_level,
+			max_momentary_loudness,
+			max_short_term_loudness,
+			coding_history,
+		};
+		(bext, time_reference)
+	}
+
+	pub fn to_bytes(&self, time_reference: u64) -> Vec<u8> {
+		let mut out = Vec::with_capacity(BEXT_FIXED_LEN + self.coding_history.len());
+		push_text(&mut out, &self.description, 256);
+		push_text(&mut out, &self.originator, 32);
+		push_text(&mut out, &self.originator_reference, 32);
+		push_text(&mut out, &self.origination_date, 10);
+		push_text(&mut out, &self.origination_time, 8);
+		out.extend_from_slice(&(time_reference as u32).to_le_bytes());
+		out.extend_from_slice(&((time_reference >> 32) as u32).to_le_bytes());
+		// Loudness fields only exist from version 2 on
+		out.extend_from_slice(&self.version.max(2).to_le_bytes());
+		let mut umid = self.umid.clone();
+		umid.resize(64, 0);
+		out.extend_from_slice(&umid);
+		push_loudness(&mut out, self.loudness_value);
+		push_loudness(&mut out, self.loudness_range);
+		push_loudness(&mut out, self.max_true_peak_level);
+		push_loudness(&mut out, self.max_momentary_loudness);
+		push_loudness(&mut out, self.max_short_term_loudness);
+		out.resize(BEXT_FIXED_LEN, 0);
+		push_text(&mut out, &self.coding_history, self.coding_history.len());
+		out
+	}
+}
+
+impl Cart {
+	pub fn parse(data: &[u8]) -> Cart {
+		let mut f = Fields { data, pos: 0 };
+		let mut cart = Cart {
+			version: f.text(4),
+			title: f.text(64),
+			artist: f.text(64),
+			cut_id: f.text(64),
+			client_id: f.text(64),
+			category: f.text(64),
+			classification: f.text(64),
+			out_cue: f.text(64),
+			start_date: f.text(10),
+			start_time: f.text(8),
+			end_date: f.text(10),
+			end_time: f.text(8),
+			producer_app_id: f.text(64),
+			producer_app_version: f.text(64),
+			user_def: f.text(64),
+			level_reference: f.u32() as i32,
+			..Default::default()
+		};
+		for _ in 0..8 {
+			let usage : [u8; 4] = f.bytes(4).try_into().unwrap_or([0; 4]);
+			let value = f.u32();
+			if usage != [0; 4] {
+				cart.post_timers.push((usage, value));
+			}
+		}
+		f.bytes(276);
+		cart.url = f.text(1024);
+		cart.tag_text = f.rest();
+		cart
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut out = Vec::with_capacity(CART_FIXED_LEN + self.tag_text.len());
+		push_text(&mut out, if self.version.is_empty() { "0101" } else { &self.version }, 4);
+		push_text(&mut out, &self.title, 64);
+		push_text(&mut out, &self.artist, 64);
+		push_text(&mut out, &self.cut_id, 64);
+		push_text(&mut out, &self.client_id, 64);
+		push_text(&mut out, &self.category, 64);
+		push_text(&mut out, &self.classification, 64);
+		push_text(&mut out, &self.out_cue, 64);
+		push_text(&mut out, &self.start_date, 10);
+		push_text(&mut out, &self.start_time, 8);
+		push_text(&mut out, &self.end_date, 10);
+		push_text(&mut out, &self.end_time, 8);
+		push_text(&mut out, &self.producer_app_id, 64);
+		push_text(&mut out, &self.producer_app_version, 64);
+		push_text(&mut out, &self.user_def, 64);
+		out.extend_from_slice(&self.level_reference.to_le_bytes());
+		for i in 0..8 {
+			let (usage, value) = self.post_timers.get(i).cloned().unwrap_or(([0; 4], 0));
+			out.extend_from_slice(&usage);
+			out.extend_from_slice(&value.to_le_bytes());
+		}
+		out.resize(out.len() + 276, 0);
+		push_text(&mut out, &self.url, 1024);
+		push_text(&mut out, &self.tag_text, self.tag_text.len());
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bext_round_trip() {
+		let bext = Bext {
+			description: "Interview".to_string(),
+			originator: "Recorder".to_string(),
+			originator_reference: "REF0001".to_string(),
+			origination_date: "2021:03:04".to_string(),
+			origination_time: "12:34:56".to_string(),
+			version: 2,
+			umid: (0..64).collect(),
+			loudness_value: Some(-23.0),
+			loudness_range: Some(5.25),
+			max_true_peak_level: Some(-1.5),
+			max_momentary_loudness: None,
+			max_short_term_loudness: Some(-18.75),
+			coding_history: "A=PCM,F=48000,W=24,M=stereo\r\n".to_string(),
+		};
+		// A time reference past 32 bits
+		let time_reference = 0x1_2345_6789;
+		let bytes = bext.to_bytes(time_reference);
+		assert_eq!(bytes.len(), BEXT_FIXED_LEN + bext.coding_history.len());
+		assert_eq!(Bext::parse(&bytes), (bext, time_reference));
+	}
+
+	#[test]
+	fn bext_versions() {
+		// Version 0 and 1 chunks have no loudness fields, whatever the bytes say
+		let mut bytes = Bext { loudness_value: Some(-23.0), ..Bext::default() }.to_bytes(0);
+		bytes[346] = 1;
+		let (bext, _) = Bext::parse(&bytes);
+		assert_eq!(bext.version, 1);
+		assert_eq!(bext.loudness_value, None);
+
+		// Written as version 2 with the unset fields marked
+		let (bext, time_reference) = Bext::parse(&Bext::default().to_bytes(48000));
+		assert_eq!(bext.version, 2);
+		assert_eq!(bext.loudness_range, None);
+		assert_eq!(time_reference, 48000);
+
+		// A short chunk reads as far as it goes
+		let (bext, _) = Bext::parse(b"Desc");
+		assert_eq!(bext.description, "Desc");
+		assert_eq!(bext.originator, "");
+	}
+
+	#[test]
+	fn cart_round_trip() {
+		let cart = Cart {
+			version: "0101".to_string(),
+			title: "Spot".to_string(),
+			artist: "Voice".to_string(),
+			cut_id: "1234".to_string(),
+			category: "COM".to_string(),
+			out_cue: "thank you".to_string(),
+			start_date: "2021/01/01".to_string(),
+			start_time: "00:00:00".to_string(),
+			producer_app_id: "chaud".to_string(),
+			level_reference: -32768,
+			post_timers: vec![(*b"SEC1", 44100), (*b"EOD ", 1323000)],
+			url: "https://example.com/spot".to_string(),
+			tag_text: "<tag/>".to_string(),
+			..Cart::default()
+		};
+		let bytes = cart.to_bytes();
+		assert_eq!(bytes.len(), CART_FIXED_LEN + cart.tag_text.len());
+		assert_eq!(Cart::parse(&bytes), cart);
+
+		// The version defaults to 1.01
+		assert_eq!(Cart::parse(&Cart::default().to_bytes()).version, "0101");
+	}
+}
@@ -0,0 +1,234 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Editing the metadata at the head of an existing FLAC file through
+// libFLAC's metadata chain interface, without touching the audio. Changes
+// are absorbed by padding when there is enough of it; otherwise libFLAC
+// writes the whole file to a temporary copy, the audio frames copied as
+// they are, and renames it over the original only once that succeeded.
+
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+
+use crate::codec::{Picture, Tags};
+
+use super::ffi::*;
+use super::metadata::{MetadataBlock, MetadataObject, VorbisComment};
+
+// Padding left for later edits whenever the file has to be rewritten
+// anyway, as much as the encoder leaves by default
+const REWRITE_PADDING : usize = 8192;
+
+// FLAC__Metadata_ChainStatusString, by status
+const CHAIN_STATUS_NAMES : [&str; 16] = [
+	"OK",
+	"ILLEGAL_INPUT",
+	"ERROR_OPENING_FILE",
+	"NOT_A_FLAC_FILE",
+	"NOT_WRITABLE",
+	"BAD_METADATA",
+	"READ_ERROR",
+	"SEEK_ERROR",
+	"WRITE_ERROR",
+	"RENAME_ERROR",
+	"UNLINK_ERROR",
+	"MEMORY_ALLOCATION_ERROR",
+	"INTERNAL_ERROR",
+	"INVALID_CALLBACKS",
+	"READ_WRITE_MISMATCH",
+	"WRONG_WRITE_CALL",
+];
+
+struct Chain(*mut FLAC__Metadata_Chain);
+
+impl Chain {
+	fn read(path: &str) -> io::Result<Chain> {
+		let chain = unsafe { FLAC__metadata_chain_new() };
+		if chain.is_null() {
+			return Err(io::Error::new(ErrorKind::OutOfMemory, "Failed to create FLAC metadata chain"));
+		}
+		let chain = Chain(chain);
+		let path = CString::new(path).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Path contains a NUL byte"))?;
+		if unsafe { FLAC__metadata_chain_read(chain.0, path.as_ptr()) } != 1 {
+			return Err(chain.error("Failed to read FLAC metadata"));
+		}
+		Ok(chain)
+	}
+
+	fn status(&self) -> FLAC__Metadata_ChainStatus {
+		unsafe { FLAC__metadata_chain_status(self.0) }
+	}
+
+	fn error(&self, message: &str) -> io::Error {
+		let status = self.status();
+		let kind = match status {
+			3 | 5 => ErrorKind::InvalidData,
+			4 => ErrorKind::PermissionDenied,
+			_ => ErrorKind::Other,
+		};
+		let name = CHAIN_STATUS_NAMES.get(status as usize).unwrap_or(&"UNKNOWN");
+		io::Error::new(kind, format!("{} (chain status {} {})", message, status, name))
+	}
+
+	// Padding is gathered at the end first, where libFLAC can grow or shrink it
+	fn write(&mut self) -> io::Result<()> {
+		unsafe { FLAC__metadata_chain_sort_padding(self.0); }
+		if unsafe { FLAC__metadata_chain_check_if_tempfile_needed(self.0, 1) } == 1 {
+			let mut iterator = BlockIterator::new(self)?;
+			while iterator.next() {}
+			iterator.insert_block_after(MetadataObject::padding(REWRITE_PADDING))?;
+			drop(iterator);
+			unsafe { FLAC__metadata_chain_sort_padding(self.0); }
+		}
+		if unsafe { FLAC__metadata_chain_write(self.0, 1, 0) } != 1 {
+			return Err(self.error("Failed to write FLAC metadata"));
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Chain {
+	fn drop(&mut self) {
+		unsafe { FLAC__metadata_chain_delete(self.0); }
+	}
+}
+
+// Must not outlive the chain it walks
+struct BlockIterator(*mut FLAC__Metadata_Iterator);
+
+impl BlockIterator {
+	fn new(chain: &Chain) -> io::Result<BlockIterator> {
+		let iterator = unsafe { FLAC__metadata_iterator_new() };
+		if iterator.is_null() {
+			return Err(io::Error::new(ErrorKind::OutOfMemory, "Failed to create FLAC metadata iterator"));
+		}
+		unsafe { FLAC__metadata_iterator_init(iterator, chain.0); }
+		Ok(BlockIterator(iterator))
+	}
+
+	fn next(&mut self) -> bool {
+		unsafe { FLAC__metadata_iterator_next(self.0) == 1 }
+	}
+
+	fn block_type(&self) -> FLAC__MetadataType {
+		unsafe { FLAC__metadata_iterator_get_block_type(self.0) }
+	}
+
+	fn block(&mut self) -> MetadataBlock {
+		unsafe { MetadataBlock::from_raw(&*FLAC__metadata_iterator_get_block(self.0)) }
+	}
+
+	// These hand the object to the chain, which frees any block it replaces.
+	// Inserting moves the iterator onto the new block, deleting onto the one
+	// before. They leave the chain status alone, failing only for want of
+	// memory or on the STREAMINFO block.
+	fn set_block(&mut self, object: MetadataObject) -> io::Result<()> {
+		if unsafe { FLAC__metadata_iterator_set_block(self.0, object.into_raw()) } != 1 {
+			return Err(io::Error::other("Failed to replace FLAC metadata block"));
+		}
+		Ok(())
+	}
+
+	fn insert_block_after(&mut self, object: MetadataObject) -> io::Result<()> {
+		if unsafe { FLAC__metadata_iterator_insert_block_after(self.0, object.into_raw()) } != 1 {
+			return Err(io::Error::other("Failed to insert FLAC metadata block"));
+		}
+		Ok(())
+	}
+
+	fn delete_block(&mut self) -> io::Result<()> {
+		if unsafe { FLAC__metadata_iterator_delete_block(self.0, 0) } != 1 {
+			return Err(io::Error::other("Failed to delete FLAC metadata block"));
+		}
+		Ok(())
+	}
+}
+
+impl Drop for BlockIterator {
+	fn drop(&mut self) {
+		unsafe { FLAC__metadata_iterator_delete(self.0); }
+	}
+}
+
+// The first comment block, if any, and every picture
+fn read_blocks(chain: &Chain) -> io::Result<(Option<VorbisComment>, Vec<Picture>)> {
+	let mut iterator = BlockIterator::new(chain)?;
+	let mut comment = None;
+	let mut pictures = Vec::new();
+	loop {
+		match iterator.block() {
+			MetadataBlock::VorbisComment(vc) if comment.is_none() => comment = Some(vc),
+			MetadataBlock::Picture(picture) => pictures.push(picture),
+			_ => {},
+		}
+		if !iterator.next() {
+			break;
+		}
+	}
+	Ok((comment, pictures))
+}
+
+pub fn read_flac_tags(path: &str) -> io::Result<Tags> {
+	let (comment, pictures) = read_blocks(&Chain::read(path)?)?;
+	Ok(Tags { comments: comment.map(|vc| vc.comments).unwrap_or_default(), pictures })
+}
+
+// Edits the comments and pictures of a FLAC file. A file without a comment
+// block gets one straight after STREAMINFO; pictures follow the comments,
+// and are only rewritten if the edit changed them.
+pub fn update_flac_tags(path: &str, edit: impl FnOnce(&mut Tags)) -> io::Result<()> {
+	let mut chain = Chain::read(path)?;
+	let (comment, pictures) = read_blocks(&chain)?;
+	let had_comment = comment.is_some();
+	let mut comment = comment.unwrap_or_default();
+	let mut tags = Tags { comments: comment.comments, pictures };
+	let old_pictures = tags.pictures.clone();
+	edit(&mut tags);
+	comment.comments = tags.comments;
+	let pictures_changed = tags.pictures != old_pictures;
+
+	{
+		let mut iterator = BlockIterator::new(&chain)?;
+		let mut comment = Some(comment);
+		loop {
+			let placed = match iterator.block_type() {
+				FLAC__METADATA_TYPE_STREAMINFO if !had_comment => {
+					iterator.insert_block_after(MetadataObject::vorbis_comment(&comment.take().unwrap()))?;
+					true
+				},
+				FLAC__METADATA_TYPE_VORBIS_COMMENT if comment.is_some() => {
+					iterator.set_block(MetadataObject::vorbis_comment(&comment.take().unwrap()))?;
+					true
+				},
+				FLAC__METADATA_TYPE_PICTURE if pictures_changed => {
+					iterator.delete_block()?;
+					false
+				},
+				_ => false,
+			};
+			if placed && pictures_changed {
+				for picture in &tags.pictures {
+					iterator.insert_block_after(MetadataObject::picture(picture))?;
+				}
+			}
+			if !iterator.next() {
+				break;
+			}
+		}
+	}
+	chain.write()
+}
@@ -0,0 +1,435 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+#![allow(non_camel_case_types)]
+#![allow(dead_code)]
+
+use cty;
+
+pub type FLAC__int8 = i8;
+pub type FLAC__uint8 = u8;
+
+pub type FLAC__int16 = i16;
+pub type FLAC__int32 = i32;
+pub type FLAC__int64 = i64;
+
+pub type FLAC__uint16 = u16;
+pub type FLAC__uint32 = u32;
+pub type FLAC__uint64 = u64;
+
+pub type FLAC__bool = cty::c_int;
+pub type FLAC__byte = FLAC__uint8;
+
+
+#[repr(C)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum FLAC__StreamDecoderState {
+	FLAC__STREAM_DECODER_SEARCH_FOR_METADATA,
+	FLAC__STREAM_DECODER_READ_METADATA,
+	FLAC__STREAM_DECODER_SEARCH_FOR_FRAME_SYNC,
+	FLAC__STREAM_DECODER_READ_FRAME,
+	FLAC__STREAM_DECODER_END_OF_STREAM,
+	FLAC__STREAM_DECODER_OGG_ERROR,
+	FLAC__STREAM_DECODER_SEEK_ERROR,
+	FLAC__STREAM_DECODER_ABORTED,
+	FLAC__STREAM_DECODER_MEMORY_ALLOCATION_ERROR,
+	FLAC__STREAM_DECODER_UNINITIALIZED,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum FLAC__StreamDecoderInitStatus {
+	FLAC__STREAM_DECODER_INIT_STATUS_OK = 0,
+	FLAC__STREAM_DECODER_INIT_STATUS_UNSUPPORTED_CONTAINER,
+	FLAC__STREAM_DECODER_INIT_STATUS_INVALID_CALLBACKS,
+	FLAC__STREAM_DECODER_INIT_STATUS_MEMORY_ALLOCATION_ERROR,
+	FLAC__STREAM_DECODER_INIT_STATUS_ERROR_OPENING_FILE,
+	FLAC__STREAM_DECODER_INIT_STATUS_ALREADY_INITIALIZED,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum FLAC__StreamEncoderInitStatus {
+    FLAC__STREAM_ENCODER_INIT_STATUS_OK = 0,
+    FLAC__STREAM_ENCODER_INIT_STATUS_ENCODER_ERROR,
+    FLAC__STREAM_ENCODER_INIT_STATUS_UNSUPPORTED_CONTAINER,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_CALLBACKS,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_NUMBER_OF_CHANNELS,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_BITS_PER_SAMPLE,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_SAMPLE_RATE,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_BLOCK_SIZE,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_MAX_LPC_ORDER,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_QLP_COEFF_PRECISION,
+    FLAC__STREAM_ENCODER_INIT_STATUS_BLOCK_SIZE_TOO_SMALL_FOR_LPC_ORDER,
+    FLAC__STREAM_ENCODER_INIT_STATUS_NOT_STREAMABLE,
+    FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_METADATA,
+    FLAC__STREAM_ENCODER_INIT_STATUS_ALREADY_INITIALIZED
+}
+
+#[repr(C)]
+pub enum FLAC__StreamDecoderReadStatus {
+	FLAC__STREAM_DECODER_READ_STATUS_CONTINUE,
+	FLAC__STREAM_DECODER_READ_STATUS_END_OF_STREAM,
+	FLAC__STREAM_DECODER_READ_STATUS_ABORT,
+}
+
+
+#[repr(C)]
+pub enum FLAC__StreamDecoderSeekStatus {
+	FLAC__STREAM_DECODER_SEEK_STATUS_OK,
+	FLAC__STREAM_DECODER_SEEK_STATUS_ERROR,
+	FLAC__STREAM_DECODER_SEEK_STATUS_UNSUPPORTED,
+}
+
+#[repr(C)]
+pub enum FLAC__StreamDecoderTellStatus {
+	FLAC__STREAM_DECODER_TELL_STATUS_OK,
+	FLAC__STREAM_DECODER_TELL_STATUS_ERROR,
+	FLAC__STREAM_DECODER_TELL_STATUS_UNSUPPORTED,
+}
+
+#[repr(C)]
+pub enum FLAC__StreamDecoderLengthStatus {
+	FLAC__STREAM_DECODER_LENGTH_STATUS_OK,
+	FLAC__STREAM_DECODER_LENGTH_STATUS_ERROR,
+	FLAC__STREAM_DECODER_LENGTH_STATUS_UNSUPPORTED,
+}
+
+#[repr(C)]
+pub enum FLAC__StreamDecoderWriteStatus {
+	FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE,
+	FLAC__STREAM_DECODER_WRITE_STATUS_ABORT,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub enum FLAC__StreamDecoderErrorStatus {
+	FLAC__STREAM_DECODER_ERROR_STATUS_LOST_SYNC,
+	FLAC__STREAM_DECODER_ERROR_STATUS_BAD_HEADER,
+	FLAC__STREAM_DECODER_ERROR_STATUS_FRAME_CRC_MISMATCH,
+	FLAC__STREAM_DECODER_ERROR_STATUS_UNPARSEABLE_STREAM,
+	FLAC__STREAM_DECODER_ERROR_STATUS_BAD_METADATA,
+}
+
+#[repr(C)]
+pub enum FLAC__StreamEncoderWriteStatus {
+	FLAC__STREAM_ENCODER_WRITE_STATUS_OK,
+	FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR,
+}
+
+#[repr(C)]
+pub enum FLAC__StreamEncoderSeekStatus {
+	FLAC__STREAM_ENCODER_SEEK_STATUS_OK,
+	FLAC__STREAM_ENCODER_SEEK_STATUS_ERROR,
+	FLAC__STREAM_ENCODER_SEEK_STATUS_UNSUPPORTED,
+}
+
+#[repr(C)]
+pub enum FLAC__StreamEncoderTellStatus {
+	FLAC__STREAM_ENCODER_TELL_STATUS_OK,
+	FLAC__STREAM_ENCODER_TELL_STATUS_ERROR,
+	FLAC__STREAM_ENCODER_TELL_STATUS_UNSUPPORTED,
+}
+
+pub type FLAC__MetadataType = cty::c_int;
+
+pub const FLAC__METADATA_TYPE_STREAMINFO : FLAC__MetadataType = 0;
+pub const FLAC__METADATA_TYPE_PADDING : FLAC__MetadataType = 1;
+pub const FLAC__METADATA_TYPE_APPLICATION : FLAC__MetadataType = 2;
+pub const FLAC__METADATA_TYPE_SEEKTABLE : FLAC__MetadataType = 3;
+pub const FLAC__METADATA_TYPE_VORBIS_COMMENT : FLAC__MetadataType = 4;
+pub const FLAC__METADATA_TYPE_CUESHEET : FLAC__MetadataType = 5;
+pub const FLAC__METADATA_TYPE_PICTURE : FLAC__MetadataType = 6;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_StreamInfo {
+	pub min_blocksize : cty::c_uint,
+	pub max_blocksize : cty::c_uint,
+	pub min_framesize : cty::c_uint,
+	pub max_framesize : cty::c_uint,
+	pub sample_rate : cty::c_uint,
+	pub channels : cty::c_uint,
+	pub bits_per_sample : cty::c_uint,
+	pub total_samples : FLAC__uint64,
+	pub md5sum : [FLAC__byte; 16],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_Padding {
+	pub dummy : cty::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_Application {
+	pub id : [FLAC__byte; 4],
+	pub data : *mut FLAC__byte,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_SeekPoint {
+	pub sample_number : FLAC__uint64,
+	pub stream_offset : FLAC__uint64,
+	pub frame_samples : cty::c_uint,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_SeekTable {
+	pub num_points : cty::c_uint,
+	pub points : *mut FLAC__StreamMetadata_SeekPoint,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_VorbisComment_Entry {
+	pub length : FLAC__uint32,
+	pub entry : *mut FLAC__byte,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_VorbisComment {
+	pub vendor_string : FLAC__StreamMetadata_VorbisComment_Entry,
+	pub num_comments : FLAC__uint32,
+	pub comments : *mut FLAC__StreamMetadata_VorbisComment_Entry,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_CueSheet_Index {
+	pub offset : FLAC__uint64,
+	pub number : FLAC__byte,
+}
+
+// `type:1` and `pre_emphasis:1` are C bit-fields sharing the byte after isrc
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_CueSheet_Track {
+	pub offset : FLAC__uint64,
+	pub number : FLAC__byte,
+	pub isrc : [cty::c_char; 13],
+	pub type_pre_emphasis : FLAC__byte,
+	pub num_indices : FLAC__byte,
+	pub indices : *mut FLAC__StreamMetadata_CueSheet_Index,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_CueSheet {
+	pub media_catalog_number : [cty::c_char; 129],
+	pub lead_in : FLAC__uint64,
+	pub is_cd : FLAC__bool,
+	pub num_tracks : cty::c_uint,
+	pub tracks : *mut FLAC__StreamMetadata_CueSheet_Track,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_Picture {
+	pub type_ : cty::c_int,
+	pub mime_type : *mut cty::c_char,
+	pub description : *mut FLAC__byte,
+	pub width : FLAC__uint32,
+	pub height : FLAC__uint32,
+	pub depth : FLAC__uint32,
+	pub colors : FLAC__uint32,
+	pub data_length : FLAC__uint32,
+	pub data : *mut FLAC__byte,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FLAC__StreamMetadata_Unknown {
+	pub data : *mut FLAC__byte,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union FLAC__StreamMetadata_Data {
+	pub stream_info : FLAC__StreamMetadata_StreamInfo,
+	pub padding : FLAC__StreamMetadata_Padding,
+	pub application : FLAC__StreamMetadata_Application,
+	pub seek_table : FLAC__StreamMetadata_SeekTable,
+	pub vorbis_comment : FLAC__StreamMetadata_VorbisComment,
+	pub cue_sheet : FLAC__StreamMetadata_CueSheet,
+	pub picture : FLAC__StreamMetadata_Picture,
+	pub unknown : FLAC__StreamMetadata_Unknown,
+}
+
+#[repr(C)]
+pub struct FLAC__StreamMetadata {
+	pub type_ : FLAC__MetadataType,
+	pub is_last : FLAC__bool,
+	pub length : cty::c_uint,
+	pub data : FLAC__StreamMetadata_Data,
+}
+
+pub type FLAC__StreamDecoder = cty::c_void;
+
+#[repr(C)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum FLAC__ChannelAssignment {
+	FLAC__CHANNEL_ASSIGNMENT_INDEPENDENT = 0,
+	FLAC__CHANNEL_ASSIGNMENT_LEFT_SIDE = 1,
+	FLAC__CHANNEL_ASSIGNMENT_RIGHT_SIDE = 2,
+	FLAC__CHANNEL_ASSIGNMENT_MID_SIDE = 3,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum FLAC__FrameNumberType {
+	FLAC__FRAME_NUMBER_TYPE_FRAME_NUMBER,
+	FLAC__FRAME_NUMBER_TYPE_SAMPLE_NUMBER,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union FLAC__FrameHeader_Number {
+	pub frame_number : FLAC__uint32,
+	pub sample_number : FLAC__uint64,
+}
+
+#[repr(C)]
+pub struct FLAC__FrameHeader {
+	pub blocksize : cty::c_uint,
+	pub sample_rate : cty::c_uint,
+	pub channels : cty::c_uint,
+	pub channel_assignment : FLAC__ChannelAssignment,
+	pub bits_per_sample : cty::c_uint,
+	pub number_type : FLAC__FrameNumberType,
+	pub number : FLAC__FrameHeader_Number,
+	pub crc : FLAC__uint8,
+}
+
+// Only ever read through libFLAC's pointer, so the subframes and footer
+// that follow the header are left out
+#[repr(C)]
+pub struct FLAC__Frame {
+	pub header : FLAC__FrameHeader,
+}
+
+pub type FLAC__StreamEncoder = cty::c_void;
+
+pub type FLAC__Metadata_Chain = cty::c_void;
+pub type FLAC__Metadata_Iterator = cty::c_void;
+pub type FLAC__Metadata_ChainStatus = cty::c_int;
+
+pub type FLAC__StreamDecoderReadCallback = Option<extern "C" fn(*const FLAC__StreamDecoder, *mut FLAC__byte, *mut usize, *mut cty::c_void) -> FLAC__StreamDecoderReadStatus>;
+pub type FLAC__StreamDecoderSeekCallback = Option<extern "C" fn(*const FLAC__StreamDecoder, FLAC__uint64, *mut cty::c_void) -> FLAC__StreamDecoderSeekStatus>;
+pub type FLAC__StreamDecoderTellCallback = Option<extern "C" fn(*const FLAC__StreamDecoder, *mut FLAC__uint64, *mut cty::c_void) -> FLAC__StreamDecoderTellStatus>;
+pub type FLAC__StreamDecoderLengthCallback = Option<extern "C" fn(*const FLAC__StreamDecoder, *mut FLAC__uint64, *mut cty::c_void) -> FLAC__StreamDecoderLengthStatus>;
+pub type FLAC__StreamDecoderEofCallback = Option<extern "C" fn(*const FLAC__StreamDecoder, *mut cty::c_void) -> FLAC__bool>;
+pub type FLAC__StreamDecoderWriteCallback =  Option<extern "C" fn(*const FLAC__StreamDecoder, *const FLAC__Frame, *const *const FLAC__int32, *mut cty::c_void) -> FLAC__StreamDecoderWriteStatus>;
+pub type FLAC__StreamDecoderMetadataCallback = Option<extern "C" fn(*const FLAC__StreamDecoder, *const FLAC__StreamMetadata, *mut cty::c_void)>;
+pub type FLAC__StreamDecoderErrorCallback = Option<extern "C" fn(*const FLAC__StreamDecoder, FLAC__StreamDecoderErrorStatus, *mut cty::c_void)>;
+
+pub type FLAC__StreamEncoderWriteCallback = Option<extern "C" fn(*const FLAC__StreamEncoder, *const FLAC__byte, usize, cty::c_uint, cty::c_uint, *mut cty::c_void) -> FLAC__StreamEncoderWriteStatus>;
+pub type FLAC__StreamEncoderSeekCallback = Option<extern "C" fn(*const FLAC__StreamEncoder, FLAC__uint64, *mut cty::c_void) -> FLAC__StreamEncoderSeekStatus>;
+pub type FLAC__StreamEncoderTellCallback = Option<extern "C" fn(*const FLAC__StreamEncoder, *mut FLAC__uint64, *mut cty::c_void) -> FLAC__StreamEncoderTellStatus>;
+pub type FLAC__StreamEncoderMetadataCallback = Option<extern "C" fn(*const FLAC__StreamEncoder, *const FLAC__StreamMetadata, *mut cty::c_void)>;
+
+extern "C" {
+
+pub fn FLAC__stream_decoder_new() -> *mut FLAC__StreamDecoder;
+pub fn FLAC__stream_decoder_delete(decoder: *mut FLAC__StreamDecoder);
+
+pub fn FLAC__stream_decoder_init_stream(decoder: *mut FLAC__StreamDecoder,
+	read_callback: FLAC__StreamDecoderReadCallback,
+	seek_callback: FLAC__StreamDecoderSeekCallback,
+	tell_callback: FLAC__StreamDecoderTellCallback,
+	length_callback: FLAC__StreamDecoderLengthCallback,
+	eof_callback: FLAC__StreamDecoderEofCallback,
+	write_callback: FLAC__StreamDecoderWriteCallback,
+	metadata_callback: FLAC__StreamDecoderMetadataCallback,
+	error_callback: FLAC__StreamDecoderErrorCallback,
+	client_data: *mut cty::c_void) -> FLAC__StreamDecoderInitStatus;
+
+pub fn FLAC__stream_decoder_get_state(decoder: *const FLAC__StreamDecoder) -> FLAC__StreamDecoderState;
+
+pub fn FLAC__stream_decoder_set_md5_checking(decoder: *mut FLAC__StreamDecoder, value: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__stream_decoder_set_metadata_respond_all(decoder: *mut FLAC__StreamDecoder) -> FLAC__bool;
+pub fn FLAC__stream_decoder_get_channels(decoder: *const FLAC__StreamDecoder) -> cty::c_uint;
+pub fn FLAC__stream_decoder_get_bits_per_sample(decoder: *const FLAC__StreamDecoder) -> cty::c_uint;
+pub fn FLAC__stream_decoder_get_sample_rate(decoder: *const FLAC__StreamDecoder) -> cty::c_uint;
+pub fn FLAC__stream_decoder_get_blocksize(decoder: *const FLAC__StreamDecoder) -> cty::c_uint;
+pub fn FLAC__stream_decoder_process_until_end_of_metadata(decoder: *mut FLAC__StreamDecoder) -> FLAC__bool;
+pub fn FLAC__stream_decoder_process_until_end_of_stream(decoder: *mut FLAC__StreamDecoder) -> FLAC__bool;
+pub fn FLAC__stream_decoder_process_single(decoder: *mut FLAC__StreamDecoder) -> FLAC__bool;
+pub fn FLAC__stream_decoder_seek_absolute(decoder: *mut FLAC__StreamDecoder, sample: FLAC__uint64) -> FLAC__bool;
+pub fn FLAC__stream_decoder_get_total_samples(decoder: *const FLAC__StreamDecoder) -> FLAC__uint64;
+pub fn FLAC__stream_decoder_flush(decoder: *mut FLAC__StreamDecoder) -> FLAC__bool;
+
+pub fn FLAC__stream_decoder_finish(decoder: *mut FLAC__StreamDecoder) -> FLAC__bool;
+
+pub fn FLAC__stream_encoder_new() -> *mut FLAC__StreamEncoder;
+pub fn FLAC__stream_encoder_init_stream(encoder: *mut FLAC__StreamEncoder,
+    write_callback: FLAC__StreamEncoderWriteCallback,
+    seek_callback: FLAC__StreamEncoderSeekCallback,
+    tell_callback: FLAC__StreamEncoderTellCallback,
+    metadata_callback: FLAC__StreamEncoderMetadataCallback,
+    client_data: *mut cty::c_void) -> FLAC__StreamEncoderInitStatus;
+pub fn FLAC__stream_encoder_set_channels(encoder: *mut FLAC__StreamEncoder, value: cty::c_uint) -> FLAC__bool;
+pub fn FLAC__stream_encoder_set_bits_per_sample(encoder: *mut FLAC__StreamEncoder, value: cty::c_uint) -> FLAC__bool;
+pub fn FLAC__stream_encoder_set_sample_rate(encoder: *mut FLAC__StreamEncoder, value: cty::c_uint) -> FLAC__bool;
+pub fn FLAC__stream_encoder_set_total_samples_estimate(encoder: *mut FLAC__StreamEncoder, value: FLAC__uint64) -> FLAC__bool;
+pub fn FLAC__stream_encoder_set_metadata(encoder: *mut FLAC__StreamEncoder, metadata: *mut *mut FLAC__StreamMetadata, num_blocks: cty::c_uint) -> FLAC__bool;
+pub fn FLAC__stream_encoder_process_interleaved(encoder: *mut FLAC__StreamEncoder, buffer: *const FLAC__int32, samples: cty::c_uint) -> FLAC__bool;
+pub fn FLAC__stream_encoder_finish(encoder: *mut FLAC__StreamEncoder) -> FLAC__bool;
+pub fn FLAC__stream_encoder_delete(encoder: *mut FLAC__StreamEncoder);
+
+pub fn FLAC__metadata_object_new(type_: FLAC__MetadataType) -> *mut FLAC__StreamMetadata;
+pub fn FLAC__metadata_object_delete(object: *mut FLAC__StreamMetadata);
+pub fn FLAC__metadata_object_application_set_data(object: *mut FLAC__StreamMetadata, data: *mut FLAC__byte, length: cty::c_uint, copy: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_object_seektable_template_append_point(object: *mut FLAC__StreamMetadata, sample_number: FLAC__uint64) -> FLAC__bool;
+pub fn FLAC__metadata_object_seektable_template_append_spaced_points_by_samples(object: *mut FLAC__StreamMetadata, samples: cty::c_uint, total_samples: FLAC__uint64) -> FLAC__bool;
+pub fn FLAC__metadata_object_seektable_template_sort(object: *mut FLAC__StreamMetadata, compact: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_object_vorbiscomment_set_vendor_string(object: *mut FLAC__StreamMetadata, entry: FLAC__StreamMetadata_VorbisComment_Entry, copy: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_object_vorbiscomment_append_comment(object: *mut FLAC__StreamMetadata, entry: FLAC__StreamMetadata_VorbisComment_Entry, copy: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_object_cuesheet_insert_track(object: *mut FLAC__StreamMetadata, track_num: cty::c_uint, track: *mut FLAC__StreamMetadata_CueSheet_Track, copy: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_object_cuesheet_is_legal(object: *const FLAC__StreamMetadata, check_cd_da_subset: FLAC__bool, violation: *mut *const cty::c_char) -> FLAC__bool;
+pub fn FLAC__metadata_object_picture_set_mime_type(object: *mut FLAC__StreamMetadata, mime_type: *mut cty::c_char, copy: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_object_picture_set_description(object: *mut FLAC__StreamMetadata, description: *mut FLAC__byte, copy: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_object_picture_set_data(object: *mut FLAC__StreamMetadata, data: *mut FLAC__byte, length: FLAC__uint32, copy: FLAC__bool) -> FLAC__bool;
+
+pub fn FLAC__metadata_chain_new() -> *mut FLAC__Metadata_Chain;
+pub fn FLAC__metadata_chain_delete(chain: *mut FLAC__Metadata_Chain);
+pub fn FLAC__metadata_chain_status(chain: *mut FLAC__Metadata_Chain) -> FLAC__Metadata_ChainStatus;
+pub fn FLAC__metadata_chain_read(chain: *mut FLAC__Metadata_Chain, filename: *const cty::c_char) -> FLAC__bool;
+pub fn FLAC__metadata_chain_write(chain: *mut FLAC__Metadata_Chain, use_padding: FLAC__bool, preserve_file_stats: FLAC__bool) -> FLAC__bool;
+pub fn FLAC__metadata_chain_sort_padding(chain: *mut FLAC__Metadata_Chain);
+pub fn FLAC__metadata_chain_check_if_tempfile_needed(chain: *mut FLAC__Metadata_Chain, use_padding: FLAC__bool) -> FLAC__bool;
+
+pub fn FLAC__metadata_iterator_new() -> *mut FLAC__Metadata_Iterator;
+pub fn FLAC__metadata_iterator_delete(iterator: *mut FLAC__Metadata_Iterator);
+pub fn FLAC__metadata_iterator_init(iterator: *mut FLAC__Metadata_Iterator, chain: *mut FLAC__Metadata_Chain);
+pub fn FLAC__metadata_iterator_next(iterator: *mut FLAC__Metadata_Iterator) -> FLAC__bool;
+pub fn FLAC__metadata_iterator_get_block_type(iterator: *const FLAC__Metadata_Iterator) -> FLAC__MetadataType;
+pub fn FLAC__metadata_iterator_get_block(iterator: *mut FLAC__Metadata_Iterator) -> *mut FLAC__StreamMetadata;
+pub fn FLAC__metadata_iterator_set_block(iterator: *mut FLAC__Metadata_Iterator, block: *mut FLAC__StreamMetadata) -> FLAC__bool;
+pub fn FLAC__metadata_iterator_insert_block_after(iterator: *mut FLAC__Metadata_Iterator, block: *mut FLAC__StreamMetadata) -> FLAC__bool;
+pub fn FLAC__metadata_iterator_delete_block(iterator: *mut FLAC__Metadata_Iterator, replace_with_padding: FLAC__bool) -> FLAC__bool;
+
+}
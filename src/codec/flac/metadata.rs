@@ -0,0 +1,509 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::convert::TryInto;
+use std::ffi::{CStr, CString};
+use std::slice;
+
+use crate::codec::{Metadata, Picture};
+use crate::codec::metadata::default_channel_mask;
+use crate::codec::markers::{self, MarkerReader, RIFF_APPLICATION_ID};
+use crate::cue::{self, CueTrack, CD_FRAMES_PER_SECOND};
+
+use super::ffi::*;
+
+#[derive(Clone, Debug, Default)]
+pub struct StreamInfo {
+	pub min_blocksize : u32,
+	pub max_blocksize : u32,
+	pub min_framesize : u32,
+	pub max_framesize : u32,
+	pub sample_rate : u32,
+	pub channels : u32,
+	pub bits_per_sample : u32,
+	pub total_samples : u64,
+	pub md5sum : [u8; 16],
+}
+
+#[derive(Clone, Debug)]
+pub struct SeekPoint {
+	pub sample_number : u64,
+	pub stream_offset : u64,
+	pub frame_samples : u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VorbisComment {
+	pub vendor : String,
+	pub comments : Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CueSheetIndex {
+	pub offset : u64,
+	pub number : u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct CueSheetTrack {
+	pub offset : u64,
+	pub number : u8,
+	pub isrc : String,
+	pub audio : bool,
+	pub pre_emphasis : bool,
+	pub indices : Vec<CueSheetIndex>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CueSheet {
+	pub media_catalog_number : String,
+	pub lead_in : u64,
+	pub is_cd : bool,
+	pub tracks : Vec<CueSheetTrack>,
+}
+
+// Track numbers FLAC reserves for the lead-out
+const CD_LEAD_OUT : u8 = 170;
+const LEAD_OUT : u8 = 255;
+
+impl CueSheet {
+	// Offsets become CD frames, with the exact start kept for tracks between
+	// frames; the lead-out is implied by the stream length
+	pub fn to_cue_sheet(&self, sample_rate: usize) -> cue::CueSheet {
+		let frames = |samples: u64| samples * CD_FRAMES_PER_SECOND / sample_rate.max(1) as u64;
+		let sample_start = |t: &CueSheetTrack| {
+			let index = t.indices.iter().find(|i| i.number == 1).or_else(|| t.indices.first())?;
+			let start = t.offset + index.offset;
+			Some(start).filter(|&start| cue::frames_to_samples(frames(start), sample_rate) != start)
+		};
+		let catalog = self.media_catalog_number.trim();
+		cue::CueSheet {
+			catalog: if catalog.is_empty() { None } else { Some(catalog.to_string()) },
+			files: vec![String::new()],
+			tracks: self.tracks.iter()
+				.filter(|t| t.number != CD_LEAD_OUT && t.number != LEAD_OUT)
+				.map(|t| CueTrack {
+					number: t.number as u32,
+					file: 0,
+					data_type: if t.audio { "AUDIO" } else { "MODE1/2352" }.to_string(),
+					isrc: if t.isrc.is_empty() { None } else { Some(t.isrc.clone()) },
+					flags: if t.pre_emphasis { vec!["PRE".to_string()] } else { Vec::new() },
+					indices: t.indices.iter().map(|i| (i.number as u32, frames(t.offset + i.offset))).collect(),
+					sample_start: sample_start(t),
+					..CueTrack::default()
+				})
+				.collect(),
+			..cue::CueSheet::default()
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub enum MetadataBlock {
+	StreamInfo(StreamInfo),
+	Padding(usize),
+	Application { id : [u8; 4], data : Vec<u8> },
+	SeekTable(Vec<SeekPoint>),
+	VorbisComment(VorbisComment),
+	CueSheet(CueSheet),
+	Picture(Picture),
+	Unknown { block_type : u32, data : Vec<u8> },
+}
+
+impl VorbisComment {
+	// Field names compare case-insensitively, as the Vorbis comment spec requires
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.comments.iter()
+			.find(|(k, _)| k.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.as_str())
+	}
+
+	pub fn remove(&mut self, name: &str) {
+		self.comments.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+	}
+}
+
+// Folds the blocks of a FLAC stream into the format-neutral model
+pub fn to_metadata(blocks: Vec<MetadataBlock>) -> Metadata {
+	let mut metadata = Metadata::default();
+	let mut channels = 0;
+	let mut sample_rate = 0;
+	let mut marker_reader = MarkerReader::default();
+
+	for block in blocks {
+		match block {
+			MetadataBlock::StreamInfo(info) => {
+				channels = info.channels as usize;
+				sample_rate = info.sample_rate as usize;
+				if info.total_samples > 0 {
+					metadata.total_samples = Some(info.total_samples);
+				}
+				if info.md5sum != [0; 16] {
+					metadata.audio_md5 = Some(info.md5sum);
+				}
+			},
+			MetadataBlock::VorbisComment(vc) => {
+				for (name, value) in &vc.comments {
+					metadata.set(name, value);
+				}
+			},
+			MetadataBlock::Picture(picture) => metadata.pictures.push(picture),
+			MetadataBlock::CueSheet(sheet) => metadata.cue_sheet = Some(sheet.to_cue_sheet(sample_rate)),
+			// Marker chunks are lifted out, layout chunks dropped and other
+			// RIFF chunks pass through
+			MetadataBlock::Application { id, ref data } if marker_reader.read_application(id, data, &mut metadata) => {},
+			MetadataBlock::Application { id, ref data } if id == RIFF_APPLICATION_ID && markers::is_layout_chunk(data) => {},
+			MetadataBlock::Application { id, data } => metadata.applications.push((id, data)),
+			_ => {},
+		}
+	}
+
+	marker_reader.finish(&mut metadata);
+
+	if metadata.channel_mask.is_none() && channels > 2 {
+		metadata.channel_mask = Some(default_channel_mask(channels));
+	}
+	metadata
+}
+
+unsafe fn bytes<'a>(data: *const FLAC__byte, len: usize) -> &'a [u8] {
+	if data.is_null() || len == 0 {
+		&[]
+	} else {
+		slice::from_raw_parts(data, len)
+	}
+}
+
+unsafe fn entry_string(entry: &FLAC__StreamMetadata_VorbisComment_Entry) -> String {
+	String::from_utf8_lossy(bytes(entry.entry, entry.length as usize)).into_owned()
+}
+
+fn c_chars_string(chars: &[cty::c_char]) -> String {
+	let bytes : Vec<u8> = chars.iter()
+		.take_while(|&&c| c != 0)
+		.map(|&c| c as u8)
+		.collect();
+	String::from_utf8_lossy(&bytes).into_owned()
+}
+
+impl MetadataBlock {
+	// Copies a block handed out by libFLAC into owned Rust data
+	pub(super) unsafe fn from_raw(metadata: &FLAC__StreamMetadata) -> MetadataBlock {
+		let length = metadata.length as usize;
+
+		match metadata.type_ {
+			FLAC__METADATA_TYPE_STREAMINFO => {
+				let info = &metadata.data.stream_info;
+				MetadataBlock::StreamInfo(StreamInfo {
+					min_blocksize: info.min_blocksize,
+					max_blocksize: info.max_blocksize,
+					min_framesize: info.min_framesize,
+					max_framesize: info.max_framesize,
+					sample_rate: info.sample_rate,
+					channels: info.channels,
+					bits_per_sample: info.bits_per_sample,
+					total_samples: info.total_samples,
+					md5sum: info.md5sum,
+				})
+			},
+			FLAC__METADATA_TYPE_PADDING => MetadataBlock::Padding(length),
+			FLAC__METADATA_TYPE_APPLICATION => {
+				let app = &metadata.data.application;
+				MetadataBlock::Application {
+					id: app.id,
+					data: bytes(app.data, length.saturating_sub(4)).to_vec(),
+				}
+			},
+			FLAC__METADATA_TYPE_SEEKTABLE => {
+				let table = &metadata.data.seek_table;
+				let points = if table.points.is_null() {
+					&[]
+				} else {
+					slice::from_raw_parts(table.points, table.num_points as usize)
+				};
+				MetadataBlock::SeekTable(points.iter().map(|p| SeekPoint {
+					sample_number: p.sample_number,
+					stream_offset: p.stream_offset,
+					frame_samples: p.frame_samples,
+				}).collect())
+			},
+			FLAC__METADATA_TYPE_VORBIS_COMMENT => {
+				let vc = &metadata.data.vorbis_comment;
+				let entries = if vc.comments.is_null() {
+					&[]
+				} else {
+					slice::from_raw_parts(vc.comments, vc.num_comments as usize)
+				};
+				let mut comments = Vec::with_capacity(entries.len());
+				for entry in entries {
+					let text = entry_string(entry);
+					if let Some(eq) = text.find('=') {
+						comments.push((text[..eq].to_string(), text[eq+1..].to_string()));
+					}
+				}
+				MetadataBlock::VorbisComment(VorbisComment {
+					vendor: entry_string(&vc.vendor_string),
+					comments,
+				})
+			},
+			FLAC__METADATA_TYPE_CUESHEET => {
+				let cs = &metadata.data.cue_sheet;
+				let tracks = if cs.tracks.is_null() {
+					&[]
+				} else {
+					slice::from_raw_parts(cs.tracks, cs.num_tracks as usize)
+				};
+				MetadataBlock::CueSheet(CueSheet {
+					media_catalog_number: c_chars_string(&cs.media_catalog_number),
+					lead_in: cs.lead_in,
+					is_cd: cs.is_cd != 0,
+					tracks: tracks.iter().map(|t| {
+						let indices = if t.indices.is_null() {
+							&[]
+						} else {
+							slice::from_raw_parts(t.indices, t.num_indices as usize)
+						};
+						CueSheetTrack {
+							offset: t.offset,
+							number: t.number,
+							isrc: c_chars_string(&t.isrc),
+							audio: t.type_pre_emphasis & 0x01 == 0,
+							pre_emphasis: t.type_pre_emphasis & 0x02 != 0,
+							indices: indices.iter().map(|i| CueSheetIndex {
+								offset: i.offset,
+								number: i.number,
+							}).collect(),
+						}
+					}).collect(),
+				})
+			},
+			FLAC__METADATA_TYPE_PICTURE => {
+				let pic = &metadata.data.picture;
+				let mime_type = if pic.mime_type.is_null() {
+					String::new()
+				} else {
+					CStr::from_ptr(pic.mime_type).to_string_lossy().into_owned()
+				};
+				let description = if pic.description.is_null() {
+					String::new()
+				} else {
+					CStr::from_ptr(pic.description as *const cty::c_char).to_string_lossy().into_owned()
+				};
+				MetadataBlock::Picture(Picture {
+					picture_type: pic.type_ as u32,
+					mime_type,
+					description,
+					width: pic.width,
+					height: pic.height,
+					depth: pic.depth,
+					colors: pic.colors,
+					data: bytes(pic.data, pic.data_length as usize).to_vec(),
+				})
+			},
+			block_type => MetadataBlock::Unknown {
+				block_type: block_type as u32,
+				data: bytes(metadata.data.unknown.data, length).to_vec(),
+			},
+		}
+	}
+}
+
+// Owned libFLAC metadata object, kept alive for as long as an encoder refers to it
+pub(super) struct MetadataObject(*mut FLAC__StreamMetadata);
+
+impl MetadataObject {
+	fn new(type_: FLAC__MetadataType) -> MetadataObject {
+		let object = unsafe { FLAC__metadata_object_new(type_) };
+		if object.is_null() {
+			panic!("Failed to create FLAC metadata object");
+		}
+		MetadataObject(object)
+	}
+
+	pub(super) fn as_ptr(&self) -> *mut FLAC__StreamMetadata {
+		self.0
+	}
+
+	// Hands the object over to libFLAC, which frees it from then on
+	pub(super) fn into_raw(self) -> *mut FLAC__StreamMetadata {
+		let object = self.0;
+		std::mem::forget(self);
+		object
+	}
+
+	pub(super) fn padding(length: usize) -> MetadataObject {
+		let object = MetadataObject::new(FLAC__METADATA_TYPE_PADDING);
+		unsafe { (*object.0).length = length.try_into().unwrap(); }
+		object
+	}
+
+	// Placeholder points every `interval` samples, filled in by the encoder
+	pub(super) fn seek_table(interval: u64, total_samples: u64) -> MetadataObject {
+		let object = MetadataObject::new(FLAC__METADATA_TYPE_SEEKTABLE);
+		unsafe {
+			if FLAC__metadata_object_seektable_template_append_spaced_points_by_samples(object.0, interval.try_into().unwrap(), total_samples) != 1 {
+				panic!("Failed to build FLAC seek table");
+			}
+			if FLAC__metadata_object_seektable_template_sort(object.0, 1) != 1 {
+				panic!("Failed to sort FLAC seek table");
+			}
+		}
+		object
+	}
+
+	pub(super) fn vorbis_comment(comment: &VorbisComment) -> MetadataObject {
+		let object = MetadataObject::new(FLAC__METADATA_TYPE_VORBIS_COMMENT);
+		// libFLAC fills in its own vendor string otherwise
+		if !comment.vendor.is_empty() {
+			let mut vendor = comment.vendor.clone().into_bytes();
+			let entry = FLAC__StreamMetadata_VorbisComment_Entry {
+				length: vendor.len().try_into().unwrap(),
+				entry: vendor.as_mut_ptr(),
+			};
+			if unsafe { FLAC__metadata_object_vorbiscomment_set_vendor_string(object.0, entry, 1) } != 1 {
+				panic!("Failed to set FLAC vendor string");
+			}
+		}
+		for (name, value) in &comment.comments {
+			let mut text = format!("{}={}", name, value).into_bytes();
+			let entry = FLAC__StreamMetadata_VorbisComment_Entry {
+				length: text.len().try_into().unwrap(),
+				entry: text.as_mut_ptr(),
+			};
+			if unsafe { FLAC__metadata_object_vorbiscomment_append_comment(object.0, entry, 1) } != 1 {
+				panic!("Failed to add FLAC Vorbis comment");
+			}
+		}
+		object
+	}
+
+	pub(super) fn picture(picture: &Picture) -> MetadataObject {
+		let object = MetadataObject::new(FLAC__METADATA_TYPE_PICTURE);
+		let mime_type = CString::new(picture.mime_type.as_str()).unwrap();
+		let description = CString::new(picture.description.as_str()).unwrap();
+		let mut data = picture.data.clone();
+		unsafe {
+			let p = &mut (*object.0).data.picture;
+			p.type_ = picture.picture_type as cty::c_int;
+			p.width = picture.width;
+			p.height = picture.height;
+			p.depth = picture.depth;
+			p.colors = picture.colors;
+			if FLAC__metadata_object_picture_set_mime_type(object.0, mime_type.as_ptr() as *mut cty::c_char, 1) != 1
+				|| FLAC__metadata_object_picture_set_description(object.0, description.as_ptr() as *mut FLAC__byte, 1) != 1
+				|| FLAC__metadata_object_picture_set_data(object.0, data.as_mut_ptr(), data.len().try_into().unwrap(), 1) != 1 {
+				panic!("Failed to build FLAC picture");
+			}
+		}
+		object
+	}
+
+	// Falls back to a non-CD sheet when the layout breaks the CD-DA rules,
+	// e.g. for other sample rates or more than 99 tracks, and fails when the
+	// sheet breaks even the general ones
+	pub(super) fn cue_sheet(sheet: &cue::CueSheet, sample_rate: usize, total_samples: u64) -> Result<MetadataObject, String> {
+		let samples = |frames| cue::frames_to_samples(frames, sample_rate);
+		let object = MetadataObject::new(FLAC__METADATA_TYPE_CUESHEET);
+
+		let mut tracks : Vec<FLAC__StreamMetadata_CueSheet_Track> = Vec::new();
+		let mut indices : Vec<Vec<FLAC__StreamMetadata_CueSheet_Index>> = Vec::new();
+		for track in &sheet.tracks {
+			let position = |&(_, pos): &(u32, u64)| match track.sample_start {
+				Some(start) if pos == track.start() => start,
+				_ => samples(pos),
+			};
+			let offset = track.indices.iter().map(position).min().unwrap_or(0);
+			indices.push(track.indices.iter().map(|index| FLAC__StreamMetadata_CueSheet_Index {
+				offset: position(index) - offset,
+				number: index.0 as FLAC__byte,
+			}).collect());
+
+			let mut isrc = [0; 13];
+			for (c, b) in isrc.iter_mut().zip(track.isrc.as_deref().unwrap_or("").bytes().take(12)) {
+				*c = b as cty::c_char;
+			}
+			tracks.push(FLAC__StreamMetadata_CueSheet_Track {
+				offset,
+				number: track.number as FLAC__byte,
+				isrc,
+				type_pre_emphasis: (!track.is_audio()) as FLAC__byte | (track.pre_emphasis() as FLAC__byte) << 1,
+				num_indices: 0,
+				indices: std::ptr::null_mut(),
+			});
+		}
+
+		let mut is_cd = sample_rate == 44100;
+		tracks.push(FLAC__StreamMetadata_CueSheet_Track {
+			offset: total_samples,
+			number: if is_cd { CD_LEAD_OUT } else { LEAD_OUT },
+			isrc: [0; 13],
+			type_pre_emphasis: 0,
+			num_indices: 0,
+			indices: std::ptr::null_mut(),
+		});
+		indices.push(Vec::new());
+
+		unsafe {
+			for (c, b) in (*object.0).data.cue_sheet.media_catalog_number.iter_mut().zip(sheet.catalog.as_deref().unwrap_or("").bytes().take(128)) {
+				*c = b as cty::c_char;
+			}
+			// libFLAC copies each track along with its indices
+			for (n, (track, track_indices)) in tracks.iter_mut().zip(indices.iter_mut()).enumerate() {
+				track.num_indices = track_indices.len() as FLAC__byte;
+				track.indices = track_indices.as_mut_ptr();
+				if FLAC__metadata_object_cuesheet_insert_track(object.0, n as cty::c_uint, track, 1) != 1 {
+					panic!("Failed to build FLAC cue sheet");
+				}
+			}
+
+			let mut violation = std::ptr::null();
+			if is_cd {
+				(*object.0).data.cue_sheet.lead_in = 2 * 44100;
+				(*object.0).data.cue_sheet.is_cd = 1;
+				if FLAC__metadata_object_cuesheet_is_legal(object.0, 1, &mut violation) != 1 {
+					is_cd = false;
+					let cs = &mut (*object.0).data.cue_sheet;
+					cs.lead_in = 0;
+					cs.is_cd = 0;
+					(*cs.tracks.add(cs.num_tracks as usize - 1)).number = LEAD_OUT;
+				}
+			}
+			if !is_cd && FLAC__metadata_object_cuesheet_is_legal(object.0, 0, &mut violation) != 1 {
+				return Err(CStr::from_ptr(violation).to_string_lossy().into_owned());
+			}
+		}
+		Ok(object)
+	}
+
+	pub(super) fn application(id: [u8; 4], data: &[u8]) -> MetadataObject {
+		let object = MetadataObject::new(FLAC__METADATA_TYPE_APPLICATION);
+		let mut data = data.to_vec();
+		unsafe {
+			(*object.0).data.application.id = id;
+			if FLAC__metadata_object_application_set_data(object.0, data.as_mut_ptr(), data.len().try_into().unwrap(), 1) != 1 {
+				panic!("Failed to build FLAC application block");
+			}
+		}
+		object
+	}
+}
+
+impl Drop for MetadataObject {
+	fn drop(&mut self) {
+		unsafe { FLAC__metadata_object_delete(self.0); }
+	}
+}
@@ -0,0 +1,882 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::any::Any;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+
+use cty;
+
+use crate::codec::{decode_range, Decoder, EncoderOptions, Frame, Metadata};
+use crate::codec::markers;
+
+mod ffi;
+mod metadata;
+mod chain;
+
+use self::ffi::*;
+pub use self::metadata::*;
+pub use self::chain::{read_flac_tags, update_flac_tags};
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+trait WriteSeek: Write + Seek {}
+impl<T: Write + Seek> WriteSeek for T {}
+
+enum Input<'a> {
+	Seekable(Box<dyn ReadSeek + 'a>, u64),
+	Unseekable(Box<dyn Read + 'a>),
+}
+
+type Panic = Box<dyn Any + Send + 'static>;
+
+trait Client {
+	fn panic_slot(&mut self) -> &mut Option<Panic>;
+}
+
+struct DecoderClient<'a> {
+	input: Input<'a>,
+	tx: mpsc::Sender<Frame>,
+	metadata: Vec<MetadataBlock>,
+	header_sent: bool,
+	error: Option<FLAC__StreamDecoderErrorStatus>,
+	panic: Option<Panic>,
+}
+
+impl<'a> DecoderClient<'a> {
+	// Hands the collected metadata blocks on ahead of the first audio frame
+	fn send_header(&mut self) {
+		if self.header_sent {
+			return;
+		}
+		self.header_sent = true;
+
+		let info = self.metadata.iter().find_map(|block| match block {
+			MetadataBlock::StreamInfo(info) => Some(info.clone()),
+			_ => None,
+		}).unwrap_or_default();
+
+		let frame = Frame {
+			channels: info.channels as usize,
+			sample_rate: info.sample_rate as usize,
+			bits_per_sample: info.bits_per_sample as usize,
+			samples: Vec::new(),
+			metadata: Some(to_metadata(std::mem::take(&mut self.metadata))),
+			eof: false,
+		};
+		self.tx.send(frame).unwrap();
+	}
+}
+
+impl<'a> Client for DecoderClient<'a> {
+	fn panic_slot(&mut self) -> &mut Option<Panic> {
+		&mut self.panic
+	}
+}
+
+struct EncoderClient<'a> {
+	output: &'a mut dyn WriteSeek,
+	panic: Option<Panic>,
+}
+
+impl<'a> Client for EncoderClient<'a> {
+	fn panic_slot(&mut self) -> &mut Option<Panic> {
+		&mut self.panic
+	}
+}
+
+// Runs a callback body without letting a panic unwind into libFLAC. The
+// payload is parked in the client and resumed once control is back in Rust.
+fn guarded<C: Client, T>(client: *mut cty::c_void, failed: T, body: impl FnOnce(&mut C) -> T) -> T {
+	let client = unsafe { &mut *(client as *mut C) };
+	if client.panic_slot().is_some() {
+		return failed;
+	}
+
+	match panic::catch_unwind(AssertUnwindSafe(|| body(&mut *client))) {
+		Ok(ret) => ret,
+		Err(payload) => {
+			*client.panic_slot() = Some(payload);
+			failed
+		},
+	}
+}
+
+// Owns a libFLAC decoder together with the client data its callbacks point at.
+// The handle is deleted before the client is freed.
+struct StreamDecoder<'a> {
+	decoder: *mut FLAC__StreamDecoder,
+	client: *mut DecoderClient<'a>,
+}
+
+impl<'a> StreamDecoder<'a> {
+	fn new(input: Input<'a>, tx: mpsc::Sender<Frame>) -> StreamDecoder<'a> {
+		let decoder = unsafe { FLAC__stream_decoder_new() };
+		if decoder.is_null() {
+			panic!("Failed to create FLAC decoder");
+		}
+
+		let seekable = match input {
+			Input::Seekable(_, _) => true,
+			Input::Unseekable(_) => false,
+		};
+		let client = Box::new(DecoderClient {
+			input,
+			tx,
+			metadata: Vec::new(),
+			header_sent: false,
+			error: None,
+			panic: None,
+		});
+		let stream = StreamDecoder {
+			decoder,
+			client: Box::into_raw(client),
+		};
+		let p_client = stream.client as *mut cty::c_void;
+
+		unsafe {
+			let md5_ret = FLAC__stream_decoder_set_md5_checking(decoder, 1);
+			if md5_ret != 1 {
+				panic!("Failed to set FLAC MD5 checksuming");
+			}
+
+			let respond_ret = FLAC__stream_decoder_set_metadata_respond_all(decoder);
+			if respond_ret != 1 {
+				panic!("Failed to request FLAC metadata blocks");
+			}
+
+			let init_ret = if seekable {
+				FLAC__stream_decoder_init_stream(decoder, Some(read_callback), Some(seek_callback), Some(tell_callback), Some(length_callback), Some(eof_callback), Some(write_callback), Some(metadata_callback), Some(error_callback), p_client)
+			} else {
+				FLAC__stream_decoder_init_stream(decoder, Some(read_callback), None, None, None, None, Some(write_callback), Some(metadata_callback), Some(error_callback), p_client)
+			};
+			if init_ret != FLAC__StreamDecoderInitStatus::FLAC__STREAM_DECODER_INIT_STATUS_OK {
+				panic!("Failed to initialize FLAC decoder");
+			}
+		}
+
+		stream
+	}
+
+	fn client(&mut self) -> &mut DecoderClient<'a> {
+		unsafe { &mut *self.client }
+	}
+
+	fn process_until_end_of_metadata(&mut self) -> bool {
+		let ret = unsafe { FLAC__stream_decoder_process_until_end_of_metadata(self.decoder) };
+		self.resume_panic();
+		if let Some(ref status) = self.client().error {
+			panic!("Error occurred during FLAC decoding: {:?}", status);
+		}
+		ret == 1
+	}
+
+	fn process_until_end_of_stream(&mut self) -> bool {
+		let ret = unsafe { FLAC__stream_decoder_process_until_end_of_stream(self.decoder) };
+		self.resume_panic();
+		if let Some(ref status) = self.client().error {
+			panic!("Error occurred during FLAC decoding: {:?}", status);
+		}
+		ret == 1
+	}
+
+	fn process_single(&mut self) -> bool {
+		let ret = unsafe { FLAC__stream_decoder_process_single(self.decoder) };
+		self.resume_panic();
+		if let Some(ref status) = self.client().error {
+			panic!("Error occurred during FLAC decoding: {:?}", status);
+		}
+		ret == 1
+	}
+
+	// Decodes the frame holding the sample, handing it over from that sample on
+	fn seek_absolute(&mut self, sample: u64) -> bool {
+		let ret = unsafe { FLAC__stream_decoder_seek_absolute(self.decoder, sample) };
+		self.resume_panic();
+		ret == 1
+	}
+
+	fn flush(&mut self) -> bool {
+		unsafe { FLAC__stream_decoder_flush(self.decoder) == 1 }
+	}
+
+	fn state(&self) -> FLAC__StreamDecoderState {
+		unsafe { FLAC__stream_decoder_get_state(self.decoder) }
+	}
+
+	fn total_samples(&self) -> u64 {
+		unsafe { FLAC__stream_decoder_get_total_samples(self.decoder) }
+	}
+
+	fn resume_panic(&mut self) {
+		if let Some(payload) = self.client().panic.take() {
+			panic::resume_unwind(payload);
+		}
+	}
+
+	fn channels(&self) -> usize {
+		unsafe { FLAC__stream_decoder_get_channels(self.decoder) as usize }
+	}
+
+	fn sample_rate(&self) -> usize {
+		unsafe { FLAC__stream_decoder_get_sample_rate(self.decoder) as usize }
+	}
+
+	fn bits_per_sample(&self) -> usize {
+		unsafe { FLAC__stream_decoder_get_bits_per_sample(self.decoder) as usize }
+	}
+}
+
+impl<'a> Drop for StreamDecoder<'a> {
+	fn drop(&mut self) {
+		unsafe {
+			FLAC__stream_decoder_delete(self.decoder);
+			drop(Box::from_raw(self.client));
+		}
+	}
+}
+
+// Owns a libFLAC encoder together with the client data its callbacks point at.
+// Deleting an initialized encoder flushes through the callbacks, so the client
+// is only freed afterwards.
+struct StreamEncoder<'a> {
+	encoder: *mut FLAC__StreamEncoder,
+	client: *mut EncoderClient<'a>,
+	metadata: Vec<MetadataObject>,
+}
+
+impl<'a> StreamEncoder<'a> {
+	fn new(output: &'a mut dyn WriteSeek) -> StreamEncoder<'a> {
+		let encoder = unsafe { FLAC__stream_encoder_new() };
+		if encoder.is_null() {
+			panic!("Failed to create FLAC encoder");
+		}
+
+		let client = Box::new(EncoderClient {
+			output,
+			panic: None,
+		});
+		StreamEncoder {
+			encoder,
+			client: Box::into_raw(client),
+			metadata: Vec::new(),
+		}
+	}
+
+	fn set_channels(&mut self, channels: usize) -> bool {
+		unsafe { FLAC__stream_encoder_set_channels(self.encoder, channels.try_into().unwrap()) == 1 }
+	}
+
+	fn set_bits_per_sample(&mut self, bits_per_sample: usize) -> bool {
+		unsafe { FLAC__stream_encoder_set_bits_per_sample(self.encoder, bits_per_sample.try_into().unwrap()) == 1 }
+	}
+
+	fn set_sample_rate(&mut self, sample_rate: usize) -> bool {
+		unsafe { FLAC__stream_encoder_set_sample_rate(self.encoder, sample_rate.try_into().unwrap()) == 1 }
+	}
+
+	fn set_total_samples_estimate(&mut self, total_samples: u64) -> bool {
+		unsafe { FLAC__stream_encoder_set_total_samples_estimate(self.encoder, total_samples) == 1 }
+	}
+
+	// libFLAC only copies the pointers, so the objects are kept until the encoder is deleted
+	fn set_metadata(&mut self, metadata: Vec<MetadataObject>) -> bool {
+		self.metadata = metadata;
+		let mut blocks : Vec<*mut FLAC__StreamMetadata> = self.metadata.iter().map(|m| m.as_ptr()).collect();
+		unsafe { FLAC__stream_encoder_set_metadata(self.encoder, blocks.as_mut_ptr(), blocks.len().try_into().unwrap()) == 1 }
+	}
+
+	fn init(&mut self) -> FLAC__StreamEncoderInitStatus {
+		unsafe { FLAC__stream_encoder_init_stream(self.encoder, Some(encoder_write_callback), Some(encoder_seek_callback), Some(encoder_tell_callback), None, self.client as *mut cty::c_void) }
+	}
+
+	fn process_interleaved(&mut self, samples: &[i32], channels: usize) -> bool {
+		let ret = unsafe { FLAC__stream_encoder_process_interleaved(self.encoder, samples.as_ptr(), (samples.len() / channels).try_into().unwrap()) };
+		self.resume_panic();
+		ret == 1
+	}
+
+	fn finish(&mut self) -> bool {
+		let ret = unsafe { FLAC__stream_encoder_finish(self.encoder) };
+		self.resume_panic();
+		ret == 1
+	}
+
+	fn resume_panic(&mut self) {
+		if let Some(payload) = unsafe { (*self.client).panic.take() } {
+			panic::resume_unwind(payload);
+		}
+	}
+}
+
+impl<'a> Drop for StreamEncoder<'a> {
+	fn drop(&mut self) {
+		unsafe {
+			FLAC__stream_encoder_delete(self.encoder);
+			drop(Box::from_raw(self.client));
+		}
+	}
+}
+
+extern "C" fn read_callback(_decoder: *const FLAC__StreamDecoder, buffer: *mut FLAC__byte, bytes: *mut usize, client: *mut cty::c_void) -> FLAC__StreamDecoderReadStatus {
+	let abort = FLAC__StreamDecoderReadStatus::FLAC__STREAM_DECODER_READ_STATUS_ABORT;
+	guarded(client, abort, |client: &mut DecoderClient| {
+		let buf = unsafe { std::slice::from_raw_parts_mut(buffer, *bytes) };
+		loop {
+			let read_ret = match client.input {
+				Input::Seekable(ref mut reader, _) => reader.read(buf),
+				Input::Unseekable(ref mut reader) => reader.read(buf),
+			};
+			match read_ret {
+				Ok(0) => {
+					unsafe { *bytes = 0; }
+					return FLAC__StreamDecoderReadStatus::FLAC__STREAM_DECODER_READ_STATUS_END_OF_STREAM;
+				},
+				Ok(n) => {
+					unsafe { *bytes = n; }
+					return FLAC__StreamDecoderReadStatus::FLAC__STREAM_DECODER_READ_STATUS_CONTINUE;
+				},
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(_) => {
+					unsafe { *bytes = 0; }
+					return FLAC__StreamDecoderReadStatus::FLAC__STREAM_DECODER_READ_STATUS_ABORT;
+				},
+			}
+		}
+	})
+}
+
+extern "C" fn seek_callback(_decoder: *const FLAC__StreamDecoder, offset: FLAC__uint64, client: *mut cty::c_void) -> FLAC__StreamDecoderSeekStatus {
+	let error = FLAC__StreamDecoderSeekStatus::FLAC__STREAM_DECODER_SEEK_STATUS_ERROR;
+	guarded(client, error, |client: &mut DecoderClient| {
+		match client.input {
+			Input::Seekable(ref mut reader, _) => match reader.seek(SeekFrom::Start(offset)) {
+				Ok(_) => FLAC__StreamDecoderSeekStatus::FLAC__STREAM_DECODER_SEEK_STATUS_OK,
+				Err(_) => FLAC__StreamDecoderSeekStatus::FLAC__STREAM_DECODER_SEEK_STATUS_ERROR,
+			},
+			Input::Unseekable(_) => FLAC__StreamDecoderSeekStatus::FLAC__STREAM_DECODER_SEEK_STATUS_UNSUPPORTED,
+		}
+	})
+}
+
+extern "C" fn tell_callback(_decoder: *const FLAC__StreamDecoder, offset: *mut FLAC__uint64, client: *mut cty::c_void) -> FLAC__StreamDecoderTellStatus {
+	let error = FLAC__StreamDecoderTellStatus::FLAC__STREAM_DECODER_TELL_STATUS_ERROR;
+	guarded(client, error, |client: &mut DecoderClient| {
+		match client.input {
+			Input::Seekable(ref mut reader, _) => match reader.stream_position() {
+				Ok(pos) => {
+					unsafe { *offset = pos; }
+					FLAC__StreamDecoderTellStatus::FLAC__STREAM_DECODER_TELL_STATUS_OK
+				},
+				Err(_) => FLAC__StreamDecoderTellStatus::FLAC__STREAM_DECODER_TELL_STATUS_ERROR,
+			},
+			Input::Unseekable(_) => FLAC__StreamDecoderTellStatus::FLAC__STREAM_DECODER_TELL_STATUS_UNSUPPORTED,
+		}
+	})
+}
+
+extern "C" fn length_callback(_decoder: *const FLAC__StreamDecoder, length: *mut FLAC__uint64, client: *mut cty::c_void) -> FLAC__StreamDecoderLengthStatus {
+	let error = FLAC__StreamDecoderLengthStatus::FLAC__STREAM_DECODER_LENGTH_STATUS_ERROR;
+	guarded(client, error, |client: &mut DecoderClient| {
+		match client.input {
+			Input::Seekable(_, len) => {
+				unsafe { *length = len; }
+				FLAC__StreamDecoderLengthStatus::FLAC__STREAM_DECODER_LENGTH_STATUS_OK
+			},
+			Input::Unseekable(_) => FLAC__StreamDecoderLengthStatus::FLAC__STREAM_DECODER_LENGTH_STATUS_UNSUPPORTED,
+		}
+	})
+}
+
+extern "C" fn eof_callback(_decoder: *const FLAC__StreamDecoder, client: *mut cty::c_void) -> FLAC__bool {
+	guarded(client, 1, |client: &mut DecoderClient| {
+		match client.input {
+			Input::Seekable(ref mut reader, len) => match reader.stream_position() {
+				Ok(pos) => (pos >= len) as FLAC__bool,
+				Err(_) => 1,
+			},
+			Input::Unseekable(_) => 0,
+		}
+	})
+}
+
+extern "C" fn write_callback(decoder: *const FLAC__StreamDecoder, frame: *const FLAC__Frame, buffer: *const *const FLAC__int32, client: *mut cty::c_void) -> FLAC__StreamDecoderWriteStatus {
+	let abort = FLAC__StreamDecoderWriteStatus::FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+	guarded(client, abort, |client: &mut DecoderClient| {
+		if client.error.is_some() {
+			return FLAC__StreamDecoderWriteStatus::FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+		}
+		client.send_header();
+
+		let channels = unsafe { FLAC__stream_decoder_get_channels(decoder) } as usize;
+		let sample_rate = unsafe { FLAC__stream_decoder_get_sample_rate(decoder) } as usize;
+		let bits_per_sample = unsafe { FLAC__stream_decoder_get_bits_per_sample(decoder) } as usize;
+		// After a seek libFLAC hands over only the rest of the block, which the
+		// frame header has the length of but the decoder's blocksize does not
+		let block_size = unsafe { (*frame).header.blocksize } as usize;
+
+		let ch_index = unsafe { std::slice::from_raw_parts(buffer, channels) };
+		let block_vec : Vec<&[FLAC__int32]> = ch_index.iter()
+			.map(|&ch| unsafe { std::slice::from_raw_parts(ch, block_size) })
+			.collect();
+
+		let mut packed : Vec<i32> = Vec::with_capacity(block_size * channels);
+		for i in 0..block_size {
+			for ch in &block_vec {
+				packed.push(ch[i]);
+			}
+		}
+
+		let frame = Frame {
+			channels,
+			sample_rate,
+			bits_per_sample,
+			samples: packed,
+			metadata: None,
+			eof: false,
+		};
+		client.tx.send(frame).unwrap();
+
+		FLAC__StreamDecoderWriteStatus::FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE
+	})
+}
+
+extern "C" fn metadata_callback(_decoder: *const FLAC__StreamDecoder, metadata: *const FLAC__StreamMetadata, client: *mut cty::c_void) {
+	guarded(client, (), |client: &mut DecoderClient| {
+		let metadata = unsafe { &*metadata };
+		client.metadata.push(unsafe { MetadataBlock::from_raw(metadata) });
+		if metadata.is_last != 0 {
+			client.send_header();
+		}
+	})
+}
+
+extern "C" fn error_callback(_decoder: *const FLAC__StreamDecoder, status: FLAC__StreamDecoderErrorStatus, client: *mut cty::c_void) {
+	guarded(client, (), |client: &mut DecoderClient| {
+		if client.error.is_none() {
+			client.error = Some(status);
+		}
+	})
+}
+
+extern "C" fn encoder_write_callback(_encoder: *const FLAC__StreamEncoder, buffer: *const FLAC__byte, bytes: usize, _samples: cty::c_uint, _current_frame: cty::c_uint, client: *mut cty::c_void) -> FLAC__StreamEncoderWriteStatus {
+	let fatal = FLAC__StreamEncoderWriteStatus::FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR;
+	guarded(client, fatal, |client: &mut EncoderClient| {
+		let buf = unsafe { std::slice::from_raw_parts(buffer, bytes) };
+		match client.output.write_all(buf) {
+			Ok(_) => FLAC__StreamEncoderWriteStatus::FLAC__STREAM_ENCODER_WRITE_STATUS_OK,
+			Err(_) => FLAC__StreamEncoderWriteStatus::FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR,
+		}
+	})
+}
+
+extern "C" fn encoder_seek_callback(_encoder: *const FLAC__StreamEncoder, offset: FLAC__uint64, client: *mut cty::c_void) -> FLAC__StreamEncoderSeekStatus {
+	let error = FLAC__StreamEncoderSeekStatus::FLAC__STREAM_ENCODER_SEEK_STATUS_ERROR;
+	guarded(client, error, |client: &mut EncoderClient| {
+		match client.output.seek(SeekFrom::Start(offset)) {
+			Ok(_) => FLAC__StreamEncoderSeekStatus::FLAC__STREAM_ENCODER_SEEK_STATUS_OK,
+			Err(_) => FLAC__StreamEncoderSeekStatus::FLAC__STREAM_ENCODER_SEEK_STATUS_ERROR,
+		}
+	})
+}
+
+extern "C" fn encoder_tell_callback(_encoder: *const FLAC__StreamEncoder, offset: *mut FLAC__uint64, client: *mut cty::c_void) -> FLAC__StreamEncoderTellStatus {
+	let error = FLAC__StreamEncoderTellStatus::FLAC__STREAM_ENCODER_TELL_STATUS_ERROR;
+	guarded(client, error, |client: &mut EncoderClient| {
+		match client.output.stream_position() {
+			Ok(pos) => {
+				unsafe { *offset = pos; }
+				FLAC__StreamEncoderTellStatus::FLAC__STREAM_ENCODER_TELL_STATUS_OK
+			},
+			Err(_) => FLAC__StreamEncoderTellStatus::FLAC__STREAM_ENCODER_TELL_STATUS_ERROR,
+		}
+	})
+}
+
+pub fn read_flac(path: &str, tx: mpsc::Sender<Frame>) {
+	let file = File::open(path).unwrap();
+	read_flac_stream(BufReader::new(file), tx);
+}
+
+// Decodes from any seekable source, e.g. a File or an in-memory Cursor
+pub fn read_flac_stream<R: Read + Seek>(reader: R, tx: mpsc::Sender<Frame>) {
+	let mut reader = reader;
+	let start = reader.stream_position().unwrap();
+	let length = reader.seek(SeekFrom::End(0)).unwrap();
+	reader.seek(SeekFrom::Start(start)).unwrap();
+
+	decode(Input::Seekable(Box::new(&mut reader), length), tx);
+}
+
+// Decodes from a forward-only source such as a pipe or network stream; seeking is disabled
+pub fn read_flac_pipe<R: Read>(reader: R, tx: mpsc::Sender<Frame>) {
+	let mut reader = reader;
+	decode(Input::Unseekable(Box::new(&mut reader)), tx);
+}
+
+// Just the header frame, without decoding any audio
+pub fn read_flac_header(path: &str) -> Frame {
+	let mut reader = BufReader::new(File::open(path).unwrap());
+	let length = reader.seek(SeekFrom::End(0)).unwrap();
+	reader.seek(SeekFrom::Start(0)).unwrap();
+
+	let (tx, rx) = mpsc::channel();
+	let mut decoder = StreamDecoder::new(Input::Seekable(Box::new(&mut reader), length), tx);
+	if !decoder.process_until_end_of_metadata() {
+		panic!("Error occurred during decoding FLAC");
+	}
+	decoder.client().send_header();
+	rx.recv().unwrap()
+}
+
+// Pulls audio frame by frame on request rather than decoding the whole
+// stream, so that it can be read from any sample on
+pub struct FlacDecoder {
+	decoder : StreamDecoder<'static>,
+	rx : mpsc::Receiver<Frame>,
+	channels : usize,
+	sample_rate : usize,
+	bits_per_sample : usize,
+	metadata : Metadata,
+	total_samples : Option<u64>,
+	position : u64,
+	// Decoded samples not yet read, interleaved
+	buffer : Vec<i32>,
+	ended : bool,
+}
+
+// The libFLAC handle and its client belong to this decoder alone, as does the file they read
+unsafe impl Send for FlacDecoder {}
+
+impl FlacDecoder {
+	pub fn open(path: &str) -> FlacDecoder {
+		let mut reader = BufReader::new(File::open(path).unwrap());
+		let length = reader.seek(SeekFrom::End(0)).unwrap();
+		reader.seek(SeekFrom::Start(0)).unwrap();
+
+		let (tx, rx) = mpsc::channel();
+		let mut decoder = StreamDecoder::new(Input::Seekable(Box::new(reader), length), tx);
+		if !decoder.process_until_end_of_metadata() {
+			panic!("Error occurred during decoding FLAC");
+		}
+		decoder.client().send_header();
+		let header = rx.recv().unwrap();
+		let total_samples = decoder.total_samples();
+
+		FlacDecoder {
+			decoder,
+			rx,
+			channels: header.channels,
+			sample_rate: header.sample_rate,
+			bits_per_sample: header.bits_per_sample,
+			metadata: header.metadata.unwrap(),
+			total_samples: if total_samples > 0 { Some(total_samples) } else { None },
+			position: 0,
+			buffer: Vec::new(),
+			ended: false,
+		}
+	}
+
+	// Decodes until there are enough samples buffered or the stream runs out
+	fn fill(&mut self, samples: usize) {
+		while self.buffer.len() < samples * self.channels && !self.ended {
+			if self.decoder.state() == FLAC__StreamDecoderState::FLAC__STREAM_DECODER_END_OF_STREAM {
+				self.ended = true;
+				break;
+			}
+			if !self.decoder.process_single() {
+				panic!("Error occurred during decoding FLAC");
+			}
+			for frame in self.rx.try_iter() {
+				self.buffer.extend_from_slice(&frame.samples);
+			}
+		}
+	}
+}
+
+impl Decoder for FlacDecoder {
+	fn header(&self) -> Frame {
+		Frame {
+			channels: self.channels,
+			sample_rate: self.sample_rate,
+			bits_per_sample: self.bits_per_sample,
+			samples: Vec::new(),
+			metadata: Some(self.metadata.clone()),
+			eof: false,
+		}
+	}
+
+	fn read(&mut self, max_samples: usize) -> Frame {
+		self.fill(max_samples);
+		let n = self.buffer.len().min(max_samples * self.channels);
+		let samples : Vec<i32> = self.buffer.drain(..n).collect();
+		self.position += (n / self.channels) as u64;
+		Frame {
+			channels: self.channels,
+			sample_rate: self.sample_rate,
+			bits_per_sample: self.bits_per_sample,
+			samples,
+			metadata: None,
+			eof: self.ended && self.buffer.is_empty(),
+		}
+	}
+
+	fn seek(&mut self, sample: u64) {
+		self.buffer.clear();
+		// libFLAC cannot seek onto the end itself, but there is nothing to decode there anyway
+		if let Some(total) = self.total_samples {
+			if sample > total {
+				panic!("Cannot seek FLAC to sample {} of {}", sample, total);
+			}
+			if sample == total {
+				self.position = total;
+				self.ended = true;
+				return;
+			}
+		}
+
+		// A failed seek leaves the decoder stuck until it is flushed, after
+		// which it is worth one more try
+		let seeked = self.decoder.seek_absolute(sample) || (
+			self.decoder.state() == FLAC__StreamDecoderState::FLAC__STREAM_DECODER_SEEK_ERROR &&
+			self.decoder.flush() &&
+			self.decoder.seek_absolute(sample)
+		);
+		if !seeked {
+			panic!("Failed to seek FLAC to sample {}", sample);
+		}
+		// libFLAC writes out the rest of the block it seeked into, which
+		// starts right at the sample asked for
+		for frame in self.rx.try_iter() {
+			self.buffer.extend_from_slice(&frame.samples);
+		}
+		self.position = sample;
+		self.ended = false;
+	}
+
+	fn total_samples(&self) -> Option<u64> {
+		self.total_samples
+	}
+
+	fn position(&self) -> u64 {
+		self.position
+	}
+}
+
+// Only the samples from start up to end, seeking straight to the first
+pub fn read_flac_range(path: &str, tx: mpsc::Sender<Frame>, start: u64, end: Option<u64>) {
+	decode_range(&mut FlacDecoder::open(path), tx, start, end);
+}
+
+fn decode(input: Input, tx: mpsc::Sender<Frame>) {
+	let mut decoder = StreamDecoder::new(input, tx);
+
+	if !decoder.process_until_end_of_stream() {
+		panic!("Error occurred during decoding FLAC");
+	}
+	decoder.client().send_header();
+
+	let frame = Frame {
+		channels: decoder.channels(),
+		sample_rate: decoder.sample_rate(),
+		bits_per_sample: decoder.bits_per_sample(),
+		samples: Vec::new(),
+		metadata: None,
+		eof: true,
+	};
+	decoder.client().tx.send(frame).unwrap();
+}
+
+
+
+pub fn write_flac(path: &str, rx: mpsc::Receiver<Frame>, options: &EncoderOptions) {
+	let mut file = BufWriter::new(File::create(path).unwrap());
+	write_flac_stream(&mut file, rx, options);
+	file.flush().unwrap();
+}
+
+// Merges the metadata carried in on the header frame with the encoder options
+fn output_metadata(metadata: &Metadata, options: &EncoderOptions, channels: usize, sample_rate: usize, total_samples: u64) -> Vec<MetadataObject> {
+	let mut comment = VorbisComment {
+		vendor: String::new(),
+		comments: metadata.to_vorbis_comments(channels),
+	};
+	// Most players that loop FLAC only know the single-loop comment convention
+	if let Some(first) = metadata.sampler.as_ref().and_then(|s| s.loops.first()) {
+		comment.comments.push(("LOOPSTART".to_string(), first.start.to_string()));
+		comment.comments.push(("LOOPLENGTH".to_string(), (first.end + 1).saturating_sub(first.start).to_string()));
+	}
+
+	let mut blocks = Vec::new();
+	if !comment.comments.is_empty() {
+		blocks.push(MetadataObject::vorbis_comment(&comment));
+	}
+	// The lead-out needs the length, and a sheet spanning several files has no single timeline
+	if let Some(ref sheet) = metadata.cue_sheet {
+		if total_samples > 0 && sheet.files.len() == 1 {
+			match MetadataObject::cue_sheet(sheet, sample_rate, total_samples) {
+				Ok(block) => blocks.push(block),
+				Err(violation) => eprintln!("Leaving out the cue sheet: {}", violation),
+			}
+		}
+	}
+	for picture in &metadata.pictures {
+		blocks.push(MetadataObject::picture(picture));
+	}
+	for (id, data) in metadata.applications.iter().chain(markers::applications(metadata).iter()) {
+		blocks.push(MetadataObject::application(*id, data));
+	}
+	if let Some(interval) = options.seekpoint_interval {
+		let interval = (interval * sample_rate as f64).round() as u64;
+		if interval > 0 && total_samples > 0 {
+			blocks.push(MetadataObject::seek_table(interval, total_samples));
+		}
+	}
+	if let Some(padding) = options.padding {
+		blocks.push(MetadataObject::padding(padding));
+	}
+	blocks
+}
+
+// STREAMINFO and the seek table are rewritten in place once encoding finishes
+pub fn write_flac_stream<W: Write + Seek>(writer: W, rx: mpsc::Receiver<Frame>, options: &EncoderOptions) {
+	let mut writer = writer;
+	let mut encoder = StreamEncoder::new(&mut writer);
+
+	let mut frame = rx.recv().unwrap();
+
+	if !encoder.set_channels(frame.channels) {
+		panic!("Failed to set FLAC channel count");
+	}
+	if !encoder.set_bits_per_sample(frame.bits_per_sample) {
+		panic!("Failed to set FLAC bits per sample");
+	}
+	if !encoder.set_sample_rate(frame.sample_rate) {
+		panic!("Failed to set FLAC sample rate");
+	}
+
+	let mut metadata = frame.metadata.take().unwrap_or_default();
+	metadata.apply(options);
+	let total_samples = if frame.eof {
+		(frame.samples.len() / frame.channels) as u64
+	} else {
+		metadata.total_samples.unwrap_or(0)
+	};
+	if total_samples > 0 && !encoder.set_total_samples_estimate(total_samples) {
+		panic!("Failed to set FLAC total samples estimate");
+	}
+	if !encoder.set_metadata(output_metadata(&metadata, options, frame.channels, frame.sample_rate, total_samples)) {
+		panic!("Failed to set FLAC metadata");
+	}
+
+	let init_ret = encoder.init();
+	if init_ret != FLAC__StreamEncoderInitStatus::FLAC__STREAM_ENCODER_INIT_STATUS_OK {
+		panic!("Failed to initialize FLAC encoder: {:?}", init_ret);
+	}
+
+	while ! frame.eof {
+		if !frame.samples.is_empty() && !encoder.process_interleaved(&frame.samples, frame.channels) {
+			panic!("Error occurred while encoding FLAC");
+		}
+		frame = rx.recv().unwrap();
+	}
+
+	if !frame.samples.is_empty() && !encoder.process_interleaved(&frame.samples, frame.channels) {
+		panic!("Error occurred while encoding FLAC");
+	}
+
+	if !encoder.finish() {
+		panic!("Failed to finish encoding FLAC");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CHANNELS : usize = 2;
+	const LENGTH : usize = 20000;
+
+	// Writes a stereo ramp long enough for several blocks, returning its path
+	// and samples
+	fn encode(name: &str) -> (String, Vec<i32>) {
+		let path = std::env::temp_dir().join(format!("chaud-{}-{}.flac", name, std::process::id()));
+		let path = path.to_str().unwrap().to_string();
+		let samples : Vec<i32> = (0..LENGTH * CHANNELS)
+			.map(|i| ((i * 37) % 2001) as i32 - 1000)
+			.collect();
+
+		let (tx, rx) = mpsc::channel();
+		tx.send(Frame { channels: CHANNELS, sample_rate: 44100, bits_per_sample: 16, samples: Vec::new(), metadata: None, eof: false }).unwrap();
+		tx.send(Frame { channels: CHANNELS, sample_rate: 44100, bits_per_sample: 16, samples: samples.clone(), metadata: None, eof: true }).unwrap();
+		write_flac(&path, rx, &EncoderOptions::default());
+		(path, samples)
+	}
+
+	fn decode_all(path: &str) -> Vec<i32> {
+		let (tx, rx) = mpsc::channel();
+		read_flac(path, tx);
+		rx.iter().flat_map(|frame| frame.samples).collect()
+	}
+
+	#[test]
+	fn seek_mid_block() {
+		let (path, samples) = encode("seek-mid-block");
+		assert_eq!(decode_all(&path), samples);
+
+		// Well inside the fourth 4096 sample block
+		let start = 3 * 4096 + 1000;
+		let mut decoder = FlacDecoder::open(&path);
+		decoder.seek(start as u64);
+		let frame = decoder.read(5000);
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(frame.samples, &samples[start * CHANNELS..(start + 5000) * CHANNELS]);
+	}
+	#[test]
+	fn seek_position() {
+		let (path, samples) = encode("seek-position");
+		let mut decoder = FlacDecoder::open(&path);
+		assert_eq!(decoder.total_samples(), Some(LENGTH as u64));
+
+		let start = 2 * 4096 + 123;
+		decoder.seek(start as u64);
+		assert_eq!(decoder.position(), start as u64);
+		let frame = decoder.read(1000);
+		assert_eq!(frame.samples, &samples[start * CHANNELS..(start + 1000) * CHANNELS]);
+		assert_eq!(decoder.position(), (start + 1000) as u64);
+
+		// The rest comes out exactly, up to the end of the stream
+		let frame = decoder.read(LENGTH);
+		assert!(frame.eof);
+		assert_eq!(frame.samples, &samples[(start + 1000) * CHANNELS..]);
+		assert_eq!(decoder.position(), LENGTH as u64);
+
+		// Backwards, and into the last block
+		decoder.seek(10);
+		assert_eq!(decoder.read(100).samples, &samples[10 * CHANNELS..110 * CHANNELS]);
+		decoder.seek((LENGTH - 50) as u64);
+		let frame = decoder.read(100);
+		std::fs::remove_file(&path).unwrap();
+		assert!(frame.eof);
+		assert_eq!(frame.samples, &samples[(LENGTH - 50) * CHANNELS..]);
+		assert_eq!(decoder.position(), LENGTH as u64);
+	}
+}
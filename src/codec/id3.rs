@@ -0,0 +1,421 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// ID3v2 tags, as embedded in `id3 ` chunks of WAV files. Frames map onto
+// Vorbis comment names as follows; TXXX frames use their description as
+// the name and everything else unknown is dropped.
+//
+//   TIT2 TITLE        TCON GENRE        TCOM COMPOSER
+//   TPE1 ARTIST       COMM COMMENT      TPOS DISCNUMBER
+//   TALB ALBUM        TCOP COPYRIGHT    TSRC ISRC
+//   TRCK TRACKNUMBER  TSSE ENCODER      TBPM BPM
+//   TDRC DATE         TPE2 ALBUMARTIST  APIC (pictures)
+
+use std::convert::TryInto;
+
+use crate::codec::Picture;
+
+const FRAME_NAMES : [(&str, &str); 14] = [
+	("TIT2", "TITLE"),
+	("TPE1", "ARTIST"),
+	("TALB", "ALBUM"),
+	("TRCK", "TRACKNUMBER"),
+	("TDRC", "DATE"),
+	("TCON", "GENRE"),
+	("COMM", "COMMENT"),
+	("TCOP", "COPYRIGHT"),
+	("TSSE", "ENCODER"),
+	("TPE2", "ALBUMARTIST"),
+	("TCOM", "COMPOSER"),
+	("TPOS", "DISCNUMBER"),
+	("TSRC", "ISRC"),
+	("TBPM", "BPM"),
+];
+
+// ID3v2.2 used three character frame IDs
+const V22_FRAME_IDS : [(&str, &str); 16] = [
+	("TT2", "TIT2"), ("TP1", "TPE1"), ("TAL", "TALB"), ("TRK", "TRCK"),
+	("TYE", "TYER"), ("TCO", "TCON"), ("COM", "COMM"), ("TCR", "TCOP"),
+	("TSS", "TSSE"), ("TP2", "TPE2"), ("TCM", "TCOM"), ("TPA", "TPOS"),
+	("TRC", "TSRC"), ("TBP", "TBPM"), ("TXX", "TXXX"), ("PIC", "APIC"),
+];
+
+fn syncsafe(bytes: &[u8]) -> usize {
+	bytes.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7f) as usize)
+}
+
+fn to_syncsafe(n: usize) -> [u8; 4] {
+	[((n >> 21) & 0x7f) as u8, ((n >> 14) & 0x7f) as u8, ((n >> 7) & 0x7f) as u8, (n & 0x7f) as u8]
+}
+
+// Reverses unsynchronisation, which inserts a zero after every 0xff
+fn resync(data: &[u8]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(data.len());
+	let mut prev = 0;
+	for &b in data {
+		if !(prev == 0xff && b == 0x00) {
+			out.push(b);
+		}
+		prev = b;
+	}
+	out
+}
+
+fn decode_text(encoding: u8, data: &[u8]) -> String {
+	match encoding {
+		1 | 2 => {
+			let mut big_endian = encoding == 2;
+			let mut data = data;
+			if data.len() >= 2 && (data[0..2] == [0xfe, 0xff] || data[0..2] == [0xff, 0xfe]) {
+				big_endian = data[0] == 0xfe;
+				data = &data[2..];
+			}
+			let units : Vec<u16> = data.chunks_exact(2)
+				.map(|c| if big_endian { u16::from_be_bytes([c[0], c[1]]) } else { u16::from_le_bytes([c[0], c[1]]) })
+				.collect();
+			String::from_utf16_lossy(&units)
+		},
+		3 => String::from_utf8_lossy(data).into_owned(),
+		_ => data.iter().map(|&b| b as char).collect(),
+	}
+}
+
+// Splits off one terminated string, returning it and the remainder
+fn split_text(encoding: u8, data: &[u8]) -> (String, &[u8]) {
+	let end = if encoding == 1 || encoding == 2 {
+		(0..data.len() / 2).map(|i| i * 2).find(|&i| data[i] == 0 && data[i+1] == 0).map(|i| (i, i + 2))
+	} else {
+		data.iter().position(|&b| b == 0).map(|i| (i, i + 1))
+	};
+	match end {
+		Some((text_end, rest)) => (decode_text(encoding, &data[..text_end]), &data[rest..]),
+		None => (decode_text(encoding, data), &[]),
+	}
+}
+
+fn text_values(encoding: u8, data: &[u8]) -> Vec<String> {
+	let mut values = Vec::new();
+	let mut rest = data;
+	while !rest.is_empty() {
+		let (value, next) = split_text(encoding, rest);
+		if !value.is_empty() {
+			values.push(value);
+		}
+		rest = next;
+	}
+	values
+}
+
+fn parse_frame(id: &str, data: &[u8], v22: bool, comments: &mut Vec<(String, String)>, pictures: &mut Vec<Picture>) {
+	if data.is_empty() {
+		return;
+	}
+	let encoding = data[0];
+	let body = &data[1..];
+
+	match id {
+		"TXXX" => {
+			let (name, value) = split_text(encoding, body);
+			for value in text_values(encoding, value) {
+				comments.push((name.to_ascii_uppercase(), value));
+			}
+		},
+		"COMM" if body.len() >= 3 => {
+			let (_description, text) = split_text(encoding, &body[3..]);
+			for value in text_values(encoding, text) {
+				comments.push(("COMMENT".to_string(), value));
+			}
+		},
+		"APIC" => {
+			let (mime_type, rest) = if v22 && body.len() >= 3 {
+				let mime_type = match &body[0..3] {
+					b"PNG" => "image/png",
+					b"JPG" => "image/jpeg",
+					b"GIF" => "image/gif",
+					_ => "application/octet-stream",
+				};
+				(mime_type.to_string(), &body[3..])
+			} else {
+				split_text(0, body)
+			};
+			if rest.is_empty() {
+				return;
+			}
+			let (description, image) = split_text(encoding, &rest[1..]);
+			let mut picture = Picture::from_data(image.to_vec(), rest[0] as u32);
+			picture.description = description;
+			if !mime_type.is_empty() {
+				picture.mime_type = mime_type;
+			}
+			pictures.push(picture);
+		},
+		"TYER" => {
+			// ID3v2.3 splits the date; only the year carries over
+			for value in text_values(encoding, body) {
+				comments.push(("DATE".to_string(), value));
+			}
+		},
+		_ => {
+			if let Some((_, name)) = FRAME_NAMES.iter().find(|(frame, _)| *frame == id) {
+				for value in text_values(encoding, body) {
+					comments.push((name.to_string(), value));
+				}
+			}
+		},
+	}
+}
+
+// Parses an ID3v2.2, 2.3 or 2.4 tag into Vorbis comment fields and pictures
+pub fn parse(tag: &[u8]) -> (Vec<(String, String)>, Vec<Picture>) {
+	let mut comments = Vec::new();
+	let mut pictures = Vec::new();
+
+	if tag.len() < 10 || &tag[0..3] != b"ID3" {
+		return (comments, pictures);
+	}
+	let major = tag[3];
+	let flags = tag[5];
+	let size = syncsafe(&tag[6..10]).min(tag.len() - 10);
+
+	let mut body = tag[10..10 + size].to_vec();
+	if flags & 0x80 != 0 && major < 4 {
+		body = resync(&body);
+	}
+
+	let mut pos = 0;
+	if flags & 0x40 != 0 && major >= 3 && body.len() >= 4 {
+		// Extended header
+		pos = if major == 4 {
+			syncsafe(&body[0..4])
+		} else {
+			4 + u32::from_be_bytes(body[0..4].try_into().unwrap()) as usize
+		};
+	}
+
+	let header_len = if major == 2 { 6 } else { 10 };
+	while pos + header_len <= body.len() {
+		if body[pos] == 0 {
+			break; // padding
+		}
+
+		let (id, frame_size, frame_flags) = match major {
+			2 => {
+				let id = String::from_utf8_lossy(&body[pos..pos+3]).into_owned();
+				let size = (body[pos+3] as usize) << 16 | (body[pos+4] as usize) << 8 | body[pos+5] as usize;
+				let id = V22_FRAME_IDS.iter().find(|(old, _)| *old == id).map(|(_, new)| new.to_string()).unwrap_or(id);
+				(id, size, 0u16)
+			},
+			3 => (String::from_utf8_lossy(&body[pos..pos+4]).into_owned(), u32::from_be_bytes(body[pos+4..pos+8].try_into().unwrap()) as usize, u16::from_be_bytes([body[pos+8], body[pos+9]])),
+			_ => (String::from_utf8_lossy(&body[pos..pos+4]).into_owned(), syncsafe(&body[pos+4..pos+8]), u16::from_be_bytes([body[pos+8], body[pos+9]])),
+		};
+		pos += header_len;
+		if pos + frame_size > body.len() {
+			break;
+		}
+		let mut data = body[pos..pos+frame_size].to_vec();
+		pos += frame_size;
+
+		// Skip compressed and encrypted frames
+		let (skip, unsync, length_indicator) = match major {
+			3 => (frame_flags & 0x00c0 != 0, false, false),
+			4 => (frame_flags & 0x000c != 0, frame_flags & 0x0002 != 0, frame_flags & 0x0001 != 0),
+			_ => (false, false, false),
+		};
+		if skip {
+			continue;
+		}
+		if unsync {
+			data = resync(&data);
+		}
+		if length_indicator {
+			if data.len() < 4 {
+				continue;
+			}
+			data.drain(0..4);
+		}
+
+		parse_frame(&id, &data, major == 2, &mut comments, &mut pictures);
+	}
+
+	(comments, pictures)
+}
+
+fn push_frame(tag: &mut Vec<u8>, id: &str, data: &[u8]) {
+	tag.extend_from_slice(id.as_bytes());
+	tag.extend_from_slice(&to_syncsafe(data.len()));
+	tag.extend_from_slice(&[0, 0]);
+	tag.extend_from_slice(data);
+}
+
+// Builds an ID3v2.4 tag, with all text in UTF-8. A frame ID appears only
+// once, so repeated fields go into one frame as a NUL-separated list, and
+// TXXX frames are grouped by description the same way.
+pub fn build(comments: &[(String, String)], pictures: &[Picture]) -> Vec<u8> {
+	let mut frames = Vec::new();
+
+	let track_total = comments.iter()
+		.find(|(name, _)| name.eq_ignore_ascii_case("TRACKTOTAL"))
+		.map(|(_, value)| value.clone());
+	let has_track = comments.iter().any(|(n, _)| n.eq_ignore_ascii_case("TRACKNUMBER"));
+
+	// (frame ID, TXXX description, values), in order of first appearance
+	let mut groups : Vec<(&str, Option<&str>, Vec<&str>)> = Vec::new();
+	for (name, value) in comments {
+		let upper = name.to_ascii_uppercase();
+		if upper == "TRACKTOTAL" && has_track {
+			continue;
+		}
+		let (frame, description) = match FRAME_NAMES.iter().find(|(_, vorbis)| *vorbis == upper) {
+			Some((frame, _)) => (*frame, None),
+			None => ("TXXX", Some(name.as_str())),
+		};
+		let same = |d: Option<&str>| match (d, description) {
+			(Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+			(a, b) => a == b,
+		};
+		match groups.iter_mut().find(|(f, d, _)| *f == frame && same(*d)) {
+			Some(group) => group.2.push(value),
+			None => groups.push((frame, description, vec![value])),
+		}
+	}
+
+	for (frame, description, values) in groups {
+		let mut data = vec![3];
+		match frame {
+			"COMM" => data.extend_from_slice(b"eng\0"),
+			"TXXX" => {
+				data.extend_from_slice(description.unwrap_or_default().as_bytes());
+				data.push(0);
+			},
+			_ => {},
+		}
+		for (i, value) in values.iter().enumerate() {
+			if i > 0 {
+				data.push(0);
+			}
+			data.extend_from_slice(value.as_bytes());
+			// The total goes with the first track number
+			if frame == "TRCK" && i == 0 {
+				if let Some(ref total) = track_total {
+					data.extend_from_slice(format!("/{}", total).as_bytes());
+				}
+			}
+		}
+		push_frame(&mut frames, frame, &data);
+	}
+
+	for picture in pictures {
+		let mut data = vec![3];
+		data.extend_from_slice(picture.mime_type.as_bytes());
+		data.push(0);
+		data.push(picture.picture_type as u8);
+		data.extend_from_slice(picture.description.as_bytes());
+		data.push(0);
+		data.extend_from_slice(&picture.data);
+		push_frame(&mut frames, "APIC", &data);
+	}
+
+	let mut tag = Vec::with_capacity(10 + frames.len());
+	tag.extend_from_slice(b"ID3\x04\x00\x00");
+	tag.extend_from_slice(&to_syncsafe(frames.len()));
+	tag.extend_from_slice(&frames);
+	tag
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+		pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+	}
+
+	// The frame IDs in a built tag, in order
+	fn frame_ids(tag: &[u8]) -> Vec<String> {
+		let mut ids = Vec::new();
+		let mut pos = 10;
+		while pos + 10 <= tag.len() {
+			ids.push(String::from_utf8_lossy(&tag[pos..pos+4]).into_owned());
+			pos += 10 + syncsafe(&tag[pos+4..pos+8]);
+		}
+		ids
+	}
+
+	#[test]
+	fn round_trip() {
+		let comments = fields(&[
+			("TITLE", "Song"),
+			("ARTIST", "First"),
+			("Mood", "calm"),
+			("ARTIST", "Second"),
+			("COMMENT", "Ünïcödé"),
+			("MOOD", "slow"),
+			("DATE", "2001"),
+		]);
+		let (parsed, pictures) = parse(&build(&comments, &[]));
+		assert!(pictures.is_empty());
+		assert_eq!(parsed, fields(&[
+			("TITLE", "Song"),
+			("ARTIST", "First"),
+			("ARTIST", "Second"),
+			("MOOD", "calm"),
+			("MOOD", "slow"),
+			("COMMENT", "Ünïcödé"),
+			("DATE", "2001"),
+		]));
+	}
+
+	#[test]
+	fn frames_once() {
+		let comments = fields(&[("ARTIST", "A"), ("GENRE", "Rock"), ("ARTIST", "B"), ("GENRE", "Pop"), ("X", "1"), ("Y", "2"), ("x", "3")]);
+		assert_eq!(frame_ids(&build(&comments, &[])), vec!["TPE1", "TCON", "TXXX", "TXXX"]);
+	}
+
+	#[test]
+	fn track_total() {
+		let comments = fields(&[("TRACKTOTAL", "12"), ("TRACKNUMBER", "3")]);
+		let (parsed, _) = parse(&build(&comments, &[]));
+		assert_eq!(parsed, fields(&[("TRACKNUMBER", "3/12")]));
+
+		// With no track number the total is kept as it is
+		let (parsed, _) = parse(&build(&fields(&[("TRACKTOTAL", "12")]), &[]));
+		assert_eq!(parsed, fields(&[("TRACKTOTAL", "12")]));
+	}
+
+	#[test]
+	fn pictures() {
+		let picture = Picture {
+			picture_type: 3,
+			mime_type: "image/png".to_string(),
+			description: "Front".to_string(),
+			data: vec![0x89, b'P', b'N', b'G', 0, 0xff, 0],
+			..Picture::default()
+		};
+		let (_, pictures) = parse(&build(&[], std::slice::from_ref(&picture)));
+		assert_eq!(pictures.len(), 1);
+		assert_eq!(pictures[0].picture_type, 3);
+		assert_eq!(pictures[0].mime_type, "image/png");
+		assert_eq!(pictures[0].description, "Front");
+		assert_eq!(pictures[0].data, picture.data);
+	}
+
+	#[test]
+	fn not_a_tag() {
+		assert_eq!(parse(b"RIFF"), (Vec::new(), Vec::new()));
+		assert_eq!(parse(b"ID3\x04\x00\x00\x00\x00\x00\x00"), (Vec::new(), Vec::new()));
+	}
+}
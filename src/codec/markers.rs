@@ -0,0 +1,391 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// RIFF marker chunks: `cue ` points, their LIST/adtl labels, notes and
+// region lengths, and `smpl` loops. WAV files carry them as ordinary chunks;
+// FLAC output carries the same chunks in APPLICATION blocks of our own. They
+// are also read from the "riff" blocks of flac --keep-foreign-metadata,
+// though not written there: flac expects a complete RIFF layout in those,
+// header, format and data chunks included.
+
+use std::convert::TryInto;
+
+use crate::codec::{Metadata, Marker, Loop, LoopMode, Sampler};
+
+pub const RIFF_APPLICATION_ID : [u8; 4] = *b"riff";
+// Not in the FLAC application ID registry; this is a private ID, so another
+// program may use it too. Such blocks pass through untouched, as only a block
+// holding exactly one marker chunk is taken for ours.
+pub const MARKER_APPLICATION_ID : [u8; 4] = *b"CHMK";
+
+fn le_u32(data: &[u8], pos: usize) -> u32 {
+	u32::from_le_bytes(data[pos..pos+4].try_into().unwrap())
+}
+
+fn text(data: &[u8]) -> String {
+	let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+	String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn push_text(out: &mut Vec<u8>, text: &str) {
+	out.extend_from_slice(text.as_bytes());
+	out.push(0);
+}
+
+// cue, adtl and smpl chunks may come in any order, so labels are collected
+// here by cue point ID and joined to their points once every chunk is read
+#[derive(Default)]
+pub struct MarkerReader {
+	points : Vec<(u32, u64)>,
+	labels : Vec<(u32, String)>,
+	notes : Vec<(u32, String)>,
+	lengths : Vec<(u32, u64)>,
+}
+
+impl MarkerReader {
+	// Returns false for chunks that carry no markers
+	pub fn read_chunk(&mut self, id: &[u8; 4], data: &[u8], metadata: &mut Metadata) -> bool {
+		match id {
+			b"cue " => self.read_cue(data),
+			b"LIST" if data.len() >= 4 && &data[..4] == b"adtl" => self.read_adtl(&data[4..]),
+			b"smpl" => metadata.sampler = read_smpl(data),
+			_ => return false,
+		}
+		true
+	}
+
+	// Reads a whole chunk as stored in a FLAC APPLICATION block. flac's own
+	// blocks may be cut short, ours must be framed exactly.
+	pub fn read_application(&mut self, application_id: [u8; 4], data: &[u8], metadata: &mut Metadata) -> bool {
+		if data.len() < 8 {
+			return false;
+		}
+		let id : [u8; 4] = data[..4].try_into().unwrap();
+		let size = le_u32(data, 4) as usize;
+		match application_id {
+			RIFF_APPLICATION_ID => self.read_chunk(&id, &data[8..8+size.min(data.len() - 8)], metadata),
+			MARKER_APPLICATION_ID if (size + 1) & !1 == data.len() - 8 => self.read_chunk(&id, &data[8..8+size], metadata),
+			_ => false,
+		}
+	}
+
+	fn read_cue(&mut self, data: &[u8]) {
+		if data.len() < 4 {
+			return;
+		}
+		let count = le_u32(data, 0) as usize;
+		for point in data[4..].chunks_exact(24).take(count) {
+			// The sample offset is what every editor writes; the play order
+			// position is often left as zero
+			self.points.push((le_u32(point, 0), le_u32(point, 20) as u64));
+		}
+	}
+
+	fn read_adtl(&mut self, data: &[u8]) {
+		let mut pos = 0;
+		while pos + 8 <= data.len() {
+			let id = &data[pos..pos+4];
+			let size = le_u32(data, pos + 4) as usize;
+			let sub = &data[pos+8..(pos + 8 + size).min(data.len())];
+			pos += 8 + ((size + 1) & !1);
+
+			if sub.len() < 4 {
+				continue;
+			}
+			let cue_id = le_u32(sub, 0);
+			match id {
+				b"labl" => self.labels.push((cue_id, text(&sub[4..]))),
+				b"note" => self.notes.push((cue_id, text(&sub[4..]))),
+				b"ltxt" if sub.len() >= 8 => {
+					self.lengths.push((cue_id, le_u32(sub, 4) as u64));
+					// Labelled text carries its own text after a 20 byte header
+					if sub.len() > 20 && !self.labels.iter().any(|(i, _)| *i == cue_id) {
+						let label = text(&sub[20..]);
+						if !label.is_empty() {
+							self.labels.push((cue_id, label));
+						}
+					}
+				},
+				_ => {},
+			}
+		}
+	}
+
+	pub fn finish(self, metadata: &mut Metadata) {
+		let find = |list: &[(u32, String)], id: u32| list.iter()
+			.find(|(i, _)| *i == id)
+			.map(|(_, t)| t.clone())
+			.unwrap_or_default();
+
+		for (id, position) in &self.points {
+			metadata.markers.push(Marker {
+				position: *position,
+				length: self.lengths.iter().find(|(i, _)| i == id).map(|(_, l)| *l).unwrap_or(0),
+				label: find(&self.labels, *id),
+				note: find(&self.notes, *id),
+			});
+		}
+		metadata.markers.sort_by_key(|m| m.position);
+	}
+}
+
+fn read_smpl(data: &[u8]) -> Option<Sampler> {
+	if data.len() < 36 {
+		return None;
+	}
+	let loop_count = le_u32(data, 28) as usize;
+	let data_len = le_u32(data, 32) as usize;
+
+	let mut loops = Vec::new();
+	for l in data[36..].chunks_exact(24).take(loop_count) {
+		loops.push(Loop {
+			start: le_u32(l, 8) as u64,
+			end: le_u32(l, 12) as u64,
+			mode: match le_u32(l, 4) {
+				1 => LoopMode::Alternating,
+				2 => LoopMode::Backward,
+				_ => LoopMode::Forward,
+			},
+			play_count: le_u32(l, 20),
+		});
+	}
+	let data_start = (36 + loops.len() * 24).min(data.len());
+	let data_end = (data_start + data_len).min(data.len());
+
+	Some(Sampler {
+		manufacturer: le_u32(data, 0),
+		product: le_u32(data, 4),
+		sample_period: le_u32(data, 8),
+		midi_unity_note: le_u32(data, 12),
+		midi_pitch_fraction: le_u32(data, 16),
+		smpte_format: le_u32(data, 20),
+		smpte_offset: le_u32(data, 24),
+		loops,
+		sampler_data: data[data_start..data_end].to_vec(),
+	})
+}
+
+// The cue, LIST/adtl and smpl chunks for a stream, each as (ID, contents).
+// Cue point IDs are renumbered from 1.
+pub fn chunks(metadata: &Metadata) -> Vec<([u8; 4], Vec<u8>)> {
+	let mut chunks = Vec::new();
+
+	if !metadata.markers.is_empty() {
+		let mut cue = Vec::new();
+		cue.extend_from_slice(&(metadata.markers.len() as u32).to_le_bytes());
+		for (i, marker) in metadata.markers.iter().enumerate() {
+			cue.extend_from_slice(&(i as u32 + 1).to_le_bytes());
+			cue.extend_from_slice(&(marker.position as u32).to_le_bytes());
+			cue.extend_from_slice(b"data");
+			cue.extend_from_slice(&0u32.to_le_bytes());
+			cue.extend_from_slice(&0u32.to_le_bytes());
+			cue.extend_from_slice(&(marker.position as u32).to_le_bytes());
+		}
+		chunks.push((*b"cue ", cue));
+
+		let mut adtl = b"adtl".to_vec();
+		let mut sub = |id: &[u8; 4], body: Vec<u8>| {
+			adtl.extend_from_slice(id);
+			adtl.extend_from_slice(&(body.len() as u32).to_le_bytes());
+			adtl.extend_from_slice(&body);
+			if body.len() % 2 == 1 {
+				adtl.push(0);
+			}
+		};
+		for (i, marker) in metadata.markers.iter().enumerate() {
+			let cue_id = (i as u32 + 1).to_le_bytes();
+			if !marker.label.is_empty() {
+				let mut body = cue_id.to_vec();
+				push_text(&mut body, &marker.label);
+				sub(b"labl", body);
+			}
+			if !marker.note.is_empty() {
+				let mut body = cue_id.to_vec();
+				push_text(&mut body, &marker.note);
+				sub(b"note", body);
+			}
+			if marker.length > 0 {
+				// Region: purpose "rgn ", no country, language, dialect or code page
+				let mut body = cue_id.to_vec();
+				body.extend_from_slice(&(marker.length as u32).to_le_bytes());
+				body.extend_from_slice(b"rgn ");
+				body.extend_from_slice(&[0; 8]);
+				sub(b"ltxt", body);
+			}
+		}
+		if adtl.len() > 4 {
+			chunks.push((*b"LIST", adtl));
+		}
+	}
+
+	if let Some(ref sampler) = metadata.sampler {
+		let mut smpl = Vec::new();
+		for value in &[
+			sampler.manufacturer,
+			sampler.product,
+			sampler.sample_period,
+			sampler.midi_unity_note,
+			sampler.midi_pitch_fraction,
+			sampler.smpte_format,
+			sampler.smpte_offset,
+			sampler.loops.len() as u32,
+			sampler.sampler_data.len() as u32,
+		] {
+			smpl.extend_from_slice(&value.to_le_bytes());
+		}
+		for (i, l) in sampler.loops.iter().enumerate() {
+			let mode = match l.mode {
+				LoopMode::Forward => 0u32,
+				LoopMode::Alternating => 1,
+				LoopMode::Backward => 2,
+			};
+			for value in &[i as u32, mode, l.start as u32, l.end as u32, 0, l.play_count] {
+				smpl.extend_from_slice(&value.to_le_bytes());
+			}
+		}
+		smpl.extend_from_slice(&sampler.sampler_data);
+		chunks.push((*b"smpl", smpl));
+	}
+
+	chunks
+}
+
+// Whether a foreign-metadata chunk describes the layout of the file it came
+// from, which no longer holds once the audio has been decoded
+pub fn is_layout_chunk(data: &[u8]) -> bool {
+	data.len() >= 4 && matches!(&data[..4], b"RIFF" | b"fmt " | b"data" | b"ds64")
+}
+
+// The same chunks, each framed as a whole RIFF chunk for a FLAC APPLICATION block
+pub fn applications(metadata: &Metadata) -> Vec<([u8; 4], Vec<u8>)> {
+	chunks(metadata).into_iter().map(|(id, data)| {
+		let mut block = id.to_vec();
+		block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		block.extend_from_slice(&data);
+		if data.len() % 2 == 1 {
+			block.push(0);
+		}
+		(MARKER_APPLICATION_ID, block)
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sample_metadata() -> Metadata {
+		Metadata {
+			markers: vec![
+				Marker { position: 0, length: 0, label: "Start".to_string(), note: String::new() },
+				Marker { position: 44100, length: 22050, label: "Verse".to_string(), note: "Odd".to_string() },
+				Marker { position: 88200, length: 0, label: String::new(), note: String::new() },
+			],
+			sampler: Some(Sampler {
+				manufacturer: 0,
+				product: 0,
+				sample_period: 22675,
+				midi_unity_note: 60,
+				midi_pitch_fraction: 0,
+				smpte_format: 25,
+				smpte_offset: 0,
+				loops: vec![
+					Loop { start: 1000, end: 1999, mode: LoopMode::Forward, play_count: 0 },
+					Loop { start: 3000, end: 3999, mode: LoopMode::Alternating, play_count: 4 },
+				],
+				sampler_data: vec![1, 2, 3],
+			}),
+			..Metadata::default()
+		}
+	}
+
+	#[test]
+	fn chunk_round_trip() {
+		let written = sample_metadata();
+		let chunks = chunks(&written);
+		assert_eq!(chunks.iter().map(|(id, _)| id).collect::<Vec<_>>(), vec![b"cue ", b"LIST", b"smpl"]);
+
+		// Read back in another order, as chunks may come in any
+		let mut metadata = Metadata::default();
+		let mut reader = MarkerReader::default();
+		for (id, data) in chunks.iter().rev() {
+			assert!(reader.read_chunk(id, data, &mut metadata));
+		}
+		assert!(!reader.read_chunk(b"LIST", b"INFO", &mut metadata));
+		reader.finish(&mut metadata);
+		assert_eq!(metadata.markers, written.markers);
+		assert_eq!(metadata.sampler, written.sampler);
+	}
+
+	#[test]
+	fn labelled_text() {
+		// A region with its text in the ltxt sub-chunk rather than a labl
+		let mut ltxt = 7u32.to_le_bytes().to_vec();
+		ltxt.extend_from_slice(&500u32.to_le_bytes());
+		ltxt.extend_from_slice(b"rgn ");
+		ltxt.extend_from_slice(&[0; 8]);
+		ltxt.extend_from_slice(b"Chorus\0");
+		let mut adtl = b"adtl".to_vec();
+		adtl.extend_from_slice(b"ltxt");
+		adtl.extend_from_slice(&(ltxt.len() as u32).to_le_bytes());
+		adtl.extend_from_slice(&ltxt);
+		let mut cue = 1u32.to_le_bytes().to_vec();
+		for value in [7, 0, 0x61746164, 0, 0, 1234] {
+			cue.extend_from_slice(&(value as u32).to_le_bytes());
+		}
+
+		let mut metadata = Metadata::default();
+		let mut reader = MarkerReader::default();
+		reader.read_chunk(b"LIST", &adtl, &mut metadata);
+		reader.read_chunk(b"cue ", &cue, &mut metadata);
+		reader.finish(&mut metadata);
+		assert_eq!(metadata.markers, vec![Marker { position: 1234, length: 500, label: "Chorus".to_string(), note: String::new() }]);
+	}
+
+	#[test]
+	fn applications_round_trip() {
+		let written = sample_metadata();
+		let mut metadata = Metadata::default();
+		let mut reader = MarkerReader::default();
+		for (id, data) in applications(&written) {
+			assert_eq!(id, MARKER_APPLICATION_ID);
+			assert!(reader.read_application(id, &data, &mut metadata));
+		}
+		reader.finish(&mut metadata);
+		assert_eq!(metadata.markers, written.markers);
+		assert_eq!(metadata.sampler, written.sampler);
+	}
+
+	#[test]
+	fn foreign_applications() {
+		let (_, cue) = applications(&sample_metadata()).remove(0);
+		let mut metadata = Metadata::default();
+		let mut reader = MarkerReader::default();
+
+		// Another program's block under the same ID is not taken for ours
+		assert!(!reader.read_application(MARKER_APPLICATION_ID, b"some other data", &mut metadata));
+		assert!(!reader.read_application(MARKER_APPLICATION_ID, &cue[..cue.len() - 4], &mut metadata));
+		let mut longer = cue.clone();
+		longer.extend_from_slice(b"more");
+		assert!(!reader.read_application(MARKER_APPLICATION_ID, &longer, &mut metadata));
+		assert!(!reader.read_application(*b"ABCD", &cue, &mut metadata));
+
+		// flac's riff blocks are read even when cut short
+		assert!(reader.read_application(RIFF_APPLICATION_ID, &cue[..cue.len() - 24], &mut metadata));
+		reader.finish(&mut metadata);
+		assert_eq!(metadata.markers.len(), 2);
+	}
+}
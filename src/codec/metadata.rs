@@ -0,0 +1,433 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+
+use crate::codec::EncoderOptions;
+use crate::codec::bwf::{Bext, Cart};
+use crate::cue::CueSheet;
+
+// Metadata is named after Vorbis comment fields wherever a format has no
+// better convention of its own. The well-known fields map as follows:
+//
+//   title        TITLE
+//   artist       ARTIST
+//   album        ALBUM
+//   track        TRACKNUMBER (also accepts "n/total")
+//   track_total  TRACKTOTAL, TOTALTRACKS
+//   date         DATE
+//   replay_gain  REPLAYGAIN_{TRACK,ALBUM}_{GAIN,PEAK}
+//   channel_mask WAVEFORMATEXTENSIBLE_CHANNEL_MASK
+//   sampler      LOOPSTART, LOOPLENGTH (first loop only, written for FLAC alone)
+//
+// Anything else is kept verbatim in `fields`, as are repeats of the
+// single-valued fields and values that do not parse.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+	pub title : Option<String>,
+	pub artist : Option<String>,
+	pub album : Option<String>,
+	pub track : Option<u32>,
+	pub track_total : Option<u32>,
+	pub date : Option<String>,
+	pub fields : Vec<(String, String)>,
+
+	pub pictures : Vec<Picture>,
+	pub markers : Vec<Marker>,
+	pub sampler : Option<Sampler>,
+	// Track layout of a CD image, as carried by a FLAC CUESHEET block
+	pub cue_sheet : Option<CueSheet>,
+
+	// WAVE_FORMAT_EXTENSIBLE speaker bits, e.g. 0x3 for front left and right
+	pub channel_mask : Option<u32>,
+	pub replay_gain : ReplayGain,
+
+	// Facts about the stream, when the decoder knows them up front
+	pub total_samples : Option<u64>,
+	pub audio_md5 : Option<[u8; 16]>,
+
+	// Samples since midnight at the first sample of the stream, as carried
+	// by the Broadcast Wave bext chunk
+	pub time_reference : Option<u64>,
+
+	// FLAC APPLICATION blocks, passed through untouched
+	pub applications : Vec<([u8; 4], Vec<u8>)>,
+
+	// Broadcast Wave chunks, passed through to WAV output
+	pub bext : Option<Bext>,
+	pub cart : Option<Cart>,
+	pub ixml : Option<String>,
+}
+
+// picture_type uses the ID3v2 APIC numbering, e.g. 3 for the front cover
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Picture {
+	pub picture_type : u32,
+	pub mime_type : String,
+	pub description : String,
+	pub width : u32,
+	pub height : u32,
+	pub depth : u32,
+	pub colors : u32,
+	pub data : Vec<u8>,
+}
+
+// A point (length 0) or region, in samples per channel from the start of the stream
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Marker {
+	pub position : u64,
+	pub length : u64,
+	pub label : String,
+	pub note : String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LoopMode {
+	#[default]
+	Forward,
+	Alternating,
+	Backward,
+}
+
+// Sample positions; `end` is the last sample played, as in a WAV smpl chunk
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Loop {
+	pub start : u64,
+	pub end : u64,
+	pub mode : LoopMode,
+	// 0 loops forever
+	pub play_count : u32,
+}
+
+// Sampler playback information, as carried by a WAV smpl chunk
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sampler {
+	pub manufacturer : u32,
+	pub product : u32,
+	// Nanoseconds per sample
+	pub sample_period : u32,
+	pub midi_unity_note : u32,
+	pub midi_pitch_fraction : u32,
+	pub smpte_format : u32,
+	pub smpte_offset : u32,
+	pub loops : Vec<Loop>,
+	pub sampler_data : Vec<u8>,
+}
+
+impl Default for Sampler {
+	fn default() -> Sampler {
+		Sampler {
+			manufacturer: 0,
+			product: 0,
+			sample_period: 0,
+			midi_unity_note: 60,
+			midi_pitch_fraction: 0,
+			smpte_format: 0,
+			smpte_offset: 0,
+			loops: Vec::new(),
+			sampler_data: Vec::new(),
+		}
+	}
+}
+
+// A file's tags as stored, for editing them in place: comments under
+// their own names and in their own order, and the pictures
+#[derive(Clone, Debug, Default)]
+pub struct Tags {
+	pub comments : Vec<(String, String)>,
+	pub pictures : Vec<Picture>,
+}
+
+// Gains in dB, peaks as a linear fraction of full scale
+#[derive(Clone, Debug, Default)]
+pub struct ReplayGain {
+	pub track_gain : Option<f64>,
+	pub track_peak : Option<f64>,
+	pub album_gain : Option<f64>,
+	pub album_peak : Option<f64>,
+}
+
+// The speaker layout FLAC implies for each channel count
+pub fn default_channel_mask(channels: usize) -> u32 {
+	match channels {
+		1 => 0x0004,
+		2 => 0x0003,
+		3 => 0x0007,
+		4 => 0x0033,
+		5 => 0x0037,
+		6 => 0x003f,
+		7 => 0x070f,
+		8 => 0x063f,
+		_ => 0,
+	}
+}
+
+fn parse_gain(value: &str) -> Option<f64> {
+	value.trim().trim_end_matches("dB").trim_end_matches("db").trim().parse().ok()
+}
+
+impl Metadata {
+	pub fn from_vorbis_comments(comments: &[(String, String)]) -> Metadata {
+		let mut metadata = Metadata::default();
+		for (name, value) in comments {
+			metadata.set(name, value);
+		}
+		metadata
+	}
+
+	// Sets a field by its Vorbis comment name. A repeat of a single-valued
+	// field, or a value that does not parse, is kept in `fields` as given.
+	pub fn set(&mut self, name: &str, value: &str) {
+		if !self.set_known(name, value) {
+			self.fields.push((name.to_string(), value.to_string()));
+		}
+	}
+
+	// False when the value belongs in `fields` instead
+	fn set_known(&mut self, name: &str, value: &str) -> bool {
+		// An identical repeat is taken as already set
+		fn first<T: PartialEq>(slot: &mut Option<T>, value: Option<T>) -> bool {
+			match (slot.as_ref(), value) {
+				(_, None) => false,
+				(None, value) => {
+					*slot = value;
+					true
+				},
+				(Some(old), Some(new)) => *old == new,
+			}
+		}
+		let number = |n: &str| n.trim().parse::<u32>().ok();
+
+		match name.to_ascii_uppercase().as_str() {
+			"TITLE" => first(&mut self.title, Some(value.to_string())),
+			"ARTIST" => first(&mut self.artist, Some(value.to_string())),
+			"ALBUM" => first(&mut self.album, Some(value.to_string())),
+			"DATE" => first(&mut self.date, Some(value.to_string())),
+			"TRACKNUMBER" => {
+				let (track, total) = match value.split_once('/') {
+					Some((track, total)) => match number(total) {
+						Some(total) => (number(track), Some(total)),
+						None => return false,
+					},
+					None => (number(value), None),
+				};
+				if track.is_none() || (self.track.is_some() && self.track != track) {
+					return false;
+				}
+				if total.is_some() && !first(&mut self.track_total, total) {
+					return false;
+				}
+				self.track = track;
+				true
+			},
+			"TRACKTOTAL" | "TOTALTRACKS" => first(&mut self.track_total, number(value)),
+			"LOOPSTART" | "LOOPLENGTH" => {
+				// The common single-loop convention; the two may come in either order
+				let value : u64 = match value.trim().parse() {
+					Ok(v) => v,
+					Err(_) => return false,
+				};
+				let sampler = self.sampler.get_or_insert_with(Sampler::default);
+				if sampler.loops.is_empty() {
+					sampler.loops.push(Loop::default());
+				}
+				let first = &mut sampler.loops[0];
+				if name.eq_ignore_ascii_case("LOOPSTART") {
+					first.end = value + first.end.saturating_sub(first.start);
+					first.start = value;
+				} else {
+					first.end = first.start + value.saturating_sub(1);
+				}
+				true
+			},
+			"REPLAYGAIN_TRACK_GAIN" => parse_gain(value).map(|g| self.replay_gain.track_gain = Some(g)).is_some(),
+			"REPLAYGAIN_TRACK_PEAK" => value.trim().parse().ok().map(|p| self.replay_gain.track_peak = Some(p)).is_some(),
+			"REPLAYGAIN_ALBUM_GAIN" => parse_gain(value).map(|g| self.replay_gain.album_gain = Some(g)).is_some(),
+			"REPLAYGAIN_ALBUM_PEAK" => value.trim().parse().ok().map(|p| self.replay_gain.album_peak = Some(p)).is_some(),
+			"WAVEFORMATEXTENSIBLE_CHANNEL_MASK" => {
+				let hex = value.trim().trim_start_matches("0x").trim_start_matches("0X");
+				u32::from_str_radix(hex, 16).ok().map(|mask| self.channel_mask = Some(mask)).is_some()
+			},
+			_ => false,
+		}
+	}
+
+	// Clears a field by its Vorbis comment name
+	pub fn remove(&mut self, name: &str) {
+		match name.to_ascii_uppercase().as_str() {
+			"TITLE" => self.title = None,
+			"ARTIST" => self.artist = None,
+			"ALBUM" => self.album = None,
+			"DATE" => self.date = None,
+			"TRACKNUMBER" => self.track = None,
+			"TRACKTOTAL" | "TOTALTRACKS" => self.track_total = None,
+			"LOOPSTART" | "LOOPLENGTH" => {
+				if let Some(ref mut sampler) = self.sampler {
+					if !sampler.loops.is_empty() {
+						sampler.loops.remove(0);
+					}
+				}
+			},
+			"REPLAYGAIN_TRACK_GAIN" => self.replay_gain.track_gain = None,
+			"REPLAYGAIN_TRACK_PEAK" => self.replay_gain.track_peak = None,
+			"REPLAYGAIN_ALBUM_GAIN" => self.replay_gain.album_gain = None,
+			"REPLAYGAIN_ALBUM_PEAK" => self.replay_gain.album_peak = None,
+			"WAVEFORMATEXTENSIBLE_CHANNEL_MASK" => self.channel_mask = None,
+			_ => (),
+		}
+		// Along with any repeats or unparsed values
+		self.fields.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+	}
+
+	// Narrows everything tied to the timeline to the samples from start up
+	// to end, for when only that range of the audio is kept
+	pub fn trim(&mut self, start: u64, end: Option<u64>) {
+		let end = match (end, self.total_samples) {
+			(Some(end), Some(total)) => Some(end.min(total)),
+			(end, total) => end.or(total),
+		};
+		self.total_samples = end.map(|end| end.saturating_sub(start));
+		self.time_reference = self.time_reference.map(|t| t + start);
+
+		// Regions are cut down to the part that is kept
+		let end = end.unwrap_or(u64::MAX);
+		self.markers.retain(|m| m.position < end && (m.position >= start || m.position + m.length > start));
+		for marker in &mut self.markers {
+			let marker_end = (marker.position + marker.length).min(end);
+			marker.position = marker.position.max(start);
+			marker.length = marker_end.saturating_sub(marker.position);
+			marker.position -= start;
+		}
+		if let Some(ref mut sampler) = self.sampler {
+			sampler.loops.retain(|l| l.start >= start && l.end < end);
+			for l in &mut sampler.loops {
+				l.start -= start;
+				l.end -= start;
+			}
+		}
+
+		// None of these describe a part of the audio
+		self.cue_sheet = None;
+		self.fields.retain(|(name, _)| !name.eq_ignore_ascii_case("CUESHEET"));
+		self.replay_gain = ReplayGain::default();
+		self.audio_md5 = None;
+	}
+
+	// Applies the tag and picture overrides given to an encoder
+	pub fn apply(&mut self, options: &EncoderOptions) {
+		for (name, _) in &options.tags {
+			self.remove(name);
+		}
+		for (name, value) in &options.tags {
+			self.set(name, value);
+		}
+		self.pictures.extend(options.pictures.iter().cloned());
+	}
+
+	// The channel mask is only written when it differs from what FLAC would assume
+	pub fn to_vorbis_comments(&self, channels: usize) -> Vec<(String, String)> {
+		let mut comments = Vec::new();
+		let mut push = |name: &str, value: String| comments.push((name.to_string(), value));
+
+		if let Some(ref title) = self.title { push("TITLE", title.clone()); }
+		if let Some(ref artist) = self.artist { push("ARTIST", artist.clone()); }
+		if let Some(ref album) = self.album { push("ALBUM", album.clone()); }
+		if let Some(track) = self.track { push("TRACKNUMBER", track.to_string()); }
+		if let Some(total) = self.track_total { push("TRACKTOTAL", total.to_string()); }
+		if let Some(ref date) = self.date { push("DATE", date.clone()); }
+		for (name, value) in self.replay_gain.to_vorbis_comments() {
+			push(&name, value);
+		}
+
+		if let Some(mask) = self.channel_mask {
+			if mask != default_channel_mask(channels) {
+				push("WAVEFORMATEXTENSIBLE_CHANNEL_MASK", format!("0x{:04X}", mask));
+			}
+		}
+
+		comments.extend(self.fields.iter().cloned());
+		comments
+	}
+}
+
+impl ReplayGain {
+	pub fn to_vorbis_comments(&self) -> Vec<(String, String)> {
+		let mut comments = Vec::new();
+		let mut push = |name: &str, value: String| comments.push((name.to_string(), value));
+		if let Some(gain) = self.track_gain { push("REPLAYGAIN_TRACK_GAIN", format!("{:.2} dB", gain)); }
+		if let Some(peak) = self.track_peak { push("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", peak)); }
+		if let Some(gain) = self.album_gain { push("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", gain)); }
+		if let Some(peak) = self.album_peak { push("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", peak)); }
+		comments
+	}
+}
+
+impl Picture {
+	pub fn from_file(path: &str, picture_type: u32) -> io::Result<Picture> {
+		Ok(Picture::from_data(fs::read(path)?, picture_type))
+	}
+
+	// Fills in MIME type and dimensions from the image header
+	pub fn from_data(data: Vec<u8>, picture_type: u32) -> Picture {
+		let mut picture = Picture {
+			picture_type,
+			mime_type: "application/octet-stream".to_string(),
+			..Default::default()
+		};
+
+		if data.len() >= 26 && data[0..8] == [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a] {
+			picture.mime_type = "image/png".to_string();
+			picture.width = u32::from_be_bytes(data[16..20].try_into().unwrap());
+			picture.height = u32::from_be_bytes(data[20..24].try_into().unwrap());
+			let samples = match data[25] {
+				2 => 3,
+				4 => 2,
+				6 => 4,
+				_ => 1,
+			};
+			picture.depth = data[24] as u32 * samples;
+		} else if data.len() >= 4 && data[0..2] == [0xff, 0xd8] {
+			picture.mime_type = "image/jpeg".to_string();
+			// Walk the marker segments up to the first start-of-frame
+			let mut pos = 2;
+			while pos + 9 < data.len() && data[pos] == 0xff {
+				let marker = data[pos+1];
+				let len = u16::from_be_bytes([data[pos+2], data[pos+3]]) as usize;
+				if (0xc0..=0xcf).contains(&marker) && marker != 0xc4 && marker != 0xc8 && marker != 0xcc {
+					picture.height = u16::from_be_bytes([data[pos+5], data[pos+6]]) as u32;
+					picture.width = u16::from_be_bytes([data[pos+7], data[pos+8]]) as u32;
+					picture.depth = data[pos+4] as u32 * data[pos+9] as u32;
+					break;
+				}
+				pos += 2 + len;
+			}
+		} else if data.len() >= 13 && &data[0..4] == b"GIF8" {
+			picture.mime_type = "image/gif".to_string();
+			picture.width = u16::from_le_bytes([data[6], data[7]]) as u32;
+			picture.height = u16::from_le_bytes([data[8], data[9]]) as u32;
+			picture.depth = 8;
+			if data[10] & 0x80 != 0 {
+				picture.colors = 1 << ((data[10] & 0x07) + 1);
+			}
+		}
+
+		picture.data = data;
+		picture
+	}
+}
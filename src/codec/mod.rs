@@ -18,7 +18,16 @@
 pub mod wav;
 pub mod flac;
 pub mod vorbis;
+pub mod metadata;
+pub mod id3;
+pub mod bwf;
+pub mod markers;
 
+use std::sync::mpsc;
+
+pub use self::metadata::{Metadata, Picture, Marker, Loop, LoopMode, Sampler, ReplayGain, Tags};
+
+#[derive(Clone)]
 pub struct Frame {
 	pub channels : usize,
 	pub sample_rate : usize,
@@ -26,41 +35,116 @@ pub struct Frame {
 
 	pub samples : Vec<i32>,
 
+	// Only set on the header frame a decoder sends ahead of the audio
+	pub metadata : Option<Metadata>,
+
 	pub eof : bool,
 }
 
+// Reads a stream on request from any sample on. A sample here means one
+// per channel, as in total_samples.
+pub trait Decoder {
+	// Same as the header frame a decoder thread sends first
+	fn header(&self) -> Frame;
+	// Up to max_samples from the current position; eof is set once nothing is left
+	fn read(&mut self, max_samples: usize) -> Frame;
+	fn seek(&mut self, sample: u64);
+	// None when the stream does not say
+	fn total_samples(&self) -> Option<u64>;
+	fn position(&self) -> u64;
+}
+
+const RANGE_BLOCK : usize = 4096;
+
+// Sends the samples from start up to end on as a decoder thread would,
+// with the metadata trimmed to match
+pub fn decode_range(decoder: &mut dyn Decoder, tx: mpsc::Sender<Frame>, start: u64, end: Option<u64>) {
+	let total = decoder.total_samples();
+	let end = match total {
+		Some(total) => Some(end.map_or(total, |end| end.min(total))),
+		None => end,
+	};
+	let start = end.map_or(start, |end| start.min(end));
+
+	let mut header = decoder.header();
+	if start > 0 || end != total {
+		header.metadata.as_mut().unwrap().trim(start, end);
+	}
+	tx.send(header).unwrap();
+
+	if decoder.position() != start {
+		decoder.seek(start);
+	}
+	loop {
+		let wanted = end.map_or(RANGE_BLOCK, |end| (end - decoder.position()).min(RANGE_BLOCK as u64) as usize);
+		let mut frame = decoder.read(wanted);
+		frame.eof = frame.eof || end == Some(decoder.position());
+		let eof = frame.eof;
+		tx.send(frame).unwrap();
+		if eof {
+			break;
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct EncoderOptions {
+	// Tags replacing any same-named fields carried over from the input
+	pub tags : Vec<(String, String)>,
+	// Added after any pictures carried over from the input
+	pub pictures : Vec<Picture>,
+
+	// FLAC only
+	pub padding : Option<usize>,
+	// FLAC only, in seconds between seek points
+	pub seekpoint_interval : Option<f64>,
+}
+
+impl Default for EncoderOptions {
+	// Same defaults as the flac command line tool
+	fn default() -> EncoderOptions {
+		EncoderOptions {
+			tags: Vec::new(),
+			pictures: Vec::new(),
+			padding: Some(8192),
+			seekpoint_interval: Some(10.0),
+		}
+	}
+}
+
 fn unpack_pcm(data: Vec<u8>, bits_per_sample: usize) -> Vec<i32> {
     let mut pcm = Vec::with_capacity(data.len() / (bits_per_sample / 8));
 
+    // 8-bit WAV is unsigned, everything wider is signed
     if bits_per_sample == 8 {
         for n in data {
-            pcm.push(n as i32);
+            pcm.push(n as i32 - 128);
         }
     } else if bits_per_sample == 16 {
         for n in 0..(data.len()/2) {
             pcm.push((
-                ((data[n*2] as i16) << 0) |
+                (data[n*2] as i16) |
                 ((data[n*2+1] as i16) << 8))
                 as i32
             );
         }
     } else if bits_per_sample == 24 {
         for n in 0..(data.len()/3) {
+            // Shifted up and back down again to sign-extend
             pcm.push((
-                ((data[n*3] as i32) << 0) |
-                ((data[n*3+1] as i32) << 8) |
-                ((data[n*3+2] as i32) << 16))
-                as i32
+                ((data[n*3] as i32) << 8) |
+                ((data[n*3+1] as i32) << 16) |
+                ((data[n*3+2] as i32) << 24))
+                >> 8
             );
         }
     } else if bits_per_sample == 32 {
         for n in 0..(data.len()/4) {
-            pcm.push((
-                ((data[n*4] as i32) << 0) |
+            pcm.push(
+                (data[n*4] as i32) |
                 ((data[n*4+1] as i32) << 8) |
                 ((data[n*4+2] as i32) << 16) |
-                ((data[n*4+3] as i32) << 24))
-                as i32
+                ((data[n*4+3] as i32) << 24)
             );
         }
     } else {
@@ -75,7 +159,7 @@ fn pack_pcm(pcm: Vec<i32>, bits_per_sample: usize) -> Vec<u8> {
 
     if bits_per_sample == 8 {
         for n in pcm {
-            data.push(n as u8);
+            data.push((n + 128) as u8);
         }
     } else if bits_per_sample == 16 {
         for n in pcm {
@@ -15,14 +15,21 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 //
 
+use std::convert::TryInto;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::sync::mpsc;
 
-use crate::codec::Frame;
+use crate::codec::{decode_range, Decoder, Frame};
+use crate::codec::Metadata;
+use crate::codec::Tags;
+use crate::codec::EncoderOptions;
+use crate::codec::id3;
+use crate::codec::bwf::{Bext, Cart};
+use crate::codec::markers::{self, MarkerReader};
 use crate::codec::unpack_pcm;
 use crate::codec::pack_pcm;
 
@@ -30,79 +37,424 @@ const RIFF_CHUNK_ID : u32 = 0x52494646;
 const RIFF_FORMAT : u32 = 0x57415645;
 const FMT_CHUNK_ID : u32 = 0x666d7420;
 const DATA_CHUNK_ID : u32 = 0x64617461;
+const LIST_CHUNK_ID : u32 = 0x4c495354;
+const INFO_LIST_TYPE : u32 = 0x494e464f;
+const ID3_CHUNK_ID : u32 = 0x69643320;
+const ID3_UPPER_CHUNK_ID : u32 = 0x49443320;
+const BEXT_CHUNK_ID : u32 = 0x62657874;
+const CART_CHUNK_ID : u32 = 0x63617274;
+const IXML_CHUNK_ID : u32 = 0x69584d4c;
 
-pub fn read_wav(path: &str, tx: mpsc::Sender<Frame>) {
-	let mut file = File::open(path).unwrap();
+const WAVE_FORMAT_PCM : u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT : u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE : u16 = 0xfffe;
+// KSDATAFORMAT_SUBTYPE_PCM and _IEEE_FLOAT, with the leading format code stripped
+const SUBFORMAT_GUID_TAIL : [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+// RIFF INFO items and the Vorbis comment names they map to. Unknown items
+// are kept under their own ID. On output, fields with an INFO item go into
+// LIST/INFO for older readers; an `id3 ` chunk is added as well whenever
+// some field or picture has no INFO equivalent.
+const INFO_NAMES : [(&[u8; 4], &str); 15] = [
+	(b"INAM", "TITLE"),
+	(b"IART", "ARTIST"),
+	(b"IPRD", "ALBUM"),
+	(b"ICRD", "DATE"),
+	(b"ITRK", "TRACKNUMBER"),
+	(b"IPRT", "TRACKNUMBER"),
+	(b"ICMT", "COMMENT"),
+	(b"IGNR", "GENRE"),
+	(b"ICOP", "COPYRIGHT"),
+	(b"ISFT", "ENCODER"),
+	(b"IENG", "ENGINEER"),
+	(b"ITCH", "ENCODED_BY"),
+	(b"IMUS", "COMPOSER"),
+	(b"IKEY", "KEYWORDS"),
+	(b"ILNG", "LANGUAGE"),
+];
+
+struct Fmt {
+	channels : usize,
+	sample_rate : usize,
+	block_align : usize,
+	bits_per_sample : usize,
+	channel_mask : Option<u32>,
+	float : bool,
+}
+
+impl Fmt {
+	// Float samples are decoded to 32-bit integers, which a dither stage can
+	// then bring down to the output depth
+	fn decoded_bits_per_sample(&self) -> usize {
+		if self.float { 32 } else { self.bits_per_sample }
+	}
+
+	fn unpack(&self, data: Vec<u8>) -> Vec<i32> {
+		if !self.float {
+			return unpack_pcm(data, self.bits_per_sample);
+		}
+		let scale = 2f64.powi(31);
+		let to_int = |v: f64| (v * scale).round().max(i32::MIN as f64).min(i32::MAX as f64) as i32;
+		if self.bits_per_sample == 64 {
+			data.chunks_exact(8).map(|b| to_int(f64::from_le_bytes(b.try_into().unwrap()))).collect()
+		} else {
+			data.chunks_exact(4).map(|b| to_int(f32::from_le_bytes(b.try_into().unwrap()) as f64)).collect()
+		}
+	}
+}
+
+fn chunk_text(data: &[u8]) -> String {
+	let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+	match std::str::from_utf8(&data[..end]) {
+		Ok(text) => text.trim_end().to_string(),
+		Err(_) => data[..end].iter().map(|&b| b as char).collect::<String>().trim_end().to_string(),
+	}
+}
+
+fn read_fmt(data: &[u8]) -> Fmt {
+	let mut fmt = data;
+	let audiofmt = fmt.read_u16::<LittleEndian>().unwrap();
+	if audiofmt != WAVE_FORMAT_PCM && audiofmt != WAVE_FORMAT_IEEE_FLOAT && audiofmt != WAVE_FORMAT_EXTENSIBLE {
+		panic!("Unknown audio format");
+	}
+	let channels = fmt.read_u16::<LittleEndian>().unwrap() as usize;
+	let sample_rate = fmt.read_u32::<LittleEndian>().unwrap() as usize;
+	let _byterate = fmt.read_u32::<LittleEndian>().unwrap();
+	let block_align = fmt.read_u16::<LittleEndian>().unwrap() as usize;
+	let bits_per_sample = fmt.read_u16::<LittleEndian>().unwrap() as usize;
+
+	// WAVE_FORMAT_EXTENSIBLE
+	let mut channel_mask = None;
+	let mut float = audiofmt == WAVE_FORMAT_IEEE_FLOAT;
+	if audiofmt == WAVE_FORMAT_EXTENSIBLE {
+		let cb_size = fmt.read_u16::<LittleEndian>().unwrap();
+		if cb_size < 22 {
+			panic!("Bad extensible fmt chunk");
+		}
+		let _valid_bits = fmt.read_u16::<LittleEndian>().unwrap();
+		channel_mask = Some(fmt.read_u32::<LittleEndian>().unwrap());
+		let subformat = fmt.read_u16::<LittleEndian>().unwrap();
+		let mut guid_tail = [0; 14];
+		fmt.read_exact(&mut guid_tail).unwrap();
+		if (subformat != WAVE_FORMAT_PCM && subformat != WAVE_FORMAT_IEEE_FLOAT) || guid_tail != SUBFORMAT_GUID_TAIL {
+			panic!("Unknown audio format");
+		}
+		float = subformat == WAVE_FORMAT_IEEE_FLOAT;
+	}
+	if float && bits_per_sample != 32 && bits_per_sample != 64 {
+		panic!("Unsupported {}-bit float audio", bits_per_sample);
+	}
+
+	Fmt {
+		channels,
+		sample_rate,
+		block_align,
+		bits_per_sample,
+		channel_mask,
+		float,
+	}
+}
+
+fn read_info(data: &[u8]) -> Vec<(String, String)> {
+	let mut comments = Vec::new();
+	let mut pos = 0;
+	while pos + 8 <= data.len() {
+		let id = &data[pos..pos+4];
+		let size = u32::from_le_bytes([data[pos+4], data[pos+5], data[pos+6], data[pos+7]]) as usize;
+		let value = &data[pos+8..(pos + 8 + size).min(data.len())];
+		pos += 8 + ((size + 1) & !1);
+
+		let value = chunk_text(value);
+		if value.is_empty() {
+			continue;
+		}
+		let name = match INFO_NAMES.iter().find(|(info, _)| &info[..] == id) {
+			Some((_, name)) => name.to_string(),
+			None => String::from_utf8_lossy(id).into_owned(),
+		};
+		comments.push((name, value));
+	}
+	comments
+}
+
+fn write_chunk(file: &mut File, id: &[u8; 4], data: &[u8]) -> io::Result<()> {
+	file.write_all(id)?;
+	file.write_u32::<LittleEndian>(data.len() as u32)?;
+	file.write_all(data)?;
+	if data.len() % 2 == 1 {
+		file.write_u8(0)?;
+	}
+	Ok(())
+}
+
+// Every chunk in the RIFF body, as (ID, data offset, data size)
+fn chunk_list(file: &mut File) -> Vec<(u32, u64, u64)> {
+	let file_len = file.metadata().unwrap().len();
 
 	// RIFF Chunk
+	file.seek(SeekFrom::Start(0)).unwrap();
 	let riff_chunk_id = file.read_u32::<BigEndian>().unwrap();
 	if riff_chunk_id != RIFF_CHUNK_ID {
 		panic!("Bad RIFF ID");
 	}
-	let _riff_chunk_size = file.read_u32::<LittleEndian>().unwrap();
+	let riff_chunk_size = file.read_u32::<LittleEndian>().unwrap();
 	let riff_chunk_format = file.read_u32::<BigEndian>().unwrap();
 	if riff_chunk_format != RIFF_FORMAT {
 		panic!("Bad RIFF format");
 	}
+	let riff_end = (8 + riff_chunk_size as u64).min(file_len);
 
-	// fmt Chunk
-	let fmt_chunk_id = file.read_u32::<BigEndian>().unwrap();
-	if fmt_chunk_id != FMT_CHUNK_ID {
-		panic!("Bad fmt chunk ID");
+	let mut chunks = Vec::new();
+	let mut pos = 12;
+	while pos + 8 <= riff_end {
+		file.seek(SeekFrom::Start(pos)).unwrap();
+		let chunk_id = file.read_u32::<BigEndian>().unwrap();
+		let chunk_size = file.read_u32::<LittleEndian>().unwrap() as u64;
+		let chunk_start = pos + 8;
+		// Streamed files may leave the data size unset
+		let chunk_size = chunk_size.min(riff_end - chunk_start);
+		pos = chunk_start + ((chunk_size + 1) & !1);
+		chunks.push((chunk_id, chunk_start, chunk_size));
 	}
-	let _fmt_chunk_size = file.read_u32::<LittleEndian>().unwrap();
-	let fmt_audiofmt = file.read_u16::<LittleEndian>().unwrap();
-	if fmt_audiofmt != 1 {
-		panic!("Unknown audio format");
+	chunks
+}
+
+fn is_info_list(chunk: &[u8]) -> bool {
+	chunk.len() >= 4 && u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) == INFO_LIST_TYPE
+}
+
+// Walks the chunks, returning the format, where the audio is and the metadata
+fn read_chunks(file: &mut File) -> (Fmt, u64, u64, Metadata) {
+	let mut fmt = None;
+	let mut data_chunk = None;
+	let mut info_comments = Vec::new();
+	let mut id3_comments = Vec::new();
+	let mut pictures = Vec::new();
+	let mut metadata = Metadata::default();
+	let mut marker_reader = MarkerReader::default();
+
+	for (chunk_id, chunk_start, chunk_size) in chunk_list(file) {
+		if chunk_id == DATA_CHUNK_ID {
+			data_chunk = Some((chunk_start, chunk_size));
+			continue;
+		}
+
+		let mut chunk = vec![0; chunk_size as usize];
+		file.seek(SeekFrom::Start(chunk_start)).unwrap();
+		file.read_exact(&mut chunk).unwrap();
+		if marker_reader.read_chunk(&chunk_id.to_be_bytes(), &chunk, &mut metadata) {
+			continue;
+		}
+		match chunk_id {
+			FMT_CHUNK_ID => fmt = Some(read_fmt(&chunk)),
+			LIST_CHUNK_ID if is_info_list(&chunk) => {
+				info_comments = read_info(&chunk[4..]);
+			},
+			ID3_CHUNK_ID | ID3_UPPER_CHUNK_ID => {
+				let (comments, id3_pictures) = id3::parse(&chunk);
+				id3_comments = comments;
+				pictures = id3_pictures;
+			},
+			BEXT_CHUNK_ID => {
+				let (bext, time_reference) = Bext::parse(&chunk);
+				metadata.bext = Some(bext);
+				metadata.time_reference = Some(time_reference);
+			},
+			CART_CHUNK_ID => metadata.cart = Some(Cart::parse(&chunk)),
+			IXML_CHUNK_ID => metadata.ixml = Some(chunk_text(&chunk)),
+			_ => {},
+		}
+	}
+
+	let fmt = fmt.expect("Missing fmt chunk");
+	let (data_start, data_size) = data_chunk.expect("Missing data chunk");
+
+	// ID3 takes precedence over INFO for any field both carry
+	info_comments.retain(|(name, _)| !id3_comments.iter().any(|(n, _)| n == name));
+	for (name, value) in info_comments.iter().chain(id3_comments.iter()) {
+		metadata.set(name, value);
+	}
+	metadata.pictures = pictures;
+	marker_reader.finish(&mut metadata);
+	metadata.channel_mask = fmt.channel_mask;
+	metadata.total_samples = Some(data_size / fmt.block_align as u64);
+
+	(fmt, data_start, data_size, metadata)
+}
+
+fn header_frame(fmt: &Fmt, metadata: Metadata) -> Frame {
+	Frame {
+		channels: fmt.channels,
+		sample_rate: fmt.sample_rate,
+		bits_per_sample: fmt.decoded_bits_per_sample(),
+		samples: Vec::new(),
+		metadata: Some(metadata),
+		eof: false,
+	}
+}
+
+// Just the header frame, without reading any audio
+pub fn read_wav_header(path: &str) -> Frame {
+	let mut file = File::open(path).unwrap();
+	let (fmt, _, _, metadata) = read_chunks(&mut file);
+	header_frame(&fmt, metadata)
+}
+
+pub fn read_wav(path: &str, tx: mpsc::Sender<Frame>) {
+	read_wav_range(path, tx, 0, None);
+}
+
+// Only the samples from start up to end, read straight from their offset in the data chunk
+pub fn read_wav_range(path: &str, tx: mpsc::Sender<Frame>, start: u64, end: Option<u64>) {
+	decode_range(&mut WavDecoder::open(path), tx, start, end);
+}
+
+pub struct WavDecoder {
+	file : File,
+	fmt : Fmt,
+	data_start : u64,
+	total_samples : u64,
+	position : u64,
+	metadata : Metadata,
+}
+
+impl WavDecoder {
+	pub fn open(path: &str) -> WavDecoder {
+		let mut file = File::open(path).unwrap();
+		let (fmt, data_start, data_size, metadata) = read_chunks(&mut file);
+		file.seek(SeekFrom::Start(data_start)).unwrap();
+		WavDecoder {
+			file,
+			total_samples: data_size / fmt.block_align as u64,
+			fmt,
+			data_start,
+			position: 0,
+			metadata,
+		}
+	}
+}
+
+impl Decoder for WavDecoder {
+	fn header(&self) -> Frame {
+		header_frame(&self.fmt, self.metadata.clone())
+	}
+
+	fn read(&mut self, max_samples: usize) -> Frame {
+		let n = (self.total_samples - self.position).min(max_samples as u64);
+		let mut data = vec![0; (n * self.fmt.block_align as u64) as usize];
+		self.file.read_exact(&mut data).unwrap();
+		self.position += n;
+		Frame {
+			channels: self.fmt.channels,
+			sample_rate: self.fmt.sample_rate,
+			bits_per_sample: self.fmt.decoded_bits_per_sample(),
+			samples: self.fmt.unpack(data),
+			metadata: None,
+			eof: self.position == self.total_samples,
+		}
+	}
+
+	// Whole blocks of all channels, so any sample is a plain offset into the data chunk
+	fn seek(&mut self, sample: u64) {
+		if sample > self.total_samples {
+			panic!("Cannot seek WAV to sample {} of {}", sample, self.total_samples);
+		}
+		self.file.seek(SeekFrom::Start(self.data_start + sample * self.fmt.block_align as u64)).unwrap();
+		self.position = sample;
+	}
+
+	fn total_samples(&self) -> Option<u64> {
+		Some(self.total_samples)
+	}
+
+	fn position(&self) -> u64 {
+		self.position
+	}
+}
+
+fn write_tags(file: &mut File, metadata: &Metadata, channels: usize) -> io::Result<()> {
+	let comments = tags(metadata, channels).comments;
+
+	let mut info : Vec<([u8; 4], &String)> = Vec::new();
+	let mut needs_id3 = !metadata.pictures.is_empty();
+	for (name, value) in &comments {
+		let info_id = INFO_NAMES.iter()
+			.find(|(_, vorbis)| vorbis.eq_ignore_ascii_case(name))
+			.map(|(id, _)| **id);
+		match info_id {
+			Some(id) if !info.iter().any(|(i, _)| *i == id) => info.push((id, value)),
+			_ => needs_id3 = true,
+		}
+	}
+
+	if !info.is_empty() {
+		let mut list = b"INFO".to_vec();
+		for (id, value) in info {
+			let mut text = value.clone().into_bytes();
+			text.push(0);
+			list.extend_from_slice(&id);
+			list.extend_from_slice(&(text.len() as u32).to_le_bytes());
+			list.extend_from_slice(&text);
+			if text.len() % 2 == 1 {
+				list.push(0);
+			}
+		}
+		write_chunk(file, b"LIST", &list)?;
+	}
+
+	if needs_id3 {
+		write_chunk(file, b"id3 ", &id3::build(&comments, &metadata.pictures))?;
 	}
-	let fmt_channels : usize = file.read_u16::<LittleEndian>().unwrap() as usize;
-	let fmt_samplerate : usize = file.read_u32::<LittleEndian>().unwrap() as usize;
-	let _fmt_byterate = file.read_u32::<LittleEndian>().unwrap();
-	let _fmt_blockalign = file.read_u16::<LittleEndian>().unwrap();
-	let fmt_bitspersample : usize = file.read_u16::<LittleEndian>().unwrap() as usize;
-
-	// data Chunk
-	let data_chunk_id = file.read_u32::<BigEndian>().unwrap();
-	if data_chunk_id != DATA_CHUNK_ID {
-		panic!("Missing data chunk");
-	}
-    let _data_chunk_size = file.read_u32::<LittleEndian>().unwrap();
-    
-    // data
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
-    let frame = Frame {
-        channels: fmt_channels,
-        sample_rate: fmt_samplerate,
-        bits_per_sample: fmt_bitspersample,
-        samples: unpack_pcm(data, fmt_bitspersample),
-        eof: true,
-    };
-    tx.send(frame).unwrap();
-}
-
-pub fn write_wav(path: &str, rx: mpsc::Receiver<Frame>) {
+	Ok(())
+}
+
+pub fn write_wav(path: &str, rx: mpsc::Receiver<Frame>, options: &EncoderOptions) {
 	let mut file = File::create(path).unwrap();
 
 	let mut frame = rx.recv().unwrap();
+	let mut metadata = frame.metadata.take().unwrap_or_default();
+	metadata.apply(options);
 
 	file.write_u32::<BigEndian>(RIFF_CHUNK_ID).unwrap();
 	file.write_u32::<LittleEndian>(0x00000000).unwrap();
 	file.write_u32::<BigEndian>(RIFF_FORMAT).unwrap();
 
+	// A time reference alone is enough reason to write a bext chunk
+	if metadata.bext.is_some() || metadata.time_reference.is_some() {
+		let bext = metadata.bext.clone().unwrap_or_default();
+		write_chunk(&mut file, b"bext", &bext.to_bytes(metadata.time_reference.unwrap_or(0))).unwrap();
+	}
+
+	// Only use WAVE_FORMAT_EXTENSIBLE when there is a speaker layout to record
 	file.write_u32::<BigEndian>(FMT_CHUNK_ID).unwrap();
-	file.write_u32::<LittleEndian>(0x00000010).unwrap();
-	file.write_u16::<LittleEndian>(1).unwrap();
+	file.write_u32::<LittleEndian>(if metadata.channel_mask.is_some() { 40 } else { 16 }).unwrap();
+	file.write_u16::<LittleEndian>(if metadata.channel_mask.is_some() { WAVE_FORMAT_EXTENSIBLE } else { WAVE_FORMAT_PCM }).unwrap();
 	file.write_u16::<LittleEndian>(frame.channels as u16).unwrap();
 	file.write_u32::<LittleEndian>(frame.sample_rate as u32).unwrap();
 	file.write_u32::<LittleEndian>(frame.sample_rate as u32 * frame.channels as u32 * frame.bits_per_sample as u32 / 8).unwrap();
 	file.write_u16::<LittleEndian>(frame.channels as u16 * frame.bits_per_sample as u16 / 8).unwrap();
 	file.write_u16::<LittleEndian>(frame.bits_per_sample as u16).unwrap();
+	if let Some(mask) = metadata.channel_mask {
+		file.write_u16::<LittleEndian>(22).unwrap();
+		file.write_u16::<LittleEndian>(frame.bits_per_sample as u16).unwrap();
+		file.write_u32::<LittleEndian>(mask).unwrap();
+		file.write_u16::<LittleEndian>(WAVE_FORMAT_PCM).unwrap();
+		file.write_all(&SUBFORMAT_GUID_TAIL).unwrap();
+	}
+
+	if let Some(ref cart) = metadata.cart {
+		write_chunk(&mut file, b"cart", &cart.to_bytes()).unwrap();
+	}
+	if let Some(ref ixml) = metadata.ixml {
+		write_chunk(&mut file, b"iXML", ixml.as_bytes()).unwrap();
+	}
 
 	file.write_u32::<BigEndian>(DATA_CHUNK_ID).unwrap();
+	let data_size_pos = file.stream_position().unwrap();
 	file.write_u32::<LittleEndian>(0x00000000).unwrap();
 
 	let mut data_len = 0;
+	let channels = frame.channels;
 
 	while ! frame.eof {
         data_len += frame.samples.len() * (frame.bits_per_sample / 8);
@@ -110,14 +462,235 @@ pub fn write_wav(path: &str, rx: mpsc::Receiver<Frame>) {
         frame = rx.recv().unwrap();
 	}
 
-	if frame.samples.len() > 0 {
+	if !frame.samples.is_empty() {
         data_len += frame.samples.len() * (frame.bits_per_sample / 8);
         file.write_all(&pack_pcm(frame.samples, frame.bits_per_sample)).unwrap();
 	}
+	if data_len % 2 == 1 {
+		file.write_u8(0).unwrap();
+	}
+
+	// Markers and tags go after the audio so that the data can be streamed out first
+	for (id, chunk) in markers::chunks(&metadata) {
+		write_chunk(&mut file, &id, &chunk).unwrap();
+	}
+	write_tags(&mut file, &metadata, channels).unwrap();
+	let riff_len = file.stream_position().unwrap();
 
 	file.seek(SeekFrom::Start(4)).unwrap();
-	file.write_u32::<LittleEndian>(36 + data_len as u32).unwrap();
+	file.write_u32::<LittleEndian>(riff_len as u32 - 8).unwrap();
 
-	file.seek(SeekFrom::Start(40)).unwrap();
+	file.seek(SeekFrom::Start(data_size_pos)).unwrap();
 	file.write_u32::<LittleEndian>(data_len as u32).unwrap();
 }
+
+// The tags as write_tags would write them
+fn tags(metadata: &Metadata, channels: usize) -> Tags {
+	Tags {
+		comments: metadata.to_vorbis_comments(channels).into_iter()
+			.filter(|(name, _)| name != "WAVEFORMATEXTENSIBLE_CHANNEL_MASK")
+			.collect(),
+		pictures: metadata.pictures.clone(),
+	}
+}
+
+pub fn read_wav_tags(path: &str) -> Tags {
+	let mut file = File::open(path).unwrap();
+	let (fmt, _, _, metadata) = read_chunks(&mut file);
+	tags(&metadata, fmt.channels)
+}
+
+// Rewrites the file with its tags edited. Tag chunks are written anew and
+// every other chunk, the audio included, is copied as it is; the new file
+// replaces the old one once it is complete, and is removed if it cannot be.
+pub fn update_wav_tags(path: &str, edit: impl FnOnce(&mut Tags)) -> io::Result<()> {
+	let mut input = File::open(path)?;
+	let (fmt, _, _, mut metadata) = read_chunks(&mut input);
+	let mut tags = tags(&metadata, fmt.channels);
+	for (name, _) in &tags.comments {
+		metadata.remove(name);
+	}
+	edit(&mut tags);
+	for (name, value) in &tags.comments {
+		metadata.set(name, value);
+	}
+	metadata.pictures = tags.pictures;
+
+	let temp_path = format!("{}.tmp", path);
+	let result = File::create(&temp_path)
+		.and_then(|mut output| rewrite_wav(&mut input, &mut output, &metadata, fmt.channels))
+		.and_then(|_| std::fs::rename(&temp_path, path));
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp_path);
+	}
+	result
+}
+
+fn rewrite_wav(input: &mut File, output: &mut File, metadata: &Metadata, channels: usize) -> io::Result<()> {
+	output.write_u32::<BigEndian>(RIFF_CHUNK_ID)?;
+	output.write_u32::<LittleEndian>(0x00000000)?;
+	output.write_u32::<BigEndian>(RIFF_FORMAT)?;
+
+	for (chunk_id, chunk_start, chunk_size) in chunk_list(input) {
+		input.seek(SeekFrom::Start(chunk_start))?;
+		if chunk_id == LIST_CHUNK_ID || chunk_id == ID3_CHUNK_ID || chunk_id == ID3_UPPER_CHUNK_ID {
+			let mut chunk = vec![0; chunk_size as usize];
+			input.read_exact(&mut chunk)?;
+			if chunk_id == LIST_CHUNK_ID && !is_info_list(&chunk) {
+				write_chunk(output, &chunk_id.to_be_bytes(), &chunk)?;
+			}
+			continue;
+		}
+		output.write_u32::<BigEndian>(chunk_id)?;
+		output.write_u32::<LittleEndian>(chunk_size as u32)?;
+		std::io::copy(&mut input.take(chunk_size), output)?;
+		if chunk_size % 2 == 1 {
+			output.write_u8(0)?;
+		}
+	}
+	write_tags(output, metadata, channels)?;
+
+	let riff_len = output.stream_position()?;
+	output.seek(SeekFrom::Start(4))?;
+	output.write_u32::<LittleEndian>(riff_len as u32 - 8)?;
+	output.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+		pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+	}
+
+	// Writes a short stereo file with the given tags, returning its chunk IDs
+	// and its tags as read back
+	fn round_trip(name: &str, comments: &[(String, String)]) -> (Vec<u32>, Tags) {
+		let path = std::env::temp_dir().join(format!("chaud-{}-{}.wav", name, std::process::id()));
+		let path = path.to_str().unwrap();
+		let mut metadata = Metadata::default();
+		for (name, value) in comments {
+			metadata.set(name, value);
+		}
+
+		let (tx, rx) = mpsc::channel();
+		tx.send(Frame { channels: 2, sample_rate: 44100, bits_per_sample: 16, samples: Vec::new(), metadata: Some(metadata), eof: false }).unwrap();
+		tx.send(Frame { channels: 2, sample_rate: 44100, bits_per_sample: 16, samples: vec![1, -1, 2, -2], metadata: None, eof: true }).unwrap();
+		write_wav(path, rx, &EncoderOptions::default());
+
+		let ids = chunk_list(&mut File::open(path).unwrap()).into_iter().map(|(id, _, _)| id).collect();
+		let tags = read_wav_tags(path);
+		std::fs::remove_file(path).unwrap();
+		(ids, tags)
+	}
+
+	#[test]
+	fn info_round_trip() {
+		let comments = fields(&[
+			("TITLE", "Song"),
+			("ARTIST", "Odd length"),
+			("ALBUM", "Album"),
+			("TRACKNUMBER", "3"),
+			("DATE", "2001"),
+			("GENRE", "Rock"),
+			("COMPOSER", "Ünïcödé"),
+			("COPYRIGHT", "(c) 2001"),
+		]);
+		let (ids, tags) = round_trip("info", &comments);
+		assert!(ids.contains(&LIST_CHUNK_ID));
+		// Every field has an INFO item, so no ID3 tag is needed
+		assert!(!ids.contains(&ID3_CHUNK_ID));
+		assert_eq!(tags.comments, comments);
+	}
+
+	#[test]
+	fn info_with_id3() {
+		// The second artist and the mood have no INFO item of their own
+		let comments = fields(&[("TITLE", "Song"), ("ARTIST", "First"), ("ARTIST", "Second"), ("MOOD", "calm")]);
+		let (ids, tags) = round_trip("id3", &comments);
+		assert!(ids.contains(&LIST_CHUNK_ID));
+		assert!(ids.contains(&ID3_CHUNK_ID));
+		assert_eq!(tags.comments, comments);
+	}
+
+	// A mono float file, as a plain IEEE float fmt chunk or an extensible one
+	fn float_wav(name: &str, bits: u16, extensible: bool, values: &[f64]) -> String {
+		let path = std::env::temp_dir().join(format!("chaud-{}-{}.wav", name, std::process::id()));
+		let mut fmt = Vec::new();
+		fmt.extend_from_slice(&(if extensible { WAVE_FORMAT_EXTENSIBLE } else { WAVE_FORMAT_IEEE_FLOAT }).to_le_bytes());
+		fmt.extend_from_slice(&1u16.to_le_bytes());
+		fmt.extend_from_slice(&48000u32.to_le_bytes());
+		fmt.extend_from_slice(&(48000 * bits as u32 / 8).to_le_bytes());
+		fmt.extend_from_slice(&(bits / 8).to_le_bytes());
+		fmt.extend_from_slice(&bits.to_le_bytes());
+		if extensible {
+			fmt.extend_from_slice(&22u16.to_le_bytes());
+			fmt.extend_from_slice(&bits.to_le_bytes());
+			fmt.extend_from_slice(&4u32.to_le_bytes());
+			fmt.extend_from_slice(&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+			fmt.extend_from_slice(&SUBFORMAT_GUID_TAIL);
+		}
+		let mut data = Vec::new();
+		for &v in values {
+			match bits {
+				64 => data.extend_from_slice(&v.to_le_bytes()),
+				_ => data.extend_from_slice(&(v as f32).to_le_bytes()),
+			}
+		}
+
+		let mut file = File::create(&path).unwrap();
+		file.write_all(b"RIFF").unwrap();
+		file.write_u32::<LittleEndian>(4 + 8 + fmt.len() as u32 + 8 + data.len() as u32).unwrap();
+		file.write_all(b"WAVE").unwrap();
+		write_chunk(&mut file, b"fmt ", &fmt).unwrap();
+		write_chunk(&mut file, b"data", &data).unwrap();
+		path.to_str().unwrap().to_string()
+	}
+
+	#[test]
+	fn float_input() {
+		let values = [0.0, 0.5, -0.5, -1.0, 1.0, 2.0, 0.25];
+		let expected = vec![0, 1 << 30, -(1 << 30), i32::MIN, i32::MAX, i32::MAX, 1 << 29];
+		for (bits, extensible) in [(32, false), (64, false), (32, true), (64, true)] {
+			let path = float_wav(&format!("float{}{}", bits, extensible), bits, extensible, &values);
+			let mut decoder = WavDecoder::open(&path);
+			std::fs::remove_file(&path).unwrap();
+			assert_eq!(decoder.header().bits_per_sample, 32);
+			let frame = decoder.read(100);
+			assert!(frame.eof);
+			assert_eq!(frame.samples, expected);
+		}
+	}
+
+	#[test]
+	fn float_to_integer() {
+		use crate::stage::Stage;
+		use crate::stage::dither::{Dither, NoiseShaping, Quantizer};
+
+		let path = float_wav("float-dither", 32, false, &[0.5, -0.25, 1.0, 0.1]);
+		let mut decoder = WavDecoder::open(&path);
+		std::fs::remove_file(&path).unwrap();
+		let mut dither = Dither::new(16, Quantizer::Round, NoiseShaping::None, 0);
+		let header = dither.process(decoder.header());
+		assert_eq!(header.bits_per_sample, 16);
+		let frame = dither.process(decoder.read(100));
+		assert_eq!(frame.bits_per_sample, 16);
+		assert_eq!(frame.samples, vec![16384, -8192, 32767, 3277]);
+	}
+
+	#[test]
+	fn info_items() {
+		let mut list = Vec::new();
+		for (id, value) in [(b"INAM", &b"Title\0"[..]), (b"IPRT", b"7\0"), (b"IXYZ", b"other \0"), (b"ICMT", b"\0"), (b"IART", b"Odd")] {
+			list.extend_from_slice(id);
+			list.extend_from_slice(&(value.len() as u32).to_le_bytes());
+			list.extend_from_slice(value);
+			if value.len() % 2 == 1 {
+				list.push(0);
+			}
+		}
+		// Unknown items keep their ID and empty ones are dropped
+		assert_eq!(read_info(&list), fields(&[("TITLE", "Title"), ("TRACKNUMBER", "7"), ("IXYZ", "other"), ("ARTIST", "Odd")]));
+	}
+}
@@ -0,0 +1,404 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// CUE sheets as written by CD rippers. Times are kept in CD frames, 75 to
+// the second, counted from the start of the FILE the track belongs to.
+
+use std::fmt;
+use std::fs;
+
+use crate::codec::Metadata;
+
+pub const CD_FRAMES_PER_SECOND : u64 = 75;
+
+#[derive(Clone, Debug, Default)]
+pub struct CueSheet {
+	pub catalog : Option<String>,
+	pub title : Option<String>,
+	pub performer : Option<String>,
+	pub songwriter : Option<String>,
+	pub rem : Vec<(String, String)>,
+	pub files : Vec<String>,
+	pub tracks : Vec<CueTrack>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CueTrack {
+	pub number : u32,
+	// Index into CueSheet::files
+	pub file : usize,
+	// AUDIO, MODE1/2352 and so on
+	pub data_type : String,
+	pub title : Option<String>,
+	pub performer : Option<String>,
+	pub songwriter : Option<String>,
+	pub isrc : Option<String>,
+	pub flags : Vec<String>,
+	pub rem : Vec<(String, String)>,
+	// PREGAP and POSTGAP, silence that is not in the file
+	pub pregap : u64,
+	pub postgap : u64,
+	// INDEX number and position
+	pub indices : Vec<(u32, u64)>,
+	// INDEX 01 in samples, for a track that does not start on a CD frame
+	pub sample_start : Option<u64>,
+}
+
+impl CueTrack {
+	pub fn index(&self, number: u32) -> Option<u64> {
+		self.indices.iter().find(|(n, _)| *n == number).map(|(_, pos)| *pos)
+	}
+
+	// INDEX 01, or the first index when a sheet leaves it out
+	pub fn start(&self) -> u64 {
+		self.index(1).or_else(|| self.indices.first().map(|(_, pos)| *pos)).unwrap_or(0)
+	}
+
+	// INDEX 00 if there is one, otherwise the track start
+	pub fn gap_start(&self) -> u64 {
+		self.index(0).unwrap_or_else(|| self.start())
+	}
+
+	// The same two positions in samples
+	pub fn start_sample(&self, sample_rate: usize) -> u64 {
+		self.sample_start.unwrap_or_else(|| frames_to_samples(self.start(), sample_rate))
+	}
+
+	pub fn gap_start_sample(&self, sample_rate: usize) -> u64 {
+		match self.index(0) {
+			Some(pos) => frames_to_samples(pos, sample_rate),
+			None => self.start_sample(sample_rate),
+		}
+	}
+
+	pub fn pre_emphasis(&self) -> bool {
+		self.flags.iter().any(|f| f == "PRE")
+	}
+
+	pub fn is_audio(&self) -> bool {
+		self.data_type == "AUDIO"
+	}
+}
+
+pub fn frames_to_samples(frames: u64, sample_rate: usize) -> u64 {
+	frames * sample_rate as u64 / CD_FRAMES_PER_SECOND
+}
+
+fn parse_time(text: &str) -> Result<u64, String> {
+	let parts : Vec<&str> = text.split(':').collect();
+	if parts.len() != 3 {
+		return Err(format!("Bad CUE time {}", text));
+	}
+	let mut values = [0; 3];
+	for (value, part) in values.iter_mut().zip(&parts) {
+		*value = part.parse::<u64>().map_err(|_| format!("Bad CUE time {}", text))?;
+	}
+	if values[1] >= 60 || values[2] >= CD_FRAMES_PER_SECOND {
+		return Err(format!("Bad CUE time {}", text));
+	}
+	Ok((values[0] * 60 + values[1]) * CD_FRAMES_PER_SECOND + values[2])
+}
+
+pub fn format_time(frames: u64) -> String {
+	let seconds = frames / CD_FRAMES_PER_SECOND;
+	format!("{:02}:{:02}:{:02}", seconds / 60, seconds % 60, frames % CD_FRAMES_PER_SECOND)
+}
+
+// Splits a line into words, keeping quoted strings whole
+fn tokens(line: &str) -> Vec<String> {
+	let mut tokens = Vec::new();
+	let mut chars = line.trim().chars().peekable();
+	while let Some(&c) = chars.peek() {
+		if c.is_whitespace() {
+			chars.next();
+		} else if c == '"' {
+			chars.next();
+			let mut token = String::new();
+			for c in chars.by_ref() {
+				if c == '"' {
+					break;
+				}
+				token.push(c);
+			}
+			tokens.push(token);
+		} else {
+			let mut token = String::new();
+			while let Some(&c) = chars.peek() {
+				if c.is_whitespace() {
+					break;
+				}
+				token.push(c);
+				chars.next();
+			}
+			tokens.push(token);
+		}
+	}
+	tokens
+}
+
+pub fn parse(text: &str) -> Result<CueSheet, String> {
+	let mut sheet = CueSheet::default();
+
+	for (line_number, line) in text.lines().enumerate() {
+		let words = tokens(line);
+		if words.is_empty() {
+			continue;
+		}
+		let arg = |i: usize| words.get(i).cloned().ok_or_else(|| format!("CUE line {}: missing argument", line_number + 1));
+		let command = words[0].to_ascii_uppercase();
+		let track = sheet.tracks.last_mut();
+
+		match (command.as_str(), track) {
+			("FILE", _) => sheet.files.push(arg(1)?),
+			("TRACK", _) => {
+				if sheet.files.is_empty() {
+					return Err(format!("CUE line {}: TRACK before FILE", line_number + 1));
+				}
+				let number = arg(1)?.parse().map_err(|_| format!("CUE line {}: bad track number", line_number + 1))?;
+				sheet.tracks.push(CueTrack {
+					number,
+					file: sheet.files.len() - 1,
+					data_type: arg(2)?.to_ascii_uppercase(),
+					..CueTrack::default()
+				});
+			},
+			("INDEX", Some(track)) => {
+				let number = arg(1)?.parse().map_err(|_| format!("CUE line {}: bad index number", line_number + 1))?;
+				track.indices.push((number, parse_time(&arg(2)?)?));
+			},
+			("PREGAP", Some(track)) => track.pregap = parse_time(&arg(1)?)?,
+			("POSTGAP", Some(track)) => track.postgap = parse_time(&arg(1)?)?,
+			("FLAGS", Some(track)) => track.flags = words[1..].iter().map(|f| f.to_ascii_uppercase()).collect(),
+			("ISRC", Some(track)) => track.isrc = Some(arg(1)?),
+			("TITLE", Some(track)) => track.title = Some(arg(1)?),
+			("PERFORMER", Some(track)) => track.performer = Some(arg(1)?),
+			("SONGWRITER", Some(track)) => track.songwriter = Some(arg(1)?),
+			("REM", Some(track)) if words.len() > 2 => track.rem.push((words[1].to_ascii_uppercase(), words[2..].join(" "))),
+			("TITLE", None) => sheet.title = Some(arg(1)?),
+			("PERFORMER", None) => sheet.performer = Some(arg(1)?),
+			("SONGWRITER", None) => sheet.songwriter = Some(arg(1)?),
+			("CATALOG", None) => sheet.catalog = Some(arg(1)?),
+			("REM", None) if words.len() > 2 => sheet.rem.push((words[1].to_ascii_uppercase(), words[2..].join(" "))),
+			(_, None) if ["INDEX", "PREGAP", "POSTGAP", "FLAGS", "ISRC"].contains(&command.as_str()) => {
+				return Err(format!("CUE line {}: {} outside a track", line_number + 1, command));
+			},
+			// Bare REM lines, CDTEXTFILE and anything else, such as the CD-TEXT
+			// fields ARRANGER, MESSAGE or DISC_ID that some rippers write, are
+			// passed over
+			_ => {},
+		}
+	}
+
+	if sheet.tracks.is_empty() {
+		return Err("CUE sheet has no tracks".to_string());
+	}
+	Ok(sheet)
+}
+
+// Rippers write UTF-8 or the system code page; anything that is not valid
+// UTF-8 is taken to be Latin-1
+pub fn read(path: &str) -> Result<CueSheet, String> {
+	let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+	let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&bytes);
+	let text = match std::str::from_utf8(bytes) {
+		Ok(text) => text.to_string(),
+		Err(_) => bytes.iter().map(|&b| b as char).collect(),
+	};
+	parse(&text)
+}
+
+impl CueSheet {
+	// Tags for one track, layered over the tags of the whole image. REM
+	// lines like REM DATE 1999 or REM REPLAYGAIN_ALBUM_GAIN -6.2 dB become
+	// fields of the same name.
+	pub fn track_metadata(&self, index: usize, album: &Metadata) -> Metadata {
+		let track = &self.tracks[index];
+		let mut metadata = album.clone();
+
+		// Whole-image facts no longer hold for a single track
+		metadata.remove("TITLE");
+		metadata.remove("TRACKNUMBER");
+		metadata.remove("REPLAYGAIN_TRACK_GAIN");
+		metadata.remove("REPLAYGAIN_TRACK_PEAK");
+		metadata.remove("CUESHEET");
+		metadata.cue_sheet = None;
+		metadata.audio_md5 = None;
+
+		let mut set = |name: &str, value: &Option<String>| {
+			if let Some(ref value) = value {
+				metadata.remove(name);
+				metadata.set(name, value);
+			}
+		};
+		set("ALBUM", &self.title);
+		set("ALBUMARTIST", &self.performer);
+		set("ARTIST", &self.performer);
+		set("COMPOSER", &self.songwriter);
+		set("CATALOGNUMBER", &self.catalog);
+		set("TITLE", &track.title);
+		set("ARTIST", &track.performer);
+		set("COMPOSER", &track.songwriter);
+		set("ISRC", &track.isrc);
+
+		for (name, value) in self.rem.iter().chain(track.rem.iter()) {
+			let value = value.trim_matches('"');
+			metadata.remove(name);
+			metadata.set(name, value);
+		}
+
+		metadata.track = Some(track.number);
+		metadata.track_total = Some(self.tracks.len() as u32);
+		metadata
+	}
+}
+
+// Written the way EAC lays sheets out, with every audio FILE of type WAVE
+impl fmt::Display for CueSheet {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (name, value) in &self.rem {
+			writeln!(f, "REM {} {}", name, value)?;
+		}
+		if let Some(ref catalog) = self.catalog {
+			writeln!(f, "CATALOG {}", catalog)?;
+		}
+		if let Some(ref performer) = self.performer {
+			writeln!(f, "PERFORMER \"{}\"", performer)?;
+		}
+		if let Some(ref songwriter) = self.songwriter {
+			writeln!(f, "SONGWRITER \"{}\"", songwriter)?;
+		}
+		if let Some(ref title) = self.title {
+			writeln!(f, "TITLE \"{}\"", title)?;
+		}
+
+		for (file, name) in self.files.iter().enumerate() {
+			writeln!(f, "FILE \"{}\" WAVE", name)?;
+			for track in self.tracks.iter().filter(|t| t.file == file) {
+				writeln!(f, "  TRACK {:02} {}", track.number, track.data_type)?;
+				if let Some(ref title) = track.title {
+					writeln!(f, "    TITLE \"{}\"", title)?;
+				}
+				if let Some(ref performer) = track.performer {
+					writeln!(f, "    PERFORMER \"{}\"", performer)?;
+				}
+				if let Some(ref songwriter) = track.songwriter {
+					writeln!(f, "    SONGWRITER \"{}\"", songwriter)?;
+				}
+				for (name, value) in &track.rem {
+					writeln!(f, "    REM {} {}", name, value)?;
+				}
+				if !track.flags.is_empty() {
+					writeln!(f, "    FLAGS {}", track.flags.join(" "))?;
+				}
+				if let Some(ref isrc) = track.isrc {
+					writeln!(f, "    ISRC {}", isrc)?;
+				}
+				if track.pregap > 0 {
+					writeln!(f, "    PREGAP {}", format_time(track.pregap))?;
+				}
+				for (number, position) in &track.indices {
+					writeln!(f, "    INDEX {:02} {}", number, format_time(*position))?;
+				}
+				if track.postgap > 0 {
+					writeln!(f, "    POSTGAP {}", format_time(track.postgap))?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SHEET : &str = "REM GENRE Rock\n\
+		REM DATE 1999\n\
+		PERFORMER \"The Band\"\n\
+		TITLE \"Album\"\n\
+		DISC_ID 1234ABCD\n\
+		FILE \"image.wav\" WAVE\n\
+		  TRACK 01 AUDIO\n\
+		    TITLE \"One\"\n\
+		    ARRANGER \"Someone\"\n\
+		    INDEX 01 00:00:00\n\
+		  TRACK 02 AUDIO\n\
+		    TITLE \"Two words\"\n\
+		    FLAGS DCP pre\n\
+		    PREGAP 00:01:00\n\
+		    INDEX 00 02:58:70\n\
+		    INDEX 01 03:00:05\n";
+
+	#[test]
+	fn times() {
+		assert_eq!(parse_time("00:00:00"), Ok(0));
+		assert_eq!(parse_time("01:02:03"), Ok((60 + 2) * 75 + 3));
+		assert_eq!(parse_time("120:00:00"), Ok(120 * 60 * 75));
+		assert!(parse_time("00:60:00").is_err());
+		assert!(parse_time("00:00:75").is_err());
+		assert!(parse_time("00:00").is_err());
+		assert!(parse_time("aa:00:00").is_err());
+		assert_eq!(format_time(parse_time("74:59:74").unwrap()), "74:59:74");
+	}
+
+	#[test]
+	fn sheet() {
+		let sheet = parse(SHEET).unwrap();
+		assert_eq!(sheet.title.as_deref(), Some("Album"));
+		assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+		assert_eq!(sheet.rem, vec![("GENRE".to_string(), "Rock".to_string()), ("DATE".to_string(), "1999".to_string())]);
+		assert_eq!(sheet.files, vec!["image.wav".to_string()]);
+		assert_eq!(sheet.tracks.len(), 2);
+
+		let two = &sheet.tracks[1];
+		assert_eq!(two.number, 2);
+		assert_eq!(two.title.as_deref(), Some("Two words"));
+		assert_eq!(two.flags, vec!["DCP".to_string(), "PRE".to_string()]);
+		assert!(two.pre_emphasis());
+		assert_eq!(two.pregap, 75);
+		assert_eq!(two.gap_start(), (2 * 60 + 58) * 75 + 70);
+		assert_eq!(two.start(), 3 * 60 * 75 + 5);
+		assert_eq!(two.start_sample(44100), (3 * 60 * 75 + 5) * 588);
+
+		// A start between frames is kept exactly where there is one
+		let exact = CueTrack { sample_start: Some(12345), ..two.clone() };
+		assert_eq!(exact.start_sample(44100), 12345);
+		assert_eq!(exact.gap_start_sample(44100), ((2 * 60 + 58) * 75 + 70) * 588);
+	}
+
+	#[test]
+	fn errors() {
+		assert!(parse("").is_err());
+		assert!(parse("TRACK 01 AUDIO\n").is_err());
+		assert!(parse("INDEX 01 00:00:00\nFILE \"a.wav\" WAVE\nTRACK 01 AUDIO\n").is_err());
+		assert!(parse("FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:99\n").is_err());
+		assert!(parse("FILE \"a.wav\" WAVE\nTRACK xx AUDIO\n").is_err());
+	}
+
+	#[test]
+	fn written_sheet_parses_back() {
+		let sheet = parse(SHEET).unwrap();
+		let again = parse(&sheet.to_string()).unwrap();
+		assert_eq!(again.files, sheet.files);
+		assert_eq!(again.tracks.len(), sheet.tracks.len());
+		for (a, b) in again.tracks.iter().zip(&sheet.tracks) {
+			assert_eq!(a.title, b.title);
+			assert_eq!(a.indices, b.indices);
+			assert_eq!(a.pregap, b.pregap);
+		}
+	}
+}
@@ -0,0 +1,490 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Filter chains as written on the command line, such as
+// "gain=-3dB,resample=48kHz,dither=tpdf:bits=16". Filters are separated by
+// commas, each a name with an optional value after "=" and options after
+// ":", as key=value or a bare flag. Single quotes protect commas and colons
+// in a value, as in "remix='select:FL,FR'".
+//
+// A chain is checked against the format it will be given before anything
+// runs, so that a filter that cannot take what reaches it fails up front.
+
+use std::sync::mpsc;
+use std::thread;
+
+use crate::codec::Frame;
+use crate::codec::metadata::default_channel_mask;
+use crate::stage::{self, Stage};
+use crate::stage::dither::{self, Dither, NoiseShaping, Quantizer};
+use crate::stage::dynamics::{Compression, Compressor, Detector, Gate, Gating, Link};
+use crate::stage::eq::{Band, Design, Equalizer, Shape};
+use crate::stage::limiter::{self, Limit};
+use crate::stage::normalize::{self, Gain, Normalize};
+use crate::stage::remix::{self, Remix};
+use crate::stage::resample::{self, Resample};
+use crate::trim::{self, Position, Trim};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Format {
+	pub channels : usize,
+	pub sample_rate : usize,
+	pub bits_per_sample : usize,
+	// The speaker layout, as FLAC would assume it when the input has none
+	pub channel_mask : u32,
+}
+
+impl Format {
+	pub fn of(frame: &Frame) -> Format {
+		Format {
+			channels: frame.channels,
+			sample_rate: frame.sample_rate,
+			bits_per_sample: frame.bits_per_sample,
+			channel_mask: frame.metadata.as_ref().and_then(|m| m.channel_mask)
+				.unwrap_or_else(|| default_channel_mask(frame.channels)),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+	Gain(f64),
+	Remix(remix::Mode, bool),
+	Resample(usize, resample::Quality),
+	Dither { bits : usize, quantizer : Quantizer, shaping : NoiseShaping, seed : u64 },
+	Normalize { target : f64, ceiling : f64 },
+	Trim { start : Option<Position>, end : Option<Position>, duration : Option<Position> },
+	Eq(Band, Design),
+	Compress(Compression),
+	Gate(Gating),
+	Limit { ceiling : f64, lookahead : f64, release : f64 },
+}
+
+// Splits at every separator outside quotes, leaving the quotes in
+fn split(spec: &str, separator: char) -> Vec<&str> {
+	let mut parts = Vec::new();
+	let mut quoted = false;
+	let mut from = 0;
+	for (i, c) in spec.char_indices() {
+		if c == '\'' {
+			quoted = !quoted;
+		} else if c == separator && !quoted {
+			parts.push(&spec[from..i]);
+			from = i + 1;
+		}
+	}
+	parts.push(&spec[from..]);
+	parts
+}
+
+fn unquote(value: &str) -> String {
+	value.trim().replace('\'', "")
+}
+
+// A number with an optional unit after it, in any case
+fn number(value: &str, unit: &str) -> Option<f64> {
+	let value = value.trim();
+	let value = match value.len().checked_sub(unit.len()) {
+		Some(at) if value.is_char_boundary(at) && value[at..].eq_ignore_ascii_case(unit) => &value[..at],
+		_ => value,
+	};
+	value.trim().parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+pub fn decibels(value: &str) -> Option<f64> {
+	number(value, "dB")
+}
+
+// In Hz, also taking kHz
+pub fn frequency(value: &str) -> Option<f64> {
+	let hz = if value.trim().to_ascii_lowercase().ends_with("khz") {
+		number(value, "kHz")? * 1000.0
+	} else {
+		number(value, "Hz")?
+	};
+	Some(hz).filter(|&hz| hz > 0.0)
+}
+
+// In seconds, as ms or s; a bare number is in ms
+pub fn time(value: &str) -> Option<f64> {
+	let value = value.trim();
+	let seconds = if value.to_ascii_lowercase().ends_with("ms") {
+		number(value, "ms")? / 1000.0
+	} else if value.to_ascii_lowercase().ends_with('s') {
+		number(value, "s")?
+	} else {
+		number(value, "")? / 1000.0
+	};
+	Some(seconds).filter(|&s| s >= 0.0)
+}
+
+// The options given to one filter, taken as the filter reads them
+struct Options<'a> {
+	filter : &'a str,
+	values : Vec<(String, Option<String>)>,
+}
+
+impl<'a> Options<'a> {
+	fn take(&mut self, key: &str) -> Option<String> {
+		let at = self.values.iter().position(|(k, _)| k == key)?;
+		match self.values.remove(at) {
+			(_, Some(value)) => Some(value),
+			(_, None) => Some(String::new()),
+		}
+	}
+
+	fn parse<T>(&mut self, key: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+		match self.take(key) {
+			Some(value) => parse(&value).map(Some).ok_or_else(|| format!("Bad {} \"{}\" for {}", key, value, self.filter)),
+			None => Ok(None),
+		}
+	}
+
+	fn flag(&mut self, key: &str) -> bool {
+		self.take(key).is_some()
+	}
+
+	// Anything left over was not understood
+	fn done(self) -> Result<(), String> {
+		match self.values.first() {
+			Some((key, _)) => Err(format!("Unknown option {} for {}", key, self.filter)),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Filter {
+	pub fn parse(spec: &str) -> Result<Filter, String> {
+		let mut parts = split(spec, ':').into_iter();
+		let head = parts.next().unwrap();
+		let (name, value) = match head.split_once('=') {
+			Some((name, value)) => (name.trim(), Some(unquote(value))),
+			None => (head.trim(), None),
+		};
+		let mut options = Options {
+			filter: name,
+			values: parts.map(|part| match part.split_once('=') {
+				Some((key, value)) => (key.trim().to_string(), Some(unquote(value))),
+				None => (part.trim().to_string(), None),
+			}).collect(),
+		};
+		let needed = |value: Option<String>| value.ok_or_else(|| format!("{} needs a value", name));
+		let bad = |value: &str| format!("Bad value \"{}\" for {}", value, name);
+
+		let filter = match name {
+			"gain" => {
+				let value = needed(value)?;
+				Filter::Gain(decibels(&value).ok_or_else(|| bad(&value))?)
+			},
+			"remix" => {
+				let mode = remix::parse_mode(&needed(value)?)?;
+				Filter::Remix(mode, options.flag("normalize"))
+			},
+			"resample" => {
+				let value = needed(value)?;
+				let rate = frequency(&value).filter(|hz| hz.fract() == 0.0).ok_or_else(|| bad(&value))?;
+				let quality = options.parse("quality", resample::Quality::parse)?.unwrap_or(resample::Quality::High);
+				Filter::Resample(rate as usize, quality)
+			},
+			"dither" => Filter::Dither {
+				quantizer: match value {
+					Some(value) => Quantizer::parse(&value).ok_or_else(|| bad(&value))?,
+					None => Quantizer::Tpdf,
+				},
+				bits: options.parse("bits", |n| n.parse().ok().filter(|n| (8..=32).contains(n)))?.unwrap_or(16),
+				shaping: options.parse("shaping", NoiseShaping::parse)?.unwrap_or(NoiseShaping::None),
+				seed: options.parse("seed", |n| n.parse().ok())?.unwrap_or(dither::DEFAULT_SEED),
+			},
+			"normalize" => Filter::Normalize {
+				target: match value {
+					Some(value) => number(&value, "LUFS").ok_or_else(|| bad(&value))?,
+					None => normalize::DEFAULT_TARGET,
+				},
+				ceiling: options.parse("true-peak", |db| number(db, "dBTP"))?.unwrap_or(normalize::DEFAULT_CEILING),
+			},
+			"trim" => {
+				let frame_rate = options.parse("fps", |fps| fps.parse::<f64>().ok().filter(|fps| fps.is_finite() && *fps > 0.0))?;
+				let mut position = |key: &str| match options.take(key) {
+					Some(spec) => Position::parse(&spec, frame_rate).map(Some),
+					None => Ok(None),
+				};
+				Filter::Trim {
+					start: position("start")?,
+					end: position("end")?,
+					duration: position("duration")?,
+				}
+			},
+			"highpass" | "lowpass" | "lowshelf" | "highshelf" | "notch" | "peak" => {
+				let value = needed(value)?;
+				let hz = frequency(&value).ok_or_else(|| bad(&value))?;
+				let mut gain = || options.parse("gain", decibels)?.ok_or_else(|| format!("{} needs a gain", name));
+				let shape = match name {
+					"highpass" => Shape::HighPass,
+					"lowpass" => Shape::LowPass,
+					"lowshelf" => Shape::LowShelf(gain()?),
+					"highshelf" => Shape::HighShelf(gain()?),
+					"notch" => Shape::Notch,
+					_ => Shape::Peak(gain()?),
+				};
+				let mut band = Band::new(shape, hz);
+				match shape {
+					Shape::HighPass | Shape::LowPass => {
+						band.order = options.parse("order", |n| n.parse().ok().filter(|n| (1..=8).contains(n)))?.unwrap_or(band.order);
+					},
+					_ => {
+						band.q = options.parse("q", |q| q.parse::<f64>().ok().filter(|q| q.is_finite() && *q > 0.0))?.unwrap_or(band.q);
+					},
+				}
+				let design = match options.take("linear") {
+					None => Design::Iir,
+					Some(taps) if taps.is_empty() => Design::Fir(None),
+					Some(taps) => Design::Fir(Some(taps.parse().ok().filter(|n| n % 2 == 1 && *n >= 3).ok_or_else(|| format!("Bad linear \"{}\" for {}: the taps must be odd", taps, name))?)),
+				};
+				Filter::Eq(band, design)
+			},
+			"compress" => {
+				let defaults = Compression::default();
+				Filter::Compress(Compression {
+					threshold: options.parse("threshold", decibels)?.unwrap_or(defaults.threshold),
+					ratio: options.parse("ratio", |r| r.parse::<f64>().ok().filter(|r| r.is_finite() && *r >= 1.0))?.unwrap_or(defaults.ratio),
+					knee: options.parse("knee", |db| decibels(db).filter(|db| *db >= 0.0))?.unwrap_or(defaults.knee),
+					attack: options.parse("attack", time)?.unwrap_or(defaults.attack),
+					release: options.parse("release", time)?.unwrap_or(defaults.release),
+					makeup: options.parse("makeup", decibels)?.unwrap_or(defaults.makeup),
+					detector: options.parse("detector", Detector::parse)?.unwrap_or(defaults.detector),
+					link: options.parse("link", Link::parse)?.unwrap_or(defaults.link),
+				})
+			},
+			"gate" => {
+				let defaults = Gating::default();
+				Filter::Gate(Gating {
+					threshold: options.parse("threshold", decibels)?.unwrap_or(defaults.threshold),
+					range: options.parse("range", |db| decibels(db).filter(|db| *db <= 0.0))?.unwrap_or(defaults.range),
+					attack: options.parse("attack", time)?.unwrap_or(defaults.attack),
+					hold: options.parse("hold", time)?.unwrap_or(defaults.hold),
+					release: options.parse("release", time)?.unwrap_or(defaults.release),
+					detector: options.parse("detector", Detector::parse)?.unwrap_or(defaults.detector),
+					link: options.parse("link", Link::parse)?.unwrap_or(defaults.link),
+				})
+			},
+			"limit" => Filter::Limit {
+				ceiling: match value {
+					Some(value) => number(&value, "dBTP").ok_or_else(|| bad(&value))?,
+					None => normalize::DEFAULT_CEILING,
+				},
+				lookahead: options.parse("lookahead", |t| time(t).filter(|t| *t > 0.0))?.unwrap_or(limiter::DEFAULT_LOOKAHEAD),
+				release: options.parse("release", time)?.unwrap_or(limiter::DEFAULT_RELEASE),
+			},
+			_ => return Err(format!("Unknown filter {}", name)),
+		};
+		options.done()?;
+		Ok(filter)
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Filter::Gain(_) => "gain",
+			Filter::Remix(_, _) => "remix",
+			Filter::Resample(_, _) => "resample",
+			Filter::Dither { .. } => "dither",
+			Filter::Normalize { .. } => "normalize",
+			Filter::Trim { .. } => "trim",
+			Filter::Eq(band, _) => band.shape.name(),
+			Filter::Compress(_) => "compress",
+			Filter::Gate(_) => "gate",
+			Filter::Limit { .. } => "limit",
+		}
+	}
+
+	// What comes out for a given input, if the filter can take it
+	pub fn output(&self, format: Format) -> Result<Format, String> {
+		match *self {
+			Filter::Gain(_) | Filter::Normalize { .. } | Filter::Compress(_) | Filter::Gate(_) | Filter::Limit { .. } => Ok(format),
+			Filter::Remix(ref mode, normalize) => {
+				let (channels, channel_mask) = Remix::new(mode.clone(), normalize).output(format.channel_mask, format.channels)?;
+				Ok(Format { channels, channel_mask, ..format })
+			},
+			Filter::Resample(sample_rate, _) => Ok(Format { sample_rate, ..format }),
+			Filter::Dither { bits, .. } => Ok(Format { bits_per_sample: bits, ..format }),
+			Filter::Trim { start, end, duration } => trim::range(start, end, duration, format.sample_rate).map(|_| format),
+			Filter::Eq(band, _) => match band.frequency < format.sample_rate as f64 / 2.0 {
+				true => Ok(format),
+				false => Err(format!("{} Hz is not below the Nyquist frequency of {} Hz", band.frequency, format.sample_rate as f64 / 2.0)),
+			},
+		}
+	}
+
+	pub fn stage(&self) -> Box<dyn Stage + Send> {
+		match *self {
+			Filter::Gain(db) => Box::new(Gain::new(db)),
+			Filter::Remix(ref mode, normalize) => Box::new(Remix::new(mode.clone(), normalize)),
+			Filter::Resample(rate, quality) => Box::new(Resample::new(rate, quality)),
+			Filter::Dither { bits, quantizer, shaping, seed } => Box::new(Dither::new(bits, quantizer, shaping, seed)),
+			Filter::Normalize { target, ceiling } => Box::new(Normalize::new(target, ceiling)),
+			Filter::Trim { start, end, duration } => Box::new(Trim::new(start, end, duration)),
+			Filter::Eq(band, design) => Box::new(Equalizer::new(vec![band], design)),
+			Filter::Compress(settings) => Box::new(Compressor::new(settings)),
+			Filter::Gate(settings) => Box::new(Gate::new(settings)),
+			Filter::Limit { ceiling, lookahead, release } => Box::new(Limit::new(ceiling, lookahead, release)),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Graph {
+	pub filters : Vec<Filter>,
+}
+
+impl Graph {
+	pub fn parse(spec: &str) -> Result<Graph, String> {
+		if spec.matches('\'').count() % 2 == 1 {
+			return Err(format!("Unmatched quote in \"{}\"", spec));
+		}
+		let filters = split(spec, ',').into_iter().map(|filter| match filter.trim() {
+			"" => Err(format!("Empty filter in \"{}\"", spec)),
+			filter => Filter::parse(filter),
+		}).collect::<Result<Vec<Filter>, String>>()?;
+		Ok(Graph { filters })
+	}
+
+	// The format coming out of the end, or the first filter that cannot
+	// take what reaches it
+	pub fn check(&self, format: Format) -> Result<Format, String> {
+		self.filters.iter().enumerate().try_fold(format, |format, (i, filter)| {
+			filter.output(format).map_err(|e| format!("{} (filter {}): {}", filter.name(), i + 1, e))
+		})
+	}
+
+	pub fn stages(&self) -> Vec<Box<dyn Stage + Send>> {
+		self.filters.iter().map(Filter::stage).collect()
+	}
+
+	// Runs the chain between a decoder's output and an encoder's input
+	pub fn spawn(&self, rx: mpsc::Receiver<Frame>) -> (mpsc::Receiver<Frame>, Vec<thread::JoinHandle<Box<dyn Stage + Send>>>) {
+		stage::spawn_chain(self.stages(), rx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CD : Format = Format { channels: 2, sample_rate: 44100, bits_per_sample: 16, channel_mask: 0x3 };
+
+	#[test]
+	fn units() {
+		assert_eq!(decibels("-3dB"), Some(-3.0));
+		assert_eq!(decibels("-3 db"), Some(-3.0));
+		assert_eq!(decibels("loud"), None);
+		assert_eq!(frequency("1.5kHz"), Some(1500.0));
+		assert_eq!(frequency("80"), Some(80.0));
+		assert_eq!(frequency("0Hz"), None);
+		assert_eq!(time("5ms"), Some(0.005));
+		assert_eq!(time("2s"), Some(2.0));
+		assert_eq!(time("250"), Some(0.25));
+		assert_eq!(time("-1s"), None);
+	}
+
+	#[test]
+	fn chain() {
+		let graph = Graph::parse("gain=-3dB, highpass=80:order=4, remix='select:1,0', resample=48kHz:quality=best, dither:bits=16:seed=7").unwrap();
+		assert_eq!(graph.filters.len(), 5);
+		assert_eq!(graph.filters[0], Filter::Gain(-3.0));
+		match graph.filters[1] {
+			Filter::Eq(band, Design::Iir) => {
+				assert_eq!(band.shape, Shape::HighPass);
+				assert_eq!(band.frequency, 80.0);
+				assert_eq!(band.order, 4);
+			},
+			ref other => panic!("{:?}", other),
+		}
+		assert_eq!(graph.filters[2], Filter::Remix(remix::Mode::Select(vec![remix::Channel::Index(1), remix::Channel::Index(0)]), false));
+		assert_eq!(graph.filters[3], Filter::Resample(48000, resample::Quality::Best));
+		assert_eq!(graph.filters[4], Filter::Dither { bits: 16, quantizer: Quantizer::Tpdf, shaping: NoiseShaping::None, seed: 7 });
+	}
+
+	#[test]
+	fn normalize_and_trim() {
+		let graph = Graph::parse("normalize=-16LUFS:true-peak=-2,trim:start=10s:duration=5s:fps=25").unwrap();
+		assert_eq!(graph.filters[0], Filter::Normalize { target: -16.0, ceiling: -2.0 });
+		assert_eq!(graph.filters[1], Filter::Trim { start: Some(Position::Seconds(10.0)), end: None, duration: Some(Position::Seconds(5.0)) });
+		assert_eq!(Filter::parse("normalize").unwrap(), Filter::Normalize { target: normalize::DEFAULT_TARGET, ceiling: normalize::DEFAULT_CEILING });
+		match Graph::parse("peak=1kHz:gain=3:linear=255").unwrap().filters[0] {
+			Filter::Eq(band, Design::Fir(Some(255))) => assert_eq!(band.shape, Shape::Peak(3.0)),
+			ref other => panic!("{:?}", other),
+		}
+	}
+
+	#[test]
+	fn options() {
+		let graph = Graph::parse("compress:threshold=-30:ratio=2:attack=5ms:detector=peak,limit=-2:lookahead=3").unwrap();
+		match graph.filters[0] {
+			Filter::Compress(settings) => {
+				assert_eq!(settings.threshold, -30.0);
+				assert_eq!(settings.ratio, 2.0);
+				assert_eq!(settings.attack, 0.005);
+				assert_eq!(settings.detector, Detector::Peak);
+				assert_eq!(settings.release, Compression::default().release);
+			},
+			ref other => panic!("{:?}", other),
+		}
+		assert_eq!(graph.filters[1], Filter::Limit { ceiling: -2.0, lookahead: 0.003, release: limiter::DEFAULT_RELEASE });
+	}
+
+	#[test]
+	fn parse_errors() {
+		for spec in [
+			"",
+			"gain=-3,",
+			"gain",
+			"gain=loud",
+			"echo=1",
+			"gain=-3:extra=1",
+			"remix='select:0,1",
+			"normalize=loud",
+			"trim:start=1x",
+			"peak=1kHz",
+			"lowpass=1kHz:order=9",
+			"lowpass=1kHz:linear=256",
+			"compress:ratio=0.5",
+			"dither:bits=4",
+		] {
+			assert!(Graph::parse(spec).is_err(), "{}", spec);
+		}
+	}
+
+	#[test]
+	fn formats() {
+		let graph = Graph::parse("remix=mono,resample=48000,dither:bits=24").unwrap();
+		assert_eq!(graph.check(CD), Ok(Format { channels: 1, sample_rate: 48000, bits_per_sample: 24, channel_mask: 0x4 }));
+
+		// Checked against the format that reaches each filter
+		assert!(Graph::parse("lowpass=30kHz").unwrap().check(CD).is_err());
+		assert!(Graph::parse("resample=96kHz,lowpass=30kHz").unwrap().check(CD).is_ok());
+		let error = Graph::parse("gain=1,resample=22050,lowpass=15kHz").unwrap().check(CD).unwrap_err();
+		assert!(error.starts_with("lowpass (filter 3): "), "{}", error);
+		let error = Graph::parse("gain=1,remix=mono,remix='select:1'").unwrap().check(CD).unwrap_err();
+		assert!(error.starts_with("remix (filter 3): "), "{}", error);
+		assert!(Graph::parse("remix='select:1'").unwrap().check(CD).is_ok());
+		assert!(Graph::parse("remix='matrix:1,1,1'").unwrap().check(CD).is_err());
+		// Speakers are looked up in the layout that reaches the filter
+		assert!(Graph::parse("remix='select:FC'").unwrap().check(CD).is_err());
+		let surround = Format { channels: 6, channel_mask: 0x3f, ..CD };
+		assert_eq!(Graph::parse("remix='select:FC,LFE'").unwrap().check(surround).map(|f| (f.channels, f.channel_mask)), Ok((2, 0xc)));
+		assert!(Graph::parse("remix=stereo,remix='select:FC'").unwrap().check(surround).is_err());
+		assert!(Graph::parse("trim:start=10:end=5").unwrap().check(CD).is_err());
+	}
+}
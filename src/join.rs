@@ -0,0 +1,248 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Joining tracks gaplessly into one image, with a cue sheet or markers
+// showing where each began
+
+use std::sync::mpsc;
+
+use crate::codec::{Frame, Marker, Metadata};
+use crate::cue::{CueSheet, CueTrack, CD_FRAMES_PER_SECOND};
+
+// A value every track agrees on, if there is one
+fn common<F>(headers: &[Frame], get: F) -> Option<String>
+	where F: Fn(&Metadata) -> Option<String>
+{
+	let values : Vec<Option<String>> = headers.iter().map(|h| h.metadata.as_ref().and_then(&get)).collect();
+	match values.first() {
+		Some(Some(first)) if values.iter().all(|v| v.as_ref() == Some(first)) => Some(first.clone()),
+		_ => None,
+	}
+}
+
+fn field(metadata: &Metadata, name: &str) -> Option<String> {
+	metadata.fields.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone())
+}
+
+// The sheet for tracks of the given lengths laid end to end. Track starts
+// are exact in samples, and rounded down to whole CD frames only for the
+// text of the sheet.
+pub fn cue_sheet(headers: &[Frame]) -> CueSheet {
+	let mut sheet = CueSheet {
+		title: common(headers, |m| m.album.clone()),
+		performer: common(headers, |m| field(m, "ALBUMARTIST")).or_else(|| common(headers, |m| m.artist.clone())),
+		catalog: common(headers, |m| field(m, "CATALOGNUMBER")),
+		files: vec![String::new()],
+		..CueSheet::default()
+	};
+	if let Some(genre) = common(headers, |m| field(m, "GENRE")) {
+		sheet.rem.push(("GENRE".to_string(), genre));
+	}
+	if let Some(date) = common(headers, |m| m.date.clone()) {
+		sheet.rem.push(("DATE".to_string(), date));
+	}
+
+	let mut position = 0;
+	for (i, header) in headers.iter().enumerate() {
+		let metadata = header.metadata.clone().unwrap_or_default();
+		let frames = position * CD_FRAMES_PER_SECOND / header.sample_rate as u64;
+		sheet.tracks.push(CueTrack {
+			number: i as u32 + 1,
+			file: 0,
+			data_type: "AUDIO".to_string(),
+			title: metadata.title.clone(),
+			performer: metadata.artist.clone().filter(|a| Some(a) != sheet.performer.as_ref()),
+			songwriter: field(&metadata, "COMPOSER"),
+			isrc: field(&metadata, "ISRC"),
+			indices: vec![(1, frames)],
+			sample_start: Some(position),
+			..CueTrack::default()
+		});
+		position += metadata.total_samples.expect("Track length unknown");
+	}
+	sheet
+}
+
+// Whether inputs with these headers can be joined
+pub fn check(headers: &[Frame]) -> Result<(), String> {
+	let first = headers.first().ok_or("Nothing to join")?;
+	if headers.iter().any(|h| h.channels != first.channels || h.sample_rate != first.sample_rate || h.bits_per_sample != first.bits_per_sample) {
+		return Err("Inputs to join must share channels, sample rate and bits per sample".to_string());
+	}
+	if headers.iter().any(|h| h.metadata.as_ref().and_then(|m| m.total_samples).is_none()) {
+		return Err("Inputs to join must have a known length".to_string());
+	}
+	Ok(())
+}
+
+// How the joined image records where each input began
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Marks {
+	None,
+	CueSheet,
+	// A marker named after the track's title
+	Markers,
+}
+
+// Sends the header for the joined image, then the audio of each input in
+// turn. `open` starts decoding input i.
+pub fn join<F>(headers: Vec<Frame>, tx: mpsc::Sender<Frame>, marks: Marks, mut open: F) -> Result<(), String>
+	where F: FnMut(usize) -> mpsc::Receiver<Frame>
+{
+	check(&headers)?;
+	let first = &headers[0];
+
+	let sheet = cue_sheet(&headers);
+	let mut metadata = first.metadata.clone().unwrap_or_default();
+	for name in &["TITLE", "TRACKNUMBER", "ISRC", "REPLAYGAIN_TRACK_GAIN", "REPLAYGAIN_TRACK_PEAK", "CUESHEET"] {
+		metadata.remove(name);
+	}
+	metadata.artist = sheet.performer.clone();
+	metadata.audio_md5 = None;
+	metadata.sampler = None;
+	metadata.markers.clear();
+
+	// Markers from every input, moved to where that input now starts
+	let mut offset = 0;
+	for (i, header) in headers.iter().enumerate() {
+		let m = header.metadata.as_ref().unwrap();
+		if marks == Marks::Markers {
+			metadata.markers.push(Marker {
+				position: offset,
+				label: m.title.clone().unwrap_or_else(|| format!("Track {}", i + 1)),
+				..Marker::default()
+			});
+		}
+		metadata.markers.extend(m.markers.iter().cloned().map(|mut marker| {
+			marker.position += offset;
+			marker
+		}));
+		offset += m.total_samples.unwrap();
+	}
+	metadata.total_samples = Some(offset);
+	if marks == Marks::CueSheet {
+		metadata.cue_sheet = Some(sheet);
+	}
+
+	let (channels, sample_rate, bits_per_sample) = (first.channels, first.sample_rate, first.bits_per_sample);
+	tx.send(Frame {
+		channels,
+		sample_rate,
+		bits_per_sample,
+		samples: Vec::new(),
+		metadata: Some(metadata),
+		eof: false,
+	}).unwrap();
+
+	for i in 0..headers.len() {
+		let rx = open(i);
+		let _header = rx.recv().unwrap();
+		loop {
+			let mut frame = rx.recv().unwrap();
+			let last = frame.eof;
+			frame.eof = last && i + 1 == headers.len();
+			if !frame.samples.is_empty() || frame.eof {
+				tx.send(frame).unwrap();
+			}
+			if last {
+				break;
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn header(title: &str, total_samples: u64, markers: Vec<Marker>) -> Frame {
+		let metadata = Metadata {
+			title: Some(title.to_string()),
+			album: Some("Album".to_string()),
+			total_samples: Some(total_samples),
+			markers,
+			..Metadata::default()
+		};
+		Frame { channels: 1, sample_rate: 44100, bits_per_sample: 16, samples: Vec::new(), metadata: Some(metadata), eof: false }
+	}
+
+	fn marker(position: u64, label: &str) -> Marker {
+		Marker { position, label: label.to_string(), ..Marker::default() }
+	}
+
+	// Joins the inputs, returning the joined header and audio
+	fn run(headers: Vec<Frame>, marks: Marks) -> (Metadata, Vec<i32>) {
+		let lengths : Vec<u64> = headers.iter().map(|h| h.metadata.as_ref().unwrap().total_samples.unwrap()).collect();
+		let inputs = headers.clone();
+		let (tx, rx) = mpsc::channel();
+		join(headers, tx, marks, |i| {
+			let (input_tx, input_rx) = mpsc::channel();
+			input_tx.send(inputs[i].clone()).unwrap();
+			let samples = vec![i as i32 + 1; lengths[i] as usize];
+			input_tx.send(Frame { samples, eof: true, metadata: None, ..inputs[i].clone() }).unwrap();
+			input_rx
+		}).unwrap();
+
+		let metadata = rx.recv().unwrap().metadata.unwrap();
+		let mut samples = Vec::new();
+		for frame in rx {
+			samples.extend(frame.samples);
+		}
+		(metadata, samples)
+	}
+
+	#[test]
+	fn marker_offsets() {
+		let headers = vec![
+			header("One", 1000, vec![marker(10, "a")]),
+			header("Two", 588, vec![marker(0, "b"), marker(500, "c")]),
+			header("Three", 300, Vec::new()),
+		];
+		let (metadata, samples) = run(headers, Marks::Markers);
+		assert_eq!(metadata.total_samples, Some(1888));
+		assert_eq!(samples.len(), 1888);
+		assert_eq!((samples[999], samples[1000], samples[1588]), (1, 2, 3));
+
+		let markers : Vec<(u64, &str)> = metadata.markers.iter().map(|m| (m.position, m.label.as_str())).collect();
+		assert_eq!(markers, vec![(0, "One"), (10, "a"), (1000, "Two"), (1000, "b"), (1500, "c"), (1588, "Three")]);
+		assert!(metadata.cue_sheet.is_none());
+	}
+
+	#[test]
+	fn cue_sheet_offsets() {
+		let headers = vec![header("One", 1000, Vec::new()), header("Two", 588, Vec::new()), header("Three", 300, Vec::new())];
+		let (metadata, _) = run(headers, Marks::CueSheet);
+		assert!(metadata.markers.is_empty());
+		assert_eq!(metadata.title, None);
+		assert_eq!(metadata.album.as_deref(), Some("Album"));
+
+		// Exact in samples, rounded down to CD frames in the text
+		let sheet = metadata.cue_sheet.unwrap();
+		let starts : Vec<(Option<u64>, u64)> = sheet.tracks.iter().map(|t| (t.sample_start, t.start())).collect();
+		assert_eq!(starts, vec![(Some(0), 0), (Some(1000), 1), (Some(1588), 2)]);
+		assert_eq!(sheet.title.as_deref(), Some("Album"));
+	}
+
+	#[test]
+	fn mismatched_inputs() {
+		let mut other = header("Two", 100, Vec::new());
+		other.sample_rate = 48000;
+		assert!(check(&[header("One", 100, Vec::new()), other]).is_err());
+		assert!(check(&[]).is_err());
+	}
+}
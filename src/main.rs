@@ -16,31 +16,1095 @@
 //
 
 use std::env;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read};
+use std::path::Path;
 
 use std::thread;
 use std::sync::mpsc;
 
-mod codec;
+use chaud::codec;
+use chaud::codec::{EncoderOptions, Frame, Picture, Tags};
+use chaud::cue::{self, CueSheet};
+use chaud::filter::{Filter, Format, Graph};
+use chaud::join::{self, Marks};
+use chaud::mix;
+use chaud::replaygain;
+use chaud::split::{self, Pregap};
+use chaud::stage::{self, Stage};
+use chaud::stage::resample::{self, Resample};
+use chaud::stage::dither::{self, Dither, NoiseShaping, Quantizer};
+use chaud::stage::remix::{self, Remix};
+use chaud::stage::loudness::LoudnessMeter;
+use chaud::stage::normalize::{self, Gain, Normalize};
+use chaud::trim::{self, Position};
 
-fn main() -> Result<(), std::io::Error> {
-	let args: Vec <_> = env::args().collect();
+const USAGE : &str = "usage: chaud [convert] [--mark-joins cuesheet|markers] [STAGES] [OPTIONS] <input>... <output>
+       chaud split [--cue FILE] [--pregap append|prepend|drop] [--format wav|flac] [OPTIONS] <input> [<directory>]
+       chaud join [--cue FILE] [OPTIONS] <input>... <output>
+       chaud mix [--headroom DB] [--limit DBTP] [OPTIONS] [--gain DB] [--pan PAN] [--offset POS] <input>... <output>
+       chaud tee <input> {[CONVERSION] [OPTIONS] <output>}...
+       chaud cue <input> [<output>]
+       chaud loudness [--json] <input>
+       chaud tag [--set NAME=VALUE] [--add NAME=VALUE] [--remove NAME] [--remove-all] [--import FILE]
+                 [--picture [TYPE:]FILE] [--remove-pictures] [--export FILE] [--export-picture FILE] <file>...
+       chaud replaygain [--no-album] [--dry-run] <input>...
+       chaud normalize [--album] [--target LUFS] [--true-peak DBTP] [--single-pass] [--format wav|flac] [OPTIONS] <input>... <directory>
 
-    let (tx, rx) = mpsc::channel();
+STAGES: [--start POS] [--end POS | --duration POS] [--timecode-rate FPS]
+        [CONVERSION] [--normalize LUFS [--true-peak DBTP] [--single-pass]]
+        [--loudness-report FILE [--json]]
 
-	let dec_path = args[1].clone();
-	let dec_thread = thread::spawn(move || {
-		codec::wav::read_wav(&dec_path, tx)
-		//codec::flac::read_flac(&dec_path, tx)
-	});
+CONVERSION: [--filter FILTER[,FILTER]...]...
+            [--remix mono|stereo|duplicate:N|select:LIST|matrix:ROWS [--remix-normalize]]
+            [--rate HZ [--resample-quality low|medium|high|best]]
+            [--bits N [--dither truncate|round|tpdf] [--noise-shaping CURVE] [--dither-seed N]]
+
+FILTER: gain=DB | remix=MODE[:normalize] | resample=HZ[:quality=Q] | dither[=QUANTIZER][:bits=N][:shaping=CURVE][:seed=N]
+        | normalize[=LUFS][:true-peak=DBTP] | trim[:start=POS][:end=POS][:duration=POS][:fps=FPS]
+        | highpass=HZ[:order=N] | lowpass=HZ[:order=N] | notch=HZ[:q=Q] | peak=HZ:gain=DB[:q=Q]
+        | lowshelf=HZ:gain=DB[:q=Q] | highshelf=HZ:gain=DB[:q=Q]   (EQ: add :linear[=TAPS] for linear phase)
+        | compress[:threshold=DB][:ratio=R][:knee=DB][:attack=T][:release=T][:makeup=DB][:detector=D][:link=L]
+        | gate[:threshold=DB][:range=DB][:attack=T][:hold=T][:release=T][:detector=D][:link=L]
+        | limit[=DBTP][:lookahead=T][:release=T]   (T in ms or s, D rms|peak, L max|average|none)
+
+OPTIONS: [--tag NAME=VALUE] [--picture [TYPE:]FILE] [--padding BYTES | --no-padding] [--seekpoints SECONDS | --no-seektable]";
+
+fn invalid(message: &str) -> Error {
+	Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn extension(path: &str) -> String {
+	Path::new(path).extension()
+		.and_then(|ext| ext.to_str())
+		.unwrap_or("")
+		.to_ascii_lowercase()
+}
+
+fn tag_field(arg: Option<String>, option: &str) -> Result<(String, String), Error> {
+	let message = format!("{} needs NAME=VALUE", option);
+	let tag = arg.ok_or_else(|| invalid(&message))?;
+	let eq = tag.find('=').ok_or_else(|| invalid(&message))?;
+	Ok((tag[..eq].to_string(), tag[eq+1..].to_string()))
+}
+
+// An optional leading APIC picture type, defaulting to the front cover
+fn picture_option(arg: Option<String>) -> Result<Picture, Error> {
+	let spec = arg.ok_or_else(|| invalid("--picture needs a file"))?;
+	let (picture_type, file) = match spec.find(':') {
+		Some(colon) if spec[..colon].parse::<u32>().is_ok() => (spec[..colon].parse().unwrap(), &spec[colon+1..]),
+		_ => (3, spec.as_str()),
+	};
+	Picture::from_file(file, picture_type)
+}
+
+// Handles the encoder options every subcommand shares; false if arg is not one
+fn encoder_option(arg: &str, args: &mut dyn Iterator<Item = String>, options: &mut EncoderOptions) -> Result<bool, Error> {
+	match arg {
+		"--tag" => options.tags.push(tag_field(args.next(), "--tag")?),
+		"--picture" => options.pictures.push(picture_option(args.next())?),
+		"--padding" => {
+			let bytes = args.next().ok_or_else(|| invalid("--padding needs a size in bytes"))?;
+			options.padding = Some(bytes.parse().map_err(|_| invalid("--padding needs a size in bytes"))?);
+		},
+		"--no-padding" => options.padding = None,
+		"--seekpoints" => {
+			let seconds = args.next().ok_or_else(|| invalid("--seekpoints needs an interval in seconds"))?;
+			options.seekpoint_interval = Some(seconds.parse().map_err(|_| invalid("--seekpoints needs an interval in seconds"))?);
+		},
+		"--no-seektable" => options.seekpoint_interval = None,
+		_ => return Ok(false),
+	}
+	Ok(true)
+}
+
+fn spawn_decoder(path: String, tx: mpsc::Sender<Frame>) -> Result<thread::JoinHandle<()>, Error> {
+	match extension(&path).as_str() {
+		"wav" => Ok(thread::spawn(move || codec::wav::read_wav(&path, tx))),
+		"flac" => Ok(thread::spawn(move || codec::flac::read_flac(&path, tx))),
+		_ => Err(invalid("Unsupported input format")),
+	}
+}
+
+fn spawn_decoder_range(path: String, (start, end): (u64, Option<u64>), tx: mpsc::Sender<Frame>) -> Result<thread::JoinHandle<()>, Error> {
+	match extension(&path).as_str() {
+		"wav" => Ok(thread::spawn(move || codec::wav::read_wav_range(&path, tx, start, end))),
+		"flac" => Ok(thread::spawn(move || codec::flac::read_flac_range(&path, tx, start, end))),
+		_ => Err(invalid("Unsupported input format")),
+	}
+}
+
+fn read_header(path: &str) -> Result<Frame, Error> {
+	match extension(path).as_str() {
+		"wav" => Ok(codec::wav::read_wav_header(path)),
+		"flac" => Ok(codec::flac::read_flac_header(path)),
+		_ => Err(invalid("Unsupported input format")),
+	}
+}
+
+fn spawn_encoder(path: String, rx: mpsc::Receiver<Frame>, options: EncoderOptions) -> Result<thread::JoinHandle<()>, Error> {
+	match extension(&path).as_str() {
+		"wav" => Ok(thread::spawn(move || codec::wav::write_wav(&path, rx, &options))),
+		"flac" => Ok(thread::spawn(move || codec::flac::write_flac(&path, rx, &options))),
+		_ => Err(invalid("Unsupported output format")),
+	}
+}
+
+// Whether the encoder for the path can take the format
+fn check_output(path: &str, format: Format) -> Result<(), Error> {
+	let fits = match extension(path).as_str() {
+		"wav" => [8, 16, 24, 32].contains(&format.bits_per_sample),
+		"flac" => (4..=32).contains(&format.bits_per_sample) && (1..=8).contains(&format.channels) && format.sample_rate <= 655350,
+		_ => true,
+	};
+	match fits {
+		true => Ok(()),
+		false => Err(invalid(&format!("{} cannot hold {} channels of {}-bit audio at {} Hz", path, format.channels, format.bits_per_sample, format.sample_rate))),
+	}
+}
+
+fn read_tags(path: &str) -> Result<Tags, Error> {
+	match extension(path).as_str() {
+		"wav" => Ok(codec::wav::read_wav_tags(path)),
+		"flac" => codec::flac::read_flac_tags(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))),
+		_ => Err(invalid("Unsupported format for tag editing")),
+	}
+}
+
+// Edits tags in place, leaving the audio as it is
+fn update_tags(path: &str, edit: impl FnOnce(&mut Tags)) -> Result<(), Error> {
+	let result = match extension(path).as_str() {
+		"wav" => codec::wav::update_wav_tags(path, edit),
+		"flac" => codec::flac::update_flac_tags(path, edit),
+		_ => return Err(invalid("Unsupported format for tag editing")),
+	};
+	result.map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+// Inputs one after another, each through its own stages first
+fn spawn_join(paths: Vec<String>, headers: Vec<Frame>, conversions: Vec<Vec<Box<dyn Stage + Send>>>, marks: Marks, tx: mpsc::Sender<Frame>) -> thread::JoinHandle<()> {
+	thread::spawn(move || {
+		let mut conversions = conversions.into_iter();
+		let mut dec_threads = Vec::new();
+		let mut stage_threads = Vec::new();
+		join::join(headers, tx, marks, |i| {
+			let (tx, rx) = mpsc::channel();
+			dec_threads.push(spawn_decoder(paths[i].clone(), tx).unwrap());
+			let (rx, threads) = stage::spawn_chain(conversions.next().unwrap(), rx);
+			stage_threads.extend(threads);
+			rx
+		}).unwrap();
+
+		let mut ok = true;
+		for dec_thread in dec_threads {
+			ok &= dec_thread.join().is_ok();
+		}
+		for stage_thread in stage_threads {
+			ok &= stage_thread.join().is_ok();
+		}
+		if !ok {
+			panic!("Reading an input to join failed");
+		}
+	})
+}
+
+// Runs a file through stages into a meter on this thread, for the first of two passes
+fn measure(path: &str, range: (u64, Option<u64>), stages: Vec<Box<dyn Stage + Send>>, meter: &mut LoudnessMeter) -> Result<(), Error> {
+	measure_source(|tx| spawn_decoder_range(path.to_string(), range, tx), stages, meter)
+}
+
+fn measure_source<F>(source: F, stages: Vec<Box<dyn Stage + Send>>, meter: &mut LoudnessMeter) -> Result<(), Error>
+	where F: FnOnce(mpsc::Sender<Frame>) -> Result<thread::JoinHandle<()>, Error>
+{
+	let (tx, rx) = mpsc::channel();
+	let dec_thread = source(tx)?;
+	let (rx, stage_threads) = stage::spawn_chain(stages, rx);
+	loop {
+		let frame = meter.process(rx.recv().unwrap());
+		if frame.eof {
+			break;
+		}
+	}
+	let mut ok = dec_thread.join().is_ok();
+	for stage_thread in stage_threads {
+		ok &= stage_thread.join().is_ok();
+	}
+	if !ok {
+		return Err(Error::other("Measuring loudness failed"));
+	}
+	Ok(())
+}
+
+// Whatever brings an input to the channels, rate and bit depth of another,
+// resampling and dithering as the conversion options say
+fn matching_stages(header: &Frame, target: &Frame, conversion: &Conversion) -> Result<Vec<Box<dyn Stage + Send>>, Error> {
+	let mut stages : Vec<Box<dyn Stage + Send>> = Vec::new();
+	if header.channels != target.channels {
+		let mode = match (header.channels, target.channels) {
+			(_, 1) => remix::Mode::Mono,
+			(_, 2) => remix::Mode::Stereo,
+			(1, n) => remix::Mode::Duplicate(n),
+			(from, to) => return Err(invalid(&format!("Cannot remix {} channels to {} for joining", from, to))),
+		};
+		stages.push(Box::new(Remix::new(mode, false)));
+	}
+	if header.sample_rate != target.sample_rate {
+		stages.push(Box::new(Resample::new(target.sample_rate, conversion.quality)));
+	}
+	if header.bits_per_sample != target.bits_per_sample {
+		stages.push(Box::new(Dither::new(target.bits_per_sample, conversion.quantizer, conversion.shaping, conversion.seed)));
+	}
+	Ok(stages)
+}
+
+fn parse_decibels(arg: Option<String>, message: &str) -> Result<f64, Error> {
+	arg.and_then(|db| db.parse::<f64>().ok()).filter(|db| db.is_finite()).ok_or_else(|| invalid(message))
+}
+
+// The stages changing a stream's format, as set on the command line
+struct Conversion {
+	// From --filter, ahead of the rest
+	filters : Vec<Filter>,
+	remix : Option<remix::Mode>,
+	remix_normalize : bool,
+	rate : Option<usize>,
+	quality : resample::Quality,
+	bits : Option<usize>,
+	quantizer : Quantizer,
+	shaping : NoiseShaping,
+	seed : u64,
+}
+
+impl Default for Conversion {
+	fn default() -> Conversion {
+		Conversion {
+			filters: Vec::new(),
+			remix: None,
+			remix_normalize: false,
+			rate: None,
+			quality: resample::Quality::High,
+			bits: None,
+			quantizer: Quantizer::Tpdf,
+			shaping: NoiseShaping::None,
+			seed: dither::DEFAULT_SEED,
+		}
+	}
+}
+
+impl Conversion {
+	// Remixing first means any later stage has the fewest channels to work on
+	fn graph(&self) -> Graph {
+		let mut filters = self.filters.clone();
+		if let Some(ref mode) = self.remix {
+			filters.push(Filter::Remix(mode.clone(), self.remix_normalize));
+		}
+		if let Some(rate) = self.rate {
+			filters.push(Filter::Resample(rate, self.quality));
+		}
+		Graph { filters }
+	}
+
+	// Kept apart, as it has to come after anything computing new sample values
+	fn dither(&self) -> Option<Filter> {
+		self.bits.map(|bits| Filter::Dither { bits, quantizer: self.quantizer, shaping: self.shaping, seed: self.seed })
+	}
+}
+
+// Handles the format conversion options; false if arg is not one
+fn conversion_option(arg: &str, args: &mut dyn Iterator<Item = String>, conversion: &mut Conversion) -> Result<bool, Error> {
+	match arg {
+		"--filter" => {
+			let spec = args.next().ok_or_else(|| invalid("--filter needs a filter chain"))?;
+			conversion.filters.extend(Graph::parse(&spec).map_err(|e| invalid(&e))?.filters);
+		},
+		"--rate" => {
+			let hz = args.next().ok_or_else(|| invalid("--rate needs a sample rate in Hz"))?;
+			conversion.rate = Some(hz.parse::<usize>().ok().filter(|&hz| hz > 0).ok_or_else(|| invalid("--rate needs a sample rate in Hz"))?);
+		},
+		"--resample-quality" => {
+			conversion.quality = args.next().and_then(|q| resample::Quality::parse(&q))
+				.ok_or_else(|| invalid("--resample-quality needs low, medium, high or best"))?;
+		},
+		"--remix" => {
+			let spec = args.next().ok_or_else(|| invalid("--remix needs a mode"))?;
+			conversion.remix = Some(remix::parse_mode(&spec).map_err(|e| invalid(&e))?);
+		},
+		"--remix-normalize" => conversion.remix_normalize = true,
+		"--bits" => {
+			let n = args.next().ok_or_else(|| invalid("--bits needs a bit depth"))?;
+			conversion.bits = Some(n.parse::<usize>().ok().filter(|n| (8..=32).contains(n)).ok_or_else(|| invalid("--bits needs a bit depth from 8 to 32"))?);
+		},
+		"--dither" => {
+			conversion.quantizer = args.next().and_then(|q| Quantizer::parse(&q))
+				.ok_or_else(|| invalid("--dither needs truncate, round or tpdf"))?;
+		},
+		"--noise-shaping" => {
+			conversion.shaping = args.next().and_then(|s| NoiseShaping::parse(&s))
+				.ok_or_else(|| invalid("--noise-shaping needs none, simple, lipshitz, f-weighted, modified-e-weighted or improved-e-weighted"))?;
+		},
+		"--dither-seed" => {
+			let n = args.next().ok_or_else(|| invalid("--dither-seed needs a number"))?;
+			conversion.seed = n.parse().map_err(|_| invalid("--dither-seed needs a number"))?;
+		},
+		_ => return Ok(false),
+	}
+	Ok(true)
+}
+
+fn convert_command(args: Vec<String>) -> Result<(), Error> {
+	let mut options = EncoderOptions::default();
+	let mut paths = Vec::new();
+	let mut conversion = Conversion::default();
+	let mut loudness_report = None;
+	let mut json = false;
+	let mut target = None;
+	let mut ceiling = normalize::DEFAULT_CEILING;
+	let mut single_pass = false;
+	let mut start = None;
+	let mut end = None;
+	let mut duration = None;
+	let mut frame_rate = None;
+	let mut marks = Marks::None;
+
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		if encoder_option(&arg, &mut args, &mut options)? || conversion_option(&arg, &mut args, &mut conversion)? {
+			continue;
+		}
+		match arg.as_str() {
+			"--start" => start = Some(args.next().ok_or_else(|| invalid("--start needs a position"))?),
+			"--end" => end = Some(args.next().ok_or_else(|| invalid("--end needs a position"))?),
+			"--duration" => duration = Some(args.next().ok_or_else(|| invalid("--duration needs a length"))?),
+			"--timecode-rate" => {
+				let fps = args.next().ok_or_else(|| invalid("--timecode-rate needs a frame rate"))?;
+				frame_rate = Some(fps.parse::<f64>().ok().filter(|fps| fps.is_finite() && *fps > 0.0).ok_or_else(|| invalid("--timecode-rate needs a frame rate"))?);
+			},
+			"--mark-joins" => {
+				marks = match args.next().as_deref() {
+					Some("cuesheet") => Marks::CueSheet,
+					Some("markers") => Marks::Markers,
+					_ => return Err(invalid("--mark-joins needs cuesheet or markers")),
+				};
+			},
+			"--loudness-report" => loudness_report = Some(args.next().ok_or_else(|| invalid("--loudness-report needs a file"))?),
+			"--json" => json = true,
+			"--normalize" => target = Some(parse_decibels(args.next(), "--normalize needs a target in LUFS")?),
+			"--true-peak" => ceiling = parse_decibels(args.next(), "--true-peak needs a ceiling in dBTP")?,
+			"--single-pass" => single_pass = true,
+			_ if arg.starts_with("--") => return Err(invalid(USAGE)),
+			_ => paths.push(arg),
+		}
+	}
+	if paths.len() < 2 {
+		return Err(invalid(USAGE));
+	}
+	let output = paths.pop().unwrap();
+	let inputs = paths;
+
+	// Inputs unlike the first are converted to match it before joining
+	let headers = match inputs.len() {
+		1 => Vec::new(),
+		_ => inputs.iter().map(|path| read_header(path)).collect::<Result<Vec<Frame>, Error>>()?,
+	};
+	let joining = || headers.iter()
+		.map(|header| matching_stages(header, &headers[0], &conversion))
+		.collect::<Result<Vec<_>, Error>>();
+	let joined : Vec<Frame> = headers.iter().zip(joining()?)
+		.map(|(header, stages)| stages.into_iter().fold(header.clone(), |frame, mut stage| stage.process(frame)))
+		.collect();
+	if !joined.is_empty() {
+		join::check(&joined).map_err(|e| invalid(&e))?;
+	}
+	let first = match joined.first() {
+		Some(header) => header.clone(),
+		None => read_header(&inputs[0])?,
+	};
+
+	// Positions count in the input's own sample rate, so the frame rate
+	// and header are only needed once all the options are in
+	let position = |spec: Option<String>| match spec {
+		Some(spec) => Position::parse(&spec, frame_rate).map(Some).map_err(|e| invalid(&e)),
+		None => Ok(None),
+	};
+	let (start, end, duration) = (position(start)?, position(end)?, position(duration)?);
+	let range = match (start, end, duration) {
+		(None, None, None) => (0, None),
+		_ if inputs.len() > 1 => return Err(invalid("--start, --end and --duration take a single input")),
+		_ => trim::range(start, end, duration, first.sample_rate).map_err(|e| invalid(&e))?,
+	};
+	let source = |tx| match inputs.len() {
+		1 => spawn_decoder_range(inputs[0].clone(), range, tx),
+		_ => Ok(spawn_join(inputs.clone(), joined.clone(), joining()?, marks, tx)),
+	};
+
+	let mut graph = conversion.graph();
+	let gain_at = graph.filters.len();
+	if let (Some(target), true) = (target, single_pass) {
+		graph.filters.push(Filter::Normalize { target, ceiling });
+	}
+	// Dither comes last, after anything that computes new sample values
+	graph.filters.extend(conversion.dither());
+	let format = graph.check(Format::of(&first)).map_err(|e| invalid(&e))?;
+	check_output(&output, format)?;
+
+	// Two passes measure what the gain will be applied to, so the first
+	// runs the conversion too
+	if let (Some(target), false) = (target, single_pass) {
+		let mut meter = LoudnessMeter::new();
+		measure_source(source, conversion.graph().stages(), &mut meter)?;
+		graph.filters.insert(gain_at, Filter::Gain(normalize::gain(&meter.report(), target, ceiling)));
+	}
+
+	let (tx, rx) = mpsc::channel();
+	let dec_thread = source(tx)?;
+	let (rx, stage_threads) = graph.spawn(rx);
+	// The meter taps what actually goes to the encoder
+	let (rx, meter_thread) = match loudness_report {
+		Some(_) => {
+			let (rx, thread) = stage::spawn(LoudnessMeter::new(), rx);
+			(rx, Some(thread))
+		},
+		None => (rx, None),
+	};
+	let enc_thread = spawn_encoder(output, rx, options)?;
+
+	let mut ok = dec_thread.join().is_ok();
+	for stage_thread in stage_threads {
+		ok &= stage_thread.join().is_ok();
+	}
+	ok &= enc_thread.join().is_ok();
+	if !ok {
+		return Err(Error::other("Conversion failed"));
+	}
+
+	if let (Some(path), Some(thread)) = (loudness_report, meter_thread) {
+		let report = thread.join().map_err(|_| invalid("Loudness measurement failed"))?.report();
+		fs::write(path, if json { report.json() + "\n" } else { report.text() })?;
+	}
+
+	Ok(())
+}
+
+// Keeps track titles usable as file names everywhere
+fn file_name_safe(name: &str) -> String {
+	name.chars()
+		.map(|c| if "/\\:*?\"<>|".contains(c) || c.is_control() { '_' } else { c })
+		.collect::<String>()
+		.trim_matches(|c: char| c == '.' || c.is_whitespace())
+		.to_string()
+}
+
+fn split_command(args: Vec<String>) -> Result<(), Error> {
+	let mut options = EncoderOptions::default();
+	let mut paths = Vec::new();
+	let mut cue_path = None;
+	let mut pregap = Pregap::Append;
+	let mut format = None;
+
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		if encoder_option(&arg, &mut args, &mut options)? {
+			continue;
+		}
+		match arg.as_str() {
+			"--cue" => cue_path = Some(args.next().ok_or_else(|| invalid("--cue needs a file"))?),
+			"--pregap" => {
+				pregap = match args.next().as_deref() {
+					Some("append") => Pregap::Append,
+					Some("prepend") => Pregap::Prepend,
+					Some("drop") => Pregap::Drop,
+					_ => return Err(invalid("--pregap needs append, prepend or drop")),
+				};
+			},
+			"--format" => format = Some(args.next().ok_or_else(|| invalid("--format needs wav or flac"))?),
+			_ if arg.starts_with("--") => return Err(invalid(USAGE)),
+			_ => paths.push(arg),
+		}
+	}
+	if paths.is_empty() || paths.len() > 2 {
+		return Err(invalid(USAGE));
+	}
+
+	let input = Path::new(&paths[0]);
+	let directory = match paths.get(1) {
+		Some(directory) => Path::new(directory).to_path_buf(),
+		None => input.parent().unwrap_or_else(|| Path::new("")).to_path_buf(),
+	};
+	let format = format.unwrap_or_else(|| extension(&paths[0]));
+	if format != "wav" && format != "flac" {
+		return Err(invalid("Unsupported output format"));
+	}
+
+	// album.flac is described by album.cue unless told otherwise, falling
+	// back to a sheet embedded in the image
+	let sibling = input.with_extension("cue");
+	let sheet = match cue_path {
+		Some(cue_path) => cue::read(&cue_path).map_err(|e| invalid(&e))?,
+		None if sibling.exists() => cue::read(&sibling.to_string_lossy()).map_err(|e| invalid(&e))?,
+		None => embedded_cue_sheet(&paths[0])?,
+	};
+
+	// A sheet covering several files names the one being split
+	let stem = |name: &str| Path::new(name).file_stem().map(|s| s.to_string_lossy().to_lowercase());
+	let file = if sheet.files.len() == 1 {
+		0
+	} else {
+		sheet.files.iter()
+			.position(|f| stem(f) == stem(&paths[0]))
+			.ok_or_else(|| invalid("CUE sheet does not mention the input file"))?
+	};
+
+	let (tx, rx) = mpsc::channel();
+	let dec_thread = spawn_decoder(paths[0].clone(), tx)?;
 
-	let enc_path = args[2].clone();
-	let enc_thread = thread::spawn(move || {
-		//codec::wav::write_wav(&enc_path, rx)
-		codec::flac::write_flac(&enc_path, rx)
+	let mut enc_threads = Vec::new();
+	let missing = split::split(rx, &sheet, file, pregap, |track, _| {
+		let track = &sheet.tracks[track];
+		let title = track.title.as_deref().map(file_name_safe).unwrap_or_default();
+		let name = if title.is_empty() {
+			format!("{:02}.{}", track.number, format)
+		} else {
+			format!("{:02} - {}.{}", track.number, title, format)
+		};
+		let path = directory.join(name).to_string_lossy().into_owned();
+
+		let (tx, rx) = mpsc::channel();
+		enc_threads.push(spawn_encoder(path, rx, options.clone()).unwrap());
+		tx
 	});
 
-	let _dec_result = dec_thread.join();
-	let _enc_result = enc_thread.join();
+	let mut ok = dec_thread.join().is_ok();
+	for enc_thread in enc_threads {
+		ok &= enc_thread.join().is_ok();
+	}
+	if !ok {
+		return Err(Error::other("Splitting failed"));
+	}
+
+	if !missing.is_empty() {
+		let numbers : Vec<String> = missing.iter().map(|&t| sheet.tracks[t].number.to_string()).collect();
+		let tracks = if numbers.len() == 1 { "track" } else { "tracks" };
+		return Err(invalid(&format!("The image ends before {} {} start", tracks, numbers.join(", "))));
+	}
+	Ok(())
+}
+
+// A CUESHEET comment, as foobar2000 writes, keeps titles that a FLAC
+// CUESHEET block has no room for, so it wins when both are present
+fn embedded_cue_sheet(path: &str) -> Result<CueSheet, Error> {
+	let metadata = read_header(path)?.metadata.unwrap_or_default();
+	if let Some((_, text)) = metadata.fields.iter().find(|(name, _)| name.eq_ignore_ascii_case("CUESHEET")) {
+		return cue::parse(text).map_err(|e| invalid(&e));
+	}
+	metadata.cue_sheet.ok_or_else(|| invalid("No CUE sheet found"))
+}
+
+fn file_name(path: &str) -> String {
+	Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn join_command(args: Vec<String>) -> Result<(), Error> {
+	let mut options = EncoderOptions::default();
+	let mut paths = Vec::new();
+	let mut cue_path = None;
+
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		if encoder_option(&arg, &mut args, &mut options)? {
+			continue;
+		}
+		match arg.as_str() {
+			"--cue" => cue_path = Some(args.next().ok_or_else(|| invalid("--cue needs a file"))?),
+			_ if arg.starts_with("--") => return Err(invalid(USAGE)),
+			_ => paths.push(arg),
+		}
+	}
+	if paths.len() < 2 {
+		return Err(invalid(USAGE));
+	}
+	let output = paths.pop().unwrap();
+
+	let headers = paths.iter().map(|path| read_header(path)).collect::<Result<Vec<Frame>, Error>>()?;
+	join::check(&headers).map_err(|e| invalid(&e))?;
+	if let Some(cue_path) = cue_path {
+		let mut sheet = join::cue_sheet(&headers);
+		sheet.files = vec![file_name(&output)];
+		fs::write(cue_path, sheet.to_string())?;
+	}
+
+	let (tx, rx) = mpsc::channel();
+	let enc_thread = spawn_encoder(output, rx, options)?;
+
+	// Inputs are decoded one after another rather than all at once
+	let conversions = headers.iter().map(|_| Vec::new()).collect();
+	let join_thread = spawn_join(paths, headers, conversions, Marks::CueSheet, tx);
+
+	let mut ok = join_thread.join().is_ok();
+	ok &= enc_thread.join().is_ok();
+	if !ok {
+		return Err(Error::other("Joining failed"));
+	}
+	Ok(())
+}
+
+// --gain, --pan and --offset apply to the input that follows them
+fn mix_command(args: Vec<String>) -> Result<(), Error> {
+	let mut options = EncoderOptions::default();
+	let mut paths = Vec::new();
+	let mut input = (mix::Input::default(), None);
+	let mut headroom = 0.0;
+	let mut limit = None;
 
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		if encoder_option(&arg, &mut args, &mut options)? {
+			continue;
+		}
+		match arg.as_str() {
+			"--gain" => input.0.gain = parse_decibels(args.next(), "--gain needs a gain in dB")?,
+			"--pan" => {
+				let pan = args.next().ok_or_else(|| invalid("--pan needs a position from -1 to 1"))?;
+				input.0.pan = pan.parse::<f64>().ok().filter(|pan| (-1.0..=1.0).contains(pan)).ok_or_else(|| invalid("--pan needs a position from -1 to 1"))?;
+			},
+			"--offset" => {
+				let spec = args.next().ok_or_else(|| invalid("--offset needs a position"))?;
+				input.1 = Some(Position::parse(&spec, None).map_err(|e| invalid(&e))?);
+			},
+			"--headroom" => headroom = parse_decibels(args.next(), "--headroom needs a level in dB")?,
+			"--limit" => limit = Some(parse_decibels(args.next(), "--limit needs a ceiling in dBTP")?),
+			_ if arg.starts_with("--") => return Err(invalid(USAGE)),
+			_ => paths.push((arg, std::mem::take(&mut input))),
+		}
+	}
+	if paths.len() < 2 {
+		return Err(invalid(USAGE));
+	}
+	let (output, output_input) = paths.pop().unwrap();
+	if output_input != (mix::Input::default(), None) {
+		return Err(invalid("--gain, --pan and --offset go before the input they apply to"));
+	}
+
+	// Inputs at other rates are resampled to the first's
+	let mut headers = paths.iter().map(|(path, _)| read_header(path)).collect::<Result<Vec<Frame>, Error>>()?;
+	let sample_rate = headers[0].sample_rate;
+	for header in &mut headers {
+		header.sample_rate = sample_rate;
+	}
+	mix::channels(&headers).map_err(|e| invalid(&e))?;
+
+	let mut inputs = Vec::new();
+	let mut dec_threads = Vec::new();
+	let mut stage_threads = Vec::new();
+	for (path, (mut input, offset)) in paths {
+		input.offset = offset.map_or(0, |offset| offset.to_samples(sample_rate));
+		let (tx, rx) = mpsc::channel();
+		dec_threads.push(spawn_decoder(path, tx)?);
+		let (rx, threads) = stage::spawn_chain(vec![Box::new(Resample::new(sample_rate, resample::Quality::High))], rx);
+		stage_threads.extend(threads);
+		inputs.push((input, rx));
+	}
+
+	let (tx, rx) = mpsc::channel();
+	let enc_thread = spawn_encoder(output, rx, options)?;
+	let mix_thread = thread::spawn(move || mix::mix(inputs, tx, headroom, limit));
+
+	let clipped = mix_thread.join().map_err(|_| invalid("Mixing failed"))?;
+	let mut ok = true;
+	for dec_thread in dec_threads {
+		ok &= dec_thread.join().is_ok();
+	}
+	for stage_thread in stage_threads {
+		ok &= stage_thread.join().is_ok();
+	}
+	ok &= enc_thread.join().is_ok();
+	if !ok {
+		return Err(Error::other("Mixing failed"));
+	}
+
+	if clipped > 0 {
+		eprintln!("{} samples clipped; leave more --headroom or --limit the mix", clipped);
+	}
 	Ok(())
 }
+
+// Decodes once for any number of outputs, each with its own conversion and
+// encoder options given ahead of it. One output failing leaves the rest be.
+fn tee_command(args: Vec<String>) -> Result<(), Error> {
+	let mut args = args.into_iter();
+	let input = args.next().filter(|arg| !arg.starts_with("--")).ok_or_else(|| invalid(USAGE))?;
+	let mut branches = Vec::new();
+	let mut conversion = Conversion::default();
+	let mut options = EncoderOptions::default();
+	let mut pending = false;
+	while let Some(arg) = args.next() {
+		if encoder_option(&arg, &mut args, &mut options)? || conversion_option(&arg, &mut args, &mut conversion)? {
+			pending = true;
+			continue;
+		}
+		if arg.starts_with("--") {
+			return Err(invalid(USAGE));
+		}
+		branches.push((arg, std::mem::take(&mut conversion), std::mem::take(&mut options)));
+		pending = false;
+	}
+	if branches.is_empty() || pending {
+		return Err(invalid(USAGE));
+	}
+
+	// Every branch is checked before any starts
+	let input_format = Format::of(&read_header(&input)?);
+	let mut graphs = Vec::new();
+	for (path, conversion, _) in &branches {
+		let mut graph = conversion.graph();
+		graph.filters.extend(conversion.dither());
+		check_output(path, graph.check(input_format).map_err(|e| invalid(&format!("{}: {}", path, e)))?)?;
+		graphs.push(graph);
+	}
+
+	let (tx, rx) = mpsc::channel();
+	let (receivers, tee_thread) = stage::tee(rx, branches.len());
+	let mut outputs = Vec::new();
+	for (((path, _, options), graph), rx) in branches.into_iter().zip(graphs).zip(receivers) {
+		let (rx, stage_threads) = graph.spawn(rx);
+		let enc_thread = spawn_encoder(path.clone(), rx, options)?;
+		outputs.push((path, stage_threads, enc_thread));
+	}
+	let dec_thread = spawn_decoder(input.clone(), tx)?;
+
+	// Without the whole input every output is cut short, so there is no
+	// point in telling them apart
+	let decoded = dec_thread.join().is_ok() && tee_thread.join().is_ok();
+	let total = outputs.len();
+	let mut failed = 0;
+	for (path, stage_threads, enc_thread) in outputs {
+		let mut ok = true;
+		for stage_thread in stage_threads {
+			ok &= stage_thread.join().is_ok();
+		}
+		ok &= enc_thread.join().is_ok();
+		if !ok && decoded {
+			eprintln!("Writing {} failed", path);
+			failed += 1;
+		}
+	}
+
+	if !decoded {
+		return Err(Error::other(format!("Reading {} failed", input)));
+	}
+	match failed {
+		0 => Ok(()),
+		_ => Err(Error::other(format!("{} of {} outputs failed", failed, total))),
+	}
+}
+
+fn cue_command(args: Vec<String>) -> Result<(), Error> {
+	if args.is_empty() || args.len() > 2 || args.iter().any(|arg| arg.starts_with("--")) {
+		return Err(invalid(USAGE));
+	}
+
+	let mut sheet = embedded_cue_sheet(&args[0])?;
+	if sheet.files.len() == 1 {
+		sheet.files[0] = file_name(&args[0]);
+	}
+	match args.get(1) {
+		Some(output) => fs::write(output, sheet.to_string()),
+		None => {
+			print!("{}", sheet);
+			Ok(())
+		},
+	}
+}
+
+fn loudness_command(args: Vec<String>) -> Result<(), Error> {
+	let json = args.iter().any(|arg| arg == "--json");
+	let paths : Vec<&String> = args.iter().filter(|arg| *arg != "--json").collect();
+	if paths.len() != 1 || paths[0].starts_with("--") {
+		return Err(invalid(USAGE));
+	}
+
+	let mut meter = LoudnessMeter::new();
+	measure(paths[0], (0, None), Vec::new(), &mut meter)?;
+
+	let report = meter.report();
+	if json {
+		println!("{}", report.json());
+	} else {
+		print!("{}", report.text());
+	}
+	Ok(())
+}
+
+enum TagEdit {
+	Set(String, String),
+	Add(String, String),
+	Remove(String),
+	RemoveAll,
+	Picture(Picture),
+	RemovePictures,
+}
+
+impl TagEdit {
+	fn apply(&self, tags: &mut Tags) {
+		match self {
+			TagEdit::Set(name, value) => {
+				tags.comments.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+				tags.comments.push((name.clone(), value.clone()));
+			},
+			TagEdit::Add(name, value) => tags.comments.push((name.clone(), value.clone())),
+			TagEdit::Remove(name) => tags.comments.retain(|(n, _)| !n.eq_ignore_ascii_case(name)),
+			TagEdit::RemoveAll => tags.comments.clear(),
+			TagEdit::Picture(picture) => tags.pictures.push(picture.clone()),
+			TagEdit::RemovePictures => tags.pictures.clear(),
+		}
+	}
+}
+
+// "-" stands for standard input or output
+fn read_text(path: &str) -> Result<String, Error> {
+	if path == "-" {
+		let mut text = String::new();
+		io::stdin().read_to_string(&mut text)?;
+		Ok(text)
+	} else {
+		fs::read_to_string(path)
+	}
+}
+
+fn write_text(path: &str, text: &str) -> Result<(), Error> {
+	if path == "-" {
+		print!("{}", text);
+		Ok(())
+	} else {
+		fs::write(path, text)
+	}
+}
+
+// Edits are applied in the order given, all in one rewrite per file
+fn tag_command(args: Vec<String>) -> Result<(), Error> {
+	let mut edits = Vec::new();
+	let mut export = None;
+	let mut export_picture = None;
+	let mut paths = Vec::new();
+
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--set" => {
+				let (name, value) = tag_field(args.next(), "--set")?;
+				edits.push(TagEdit::Set(name, value));
+			},
+			"--add" => {
+				let (name, value) = tag_field(args.next(), "--add")?;
+				edits.push(TagEdit::Add(name, value));
+			},
+			"--remove" => edits.push(TagEdit::Remove(args.next().ok_or_else(|| invalid("--remove needs a field name"))?)),
+			"--remove-all" => edits.push(TagEdit::RemoveAll),
+			// One NAME=VALUE per line, as metaflac exports them
+			"--import" => {
+				let text = read_text(&args.next().ok_or_else(|| invalid("--import needs a file"))?)?;
+				for line in text.lines().filter(|line| !line.trim().is_empty()) {
+					let (name, value) = tag_field(Some(line.to_string()), "Each imported line")?;
+					edits.push(TagEdit::Add(name, value));
+				}
+			},
+			"--picture" => edits.push(TagEdit::Picture(picture_option(args.next())?)),
+			"--remove-pictures" => edits.push(TagEdit::RemovePictures),
+			"--export" => export = Some(args.next().ok_or_else(|| invalid("--export needs a file"))?),
+			"--export-picture" => export_picture = Some(args.next().ok_or_else(|| invalid("--export-picture needs a file"))?),
+			_ if arg.starts_with("--") => return Err(invalid(USAGE)),
+			_ => paths.push(arg),
+		}
+	}
+	if paths.is_empty() {
+		return Err(invalid(USAGE));
+	}
+	if (export.is_some() || export_picture.is_some()) && paths.len() > 1 {
+		return Err(invalid("Exporting works on one file at a time"));
+	}
+
+	for path in &paths {
+		if !edits.is_empty() {
+			update_tags(path, |tags| {
+				for edit in &edits {
+					edit.apply(tags);
+				}
+			})?;
+		}
+
+		if edits.is_empty() && export.is_none() && export_picture.is_none() {
+			let tags = read_tags(path)?;
+			if paths.len() > 1 {
+				println!("{}:", path);
+			}
+			for (name, value) in &tags.comments {
+				println!("{}={}", name, value);
+			}
+			for picture in &tags.pictures {
+				println!("[picture type {}, {}, {}x{}, {} bytes] {}", picture.picture_type, picture.mime_type, picture.width, picture.height, picture.data.len(), picture.description);
+			}
+			continue;
+		}
+
+		if let Some(ref export) = export {
+			let text : String = read_tags(path)?.comments.iter().map(|(name, value)| format!("{}={}\n", name, value)).collect();
+			write_text(export, &text)?;
+		}
+		// The front cover if there is one, otherwise whatever comes first
+		if let Some(ref export_picture) = export_picture {
+			let pictures = read_tags(path)?.pictures;
+			let picture = pictures.iter().find(|p| p.picture_type == 3).or(pictures.first()).ok_or_else(|| invalid("No picture to export"))?;
+			fs::write(export_picture, &picture.data)?;
+		}
+	}
+	Ok(())
+}
+
+fn replaygain_command(args: Vec<String>) -> Result<(), Error> {
+	let mut album = true;
+	let mut dry_run = false;
+	let mut paths = Vec::new();
+	for arg in args {
+		match arg.as_str() {
+			"--no-album" => album = false,
+			"--dry-run" => dry_run = true,
+			_ if arg.starts_with("--") => return Err(invalid(USAGE)),
+			_ => paths.push(arg),
+		}
+	}
+	if paths.is_empty() {
+		return Err(invalid(USAGE));
+	}
+	if !dry_run && paths.iter().any(|path| extension(path) != "wav" && extension(path) != "flac") {
+		return Err(invalid("Unsupported format for tag editing"));
+	}
+
+	// Each file is decoded once; the album figures come from the track meters merged
+	let mut meters = Vec::new();
+	for path in &paths {
+		let mut meter = LoudnessMeter::new();
+		measure(path, (0, None), Vec::new(), &mut meter)?;
+		meters.push(meter);
+	}
+	let mut values : Vec<_> = meters.iter().map(|meter| replaygain::track(&meter.report())).collect();
+	if album {
+		let mut album_meter = LoudnessMeter::new();
+		for meter in &meters {
+			album_meter.merge(meter);
+		}
+		replaygain::set_album(&mut values, &album_meter.report());
+	}
+
+	let gain = |gain: Option<f64>| gain.map_or("-".to_string(), |g| format!("{:+.2} dB", g));
+	for (path, replay_gain) in paths.iter().zip(&values) {
+		println!("{}  {:.6}  {}", gain(replay_gain.track_gain), replay_gain.track_peak.unwrap_or(0.0), path);
+		if !dry_run {
+			update_tags(path, |tags| replaygain::update_comments(&mut tags.comments, replay_gain))?;
+		}
+	}
+	if let Some(first) = values.first().filter(|_| album) {
+		println!("{}  {:.6}  (album)", gain(first.album_gain), first.album_peak.unwrap_or(0.0));
+	}
+	Ok(())
+}
+
+fn normalize_command(args: Vec<String>) -> Result<(), Error> {
+	let mut options = EncoderOptions::default();
+	let mut paths = Vec::new();
+	let mut target = normalize::DEFAULT_TARGET;
+	let mut ceiling = normalize::DEFAULT_CEILING;
+	let mut single_pass = false;
+	let mut album = false;
+	let mut format = None;
+
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		if encoder_option(&arg, &mut args, &mut options)? {
+			continue;
+		}
+		match arg.as_str() {
+			"--target" => target = parse_decibels(args.next(), "--target needs a loudness in LUFS")?,
+			"--true-peak" => ceiling = parse_decibels(args.next(), "--true-peak needs a ceiling in dBTP")?,
+			"--single-pass" => single_pass = true,
+			"--album" => album = true,
+			"--format" => format = Some(args.next().ok_or_else(|| invalid("--format needs wav or flac"))?),
+			_ if arg.starts_with("--") => return Err(invalid(USAGE)),
+			_ => paths.push(arg),
+		}
+	}
+	if paths.len() < 2 {
+		return Err(invalid(USAGE));
+	}
+	if album && single_pass {
+		return Err(invalid("--album needs the whole album measured first, so not --single-pass"));
+	}
+	let directory = Path::new(&paths.pop().unwrap()).to_path_buf();
+
+	let outputs = paths.iter().map(|path| {
+		let format = format.clone().unwrap_or_else(|| extension(path));
+		if format != "wav" && format != "flac" {
+			return Err(invalid("Unsupported output format"));
+		}
+		let output = directory.join(Path::new(path).with_extension(format).file_name().unwrap());
+		if fs::canonicalize(&output).ok().is_some_and(|output| fs::canonicalize(path).ok() == Some(output)) {
+			return Err(invalid("Output would overwrite its input"));
+		}
+		Ok(output.to_string_lossy().into_owned())
+	}).collect::<Result<Vec<String>, Error>>()?;
+
+	// An album is measured as one programme and gets one gain throughout
+	let album_gain = if album {
+		let mut meter = LoudnessMeter::new();
+		for path in &paths {
+			measure(path, (0, None), Vec::new(), &mut meter)?;
+		}
+		Some(normalize::gain(&meter.report(), target, ceiling))
+	} else {
+		None
+	};
+
+	for (path, output) in paths.iter().zip(outputs) {
+		let stage : Box<dyn Stage + Send> = if single_pass {
+			Box::new(Normalize::new(target, ceiling))
+		} else {
+			let gain = match album_gain {
+				Some(gain) => gain,
+				None => {
+					let mut meter = LoudnessMeter::new();
+					measure(path, (0, None), Vec::new(), &mut meter)?;
+					normalize::gain(&meter.report(), target, ceiling)
+				},
+			};
+			Box::new(Gain::new(gain))
+		};
+
+		let (tx, rx) = mpsc::channel();
+		let dec_thread = spawn_decoder(path.clone(), tx)?;
+		let (rx, stage_thread) = stage::spawn(stage, rx);
+		let enc_thread = spawn_encoder(output, rx, options.clone())?;
+		let mut ok = dec_thread.join().is_ok();
+		ok &= stage_thread.join().is_ok();
+		ok &= enc_thread.join().is_ok();
+		if !ok {
+			return Err(Error::other(format!("Normalizing {} failed", path)));
+		}
+	}
+
+	Ok(())
+}
+
+fn main() -> Result<(), Error> {
+	let args : Vec<String> = env::args().skip(1).collect();
+	match args.first().map(String::as_str) {
+		Some("convert") => convert_command(args[1..].to_vec()),
+		Some("split") => split_command(args[1..].to_vec()),
+		Some("join") => join_command(args[1..].to_vec()),
+		Some("mix") => mix_command(args[1..].to_vec()),
+		Some("tee") => tee_command(args[1..].to_vec()),
+		Some("cue") => cue_command(args[1..].to_vec()),
+		Some("loudness") => loudness_command(args[1..].to_vec()),
+		Some("tag") => tag_command(args[1..].to_vec()),
+		Some("replaygain") => replaygain_command(args[1..].to_vec()),
+		Some("normalize") => normalize_command(args[1..].to_vec()),
+		_ => convert_command(args),
+	}
+}
@@ -0,0 +1,287 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Mixing several inputs into one, each with its own gain, pan and start
+// offset. All have to share a sample rate. Inputs with the mix's channel
+// count go straight in; mono inputs can also be panned into a stereo mix.
+
+use std::collections::VecDeque;
+use std::f64::consts::FRAC_PI_4;
+use std::sync::mpsc;
+
+use crate::codec::{Frame, Metadata};
+use crate::stage;
+use crate::stage::limiter::{self, Limiter};
+
+const BLOCK : u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Input {
+	// In dB
+	pub gain : f64,
+	// -1 is hard left, 1 hard right
+	pub pan : f64,
+	// Samples of silence before the input starts
+	pub offset : u64,
+}
+
+impl Default for Input {
+	fn default() -> Input {
+		Input {
+			gain: 0.0,
+			pan: 0.0,
+			offset: 0,
+		}
+	}
+}
+
+// The channel count of a mix of inputs with these headers
+pub fn channels(headers: &[Frame]) -> Result<usize, String> {
+	let first = headers.first().ok_or("Nothing to mix")?;
+	if headers.iter().any(|h| h.sample_rate != first.sample_rate) {
+		return Err("Inputs to mix must share a sample rate".to_string());
+	}
+	let channels = headers.iter().map(|h| h.channels).max().unwrap();
+	match headers.iter().find(|h| h.channels != channels && !(h.channels == 1 && channels == 2)) {
+		Some(h) => Err(format!("Cannot mix {} channels into {}", h.channels, channels)),
+		None => Ok(channels),
+	}
+}
+
+// Gains with a row per mix channel and a column per input channel. Mono is
+// panned at constant power, so it sits 3 dB down in each side when centred;
+// for stereo the pan is a balance control.
+fn matrix(input: &Input, in_channels: usize, channels: usize, scale: f64) -> Vec<Vec<f64>> {
+	let gain = scale * 10f64.powf(input.gain / 20.0);
+	match (in_channels, channels) {
+		(1, 2) => {
+			let angle = (input.pan + 1.0) * FRAC_PI_4;
+			vec![vec![gain * angle.cos()], vec![gain * angle.sin()]]
+		},
+		(2, 2) => vec![
+			vec![gain * (1.0 - input.pan).min(1.0), 0.0],
+			vec![0.0, gain * (1.0 + input.pan).min(1.0)],
+		],
+		_ => (0..channels).map(|row| (0..in_channels).map(|col| if row == col { gain } else { 0.0 }).collect()).collect(),
+	}
+}
+
+struct Source {
+	rx : mpsc::Receiver<Frame>,
+	matrix : Vec<Vec<f64>>,
+	// Mixed down to the mix's channels but not yet summed
+	buffer : VecDeque<f64>,
+	// Where the front of the buffer goes in the mix
+	next : u64,
+	ended : bool,
+}
+
+impl Source {
+	// Decodes until the buffer reaches the given mix position or the input runs out
+	fn fill(&mut self, until: u64, channels: usize) {
+		while !self.ended && self.next + ((self.buffer.len() / channels) as u64) < until {
+			let frame = self.rx.recv().unwrap();
+			let in_channels = self.matrix[0].len();
+			for sample in stage::to_f64(&frame.samples, frame.bits_per_sample).chunks_exact(in_channels) {
+				for row in &self.matrix {
+					self.buffer.push_back(row.iter().zip(sample).map(|(g, x)| g * x).sum());
+				}
+			}
+			self.ended = frame.eof;
+		}
+	}
+}
+
+// Sends the sum of the inputs, lowered by the headroom in dB and kept under
+// the limit in dBTP if there is one. Gives the number of samples clipped.
+pub fn mix(inputs: Vec<(Input, mpsc::Receiver<Frame>)>, tx: mpsc::Sender<Frame>, headroom: f64, limit: Option<f64>) -> u64 {
+	let mut headers = Vec::new();
+	let mut receivers = Vec::new();
+	for (input, rx) in inputs {
+		headers.push(rx.recv().unwrap());
+		receivers.push((input, rx));
+	}
+	let channels = channels(&headers).unwrap();
+	let sample_rate = headers[0].sample_rate;
+	let bits_per_sample = headers.iter().map(|h| h.bits_per_sample).max().unwrap();
+
+	let scale = 10f64.powf(-headroom / 20.0);
+	let mut sources : Vec<Source> = receivers.into_iter().zip(&headers).map(|((input, rx), header)| Source {
+		rx,
+		matrix: matrix(&input, header.channels, channels, scale),
+		buffer: VecDeque::new(),
+		next: input.offset,
+		ended: false,
+	}).collect();
+
+	let lengths : Option<Vec<u64>> = headers.iter().map(|h| h.metadata.as_ref().and_then(|m| m.total_samples)).collect();
+	let metadata = Metadata {
+		total_samples: lengths.map(|lengths| lengths.iter().zip(&sources).map(|(n, s)| s.next + n).max().unwrap()),
+		..Metadata::default()
+	};
+	tx.send(Frame {
+		channels,
+		sample_rate,
+		bits_per_sample,
+		samples: Vec::new(),
+		metadata: Some(metadata),
+		eof: false,
+	}).unwrap();
+
+	let mut limiter = limit.map(|ceiling| Limiter::new(channels, sample_rate, ceiling, limiter::DEFAULT_LOOKAHEAD, limiter::DEFAULT_RELEASE));
+	let mut clipped = 0;
+	let mut position = 0;
+	loop {
+		let end = position + BLOCK;
+		let mut mixed = vec![0.0; BLOCK as usize * channels];
+		let mut more = false;
+		for source in &mut sources {
+			let finished = source.ended && source.buffer.is_empty();
+			if source.next < end && !finished {
+				source.fill(end, channels);
+				let n = (end - source.next).min((source.buffer.len() / channels) as u64);
+				let at = (source.next - position) as usize * channels;
+				for (sum, x) in mixed[at..].iter_mut().zip(source.buffer.drain(..n as usize * channels)) {
+					*sum += x;
+				}
+				source.next += n;
+			}
+			more |= !source.ended || !source.buffer.is_empty();
+		}
+		// Past the end of the last input there is nothing left to fill
+		let stop = if more { end } else { sources.iter().map(|s| s.next).max().unwrap().max(position) };
+		mixed.truncate((stop - position) as usize * channels);
+		position = stop;
+
+		if let Some(ref mut limiter) = limiter {
+			let mut limited = limiter.process(&mixed);
+			if !more {
+				limited.extend(limiter.flush());
+			}
+			mixed = limited;
+		}
+		clipped += mixed.iter().filter(|x| x.abs() > 1.0).count() as u64;
+
+		tx.send(Frame {
+			channels,
+			sample_rate,
+			bits_per_sample,
+			samples: stage::from_f64(&mixed, bits_per_sample),
+			metadata: None,
+			eof: !more,
+		}).unwrap();
+		if !more {
+			return clipped;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// A 16-bit input holding one value in every sample, sent in uneven frames
+	fn constant(channels: usize, value: i32, length: usize) -> mpsc::Receiver<Frame> {
+		let (tx, rx) = mpsc::channel();
+		let metadata = Metadata { total_samples: Some(length as u64), ..Metadata::default() };
+		tx.send(Frame { channels, sample_rate: 44100, bits_per_sample: 16, samples: Vec::new(), metadata: Some(metadata), eof: false }).unwrap();
+		let mut left = length;
+		while left > 0 {
+			let n = left.min(1500);
+			left -= n;
+			tx.send(Frame { channels, sample_rate: 44100, bits_per_sample: 16, samples: vec![value; n * channels], metadata: None, eof: left == 0 }).unwrap();
+		}
+		rx
+	}
+
+	fn run(inputs: Vec<(Input, mpsc::Receiver<Frame>)>, headroom: f64) -> (Frame, Vec<i32>, u64) {
+		let (tx, rx) = mpsc::channel();
+		let clipped = mix(inputs, tx, headroom, None);
+		let header = rx.recv().unwrap();
+		let frames : Vec<Frame> = rx.iter().collect();
+		assert!(frames.last().unwrap().eof);
+		(header, frames.into_iter().flat_map(|f| f.samples).collect(), clipped)
+	}
+
+	#[test]
+	fn offsets() {
+		// The second input starts before the first ends and crosses a block
+		let later = Input { offset: 4000, ..Input::default() };
+		let (header, samples, clipped) = run(vec![
+			(Input::default(), constant(2, 1000, 5000)),
+			(later, constant(2, 2000, 3000)),
+		], 0.0);
+		assert_eq!(header.metadata.unwrap().total_samples, Some(7000));
+		assert_eq!(clipped, 0);
+		assert_eq!(samples.len(), 7000 * 2);
+		assert!(samples[..4000 * 2].iter().all(|&x| x == 1000));
+		assert!(samples[4000 * 2..5000 * 2].iter().all(|&x| x == 3000));
+		assert!(samples[5000 * 2..].iter().all(|&x| x == 2000));
+
+		// Silence fills the gap before an input that starts late
+		let late = Input { offset: 10000, ..Input::default() };
+		let (_, samples, _) = run(vec![(late, constant(1, 500, 100))], 0.0);
+		assert_eq!(samples.len(), 10100);
+		assert!(samples[..10000].iter().all(|&x| x == 0));
+		assert!(samples[10000..].iter().all(|&x| x == 500));
+	}
+
+	#[test]
+	fn pan() {
+		let frame = |input: Input| {
+			let (header, samples, _) = run(vec![
+				(input, constant(1, 10000, 10)),
+				(Input::default(), constant(2, 0, 10)),
+			], 0.0);
+			assert_eq!(header.channels, 2);
+			(samples[0], samples[1])
+		};
+		// Mono is panned at constant power
+		assert_eq!(frame(Input::default()), (7071, 7071));
+		assert_eq!(frame(Input { pan: -1.0, ..Input::default() }), (10000, 0));
+		assert_eq!(frame(Input { pan: 1.0, ..Input::default() }), (0, 10000));
+
+		// Stereo pan is a balance control
+		let (_, samples, _) = run(vec![(Input { pan: 0.5, ..Input::default() }, constant(2, 10000, 10))], 0.0);
+		assert_eq!(&samples[..2], &[5000, 10000]);
+	}
+
+	#[test]
+	fn gain_and_clipping() {
+		let quieter = Input { gain: -20.0, ..Input::default() };
+		let (_, samples, clipped) = run(vec![(quieter, constant(1, 20000, 10))], 0.0);
+		assert_eq!(clipped, 0);
+		assert!(samples.iter().all(|&x| x == 2000));
+
+		// Two loud inputs clip in every sample unless there is headroom for them
+		let loud = || vec![(Input::default(), constant(1, 20000, 10)), (Input::default(), constant(1, 20000, 10))];
+		let (_, samples, clipped) = run(loud(), 0.0);
+		assert_eq!(clipped, 10);
+		assert!(samples.iter().all(|&x| x == 32767));
+		let (_, samples, clipped) = run(loud(), 20.0 * 2f64.log10());
+		assert_eq!(clipped, 0);
+		assert!(samples.iter().all(|&x| x == 20000));
+	}
+
+	#[test]
+	fn mismatched_inputs() {
+		let header = |channels, sample_rate| Frame { channels, sample_rate, bits_per_sample: 16, samples: Vec::new(), metadata: None, eof: false };
+		assert_eq!(channels(&[header(1, 44100), header(2, 44100)]), Ok(2));
+		assert!(channels(&[header(2, 44100), header(2, 48000)]).is_err());
+		assert!(channels(&[header(2, 44100), header(6, 44100)]).is_err());
+		assert!(channels(&[]).is_err());
+	}
+}
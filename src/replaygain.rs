@@ -0,0 +1,130 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// ReplayGain 2.0 figures from loudness measurements. The gain brings a
+// track, or an album taken as a whole, to -18 LUFS as BS.1770 measures it;
+// the peak is the true peak as a fraction of full scale.
+
+use crate::codec::ReplayGain;
+use crate::stage::loudness::Report;
+
+pub const REFERENCE_LOUDNESS : f64 = -18.0;
+
+// Silence has a peak but no gain that would mean anything
+fn values(report: &Report) -> (Option<f64>, Option<f64>) {
+	let gain = if report.integrated.is_finite() { Some(REFERENCE_LOUDNESS - report.integrated) } else { None };
+	(gain, Some(10f64.powf(report.true_peak / 20.0)))
+}
+
+pub fn track(report: &Report) -> ReplayGain {
+	let (track_gain, track_peak) = values(report);
+	ReplayGain { track_gain, track_peak, ..ReplayGain::default() }
+}
+
+pub fn set_album(tracks: &mut [ReplayGain], report: &Report) {
+	let (album_gain, album_peak) = values(report);
+	for track in tracks {
+		track.album_gain = album_gain;
+		track.album_peak = album_peak;
+	}
+}
+
+// Swaps any ReplayGain comments for the figures given. Album comments are
+// left as they are when there are no album figures to replace them.
+pub fn update_comments(comments: &mut Vec<(String, String)>, replay_gain: &ReplayGain) {
+	let album = replay_gain.album_gain.is_some() || replay_gain.album_peak.is_some();
+	comments.retain(|(name, _)| {
+		let name = name.to_ascii_uppercase();
+		!(name.starts_with("REPLAYGAIN_TRACK_") || album && name.starts_with("REPLAYGAIN_ALBUM_"))
+	});
+	comments.extend(replay_gain.to_vorbis_comments());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::f64::consts::PI;
+	use crate::codec::{Frame, Metadata};
+	use crate::stage::Stage;
+	use crate::stage::loudness::LoudnessMeter;
+
+	// A meter that has taken a few seconds of a stereo 1 kHz sine at the given level
+	fn measured(level: f64) -> LoudnessMeter {
+		let rate = 48000;
+		let amplitude = 10f64.powf(level / 20.0) * 32768.0;
+		let samples = (0..3 * rate).flat_map(|n| {
+			let x = (amplitude * (2.0 * PI * 1000.0 * n as f64 / rate as f64).sin()).round() as i32;
+			[x, x]
+		}).collect();
+		let mut meter = LoudnessMeter::new();
+		meter.process(Frame { channels: 2, sample_rate: rate, bits_per_sample: 16, samples: Vec::new(), metadata: Some(Metadata::default()), eof: false });
+		meter.process(Frame { channels: 2, sample_rate: rate, bits_per_sample: 16, samples, metadata: None, eof: true });
+		meter
+	}
+
+	#[test]
+	fn album() {
+		let loud = measured(-20.0);
+		let quiet = measured(-30.0);
+		let mut tracks = vec![track(&loud.report()), track(&quiet.report())];
+		assert!((tracks[0].track_gain.unwrap() - 2.0).abs() < 0.1);
+		assert!((tracks[1].track_gain.unwrap() - 12.0).abs() < 0.1);
+
+		// The album is measured as one programme, not averaged from its tracks
+		let mut album = LoudnessMeter::new();
+		album.merge(&loud);
+		album.merge(&quiet);
+		set_album(&mut tracks, &album.report());
+		let expected = REFERENCE_LOUDNESS - (-20.0 + 10.0 * ((1.0 + 0.1) / 2.0f64).log10());
+		for track in &tracks {
+			assert!((track.album_gain.unwrap() - expected).abs() < 0.1, "{:?}", track.album_gain);
+			assert!((track.album_peak.unwrap() - 0.1).abs() < 0.002, "{:?}", track.album_peak);
+		}
+		assert!((tracks[1].track_peak.unwrap() - 10f64.powf(-1.5)).abs() < 0.001);
+	}
+
+	#[test]
+	fn silence() {
+		let report = Report { integrated: f64::NEG_INFINITY, true_peak: f64::NEG_INFINITY, ..Report::default() };
+		let replay_gain = track(&report);
+		assert_eq!(replay_gain.track_gain, None);
+		assert_eq!(replay_gain.track_peak, Some(0.0));
+	}
+
+	#[test]
+	fn comments() {
+		let mut comments = vec![
+			("TITLE".to_string(), "Song".to_string()),
+			("replaygain_track_gain".to_string(), "-1.00 dB".to_string()),
+			("REPLAYGAIN_ALBUM_GAIN".to_string(), "-2.00 dB".to_string()),
+		];
+		// Without album figures the old album gain stays
+		update_comments(&mut comments, &ReplayGain { track_gain: Some(3.5), track_peak: Some(0.5), ..ReplayGain::default() });
+		assert_eq!(comments, vec![
+			("TITLE".to_string(), "Song".to_string()),
+			("REPLAYGAIN_ALBUM_GAIN".to_string(), "-2.00 dB".to_string()),
+			("REPLAYGAIN_TRACK_GAIN".to_string(), "3.50 dB".to_string()),
+			("REPLAYGAIN_TRACK_PEAK".to_string(), "0.500000".to_string()),
+		]);
+
+		update_comments(&mut comments, &ReplayGain { track_gain: Some(1.0), album_gain: Some(0.25), ..ReplayGain::default() });
+		assert_eq!(comments[1..], [
+			("REPLAYGAIN_TRACK_GAIN".to_string(), "1.00 dB".to_string()),
+			("REPLAYGAIN_ALBUM_GAIN".to_string(), "0.25 dB".to_string()),
+		]);
+	}
+}
@@ -0,0 +1,252 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Splitting a CD image into tracks along its CUE sheet
+
+use std::sync::mpsc;
+
+use crate::codec::{Frame, Metadata};
+use crate::cue::{self, CueSheet};
+
+// Where the audio between INDEX 00 and INDEX 01 of a track goes. Anything
+// before INDEX 01 of the first track stays with the first track unless
+// the gaps are dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pregap {
+	Append,
+	Prepend,
+	Drop,
+}
+
+// Sample positions in the image, plus silence for PREGAP and POSTGAP
+struct Span {
+	track : usize,
+	start : u64,
+	end : Option<u64>,
+	lead_silence : u64,
+	tail_silence : u64,
+}
+
+fn spans(sheet: &CueSheet, tracks: &[usize], sample_rate: usize, pregap: Pregap) -> Vec<Span> {
+	let samples = |frames| cue::frames_to_samples(frames, sample_rate);
+	let mut spans = Vec::new();
+
+	for (i, &t) in tracks.iter().enumerate() {
+		let track = &sheet.tracks[t];
+		let next = tracks.get(i + 1).map(|&n| &sheet.tracks[n]);
+
+		let start = match pregap {
+			Pregap::Append | Pregap::Prepend if i == 0 => 0,
+			Pregap::Prepend => track.gap_start_sample(sample_rate),
+			Pregap::Append | Pregap::Drop => track.start_sample(sample_rate),
+		};
+		let end = next.map(|next| match pregap {
+			Pregap::Append => next.start_sample(sample_rate),
+			Pregap::Prepend | Pregap::Drop => next.gap_start_sample(sample_rate),
+		});
+
+		// Silence that was never ripped follows the same rule as gaps in the image
+		let lead_silence = match pregap {
+			Pregap::Prepend => track.pregap,
+			Pregap::Append if i == 0 => track.pregap,
+			_ => 0,
+		};
+		let mut tail_silence = track.postgap;
+		if pregap == Pregap::Append {
+			tail_silence += next.map(|next| next.pregap).unwrap_or(0);
+		}
+
+		spans.push(Span {
+			track: t,
+			start,
+			end: end.map(|end| end.max(start)),
+			lead_silence: samples(lead_silence),
+			tail_silence: samples(tail_silence),
+		});
+	}
+	spans
+}
+
+fn track_metadata(sheet: &CueSheet, span: &Span, album: &Metadata) -> Metadata {
+	let mut metadata = sheet.track_metadata(span.track, album);
+	let shift = |position: u64| position - span.start + span.lead_silence;
+
+	let length = span.end.or(album.total_samples).map(|end| end.saturating_sub(span.start));
+	metadata.total_samples = length.map(|length| span.lead_silence + length + span.tail_silence);
+	metadata.time_reference = album.time_reference.map(|t| (t + span.start).saturating_sub(span.lead_silence));
+
+	let end = span.end.unwrap_or(u64::MAX);
+	metadata.markers.retain(|m| m.position >= span.start && m.position < end);
+	// Regions are cut off where the track ends
+	for marker in &mut metadata.markers {
+		marker.length = marker.length.min(end - marker.position);
+		marker.position = shift(marker.position);
+	}
+	// Loops only make sense for the image as a whole
+	metadata.sampler = None;
+	metadata
+}
+
+// Reads an image from rx and sends each audio track that lives in `file`
+// (an index into the sheet's FILE list) to the encoder `open` returns for
+// it, header frame first. Returns the tracks the image ended before.
+pub fn split<F>(rx: mpsc::Receiver<Frame>, sheet: &CueSheet, file: usize, pregap: Pregap, mut open: F) -> Vec<usize>
+	where F: FnMut(usize, &Metadata) -> mpsc::Sender<Frame>
+{
+	let mut header = rx.recv().unwrap();
+	let album = header.metadata.take().unwrap_or_default();
+	let channels = header.channels;
+	let sample_rate = header.sample_rate;
+	let bits_per_sample = header.bits_per_sample;
+
+	let tracks : Vec<usize> = (0..sheet.tracks.len())
+		.filter(|&t| sheet.tracks[t].file == file && sheet.tracks[t].is_audio())
+		.collect();
+	let spans = spans(sheet, &tracks, sample_rate, pregap);
+
+	let frame = |samples: Vec<i32>, metadata: Option<Metadata>, eof: bool| Frame {
+		channels,
+		sample_rate,
+		bits_per_sample,
+		samples,
+		metadata,
+		eof,
+	};
+
+	let mut next = 0;
+	let mut current : Option<mpsc::Sender<Frame>> = None;
+	let mut pos = 0;
+	let mut eof = header.eof;
+	let mut pending = header.samples;
+
+	loop {
+		let n = (pending.len() / channels) as u64;
+		let mut offset = 0;
+		while offset < n && next < spans.len() {
+			let span = &spans[next];
+			let at = pos + offset;
+			if at < span.start {
+				offset = n.min(offset + span.start - at);
+				continue;
+			}
+
+			let tx = current.get_or_insert_with(|| {
+				let metadata = track_metadata(sheet, span, &album);
+				let tx = open(span.track, &metadata);
+				tx.send(frame(Vec::new(), Some(metadata), false)).unwrap();
+				if span.lead_silence > 0 {
+					tx.send(frame(vec![0; span.lead_silence as usize * channels], None, false)).unwrap();
+				}
+				tx
+			});
+
+			let end = span.end.unwrap_or(u64::MAX);
+			let take = (n - offset).min(end - at);
+			if take > 0 {
+				let samples = pending[(offset as usize * channels)..((offset + take) as usize * channels)].to_vec();
+				tx.send(frame(samples, None, false)).unwrap();
+			}
+			offset += take;
+
+			if at + take == end {
+				let tx = current.take().unwrap();
+				tx.send(frame(vec![0; span.tail_silence as usize * channels], None, true)).unwrap();
+				next += 1;
+			}
+		}
+		pos += n;
+
+		if eof {
+			break;
+		}
+		let incoming = rx.recv().unwrap();
+		eof = incoming.eof;
+		pending = incoming.samples;
+	}
+
+	// The last track runs to the end of the image
+	if let Some(tx) = current {
+		let span = &spans[next];
+		tx.send(frame(vec![0; span.tail_silence as usize * channels], None, true)).unwrap();
+		next += 1;
+	}
+	spans[next..].iter().map(|span| span.track).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Track 1 has a hidden pregap in the image, track 2 an ordinary one, and
+	// track 3 pregap and postgap silence that is not in the image at all
+	const SHEET : &str = "FILE \"image.wav\" WAVE\n\
+		  TRACK 01 AUDIO\n\
+		    INDEX 00 00:00:00\n\
+		    INDEX 01 00:02:00\n\
+		  TRACK 02 AUDIO\n\
+		    INDEX 00 01:00:00\n\
+		    INDEX 01 01:02:00\n\
+		  TRACK 03 AUDIO\n\
+		    PREGAP 00:01:00\n\
+		    INDEX 01 02:00:00\n\
+		    POSTGAP 00:00:30\n";
+
+	// (start, end, lead silence, tail silence), in CD frames
+	fn frames(pregap: Pregap) -> Vec<(u64, Option<u64>, u64, u64)> {
+		let sheet = cue::parse(SHEET).unwrap();
+		let frame = |samples| samples / 588;
+		spans(&sheet, &[0, 1, 2], 44100, pregap).iter()
+			.map(|span| (frame(span.start), span.end.map(frame), frame(span.lead_silence), frame(span.tail_silence)))
+			.collect()
+	}
+
+	#[test]
+	fn append_gaps() {
+		assert_eq!(frames(Pregap::Append), vec![
+			(0, Some(4650), 0, 0),
+			(4650, Some(9000), 0, 75),
+			(9000, None, 0, 30),
+		]);
+	}
+
+	#[test]
+	fn prepend_gaps() {
+		assert_eq!(frames(Pregap::Prepend), vec![
+			(0, Some(4500), 0, 0),
+			(4500, Some(9000), 0, 0),
+			(9000, None, 75, 30),
+		]);
+	}
+
+	#[test]
+	fn drop_gaps() {
+		assert_eq!(frames(Pregap::Drop), vec![
+			(150, Some(4500), 0, 0),
+			(4650, Some(9000), 0, 0),
+			(9000, None, 0, 30),
+		]);
+	}
+
+	#[test]
+	fn selected_tracks() {
+		// With track 2 left out, track 1 runs on to track 3
+		let sheet = cue::parse(SHEET).unwrap();
+		let spans = spans(&sheet, &[0, 2], 44100, Pregap::Drop);
+		assert_eq!(spans.iter().map(|span| span.track).collect::<Vec<_>>(), vec![0, 2]);
+		assert_eq!(spans[0].end, Some(9000 * 588));
+	}
+}
@@ -0,0 +1,256 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Bit-depth conversion. Reducing the depth quantizes in units of the new
+// least significant bit, optionally adding triangular (TPDF) dither and
+// feeding the quantization error back through a noise-shaping filter, so
+// that the noise spectrum is (1 - H(z)) times flat.
+
+use crate::codec::Frame;
+use crate::stage::Stage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantizer {
+	Truncate,
+	Round,
+	Tpdf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseShaping {
+	None,
+	// First order, pushing the noise up at 6 dB per octave at any rate
+	Simple,
+	// The curves below are designed for 44.1 kHz (as in SoX) and are used at
+	// 48 kHz too; other rates fall back to Simple
+	Lipshitz,
+	FWeighted,
+	ModifiedEWeighted,
+	ImprovedEWeighted,
+}
+
+impl Quantizer {
+	pub fn parse(name: &str) -> Option<Quantizer> {
+		match name {
+			"truncate" => Some(Quantizer::Truncate),
+			"round" => Some(Quantizer::Round),
+			"tpdf" => Some(Quantizer::Tpdf),
+			_ => None,
+		}
+	}
+}
+
+impl NoiseShaping {
+	pub fn parse(name: &str) -> Option<NoiseShaping> {
+		match name {
+			"none" => Some(NoiseShaping::None),
+			"simple" => Some(NoiseShaping::Simple),
+			"lipshitz" => Some(NoiseShaping::Lipshitz),
+			"f-weighted" => Some(NoiseShaping::FWeighted),
+			"modified-e-weighted" => Some(NoiseShaping::ModifiedEWeighted),
+			"improved-e-weighted" => Some(NoiseShaping::ImprovedEWeighted),
+			_ => None,
+		}
+	}
+
+	fn coefficients(self, sample_rate: usize) -> &'static [f64] {
+		if self != NoiseShaping::None && sample_rate != 44100 && sample_rate != 48000 {
+			return &[1.0];
+		}
+		match self {
+			NoiseShaping::None => &[],
+			NoiseShaping::Simple => &[1.0],
+			NoiseShaping::Lipshitz => &[2.033, -2.165, 1.959, -1.590, 0.6149],
+			NoiseShaping::FWeighted => &[2.412, -3.370, 3.937, -4.174, 3.353, -2.205, 1.281, -0.569, 0.0847],
+			NoiseShaping::ModifiedEWeighted => &[1.662, -1.263, 0.4827, -0.2913, 0.1268, -0.1124, 0.03252, -0.01265, -0.03524],
+			NoiseShaping::ImprovedEWeighted => &[2.847, -4.685, 6.214, -7.184, 6.639, -5.032, 3.263, -1.632, 0.4191],
+		}
+	}
+}
+
+// xorshift64*, so that a given seed always gives the same output
+pub struct Rng(u64);
+
+impl Rng {
+	pub fn new(seed: u64) -> Rng {
+		// The state must never be zero
+		Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 ^= self.0 >> 12;
+		self.0 ^= self.0 << 25;
+		self.0 ^= self.0 >> 27;
+		self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+	}
+
+	// Uniform in [0, 1)
+	pub fn next_f64(&mut self) -> f64 {
+		(self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+	}
+}
+
+pub const DEFAULT_SEED : u64 = 0;
+
+pub struct Dither {
+	bits_per_sample : usize,
+	quantizer : Quantizer,
+	shaping : NoiseShaping,
+	rng : Rng,
+	coefficients : &'static [f64],
+	// Most recent error first, one history per channel
+	errors : Vec<Vec<f64>>,
+	shift : usize,
+}
+
+impl Dither {
+	pub fn new(bits_per_sample: usize, quantizer: Quantizer, shaping: NoiseShaping, seed: u64) -> Dither {
+		Dither {
+			bits_per_sample,
+			quantizer,
+			shaping,
+			rng: Rng::new(seed),
+			coefficients: &[],
+			errors: Vec::new(),
+			shift: 0,
+		}
+	}
+
+	fn quantize(&mut self, samples: &mut [i32], channels: usize) {
+		let scale = (1u64 << self.shift) as f64;
+		let max = (1i64 << (self.bits_per_sample - 1)) - 1;
+		let min = -max - 1;
+
+		for (n, sample) in samples.iter_mut().enumerate() {
+			let errors = &mut self.errors[n % channels];
+			let mut v = *sample as f64 / scale;
+			for (c, e) in self.coefficients.iter().zip(errors.iter()) {
+				v -= c * e;
+			}
+
+			let y = match self.quantizer {
+				Quantizer::Truncate => v.floor(),
+				Quantizer::Round => v.round(),
+				Quantizer::Tpdf => (v + self.rng.next_f64() - self.rng.next_f64()).round(),
+			};
+
+			if !errors.is_empty() {
+				errors.pop();
+				errors.insert(0, y - v);
+			}
+			*sample = (y as i64).max(min).min(max) as i32;
+		}
+	}
+}
+
+impl Stage for Dither {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		if let Some(ref mut metadata) = frame.metadata {
+			self.shift = frame.bits_per_sample.saturating_sub(self.bits_per_sample);
+			self.coefficients = self.shaping.coefficients(frame.sample_rate);
+			self.errors = vec![vec![0.0; self.coefficients.len()]; frame.channels];
+			if frame.bits_per_sample != self.bits_per_sample {
+				metadata.audio_md5 = None;
+			}
+		}
+
+		if self.bits_per_sample > frame.bits_per_sample {
+			// Widening is exact
+			let shift = self.bits_per_sample - frame.bits_per_sample;
+			for sample in &mut frame.samples {
+				*sample <<= shift;
+			}
+		} else if self.shift > 0 {
+			self.quantize(&mut frame.samples, frame.channels);
+		}
+		frame.bits_per_sample = self.bits_per_sample;
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::codec::Metadata;
+
+	// Runs one channel of samples through a fresh stage, header first
+	fn run(mut dither: Dither, bits_per_sample: usize, samples: Vec<i32>) -> (Frame, Frame) {
+		let frame = |samples, metadata| Frame { channels: 1, sample_rate: 44100, bits_per_sample, samples, metadata, eof: false };
+		let metadata = Metadata { audio_md5: Some([1; 16]), ..Metadata::default() };
+		let header = dither.process(frame(Vec::new(), Some(metadata)));
+		(header, dither.process(frame(samples, None)))
+	}
+
+	#[test]
+	fn truncate_and_round() {
+		let samples = vec![5 * 256 + 127, 5 * 256 + 128, 5 * 256 + 255, -5 * 256 - 1, -5 * 256 - 128];
+		let (header, frame) = run(Dither::new(16, Quantizer::Truncate, NoiseShaping::None, 0), 24, samples.clone());
+		assert_eq!(header.bits_per_sample, 16);
+		assert_eq!(header.metadata.unwrap().audio_md5, None);
+		assert_eq!(frame.bits_per_sample, 16);
+		assert_eq!(frame.samples, vec![5, 5, 5, -6, -6]);
+
+		let (_, frame) = run(Dither::new(16, Quantizer::Round, NoiseShaping::None, 0), 24, samples);
+		assert_eq!(frame.samples, vec![5, 6, 6, -5, -6]);
+	}
+
+	#[test]
+	fn clips() {
+		let (_, frame) = run(Dither::new(16, Quantizer::Round, NoiseShaping::None, 0), 24, vec![8388607, -8388608]);
+		assert_eq!(frame.samples, vec![32767, -32768]);
+	}
+
+	#[test]
+	fn widens_exactly() {
+		let (header, frame) = run(Dither::new(24, Quantizer::Tpdf, NoiseShaping::None, 0), 16, vec![1, -1, 32767, -32768]);
+		assert_eq!(header.metadata.unwrap().audio_md5, None);
+		assert_eq!(frame.samples, vec![256, -256, 32767 * 256, -32768 * 256]);
+
+		// Same depth in and out is left alone
+		let (header, frame) = run(Dither::new(16, Quantizer::Tpdf, NoiseShaping::Lipshitz, 0), 16, vec![1, -1, 12345]);
+		assert_eq!(header.metadata.unwrap().audio_md5, Some([1; 16]));
+		assert_eq!(frame.samples, vec![1, -1, 12345]);
+	}
+
+	#[test]
+	fn tpdf_stays_within_one_step() {
+		let samples : Vec<i32> = (0..10000).map(|n| (n * 977 % 65536 - 32768) * 256 + n % 256).collect();
+		for seed in 0..4 {
+			let (_, frame) = run(Dither::new(16, Quantizer::Tpdf, NoiseShaping::None, seed), 24, samples.clone());
+			for (&y, &x) in frame.samples.iter().zip(&samples) {
+				assert!((y as f64 - x as f64 / 256.0).abs() <= 1.5, "{} from {}", y, x);
+			}
+		}
+	}
+
+	#[test]
+	fn simple_shaping_keeps_the_sum() {
+		// With first order error feedback the errors cancel but for the last
+		let samples : Vec<i32> = (0..1000).map(|n| (n * 7919 % 2000 - 1000) * 37).collect();
+		let (_, frame) = run(Dither::new(16, Quantizer::Round, NoiseShaping::Simple, 0), 24, samples.clone());
+		let sum_in = samples.iter().map(|&x| x as f64 / 256.0).sum::<f64>();
+		let sum_out = frame.samples.iter().map(|&y| y as f64).sum::<f64>();
+		assert!((sum_out - sum_in).abs() <= 0.5, "{} {}", sum_in, sum_out);
+	}
+
+	#[test]
+	fn same_seed_same_output() {
+		let samples : Vec<i32> = (0..1000).map(|n| n * 1234).collect();
+		let dither = || Dither::new(16, Quantizer::Tpdf, NoiseShaping::FWeighted, 42);
+		assert_eq!(run(dither(), 24, samples.clone()).1.samples, run(dither(), 24, samples).1.samples);
+	}
+}
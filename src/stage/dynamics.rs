@@ -0,0 +1,400 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Compressor and noise gate. Both work sample by sample on the stream
+// without looking ahead: a side chain measures the level, by peak or by
+// RMS, and a gain follows it with separate attack and release times.
+//
+// The side chain can be linked across channels, so that every channel gets
+// the same gain and the stereo image holds, taking the loudest channel or
+// the average power of all of them; unlinked, each channel is on its own.
+
+use crate::codec::{Frame, ReplayGain};
+use crate::stage::{self, Stage};
+
+// Averaging time of the RMS detector, in seconds
+const RMS_WINDOW : f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Detector {
+	Peak,
+	Rms,
+}
+
+impl Detector {
+	pub fn parse(name: &str) -> Option<Detector> {
+		match name {
+			"peak" => Some(Detector::Peak),
+			"rms" => Some(Detector::Rms),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Link {
+	None,
+	Max,
+	Average,
+}
+
+impl Link {
+	pub fn parse(name: &str) -> Option<Link> {
+		match name {
+			"none" => Some(Link::None),
+			"max" => Some(Link::Max),
+			"average" => Some(Link::Average),
+			_ => None,
+		}
+	}
+}
+
+// One-pole smoothing coefficient for a time in seconds
+fn coefficient(seconds: f64, sample_rate: usize) -> f64 {
+	if seconds > 0.0 { (-1.0 / (seconds * sample_rate as f64)).exp() } else { 0.0 }
+}
+
+// Levels as linear amplitudes, one per channel or one for all when linked
+struct Sidechain {
+	detector : Detector,
+	link : Link,
+	rms : f64,
+	power : Vec<f64>,
+	levels : Vec<f64>,
+}
+
+impl Sidechain {
+	fn new(detector: Detector, link: Link, channels: usize, sample_rate: usize) -> Sidechain {
+		Sidechain {
+			detector,
+			link,
+			rms: coefficient(RMS_WINDOW, sample_rate),
+			power: vec![0.0; channels],
+			levels: vec![0.0; if link == Link::None { channels } else { 1 }],
+		}
+	}
+
+	fn detect(&mut self, sample: &[f64]) -> &[f64] {
+		for (power, &x) in self.power.iter_mut().zip(sample) {
+			*power = match self.detector {
+				Detector::Peak => x * x,
+				Detector::Rms => x * x + (*power - x * x) * self.rms,
+			};
+		}
+		match self.link {
+			Link::None => {
+				for (level, power) in self.levels.iter_mut().zip(&self.power) {
+					*level = power.sqrt();
+				}
+			},
+			Link::Max => self.levels[0] = self.power.iter().fold(0.0f64, |a, &b| a.max(b)).sqrt(),
+			Link::Average => self.levels[0] = (self.power.iter().sum::<f64>() / self.power.len() as f64).sqrt(),
+		}
+		&self.levels
+	}
+}
+
+// Levels and gains in dB, times in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compression {
+	pub threshold : f64,
+	pub ratio : f64,
+	pub knee : f64,
+	pub attack : f64,
+	pub release : f64,
+	pub makeup : f64,
+	pub detector : Detector,
+	pub link : Link,
+}
+
+impl Default for Compression {
+	fn default() -> Compression {
+		Compression {
+			threshold: -20.0,
+			ratio: 4.0,
+			knee: 6.0,
+			attack: 0.01,
+			release: 0.1,
+			makeup: 0.0,
+			detector: Detector::Rms,
+			link: Link::Max,
+		}
+	}
+}
+
+impl Compression {
+	// The gain change for a level, with the knee spread evenly either side
+	// of the threshold
+	fn gain(&self, level: f64) -> f64 {
+		let over = level - self.threshold;
+		let slope = 1.0 / self.ratio - 1.0;
+		if 2.0 * over <= -self.knee {
+			0.0
+		} else if 2.0 * over.abs() < self.knee {
+			slope * (over + self.knee / 2.0).powi(2) / (2.0 * self.knee)
+		} else {
+			slope * over
+		}
+	}
+}
+
+pub struct Compressor {
+	settings : Compression,
+	channels : usize,
+	bits_per_sample : usize,
+	attack : f64,
+	release : f64,
+	sidechain : Option<Sidechain>,
+	// Gain in dB, for each side-chain level, and as a linear factor with
+	// the makeup gain in
+	gains : Vec<f64>,
+	factors : Vec<f64>,
+}
+
+impl Compressor {
+	pub fn new(settings: Compression) -> Compressor {
+		Compressor {
+			settings,
+			channels: 0,
+			bits_per_sample: 0,
+			attack: 0.0,
+			release: 0.0,
+			sidechain: None,
+			gains: Vec::new(),
+			factors: Vec::new(),
+		}
+	}
+}
+
+impl Stage for Compressor {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		if let Some(ref mut metadata) = frame.metadata {
+			let settings = &self.settings;
+			let sidechain = Sidechain::new(settings.detector, settings.link, frame.channels, frame.sample_rate);
+			self.channels = frame.channels;
+			self.bits_per_sample = frame.bits_per_sample;
+			self.attack = coefficient(settings.attack, frame.sample_rate);
+			self.release = coefficient(settings.release, frame.sample_rate);
+			self.gains = vec![0.0; sidechain.levels.len()];
+			self.factors = vec![1.0; sidechain.levels.len()];
+			self.sidechain = Some(sidechain);
+			// The gain varies, so no earlier ReplayGain figure is right any more
+			metadata.replay_gain = ReplayGain::default();
+			metadata.audio_md5 = None;
+		}
+
+		let sidechain = match self.sidechain {
+			Some(ref mut sidechain) => sidechain,
+			None => return frame,
+		};
+		let mut samples = stage::to_f64(&frame.samples, self.bits_per_sample);
+		for sample in samples.chunks_exact_mut(self.channels) {
+			for ((gain, factor), &level) in self.gains.iter_mut().zip(self.factors.iter_mut()).zip(sidechain.detect(sample)) {
+				let target = self.settings.gain(20.0 * level.max(1e-10).log10());
+				let coeff = if target < *gain { self.attack } else { self.release };
+				*gain = target + (*gain - target) * coeff;
+				*factor = 10f64.powf((*gain + self.settings.makeup) / 20.0);
+			}
+			for (ch, x) in sample.iter_mut().enumerate() {
+				*x *= self.factors[ch.min(self.factors.len() - 1)];
+			}
+		}
+		frame.samples = stage::from_f64(&samples, self.bits_per_sample);
+		frame
+	}
+}
+
+// Levels in dB, times in seconds. The range is how far a closed gate
+// turns the signal down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gating {
+	pub threshold : f64,
+	pub range : f64,
+	pub attack : f64,
+	pub hold : f64,
+	pub release : f64,
+	pub detector : Detector,
+	pub link : Link,
+}
+
+impl Default for Gating {
+	fn default() -> Gating {
+		Gating {
+			threshold: -50.0,
+			range: -80.0,
+			attack: 0.001,
+			hold: 0.05,
+			release: 0.1,
+			detector: Detector::Peak,
+			link: Link::Max,
+		}
+	}
+}
+
+pub struct Gate {
+	settings : Gating,
+	channels : usize,
+	bits_per_sample : usize,
+	threshold : f64,
+	floor : f64,
+	attack : f64,
+	release : f64,
+	hold : u64,
+	sidechain : Option<Sidechain>,
+	// Linear gain, and samples left to stay open, for each side-chain level
+	gains : Vec<f64>,
+	held : Vec<u64>,
+}
+
+impl Gate {
+	pub fn new(settings: Gating) -> Gate {
+		Gate {
+			settings,
+			channels: 0,
+			bits_per_sample: 0,
+			threshold: 10f64.powf(settings.threshold / 20.0),
+			floor: 10f64.powf(settings.range / 20.0),
+			attack: 0.0,
+			release: 0.0,
+			hold: 0,
+			sidechain: None,
+			gains: Vec::new(),
+			held: Vec::new(),
+		}
+	}
+}
+
+impl Stage for Gate {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		if let Some(ref mut metadata) = frame.metadata {
+			let settings = &self.settings;
+			let sidechain = Sidechain::new(settings.detector, settings.link, frame.channels, frame.sample_rate);
+			self.channels = frame.channels;
+			self.bits_per_sample = frame.bits_per_sample;
+			self.attack = coefficient(settings.attack, frame.sample_rate);
+			self.release = coefficient(settings.release, frame.sample_rate);
+			self.hold = (settings.hold * frame.sample_rate as f64).round() as u64;
+			// Open to start with, so nothing at the very beginning is lost
+			self.gains = vec![1.0; sidechain.levels.len()];
+			self.held = vec![self.hold; sidechain.levels.len()];
+			self.sidechain = Some(sidechain);
+			metadata.replay_gain = ReplayGain::default();
+			metadata.audio_md5 = None;
+		}
+
+		let sidechain = match self.sidechain {
+			Some(ref mut sidechain) => sidechain,
+			None => return frame,
+		};
+		let mut samples = stage::to_f64(&frame.samples, self.bits_per_sample);
+		for sample in samples.chunks_exact_mut(self.channels) {
+			let levels = sidechain.detect(sample);
+			for ((gain, held), &level) in self.gains.iter_mut().zip(self.held.iter_mut()).zip(levels) {
+				let open = if level >= self.threshold {
+					*held = self.hold;
+					true
+				} else if *held > 0 {
+					*held -= 1;
+					true
+				} else {
+					false
+				};
+				let (target, coeff) = if open { (1.0, self.attack) } else { (self.floor, self.release) };
+				*gain = target + (*gain - target) * coeff;
+			}
+			for (ch, x) in sample.iter_mut().enumerate() {
+				*x *= self.gains[ch.min(self.gains.len() - 1)];
+			}
+		}
+		frame.samples = stage::from_f64(&samples, self.bits_per_sample);
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::codec::Metadata;
+
+	// 16-bit at 1 kHz, so that times are easy to count in samples
+	fn run(stage: &mut dyn Stage, channels: usize, samples: Vec<i32>) -> Vec<i32> {
+		let header = Frame { channels, sample_rate: 1000, bits_per_sample: 16, samples: Vec::new(), metadata: Some(Metadata::default()), eof: false };
+		stage.process(header.clone());
+		stage.process(Frame { samples, metadata: None, eof: true, ..header }).samples
+	}
+
+	fn db(gain: f64) -> f64 {
+		10f64.powf(gain / 20.0)
+	}
+
+	#[test]
+	fn knee() {
+		let settings = Compression::default();
+		assert_eq!(settings.gain(-40.0), 0.0);
+		assert_eq!(settings.gain(-23.0), 0.0);
+		// Halfway through the knee, and then the full ratio from its top on
+		assert!((settings.gain(-20.0) + 0.5625).abs() < 1e-12);
+		assert!((settings.gain(-17.0) + 2.25).abs() < 1e-12);
+		assert!((settings.gain(-17.0 - 1e-9) + 2.25).abs() < 1e-6);
+		assert!((settings.gain(0.0) + 15.0).abs() < 1e-12);
+
+		let hard = Compression { knee: 0.0, ..Compression::default() };
+		assert_eq!(hard.gain(-20.0), 0.0);
+		assert!((hard.gain(-10.0) + 7.5).abs() < 1e-12);
+	}
+
+	#[test]
+	fn gate_hold_and_floor() {
+		let mut gate = Gate::new(Gating { threshold: -20.0, range: -40.0, attack: 0.0, hold: 0.01, release: 0.0, ..Gating::default() });
+		let mut input = vec![16384; 50];
+		input.extend(vec![1000; 100]);
+		let output = run(&mut gate, 1, input.clone());
+		// Open through the hold time after the last loud sample, then down to the floor
+		assert_eq!(&output[..60], &input[..60]);
+		assert!(output[60..].iter().all(|&x| x == 10));
+
+		// Opening again as soon as it is loud enough
+		let mut gate = Gate::new(Gating { threshold: -20.0, range: -40.0, attack: 0.0, hold: 0.0, release: 0.0, ..Gating::default() });
+		let output = run(&mut gate, 1, vec![1000, 1000, 16384, 1000]);
+		assert_eq!(output, vec![10, 10, 16384, 10]);
+	}
+
+	#[test]
+	fn linking() {
+		// Left 6 dB below full scale, right 30 dB below, with an instant hard knee
+		let settings = Compression { knee: 0.0, attack: 0.0, release: 0.0, makeup: 2.0, detector: Detector::Peak, ..Compression::default() };
+		let (left, right) = (16384.0 / 32768.0, 1037.0 / 32768.0f64);
+		let input = [16384, 1037].repeat(10);
+		let level = |x: f64| 20.0 * x.log10();
+		let expected = |left_gain: f64, right_gain: f64| {
+			let (l, r) = ((16384.0 * db(left_gain + 2.0)).round() as i32, (1037.0 * db(right_gain + 2.0)).round() as i32);
+			[l, r].repeat(10)
+		};
+
+		let output = run(&mut Compressor::new(Compression { link: Link::None, ..settings }), 2, input.clone());
+		assert_eq!(output, expected(settings.gain(level(left)), 0.0));
+
+		let output = run(&mut Compressor::new(Compression { link: Link::Max, ..settings }), 2, input.clone());
+		let gain = settings.gain(level(left));
+		assert_eq!(output, expected(gain, gain));
+
+		let output = run(&mut Compressor::new(Compression { link: Link::Average, ..settings }), 2, input);
+		let gain = settings.gain(level(((left * left + right * right) / 2.0).sqrt()));
+		assert!(gain < 0.0 && gain > settings.gain(level(left)));
+		assert_eq!(output, expected(gain, gain));
+	}
+}
@@ -0,0 +1,520 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Equalization with a bank of biquad filters: Butterworth high- and
+// low-pass of any order up to 8, shelves, notches and peaking bands, the
+// coefficients following the Audio EQ Cookbook.
+//
+// Run as they are, the biquads shift phase around each band as analogue
+// filters would. The linear-phase design instead samples the magnitude
+// response of the whole bank and turns it into a symmetric FIR kernel,
+// applied by FFT convolution. Its look-ahead is compensated for, so the
+// output lines up with the input and has as many samples.
+
+use std::f64::consts::PI;
+
+use crate::codec::{Frame, ReplayGain};
+use crate::stage::{self, Stage};
+use crate::stage::resample::bessel_i0;
+
+// Linear phase: Kaiser window beta, and the frequency resolution the
+// default kernel length aims for, in Hz
+const FIR_BETA : f64 = 8.0;
+const FIR_RESOLUTION : usize = 4;
+
+// One second-order section in direct form I, the state kept in f64
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Biquad {
+	b : [f64; 3],
+	a : [f64; 2],
+	x : [f64; 2],
+	y : [f64; 2],
+}
+
+impl Biquad {
+	// Coefficients already divided by a0
+	pub fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+		Biquad { b, a, ..Biquad::default() }
+	}
+
+	fn normalized(b: [f64; 3], a: [f64; 3]) -> Biquad {
+		Biquad::new([b[0] / a[0], b[1] / a[0], b[2] / a[0]], [a[1] / a[0], a[2] / a[0]])
+	}
+
+	pub fn process(&mut self, x: f64) -> f64 {
+		let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+		self.x = [x, self.x[0]];
+		self.y = [y, self.y[0]];
+		y
+	}
+
+	// Gain at w radians per sample
+	fn magnitude(&self, w: f64) -> f64 {
+		let (c1, s1, c2, s2) = (w.cos(), w.sin(), (2.0 * w).cos(), (2.0 * w).sin());
+		let num = (self.b[0] + self.b[1] * c1 + self.b[2] * c2).hypot(self.b[1] * s1 + self.b[2] * s2);
+		let den = (1.0 + self.a[0] * c1 + self.a[1] * c2).hypot(self.a[0] * s1 + self.a[1] * s2);
+		num / den
+	}
+}
+
+// Gains in dB
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+	HighPass,
+	LowPass,
+	LowShelf(f64),
+	HighShelf(f64),
+	Notch,
+	Peak(f64),
+}
+
+impl Shape {
+	pub fn name(self) -> &'static str {
+		match self {
+			Shape::HighPass => "highpass",
+			Shape::LowPass => "lowpass",
+			Shape::LowShelf(_) => "lowshelf",
+			Shape::HighShelf(_) => "highshelf",
+			Shape::Notch => "notch",
+			Shape::Peak(_) => "peak",
+		}
+	}
+
+	// A shelf with a Q of 1/sqrt(2) has the steepest slope without overshoot
+	pub fn default_q(self) -> f64 {
+		match self {
+			Shape::Notch => 10.0,
+			Shape::Peak(_) => 1.0,
+			_ => 0.5f64.sqrt(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+	pub shape : Shape,
+	// In Hz
+	pub frequency : f64,
+	// Not used by high- and low-pass, which are Butterworth
+	pub q : f64,
+	// Of high- and low-pass, 6 dB per octave each; the rest have one section
+	pub order : usize,
+}
+
+impl Band {
+	pub fn new(shape: Shape, frequency: f64) -> Band {
+		Band { shape, frequency, q: shape.default_q(), order: 2 }
+	}
+
+	pub fn sections(&self, sample_rate: usize) -> Vec<Biquad> {
+		let w0 = 2.0 * PI * self.frequency / sample_rate as f64;
+		let (cos, sin) = (w0.cos(), w0.sin());
+		let section = |q: f64| -> Biquad {
+			let alpha = sin / (2.0 * q);
+			match self.shape {
+				Shape::HighPass => Biquad::normalized([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+				Shape::LowPass => Biquad::normalized([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+				Shape::Notch => Biquad::normalized([1.0, -2.0 * cos, 1.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+				Shape::Peak(gain) => {
+					let a = 10f64.powf(gain / 40.0);
+					Biquad::normalized([1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a], [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a])
+				},
+				Shape::LowShelf(gain) => {
+					let a = 10f64.powf(gain / 40.0);
+					let k = 2.0 * a.sqrt() * alpha;
+					Biquad::normalized(
+						[a * ((a + 1.0) - (a - 1.0) * cos + k), 2.0 * a * ((a - 1.0) - (a + 1.0) * cos), a * ((a + 1.0) - (a - 1.0) * cos - k)],
+						[(a + 1.0) + (a - 1.0) * cos + k, -2.0 * ((a - 1.0) + (a + 1.0) * cos), (a + 1.0) + (a - 1.0) * cos - k],
+					)
+				},
+				Shape::HighShelf(gain) => {
+					let a = 10f64.powf(gain / 40.0);
+					let k = 2.0 * a.sqrt() * alpha;
+					Biquad::normalized(
+						[a * ((a + 1.0) + (a - 1.0) * cos + k), -2.0 * a * ((a - 1.0) + (a + 1.0) * cos), a * ((a + 1.0) + (a - 1.0) * cos - k)],
+						[(a + 1.0) - (a - 1.0) * cos + k, 2.0 * ((a - 1.0) - (a + 1.0) * cos), (a + 1.0) - (a - 1.0) * cos - k],
+					)
+				},
+			}
+		};
+
+		match self.shape {
+			Shape::HighPass | Shape::LowPass => {
+				// Butterworth: one section per pole pair, each with its own Q,
+				// and a first-order section for the odd pole
+				let n = self.order;
+				let mut sections : Vec<Biquad> = (0..n / 2).map(|k| {
+					let angle = (2 * k + 1 + n % 2) as f64 * PI / (2 * n) as f64;
+					section(1.0 / (2.0 * angle.cos()))
+				}).collect();
+				if n % 2 == 1 {
+					let k = (w0 / 2.0).tan();
+					sections.push(match self.shape {
+						Shape::HighPass => Biquad::new([1.0 / (1.0 + k), -1.0 / (1.0 + k), 0.0], [(k - 1.0) / (k + 1.0), 0.0]),
+						_ => Biquad::new([k / (1.0 + k), k / (1.0 + k), 0.0], [(k - 1.0) / (k + 1.0), 0.0]),
+					});
+				}
+				sections
+			},
+			_ => vec![section(self.q)],
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Design {
+	Iir,
+	// Linear phase, with the kernel length in taps (odd), or one chosen
+	// from the sample rate
+	Fir(Option<usize>),
+}
+
+// In-place radix-2 FFT, the inverse scaled by 1/n
+fn fft(re: &mut [f64], im: &mut [f64], inverse: bool) {
+	let n = re.len();
+	let mut j = 0;
+	for i in 1..n {
+		let mut bit = n >> 1;
+		while j & bit != 0 {
+			j ^= bit;
+			bit >>= 1;
+		}
+		j |= bit;
+		if i < j {
+			re.swap(i, j);
+			im.swap(i, j);
+		}
+	}
+
+	let sign = if inverse { 1.0 } else { -1.0 };
+	let mut len = 2;
+	while len <= n {
+		let twiddles : Vec<(f64, f64)> = (0..len / 2).map(|k| {
+			let angle = sign * 2.0 * PI * k as f64 / len as f64;
+			(angle.cos(), angle.sin())
+		}).collect();
+		for start in (0..n).step_by(len) {
+			for (k, &(wr, wi)) in twiddles.iter().enumerate() {
+				let (a, b) = (start + k, start + k + len / 2);
+				let tr = re[b] * wr - im[b] * wi;
+				let ti = re[b] * wi + im[b] * wr;
+				re[b] = re[a] - tr;
+				im[b] = im[a] - ti;
+				re[a] += tr;
+				im[a] += ti;
+			}
+		}
+		len <<= 1;
+	}
+
+	if inverse {
+		for x in re.iter_mut().chain(im.iter_mut()) {
+			*x /= n as f64;
+		}
+	}
+}
+
+// A zero-phase kernel with the combined magnitude response of the
+// sections, centred on its middle tap
+fn linear_phase_kernel(sections: &[Biquad], taps: usize) -> Vec<f64> {
+	// Sampled more finely than the kernel is long, to keep time aliasing
+	// out of the part that is kept
+	let size = (taps + 1).next_power_of_two() * 2;
+	let mut re = vec![0.0; size];
+	let mut im = vec![0.0; size];
+	for j in 0..=size / 2 {
+		let w = 2.0 * PI * j as f64 / size as f64;
+		let gain = sections.iter().map(|s| s.magnitude(w)).product();
+		re[j] = gain;
+		re[(size - j) % size] = gain;
+	}
+	fft(&mut re, &mut im, true);
+
+	let half = (taps - 1) / 2;
+	let window_norm = bessel_i0(FIR_BETA);
+	(0..taps).map(|i| {
+		let n = i as i64 - half as i64;
+		let w = n as f64 / (half + 1) as f64;
+		re[n.rem_euclid(size as i64) as usize] * bessel_i0(FIR_BETA * (1.0 - w * w).sqrt()) / window_norm
+	}).collect()
+}
+
+// Overlap-save convolution of one channel with a fixed kernel
+struct Convolver {
+	// Of the kernel, zero-padded to the FFT size
+	spectrum : (Vec<f64>, Vec<f64>),
+	// The last taps - 1 inputs, then those waiting for a full block
+	input : Vec<f64>,
+	overlap : usize,
+}
+
+impl Convolver {
+	fn new(kernel: &[f64]) -> Convolver {
+		let size = (kernel.len() * 2).next_power_of_two();
+		let mut re = kernel.to_vec();
+		re.resize(size, 0.0);
+		let mut im = vec![0.0; size];
+		fft(&mut re, &mut im, false);
+		Convolver {
+			spectrum: (re, im),
+			input: vec![0.0; kernel.len() - 1],
+			overlap: kernel.len() - 1,
+		}
+	}
+
+	fn block(&self) -> usize {
+		self.spectrum.0.len() - self.overlap
+	}
+
+	fn run(&mut self, out: &mut Vec<f64>) {
+		let size = self.spectrum.0.len();
+		let mut re = self.input[..size].to_vec();
+		let mut im = vec![0.0; size];
+		fft(&mut re, &mut im, false);
+		let (kr, ki) = &self.spectrum;
+		for j in 0..size {
+			let r = re[j] * kr[j] - im[j] * ki[j];
+			im[j] = re[j] * ki[j] + im[j] * kr[j];
+			re[j] = r;
+		}
+		fft(&mut re, &mut im, true);
+		out.extend(&re[self.overlap..]);
+		let block = self.block();
+		self.input.drain(..block);
+	}
+
+	// Causal output, one sample for each input once a block is full
+	fn push(&mut self, samples: impl Iterator<Item = f64>, out: &mut Vec<f64>) {
+		self.input.extend(samples);
+		while self.input.len() >= self.spectrum.0.len() {
+			self.run(out);
+		}
+	}
+
+	fn flush(&mut self, out: &mut Vec<f64>) {
+		let pending = self.input.len() - self.overlap;
+		if pending > 0 {
+			self.input.resize(self.spectrum.0.len(), 0.0);
+			self.run(out);
+			out.truncate(out.len() - (self.block() - pending));
+		}
+	}
+}
+
+enum State {
+	// Per channel
+	Iir(Vec<Vec<Biquad>>),
+	// Per channel, and the leading outputs still to drop to take out the
+	// kernel's delay
+	Fir(Vec<Convolver>, usize),
+}
+
+pub struct Equalizer {
+	bands : Vec<Band>,
+	design : Design,
+	channels : usize,
+	bits_per_sample : usize,
+	state : Option<State>,
+}
+
+impl Equalizer {
+	pub fn new(bands: Vec<Band>, design: Design) -> Equalizer {
+		Equalizer {
+			bands,
+			design,
+			channels: 0,
+			bits_per_sample: 0,
+			state: None,
+		}
+	}
+
+	// Input samples the linear-phase kernel looks ahead, already compensated for
+	pub fn latency(&self) -> usize {
+		match self.state {
+			Some(State::Fir(ref convolvers, _)) => convolvers.first().map(|c| c.overlap / 2).unwrap_or(0),
+			_ => 0,
+		}
+	}
+}
+
+impl Stage for Equalizer {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		let sample_rate = frame.sample_rate;
+		if let Some(ref mut metadata) = frame.metadata {
+			self.channels = frame.channels;
+			self.bits_per_sample = frame.bits_per_sample;
+			let sections : Vec<Biquad> = self.bands.iter().flat_map(|band| band.sections(sample_rate)).collect();
+			self.state = Some(match self.design {
+				Design::Iir => State::Iir(vec![sections; frame.channels]),
+				Design::Fir(taps) => {
+					let taps = taps.unwrap_or_else(|| (sample_rate / FIR_RESOLUTION).next_power_of_two() - 1);
+					let kernel = linear_phase_kernel(&sections, taps);
+					State::Fir((0..frame.channels).map(|_| Convolver::new(&kernel)).collect(), taps / 2)
+				},
+			});
+			// The loudness changes with the response
+			metadata.replay_gain = ReplayGain::default();
+			metadata.audio_md5 = None;
+		}
+
+		let channels = self.channels;
+		let samples = stage::to_f64(&frame.samples, self.bits_per_sample);
+		let out = match self.state {
+			Some(State::Iir(ref mut filters)) => {
+				let mut out = samples;
+				for sample in out.chunks_exact_mut(channels) {
+					for (x, sections) in sample.iter_mut().zip(filters.iter_mut()) {
+						*x = sections.iter_mut().fold(*x, |x, section| section.process(x));
+					}
+				}
+				out
+			},
+			Some(State::Fir(ref mut convolvers, ref mut skip)) => {
+				let mut outputs : Vec<Vec<f64>> = Vec::with_capacity(channels);
+				for (ch, convolver) in convolvers.iter_mut().enumerate() {
+					let mut out = Vec::new();
+					convolver.push(samples.iter().skip(ch).step_by(channels).copied(), &mut out);
+					if frame.eof {
+						// Run the delay out
+						convolver.push(std::iter::repeat_n(0.0, convolver.overlap / 2), &mut out);
+						convolver.flush(&mut out);
+					}
+					outputs.push(out);
+				}
+				let len = outputs[0].len();
+				let dropped = len.min(*skip);
+				*skip -= dropped;
+				let mut out = Vec::with_capacity((len - dropped) * channels);
+				for i in dropped..len {
+					out.extend(outputs.iter().map(|o| o[i]));
+				}
+				out
+			},
+			None => return frame,
+		};
+		frame.samples = stage::from_f64(&out, self.bits_per_sample);
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::codec::Metadata;
+
+	// Gain of a band in dB at a frequency in Hz, at 48 kHz
+	fn response(band: Band, frequency: f64) -> f64 {
+		let w = 2.0 * PI * frequency / 48000.0;
+		let gain : f64 = band.sections(48000).iter().map(|s| s.magnitude(w)).product();
+		20.0 * gain.log10()
+	}
+
+	#[test]
+	fn butterworth_cutoff() {
+		for order in 1..=8 {
+			let low = Band { order, ..Band::new(Shape::LowPass, 1000.0) };
+			let high = Band { order, ..Band::new(Shape::HighPass, 1000.0) };
+			assert!((response(low, 1000.0) + 3.0103).abs() < 1e-6, "{} {}", order, response(low, 1000.0));
+			assert!((response(high, 1000.0) + 3.0103).abs() < 1e-6, "{} {}", order, response(high, 1000.0));
+			assert_eq!(low.sections(48000).len(), order.div_ceil(2));
+
+			// Flat in the pass band, and at least 6 dB per octave each beyond
+			assert!(response(low, 10.0).abs() < 0.01);
+			assert!(response(high, 20000.0).abs() < 0.01);
+			assert!(response(low, 4000.0) < -12.0 * order as f64 + 0.5, "{} {}", order, response(low, 4000.0));
+			assert!(response(high, 250.0) < -12.0 * order as f64 + 0.5, "{} {}", order, response(high, 250.0));
+		}
+	}
+
+	#[test]
+	fn shelf_and_peak_gains() {
+		// Shelves reach their gain at the far end and half of it at their frequency
+		let low = Band::new(Shape::LowShelf(6.0), 200.0);
+		assert!((response(low, 1.0) - 6.0).abs() < 1e-3);
+		assert!((response(low, 200.0) - 3.0).abs() < 1e-6);
+		assert!(response(low, 20000.0).abs() < 0.01);
+
+		let high = Band::new(Shape::HighShelf(-9.0), 5000.0);
+		assert!((response(high, 24000.0) + 9.0).abs() < 1e-6);
+		assert!((response(high, 5000.0) + 4.5).abs() < 1e-6);
+		assert!(response(high, 20.0).abs() < 1e-3);
+
+		let peak = Band::new(Shape::Peak(-12.0), 1000.0);
+		assert!((response(peak, 1000.0) + 12.0).abs() < 1e-6);
+		assert!(response(peak, 20.0).abs() < 0.01);
+		assert!(response(peak, 20000.0).abs() < 0.01);
+
+		let notch = Band::new(Shape::Notch, 1000.0);
+		assert!(response(notch, 1000.0) < -100.0);
+		assert!(response(notch, 500.0).abs() < 0.1);
+	}
+
+	// Stereo 16-bit at 48 kHz, an impulse in the left channel only, run
+	// through in uneven frames
+	fn impulse_response(design: Design, length: usize, at: usize) -> Vec<i32> {
+		let mut eq = Equalizer::new(vec![Band::new(Shape::Peak(6.0), 2000.0)], design);
+		let header = Frame { channels: 2, sample_rate: 48000, bits_per_sample: 16, samples: Vec::new(), metadata: Some(Metadata::default()), eof: false };
+		eq.process(header.clone());
+		let mut input = vec![0; length * 2];
+		input[at * 2] = 10000;
+		let chunks : Vec<&[i32]> = input.chunks(1400).collect();
+		let mut output = Vec::new();
+		for (i, chunk) in chunks.iter().enumerate() {
+			let frame = eq.process(Frame { samples: chunk.to_vec(), metadata: None, eof: i == chunks.len() - 1, ..header.clone() });
+			output.extend(frame.samples);
+		}
+		output
+	}
+
+	#[test]
+	fn fir_alignment() {
+		for &(taps, expected) in &[(Some(255), 255), (None, 16383)] {
+			let output = impulse_response(Design::Fir(taps), 3000, 1000);
+			assert_eq!(output.len(), 3000 * 2);
+			assert!(output.iter().skip(1).step_by(2).all(|&x| x == 0));
+
+			// Symmetric about the impulse, which stays where it was
+			let left : Vec<i32> = output.iter().step_by(2).copied().collect();
+			let peak = (0..left.len()).max_by_key(|&i| left[i]).unwrap();
+			assert_eq!(peak, 1000);
+			assert!(left[peak] > 10000);
+			for k in 1..(expected / 2).min(1000) {
+				assert!((left[1000 - k] - left[1000 + k]).abs() <= 1, "{} {}", k, left[1000 - k] - left[1000 + k]);
+			}
+		}
+
+		// The same peak in the IIR design only starts at the impulse
+		let output = impulse_response(Design::Iir, 3000, 1000);
+		assert_eq!(output.len(), 3000 * 2);
+		assert!(output[..1000 * 2].iter().all(|&x| x == 0));
+		assert!(output[1000 * 2] > 10000);
+	}
+
+	#[test]
+	fn fir_matches_iir_magnitude() {
+		// The kernel keeps the bank's response at the centre of the band
+		let sections = Band::new(Shape::Peak(6.0), 2000.0).sections(48000);
+		let kernel = linear_phase_kernel(&sections, 1023);
+		assert_eq!(kernel.len(), 1023);
+		let w = 2.0 * PI * 2000.0 / 48000.0;
+		let (re, im) = kernel.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, &h)| {
+			(re + h * (w * n as f64).cos(), im - h * (w * n as f64).sin())
+		});
+		let gain = 20.0 * f64::hypot(re, im).log10();
+		assert!((gain - 6.0).abs() < 0.05, "{}", gain);
+	}
+}
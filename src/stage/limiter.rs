@@ -0,0 +1,261 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Look-ahead peak limiter. The audio is delayed so that the gain can come
+// down before a peak arrives instead of after it: the gain each sample
+// needs to stay under the ceiling is reduced to its minimum over the
+// look-ahead window, then averaged over the same window, which ramps into
+// every peak and still reaches the required gain on it. Release is a
+// one-pole return towards unity.
+//
+// Peaks are detected on a 4x oversampled signal, so the ceiling holds
+// for true peaks as well as sample values.
+
+use std::collections::VecDeque;
+
+use crate::codec::{Frame, ReplayGain};
+use crate::stage::{self, Stage};
+use crate::stage::loudness::{TruePeak, TRUE_PEAK_DELAY};
+
+// In seconds
+pub const DEFAULT_LOOKAHEAD : f64 = 0.005;
+pub const DEFAULT_RELEASE : f64 = 0.1;
+
+pub struct Limiter {
+	channels : usize,
+	ceiling : f64,
+	lookahead : usize,
+	release : f64,
+
+	peaks : TruePeak,
+	last_peak : f64,
+	// Input samples and gains computed so far, counted per channel group
+	received : u64,
+	detected : u64,
+	emitted : u64,
+	// Ascending minima of the gains in the look-ahead window, by index
+	minima : VecDeque<(u64, f64)>,
+	averaged : VecDeque<f64>,
+	sum : f64,
+	envelope : f64,
+	delay : VecDeque<f64>,
+}
+
+impl Limiter {
+	// Ceiling in dBFS (true peak), look-ahead and release in seconds
+	pub fn new(channels: usize, sample_rate: usize, ceiling: f64, lookahead: f64, release: f64) -> Limiter {
+		let lookahead = ((lookahead * sample_rate as f64).round() as usize).max(1);
+		let release = (-1.0 / (release * sample_rate as f64).max(1.0)).exp();
+		Limiter {
+			channels,
+			ceiling: 10f64.powf(ceiling / 20.0),
+			lookahead,
+			release,
+			peaks: TruePeak::new(channels),
+			last_peak: 0.0,
+			received: 0,
+			detected: 0,
+			emitted: 0,
+			minima: VecDeque::new(),
+			averaged: vec![1.0; lookahead].into(),
+			sum: lookahead as f64,
+			envelope: 1.0,
+			delay: VecDeque::new(),
+		}
+	}
+
+	// Samples held back between input and output
+	pub fn latency(&self) -> usize {
+		TRUE_PEAK_DELAY + self.lookahead - 1
+	}
+
+	// Interleaved samples in, as many as are ready out
+	pub fn process(&mut self, samples: &[f64]) -> Vec<f64> {
+		let mut out = Vec::with_capacity(samples.len());
+		for sample in samples.chunks_exact(self.channels) {
+			self.push(sample, &mut out);
+		}
+		out
+	}
+
+	// Everything still held back
+	pub fn flush(&mut self) -> Vec<f64> {
+		let mut out = Vec::new();
+		let silence = vec![0.0; self.channels];
+		let total = self.received;
+		while self.emitted < total {
+			self.push(&silence, &mut out);
+		}
+		out
+	}
+
+	fn push(&mut self, sample: &[f64], out: &mut Vec<f64>) {
+		self.delay.extend(sample);
+		self.received += 1;
+
+		// Each detection covers the intervals either side of the sample it lags by
+		let mut peak : f64 = 0.0;
+		for (ch, &x) in sample.iter().enumerate() {
+			peak = peak.max(self.peaks.peak(ch, x));
+		}
+		let detection = peak.max(self.last_peak);
+		self.last_peak = peak;
+		if self.received <= TRUE_PEAK_DELAY as u64 {
+			return;
+		}
+		let gain = if detection > self.ceiling { self.ceiling / detection } else { 1.0 };
+
+		let index = self.detected;
+		self.detected += 1;
+		while self.minima.back().is_some_and(|&(_, g)| g >= gain) {
+			self.minima.pop_back();
+		}
+		self.minima.push_back((index, gain));
+		let start = self.detected.saturating_sub(self.lookahead as u64);
+		while self.minima.front().is_some_and(|&(i, _)| i < start) {
+			self.minima.pop_front();
+		}
+		let minimum = self.minima.front().unwrap().1;
+
+		// Minima from the start of the stream go into the average too, or a
+		// peak in the first window would only be partly brought down
+		self.sum += minimum - self.averaged.pop_front().unwrap();
+		self.averaged.push_back(minimum);
+		if self.detected < self.lookahead as u64 {
+			return;
+		}
+		let smoothed = (self.sum / self.lookahead as f64).min(1.0);
+		self.envelope = if smoothed < self.envelope {
+			smoothed
+		} else {
+			smoothed + (self.envelope - smoothed) * self.release
+		};
+
+		for x in self.delay.drain(..self.channels) {
+			out.push(x * self.envelope);
+		}
+		self.emitted += 1;
+	}
+}
+
+// The limiter as a stage of its own
+pub struct Limit {
+	ceiling : f64,
+	lookahead : f64,
+	release : f64,
+	bits_per_sample : usize,
+	limiter : Option<Limiter>,
+}
+
+impl Limit {
+	// Ceiling in dBFS (true peak), look-ahead and release in seconds
+	pub fn new(ceiling: f64, lookahead: f64, release: f64) -> Limit {
+		Limit {
+			ceiling,
+			lookahead,
+			release,
+			bits_per_sample: 0,
+			limiter: None,
+		}
+	}
+}
+
+impl Stage for Limit {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		if let Some(ref mut metadata) = frame.metadata {
+			let limiter = Limiter::new(frame.channels, frame.sample_rate, self.ceiling, self.lookahead, self.release);
+			self.bits_per_sample = frame.bits_per_sample;
+			self.limiter = Some(limiter);
+			metadata.replay_gain = ReplayGain::default();
+			metadata.audio_md5 = None;
+		}
+
+		let limiter = match self.limiter {
+			Some(ref mut limiter) => limiter,
+			None => return frame,
+		};
+		let mut out = limiter.process(&stage::to_f64(&frame.samples, self.bits_per_sample));
+		if frame.eof {
+			out.extend(limiter.flush());
+		}
+		frame.samples = stage::from_f64(&out, self.bits_per_sample);
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::codec::Metadata;
+	use crate::stage::dither::Rng;
+
+	// Stereo noise with loud bursts, starting on one
+	fn bursts(n: usize) -> Vec<f64> {
+		let mut rng = Rng::new(1);
+		(0..n * 2).map(|i| {
+			let level = if (i / 2) % 1000 < 50 { 1.0 } else { 0.2 };
+			level * (2.0 * rng.next_f64() - 1.0)
+		}).collect()
+	}
+
+	#[test]
+	fn ceiling() {
+		let ceiling = 10f64.powf(-3.0 / 20.0);
+		let input = bursts(20000);
+		let mut limiter = Limiter::new(2, 48000, -3.0, DEFAULT_LOOKAHEAD, DEFAULT_RELEASE);
+		let mut output = Vec::new();
+		for chunk in input.chunks(1234) {
+			output.extend(limiter.process(chunk));
+		}
+		output.extend(limiter.flush());
+		assert_eq!(output.len(), input.len());
+		assert!(output.iter().all(|x| x.abs() <= ceiling + 1e-9));
+
+		// Between samples too
+		let mut peaks = TruePeak::new(2);
+		let true_peak = output.iter().enumerate().fold(0.0f64, |peak, (i, &x)| peak.max(peaks.peak(i % 2, x)));
+		assert!(true_peak <= ceiling * 1.01, "{}", true_peak);
+	}
+
+	#[test]
+	fn quiet_input() {
+		// Nothing to limit, so the audio only comes out later
+		let input : Vec<f64> = (0..2000).map(|i| 0.25 * (i as f64 * 0.01).sin()).collect();
+		let mut limiter = Limiter::new(2, 44100, -1.0, DEFAULT_LOOKAHEAD, DEFAULT_RELEASE);
+		let mut output = limiter.process(&input);
+		assert_eq!(output.len(), input.len() - limiter.latency() * 2);
+		output.extend(limiter.flush());
+		assert_eq!(output, input);
+	}
+
+	#[test]
+	fn stage() {
+		let mut stage = Limit::new(-6.0, 0.002, DEFAULT_RELEASE);
+		let header = Frame { channels: 1, sample_rate: 8000, bits_per_sample: 16, samples: Vec::new(), metadata: Some(Metadata::default()), eof: false };
+		stage.process(header.clone());
+		let mut count = 0;
+		let mut peak = 0;
+		for i in 0..5 {
+			let samples = [30000, -30000, 1000, 0].repeat(100);
+			let frame = stage.process(Frame { samples, metadata: None, eof: i == 4, ..header.clone() });
+			count += frame.samples.len();
+			peak = frame.samples.iter().map(|x| x.abs()).max().unwrap_or(0).max(peak);
+		}
+		assert_eq!(count, 2000);
+		assert!(peak as f64 <= 32768.0 * 10f64.powf(-6.0 / 20.0), "{}", peak);
+	}
+}
@@ -0,0 +1,459 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Loudness and true-peak measurement per ITU-R BS.1770-4, with the
+// momentary, short-term and loudness range figures of EBU Tech 3341 and
+// 3342. The meter passes frames through untouched, so it can sit anywhere
+// in a pipeline as a tap.
+//
+// K-weighted energy is kept per 100 ms; momentary loudness is over 4 of
+// those, short-term over 30, and the gated integrated loudness over 400 ms
+// blocks overlapping by 75%.
+
+use std::f64::consts::PI;
+
+use crate::codec::Frame;
+use crate::codec::metadata::default_channel_mask;
+use crate::stage::{self, Stage};
+use crate::stage::eq::Biquad;
+use crate::stage::remix::{self, BACK_LEFT, BACK_RIGHT, LOW_FREQUENCY, SIDE_LEFT, SIDE_RIGHT};
+
+const ABSOLUTE_GATE : f64 = -70.0;
+const RELATIVE_GATE : f64 = -10.0;
+const RANGE_RELATIVE_GATE : f64 = -20.0;
+
+const MOMENTARY_BLOCKS : usize = 4;
+const SHORT_TERM_BLOCKS : usize = 30;
+
+// True peak oversampling, and taps per interpolation phase
+const OVERSAMPLE : usize = 4;
+const PHASE_TAPS : usize = 12;
+
+fn loudness(power: f64) -> f64 {
+	-0.691 + 10.0 * power.log10()
+}
+
+// The two K-weighting stages, re-derived for any sample rate as libebur128 does
+fn k_weighting(sample_rate: usize) -> [Biquad; 2] {
+	let fs = sample_rate as f64;
+
+	let f0 = 1681.974450955533;
+	let gain = 3.999843853973347;
+	let q = 0.7071752369554196;
+	let k = (PI * f0 / fs).tan();
+	let vh = 10f64.powf(gain / 20.0);
+	let vb = vh.powf(0.4996667741545416);
+	let a0 = 1.0 + k / q + k * k;
+	let shelf = Biquad::new(
+		[(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+		[2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	);
+
+	let f0 = 38.13547087602444;
+	let q = 0.5003270373238773;
+	let k = (PI * f0 / fs).tan();
+	let a0 = 1.0 + k / q + k * k;
+	let highpass = Biquad::new(
+		[1.0, -2.0, 1.0],
+		[2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	);
+
+	[shelf, highpass]
+}
+
+// Surrounds count 1.5 dB more; the LFE channel is not measured
+fn channel_weight(speaker: u32) -> f64 {
+	match speaker {
+		LOW_FREQUENCY => 0.0,
+		BACK_LEFT | BACK_RIGHT | SIDE_LEFT | SIDE_RIGHT => 1.41,
+		_ => 1.0,
+	}
+}
+
+// Windowed-sinc interpolation phases for the true peak
+fn interpolator() -> Vec<[f64; PHASE_TAPS]> {
+	let half = PHASE_TAPS as f64 / 2.0;
+	(0..OVERSAMPLE).map(|phase| {
+		let mut taps = [0.0; PHASE_TAPS];
+		for (j, tap) in taps.iter_mut().enumerate() {
+			let t = j as f64 - half + 1.0 - phase as f64 / OVERSAMPLE as f64;
+			let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+			let window = 0.5 + 0.5 * (PI * t / half).cos();
+			*tap = sinc * window;
+		}
+		taps
+	}).collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+	// LUFS, LU and dBTP; -inf for silence
+	pub integrated : f64,
+	pub range : f64,
+	pub momentary_max : f64,
+	pub short_term_max : f64,
+	pub true_peak : f64,
+	pub sample_peak : f64,
+}
+
+fn text_value(value: f64) -> String {
+	if value.is_finite() { format!("{:.1}", value) } else { "-inf".to_string() }
+}
+
+fn json_value(value: f64) -> String {
+	if value.is_finite() { format!("{:.2}", value) } else { "null".to_string() }
+}
+
+impl Report {
+	pub fn text(&self) -> String {
+		format!(
+			"Integrated loudness: {} LUFS\nLoudness range:      {} LU\nMomentary max:       {} LUFS\nShort-term max:      {} LUFS\nTrue peak:           {} dBTP\nSample peak:         {} dBFS\n",
+			text_value(self.integrated),
+			text_value(self.range),
+			text_value(self.momentary_max),
+			text_value(self.short_term_max),
+			text_value(self.true_peak),
+			text_value(self.sample_peak),
+		)
+	}
+
+	pub fn json(&self) -> String {
+		format!(
+			"{{\"integrated\": {}, \"range\": {}, \"momentary_max\": {}, \"short_term_max\": {}, \"true_peak\": {}, \"sample_peak\": {}}}",
+			json_value(self.integrated),
+			json_value(self.range),
+			json_value(self.momentary_max),
+			json_value(self.short_term_max),
+			json_value(self.true_peak),
+			json_value(self.sample_peak),
+		)
+	}
+}
+
+// K-weighted power of each multichannel sample, with the channel weights applied
+#[derive(Clone, Default)]
+pub(crate) struct KWeighting {
+	filters : Vec<[Biquad; 2]>,
+	weights : Vec<f64>,
+}
+
+impl KWeighting {
+	pub(crate) fn new(sample_rate: usize, channels: usize, mask: u32) -> KWeighting {
+		let mut weights = vec![1.0; channels];
+		for (speaker, index) in remix::speakers(mask, channels) {
+			weights[index] = channel_weight(speaker);
+		}
+		KWeighting { filters: vec![k_weighting(sample_rate); channels], weights }
+	}
+
+	pub(crate) fn power(&mut self, sample: &[f64]) -> f64 {
+		let mut power = 0.0;
+		for (ch, &x) in sample.iter().enumerate() {
+			let [shelf, highpass] = &mut self.filters[ch];
+			let y = highpass.process(shelf.process(x));
+			power += self.weights[ch] * y * y;
+		}
+		power
+	}
+}
+
+// Oversampled peak detector. Each call returns the largest magnitude over
+// the interval starting TRUE_PEAK_DELAY samples back, that sample included.
+pub(crate) const TRUE_PEAK_DELAY : usize = PHASE_TAPS / 2;
+
+#[derive(Clone, Default)]
+pub(crate) struct TruePeak {
+	interpolator : Vec<[f64; PHASE_TAPS]>,
+	history : Vec<[f64; PHASE_TAPS]>,
+}
+
+impl TruePeak {
+	pub(crate) fn new(channels: usize) -> TruePeak {
+		TruePeak { interpolator: interpolator(), history: vec![[0.0; PHASE_TAPS]; channels] }
+	}
+
+	pub(crate) fn peak(&mut self, ch: usize, x: f64) -> f64 {
+		let history = &mut self.history[ch];
+		history.copy_within(1.., 0);
+		history[PHASE_TAPS - 1] = x;
+		self.interpolator.iter().fold(0.0, |peak, phase| {
+			let y : f64 = phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum();
+			peak.max(y.abs())
+		})
+	}
+}
+
+// Integrated loudness of everything seen so far, cheap enough to ask for
+// after every block: gating blocks are binned by loudness in 0.01 LU steps
+// rather than kept, as libebur128 does.
+const HISTOGRAM_TOP : f64 = 10.0;
+const HISTOGRAM_STEP : f64 = 0.01;
+
+#[derive(Clone)]
+pub(crate) struct RunningLoudness {
+	block_len : usize,
+	block : f64,
+	block_fill : usize,
+	// The last few 100 ms blocks, for the 400 ms gating window
+	recent : Vec<f64>,
+	bins : Vec<(usize, f64)>,
+}
+
+impl RunningLoudness {
+	pub(crate) fn new(sample_rate: usize) -> RunningLoudness {
+		let bins = ((HISTOGRAM_TOP - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize + 1;
+		RunningLoudness {
+			block_len: (sample_rate / 10).max(1),
+			block: 0.0,
+			block_fill: 0,
+			recent: Vec::new(),
+			bins: vec![(0, 0.0); bins],
+		}
+	}
+
+	fn bin(value: f64) -> usize {
+		(((value - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize).min(((HISTOGRAM_TOP - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize)
+	}
+
+	// Takes one sample's K-weighted power; true when a 100 ms block completes
+	pub(crate) fn add(&mut self, power: f64) -> bool {
+		self.block += power;
+		self.block_fill += 1;
+		if self.block_fill < self.block_len {
+			return false;
+		}
+		if self.recent.len() == MOMENTARY_BLOCKS {
+			self.recent.remove(0);
+		}
+		self.recent.push(self.block);
+		self.block = 0.0;
+		self.block_fill = 0;
+
+		if self.recent.len() == MOMENTARY_BLOCKS {
+			let power = self.recent.iter().sum::<f64>() / (MOMENTARY_BLOCKS * self.block_len) as f64;
+			let value = loudness(power);
+			if value > ABSOLUTE_GATE {
+				let bin = &mut self.bins[RunningLoudness::bin(value)];
+				bin.0 += 1;
+				bin.1 += power;
+			}
+		}
+		true
+	}
+
+	pub(crate) fn integrated(&self) -> f64 {
+		let (count, sum) = self.bins.iter().fold((0, 0.0), |(n, s), &(count, sum)| (n + count, s + sum));
+		if count == 0 {
+			return f64::NEG_INFINITY;
+		}
+		let threshold = loudness(sum / count as f64) + RELATIVE_GATE;
+		let (count, sum) = self.bins[RunningLoudness::bin(threshold.max(ABSOLUTE_GATE))..].iter()
+			.fold((0, 0.0), |(n, s), &(count, sum)| (n + count, s + sum));
+		if count == 0 { f64::NEG_INFINITY } else { loudness(sum / count as f64) }
+	}
+}
+
+#[derive(Default)]
+pub struct LoudnessMeter {
+	channels : usize,
+	bits_per_sample : usize,
+	weighting : KWeighting,
+	block_len : usize,
+	// Weighted sum of squares in the 100 ms block being filled, and the mean
+	// square of every complete one
+	block : f64,
+	block_fill : usize,
+	blocks : Vec<f64>,
+
+	peaks : TruePeak,
+	true_peak : f64,
+	sample_peak : f64,
+}
+
+impl LoudnessMeter {
+	pub fn new() -> LoudnessMeter {
+		LoudnessMeter::default()
+	}
+
+	// Starts a stream. Blocks measured so far are kept, so feeding several
+	// files through one meter gives their loudness as a whole.
+	fn start(&mut self, frame: &Frame, mask: u32) {
+		self.channels = frame.channels;
+		self.bits_per_sample = frame.bits_per_sample;
+		self.weighting = KWeighting::new(frame.sample_rate, frame.channels, mask);
+		self.block_len = (frame.sample_rate / 10).max(1);
+		self.block = 0.0;
+		self.block_fill = 0;
+		self.peaks = TruePeak::new(frame.channels);
+	}
+
+	fn add(&mut self, samples: &[i32]) {
+		let samples = stage::to_f64(samples, self.bits_per_sample);
+		for sample in samples.chunks_exact(self.channels) {
+			self.block += self.weighting.power(sample);
+			for (ch, &x) in sample.iter().enumerate() {
+				self.sample_peak = self.sample_peak.max(x.abs());
+				self.true_peak = self.true_peak.max(self.peaks.peak(ch, x));
+			}
+			self.block_fill += 1;
+			if self.block_fill == self.block_len {
+				self.blocks.push(self.block / self.block_len as f64);
+				self.block = 0.0;
+				self.block_fill = 0;
+			}
+		}
+	}
+
+	// Takes in what another meter measured, as if its audio had followed on;
+	// an album measures as its tracks merged
+	pub fn merge(&mut self, other: &LoudnessMeter) {
+		self.blocks.extend_from_slice(&other.blocks);
+		self.true_peak = self.true_peak.max(other.true_peak);
+		self.sample_peak = self.sample_peak.max(other.sample_peak);
+	}
+
+	// Mean power of each window of n consecutive 100 ms blocks
+	fn windows(&self, n: usize) -> Vec<f64> {
+		if self.blocks.len() < n {
+			return Vec::new();
+		}
+		let scale = 1.0 / n as f64;
+		self.blocks.windows(n).map(|w| w.iter().sum::<f64>() * scale).collect()
+	}
+
+	pub fn report(&self) -> Report {
+		let momentary = self.windows(MOMENTARY_BLOCKS);
+		let short_term = self.windows(SHORT_TERM_BLOCKS);
+		let max = |powers: &[f64]| powers.iter().cloned().fold(f64::NEG_INFINITY, |m, p| m.max(loudness(p)));
+
+		// Integrated: absolute gate, then relative to the loudness of what passed it
+		let gated = |powers: &[f64], relative: f64| -> Vec<f64> {
+			let above : Vec<f64> = powers.iter().cloned().filter(|&p| loudness(p) > ABSOLUTE_GATE).collect();
+			if above.is_empty() {
+				return above;
+			}
+			let threshold = loudness(above.iter().sum::<f64>() / above.len() as f64) + relative;
+			above.into_iter().filter(|&p| loudness(p) > threshold).collect()
+		};
+		let integrated = gated(&momentary, RELATIVE_GATE);
+		let integrated = if integrated.is_empty() {
+			f64::NEG_INFINITY
+		} else {
+			loudness(integrated.iter().sum::<f64>() / integrated.len() as f64)
+		};
+
+		// Loudness range: 10th to 95th percentile of gated short-term loudness
+		let mut range : Vec<f64> = gated(&short_term, RANGE_RELATIVE_GATE).into_iter().map(loudness).collect();
+		range.sort_by(|a, b| a.partial_cmp(b).unwrap());
+		let percentile = |p: f64| range[((range.len() - 1) as f64 * p).round() as usize];
+		let range = if range.is_empty() { 0.0 } else { percentile(0.95) - percentile(0.10) };
+
+		Report {
+			integrated,
+			range,
+			momentary_max: max(&momentary),
+			short_term_max: max(&short_term),
+			true_peak: 20.0 * self.true_peak.max(self.sample_peak).log10(),
+			sample_peak: 20.0 * self.sample_peak.log10(),
+		}
+	}
+}
+
+impl Stage for LoudnessMeter {
+	fn process(&mut self, frame: Frame) -> Frame {
+		if let Some(ref metadata) = frame.metadata {
+			let mask = metadata.channel_mask.unwrap_or_else(|| default_channel_mask(frame.channels));
+			self.start(&frame, mask);
+		}
+		self.add(&frame.samples);
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::codec::Metadata;
+
+	const RATE : usize = 48000;
+
+	// Stereo sine segments of (seconds, dBFS), the same in both channels, as in
+	// the EBU Tech 3341 and 3342 test signals though shorter
+	fn measure(frequency: f64, phase: f64, segments: &[(usize, f64)]) -> Report {
+		let mut meter = LoudnessMeter::new();
+		meter.process(Frame { channels: 2, sample_rate: RATE, bits_per_sample: 24, samples: Vec::new(), metadata: Some(Metadata::default()), eof: false });
+		let mut n = 0;
+		for &(seconds, level) in segments {
+			let amplitude = 10f64.powf(level / 20.0) * (1 << 23) as f64;
+			let mut samples = Vec::with_capacity(seconds * RATE * 2);
+			for _ in 0..seconds * RATE {
+				let x = (amplitude * (2.0 * PI * frequency * n as f64 / RATE as f64 + phase).sin()).round() as i32;
+				samples.push(x);
+				samples.push(x);
+				n += 1;
+			}
+			meter.process(Frame { channels: 2, sample_rate: RATE, bits_per_sample: 24, samples, metadata: None, eof: false });
+		}
+		meter.report()
+	}
+
+	fn assert_near(value: f64, expected: f64, tolerance: f64) {
+		assert!((value - expected).abs() <= tolerance, "{} is not within {} of {}", value, tolerance, expected);
+	}
+
+	#[test]
+	fn sine() {
+		// Tech 3341 cases 1 and 2: a 1 kHz sine in both channels measures its level in LUFS
+		let report = measure(1000.0, 0.0, &[(5, -20.0)]);
+		assert_near(report.integrated, -20.0, 0.1);
+		assert_near(report.momentary_max, -20.0, 0.1);
+		assert_near(report.short_term_max, -20.0, 0.1);
+		assert_near(report.sample_peak, -20.0, 0.01);
+		assert_near(report.range, 0.0, 0.1);
+	}
+
+	#[test]
+	fn gating() {
+		// Tech 3341 case 3: quiet passages below the relative gate do not count
+		let report = measure(1000.0, 0.0, &[(3, -36.0), (20, -23.0), (3, -36.0)]);
+		assert_near(report.integrated, -23.0, 0.1);
+	}
+
+	#[test]
+	fn range() {
+		// Tech 3342 cases 1 and 2: a step between two levels
+		assert_near(measure(1000.0, 0.0, &[(10, -20.0), (10, -30.0)]).range, 10.0, 1.0);
+		assert_near(measure(1000.0, 0.0, &[(10, -20.0), (10, -15.0)]).range, 5.0, 1.0);
+	}
+
+	#[test]
+	fn true_peak() {
+		// A sine at a quarter of the rate, sampled 45 degrees off its peaks,
+		// has sample peaks 3 dB below its true peak
+		let report = measure(RATE as f64 / 4.0, PI / 4.0, &[(1, -6.0)]);
+		assert_near(report.sample_peak, -9.01, 0.01);
+		assert_near(report.true_peak, -6.0, 0.4);
+	}
+
+	#[test]
+	fn silence() {
+		let report = measure(1000.0, 0.0, &[(1, f64::NEG_INFINITY)]);
+		assert_eq!(report.integrated, f64::NEG_INFINITY);
+		assert_eq!(report.range, 0.0);
+		assert!(report.json().contains("\"integrated\": null"));
+	}
+}
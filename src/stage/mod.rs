@@ -0,0 +1,153 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Processing stages that sit between a decoder and an encoder. Each runs
+// in its own thread, taking frames from one channel and passing them on to
+// the next, header frame first.
+
+use std::sync::mpsc;
+use std::thread;
+
+use crate::codec::Frame;
+
+pub mod resample;
+pub mod dither;
+pub mod remix;
+pub mod loudness;
+pub mod limiter;
+pub mod normalize;
+pub mod eq;
+pub mod dynamics;
+
+pub trait Stage {
+	// Called once per frame, in order. A stage may hold samples back, but
+	// must hand everything over by the time it returns the eof frame.
+	fn process(&mut self, frame: Frame) -> Frame;
+}
+
+impl<S: Stage + ?Sized> Stage for Box<S> {
+	fn process(&mut self, frame: Frame) -> Frame {
+		(**self).process(frame)
+	}
+}
+
+pub fn run<S: Stage>(stage: &mut S, rx: mpsc::Receiver<Frame>, tx: mpsc::Sender<Frame>) {
+	loop {
+		let frame = stage.process(rx.recv().unwrap());
+		let eof = frame.eof;
+		tx.send(frame).unwrap();
+		if eof {
+			break;
+		}
+	}
+}
+
+// Runs a stage on its own thread, handing the stage back when the stream ends
+pub fn spawn<S: Stage + Send + 'static>(stage: S, rx: mpsc::Receiver<Frame>) -> (mpsc::Receiver<Frame>, thread::JoinHandle<S>) {
+	let (tx, out) = mpsc::channel();
+	let handle = thread::spawn(move || {
+		let mut stage = stage;
+		run(&mut stage, rx, tx);
+		stage
+	});
+	(out, handle)
+}
+
+// Chains stages one after another, each on its own thread
+pub fn spawn_chain(stages: Vec<Box<dyn Stage + Send>>, rx: mpsc::Receiver<Frame>) -> (mpsc::Receiver<Frame>, Vec<thread::JoinHandle<Box<dyn Stage + Send>>>) {
+	let mut rx = rx;
+	let mut threads = Vec::new();
+	for stage in stages {
+		let (next, thread) = spawn(stage, rx);
+		rx = next;
+		threads.push(thread);
+	}
+	(rx, threads)
+}
+
+// Copies every frame to n branches. A branch that stops taking frames is
+// dropped and the rest carry on; the count of branches still connected at
+// the end is handed back. If the input goes away before its end, all the
+// branches are cut off with it and none count as connected.
+pub fn tee(rx: mpsc::Receiver<Frame>, n: usize) -> (Vec<mpsc::Receiver<Frame>>, thread::JoinHandle<usize>) {
+	let (senders, receivers) : (Vec<_>, Vec<_>) = (0..n).map(|_| mpsc::channel()).unzip();
+	let handle = thread::spawn(move || {
+		let mut senders : Vec<mpsc::Sender<Frame>> = senders;
+		loop {
+			let frame = match rx.recv() {
+				Ok(frame) => frame,
+				Err(_) => return 0,
+			};
+			let eof = frame.eof;
+			senders.retain(|tx| tx.send(frame.clone()).is_ok());
+			if eof || senders.is_empty() {
+				return senders.len();
+			}
+		}
+	});
+	(receivers, handle)
+}
+
+// Samples as fractions of full scale, and back
+pub fn to_f64(samples: &[i32], bits_per_sample: usize) -> Vec<f64> {
+	let scale = (1u64 << (bits_per_sample - 1)) as f64;
+	samples.iter().map(|&s| s as f64 / scale).collect()
+}
+
+pub fn from_f64(samples: &[f64], bits_per_sample: usize) -> Vec<i32> {
+	let scale = (1u64 << (bits_per_sample - 1)) as f64;
+	samples.iter().map(|&s| (s * scale).round().max(-scale).min(scale - 1.0) as i32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn frame(eof: bool) -> Frame {
+		Frame { channels: 1, sample_rate: 44100, bits_per_sample: 16, samples: vec![1, 2, 3], metadata: None, eof }
+	}
+
+	#[test]
+	fn tee_branches() {
+		let (tx, rx) = mpsc::channel();
+		let (mut receivers, handle) = tee(rx, 3);
+		// One branch goes away early and the rest still get everything
+		receivers.remove(1);
+		tx.send(frame(false)).unwrap();
+		tx.send(frame(true)).unwrap();
+		assert_eq!(handle.join().unwrap(), 2);
+		for rx in receivers {
+			let frames : Vec<Frame> = rx.iter().collect();
+			assert_eq!(frames.len(), 2);
+			assert!(frames[1].eof);
+		}
+	}
+
+	#[test]
+	fn tee_input_lost() {
+		let (tx, rx) = mpsc::channel();
+		let (receivers, handle) = tee(rx, 2);
+		tx.send(frame(false)).unwrap();
+		drop(tx);
+		assert_eq!(handle.join().unwrap(), 0);
+		// The branches see the end of their channel rather than an end of stream
+		for rx in receivers {
+			assert!(!rx.recv().unwrap().eof);
+			assert!(rx.recv().is_err());
+		}
+	}
+}
@@ -0,0 +1,281 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Loudness normalization to a target integrated loudness under a true-peak
+// ceiling.
+//
+// With the whole programme measured beforehand (two-pass, or one
+// measurement over an album) a single linear gain does it, lowered if it
+// would push the true peak over the ceiling. Single-pass works on the
+// stream: the gain follows the integrated loudness measured so far,
+// seeing a few seconds ahead of the audio it applies to, and a look-ahead
+// limiter holds the ceiling.
+
+use std::collections::VecDeque;
+
+use crate::codec::{Frame, ReplayGain};
+use crate::codec::metadata::default_channel_mask;
+use crate::stage::{self, Stage};
+use crate::stage::limiter::{self, Limiter};
+use crate::stage::loudness::{KWeighting, Report, RunningLoudness};
+
+// EBU R 128
+pub const DEFAULT_TARGET : f64 = -23.0;
+pub const DEFAULT_CEILING : f64 = -1.0;
+
+// Single-pass: how far the loudness measurement runs ahead of the audio,
+// in 100 ms blocks, and the most gain a quiet opening can call for
+const LOUDNESS_LOOKAHEAD : usize = 30;
+const MAX_GAIN : f64 = 20.0;
+
+// The gain in dB that brings a measured programme to the target, or as
+// close as the ceiling allows
+pub fn gain(report: &Report, target: f64, ceiling: f64) -> f64 {
+	if !report.integrated.is_finite() {
+		return 0.0;
+	}
+	let gain = target - report.integrated;
+	if report.true_peak.is_finite() && report.true_peak + gain > ceiling {
+		ceiling - report.true_peak
+	} else {
+		gain
+	}
+}
+
+// A fixed gain in dB
+pub struct Gain {
+	gain : f64,
+	bits_per_sample : usize,
+}
+
+impl Gain {
+	pub fn new(gain: f64) -> Gain {
+		Gain { gain, bits_per_sample: 0 }
+	}
+}
+
+impl Stage for Gain {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		let scale = 10f64.powf(self.gain / 20.0);
+		if let Some(ref mut metadata) = frame.metadata {
+			self.bits_per_sample = frame.bits_per_sample;
+			if self.gain != 0.0 {
+				// Existing ReplayGain still holds once shifted by what was applied
+				let replay_gain = &mut metadata.replay_gain;
+				replay_gain.track_gain = replay_gain.track_gain.map(|g| g - self.gain);
+				replay_gain.album_gain = replay_gain.album_gain.map(|g| g - self.gain);
+				replay_gain.track_peak = replay_gain.track_peak.map(|p| p * scale);
+				replay_gain.album_peak = replay_gain.album_peak.map(|p| p * scale);
+				metadata.audio_md5 = None;
+			}
+		}
+		if self.gain != 0.0 && !frame.samples.is_empty() {
+			let samples : Vec<f64> = stage::to_f64(&frame.samples, self.bits_per_sample).into_iter().map(|x| x * scale).collect();
+			frame.samples = stage::from_f64(&samples, self.bits_per_sample);
+		}
+		frame
+	}
+}
+
+// Single-pass normalization
+pub struct Normalize {
+	target : f64,
+	ceiling : f64,
+
+	channels : usize,
+	bits_per_sample : usize,
+	weighting : KWeighting,
+	loudness : RunningLoudness,
+	// Samples still waiting for their gain, and how many make up each
+	// complete 100 ms block among them
+	pending : VecDeque<f64>,
+	blocks : VecDeque<usize>,
+	block_fill : usize,
+	// Linear gain reached at the end of the last block let through
+	gain : Option<f64>,
+	limiter : Option<Limiter>,
+}
+
+impl Normalize {
+	pub fn new(target: f64, ceiling: f64) -> Normalize {
+		Normalize {
+			target,
+			ceiling,
+			channels: 0,
+			bits_per_sample: 0,
+			weighting: KWeighting::default(),
+			loudness: RunningLoudness::new(0),
+			pending: VecDeque::new(),
+			blocks: VecDeque::new(),
+			block_fill: 0,
+			gain: None,
+			limiter: None,
+		}
+	}
+
+	// Applies the current gain estimate to the oldest n pending samples,
+	// ramping from the gain the previous block ended on
+	fn release(&mut self, n: usize, out: &mut Vec<f64>) {
+		let integrated = self.loudness.integrated();
+		let gain = if integrated.is_finite() {
+			10f64.powf((self.target - integrated).min(MAX_GAIN) / 20.0)
+		} else {
+			self.gain.unwrap_or(1.0)
+		};
+		let from = self.gain.unwrap_or(gain);
+		self.gain = Some(gain);
+
+		let samples : Vec<f64> = self.pending.drain(..n * self.channels).collect();
+		let mut gained = Vec::with_capacity(samples.len());
+		for (i, sample) in samples.chunks_exact(self.channels).enumerate() {
+			let g = from + (gain - from) * (i + 1) as f64 / n as f64;
+			gained.extend(sample.iter().map(|x| x * g));
+		}
+		out.extend(self.limiter.as_mut().unwrap().process(&gained));
+	}
+}
+
+impl Stage for Normalize {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		let (channels, sample_rate) = (frame.channels, frame.sample_rate);
+		if let Some(ref mut metadata) = frame.metadata {
+			let mask = metadata.channel_mask.unwrap_or_else(|| default_channel_mask(channels));
+			self.channels = channels;
+			self.bits_per_sample = frame.bits_per_sample;
+			self.weighting = KWeighting::new(sample_rate, channels, mask);
+			self.loudness = RunningLoudness::new(sample_rate);
+			self.limiter = Some(Limiter::new(channels, sample_rate, self.ceiling, limiter::DEFAULT_LOOKAHEAD, limiter::DEFAULT_RELEASE));
+			// The gain varies, so no earlier ReplayGain figure is right any more
+			metadata.replay_gain = ReplayGain::default();
+			metadata.audio_md5 = None;
+		}
+
+		let mut out = Vec::new();
+		for sample in stage::to_f64(&frame.samples, self.bits_per_sample).chunks_exact(self.channels) {
+			self.pending.extend(sample);
+			self.block_fill += 1;
+			if self.loudness.add(self.weighting.power(sample)) {
+				self.blocks.push_back(self.block_fill);
+				self.block_fill = 0;
+				if self.blocks.len() > LOUDNESS_LOOKAHEAD {
+					let n = self.blocks.pop_front().unwrap();
+					self.release(n, &mut out);
+				}
+			}
+		}
+		if frame.eof && self.limiter.is_some() {
+			while let Some(n) = self.blocks.pop_front() {
+				self.release(n, &mut out);
+			}
+			if self.block_fill > 0 {
+				self.release(self.block_fill, &mut out);
+				self.block_fill = 0;
+			}
+			out.extend(self.limiter.as_mut().unwrap().flush());
+		}
+		frame.samples = stage::from_f64(&out, self.bits_per_sample);
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::f64::consts::PI;
+	use crate::codec::Metadata;
+	use crate::stage::loudness::LoudnessMeter;
+
+	fn report(integrated: f64, true_peak: f64) -> Report {
+		Report { integrated, true_peak, ..Report::default() }
+	}
+
+	fn header(sample_rate: usize, metadata: Metadata) -> Frame {
+		Frame { channels: 2, sample_rate, bits_per_sample: 24, samples: Vec::new(), metadata: Some(metadata), eof: false }
+	}
+
+	// A stereo 1 kHz sine at the given level
+	fn sine(sample_rate: usize, seconds: f64, level: f64) -> Vec<i32> {
+		let amplitude = 10f64.powf(level / 20.0) * (1 << 23) as f64;
+		(0..(seconds * sample_rate as f64) as usize)
+			.flat_map(|n| {
+				let x = (amplitude * (2.0 * PI * 1000.0 * n as f64 / sample_rate as f64).sin()).round() as i32;
+				[x, x]
+			})
+			.collect()
+	}
+
+	#[test]
+	fn two_pass_gain() {
+		assert_eq!(gain(&report(-20.0, -10.0), -23.0, -1.0), -3.0);
+		// Held back so the true peak stays under the ceiling
+		assert_eq!(gain(&report(-30.0, -5.0), -23.0, -1.0), 4.0);
+		assert_eq!(gain(&report(-30.0, -5.0), -23.0, 0.0), 5.0);
+		assert_eq!(gain(&report(f64::NEG_INFINITY, f64::NEG_INFINITY), -23.0, -1.0), 0.0);
+	}
+
+	#[test]
+	fn fixed_gain() {
+		let mut metadata = Metadata::default();
+		metadata.replay_gain.track_gain = Some(-5.0);
+		metadata.replay_gain.track_peak = Some(0.5);
+		let mut stage = Gain::new(-6.0);
+		let header = stage.process(header(48000, metadata));
+		let replay_gain = header.metadata.unwrap().replay_gain;
+		assert_eq!(replay_gain.track_gain, Some(1.0));
+		assert!((replay_gain.track_peak.unwrap() - 0.2506).abs() < 0.0001);
+
+		let frame = stage.process(Frame { samples: vec![1 << 22, -(1 << 20)], metadata: None, eof: true, ..header });
+		let scale = 10f64.powf(-6.0 / 20.0);
+		assert_eq!(frame.samples, vec![((1 << 22) as f64 * scale).round() as i32, (-(1 << 20) as f64 * scale).round() as i32]);
+	}
+
+	#[test]
+	fn single_pass() {
+		let rate = 16000;
+		let input = sine(rate, 8.0, -30.0);
+		let mut stage = Normalize::new(-23.0, -1.0);
+		let mut meter = LoudnessMeter::new();
+		meter.process(stage.process(header(rate, Metadata::default())));
+
+		// Everything comes out once the stream ends
+		let mut output = 0;
+		for (i, chunk) in input.chunks(4096).enumerate() {
+			let eof = (i + 1) * 4096 >= input.len();
+			let frame = stage.process(Frame { samples: chunk.to_vec(), metadata: None, eof, ..header(rate, Metadata::default()) });
+			output += frame.samples.len();
+			meter.process(frame);
+		}
+		assert_eq!(output, input.len());
+
+		let report = meter.report();
+		assert!((report.integrated + 23.0).abs() < 0.5, "{}", report.integrated);
+		assert!(report.true_peak <= -1.0 + 0.1, "{}", report.true_peak);
+	}
+
+	#[test]
+	fn single_pass_ceiling() {
+		// The target would take the peaks well over the ceiling
+		let rate = 16000;
+		let input = sine(rate, 5.0, -20.0);
+		let mut stage = Normalize::new(-5.0, -6.0);
+		stage.process(header(rate, Metadata::default()));
+		let frame = stage.process(Frame { samples: input.clone(), metadata: None, eof: true, ..header(rate, Metadata::default()) });
+		assert_eq!(frame.samples.len(), input.len());
+		let ceiling = 10f64.powf(-6.0 / 20.0) * (1 << 23) as f64;
+		assert!(frame.samples.iter().all(|&x| (x.abs() as f64) <= ceiling.ceil()));
+	}
+}
@@ -0,0 +1,316 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Channel remixing. Every mode comes down to a gain matrix with a row per
+// output channel, worked out once the input layout is known. Speakers are
+// found through the input's WAVE_FORMAT_EXTENSIBLE channel mask, or the
+// layout FLAC implies for its channel count when there is none.
+
+use std::f64::consts::FRAC_1_SQRT_2;
+
+use crate::codec::Frame;
+use crate::codec::metadata::default_channel_mask;
+use crate::stage::{self, Stage};
+
+pub const FRONT_LEFT : u32 = 0x1;
+pub const FRONT_RIGHT : u32 = 0x2;
+pub const FRONT_CENTER : u32 = 0x4;
+pub const LOW_FREQUENCY : u32 = 0x8;
+pub const BACK_LEFT : u32 = 0x10;
+pub const BACK_RIGHT : u32 = 0x20;
+pub const FRONT_LEFT_OF_CENTER : u32 = 0x40;
+pub const FRONT_RIGHT_OF_CENTER : u32 = 0x80;
+pub const BACK_CENTER : u32 = 0x100;
+pub const SIDE_LEFT : u32 = 0x200;
+pub const SIDE_RIGHT : u32 = 0x400;
+
+// Short speaker names in mask bit order
+pub const SPEAKER_NAMES : [&str; 18] = [
+	"FL", "FR", "FC", "LFE", "BL", "BR", "FLC", "FRC", "BC",
+	"SL", "SR", "TC", "TFL", "TFC", "TFR", "TBL", "TBC", "TBR",
+];
+
+pub fn speaker(name: &str) -> Option<u32> {
+	SPEAKER_NAMES.iter().position(|n| n.eq_ignore_ascii_case(name)).map(|bit| 1 << bit)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Channel {
+	Index(usize),
+	Speaker(u32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mode {
+	// Extract and reorder
+	Select(Vec<Channel>),
+	// Fold down to one channel
+	Mono,
+	// ITU-R BS.775 downmix; mono is duplicated
+	Stereo,
+	// Upmix to n channels by repeating the input channels in turn
+	Duplicate(usize),
+	// Gains with a row per output channel and a column per input channel
+	Matrix(Vec<Vec<f64>>),
+}
+
+// mono, stereo, duplicate:N, select:FL,FR or select:0,1, or matrix:ROW;ROW
+// with comma-separated gains in each row
+pub fn parse_mode(spec: &str) -> Result<Mode, String> {
+	let (name, arg) = match spec.find(':') {
+		Some(colon) => (&spec[..colon], Some(&spec[colon+1..])),
+		None => (spec, None),
+	};
+	match (name, arg) {
+		("mono", None) => Ok(Mode::Mono),
+		("stereo", None) => Ok(Mode::Stereo),
+		("duplicate", Some(n)) => n.parse().ok().filter(|&n| n > 0).map(Mode::Duplicate)
+			.ok_or_else(|| format!("Bad channel count {}", n)),
+		("select", Some(list)) => list.split(',').map(|item| {
+			match item.parse() {
+				Ok(index) => Ok(Channel::Index(index)),
+				Err(_) => speaker(item).map(Channel::Speaker).ok_or_else(|| format!("Unknown channel {}", item)),
+			}
+		}).collect::<Result<Vec<Channel>, String>>().map(Mode::Select),
+		("matrix", Some(rows)) => rows.split(';').map(|row| {
+			row.split(',').map(|g| g.trim().parse::<f64>().map_err(|_| format!("Bad gain {}", g))).collect()
+		}).collect::<Result<Vec<Vec<f64>>, String>>().map(Mode::Matrix),
+		_ => Err(format!("Unknown remix {}", spec)),
+	}
+}
+
+pub struct Remix {
+	mode : Mode,
+	// Scale so that no output can exceed full scale
+	normalize : bool,
+	matrix : Vec<Vec<f64>>,
+	in_channels : usize,
+}
+
+// Which input channel carries each speaker bit, in mask order
+pub(crate) fn speakers(mask: u32, channels: usize) -> Vec<(u32, usize)> {
+	(0..32).map(|bit| 1u32 << bit)
+		.filter(|speaker| mask & speaker != 0)
+		.take(channels)
+		.enumerate()
+		.map(|(index, speaker)| (speaker, index))
+		.collect()
+}
+
+// BS.775 gains for each speaker into left and right. Centre and surrounds
+// come in at -3 dB; the LFE channel is left out, as the recommendation allows.
+fn stereo_gains(speaker: u32) -> (f64, f64) {
+	match speaker {
+		FRONT_LEFT | FRONT_LEFT_OF_CENTER => (1.0, 0.0),
+		FRONT_RIGHT | FRONT_RIGHT_OF_CENTER => (0.0, 1.0),
+		FRONT_CENTER => (FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+		BACK_LEFT | SIDE_LEFT => (FRAC_1_SQRT_2, 0.0),
+		BACK_RIGHT | SIDE_RIGHT => (0.0, FRAC_1_SQRT_2),
+		BACK_CENTER => (0.5, 0.5),
+		_ => (0.0, 0.0),
+	}
+}
+
+fn stereo_matrix(mask: u32, channels: usize) -> Vec<Vec<f64>> {
+	if channels == 1 {
+		return vec![vec![1.0], vec![1.0]];
+	}
+	let mut matrix = vec![vec![0.0; channels]; 2];
+	for (speaker, index) in speakers(mask, channels) {
+		let (left, right) = stereo_gains(speaker);
+		matrix[0][index] = left;
+		matrix[1][index] = right;
+	}
+	matrix
+}
+
+impl Remix {
+	pub fn new(mode: Mode, normalize: bool) -> Remix {
+		Remix {
+			mode,
+			normalize,
+			matrix: Vec::new(),
+			in_channels: 0,
+		}
+	}
+
+	// The matrix and the output channel mask, if the output has a known layout
+	fn resolve(&self, mask: u32, channels: usize) -> Result<(Vec<Vec<f64>>, Option<u32>), String> {
+		let layout = speakers(mask, channels);
+		let unit = |index: usize| {
+			let mut row = vec![0.0; channels];
+			row[index] = 1.0;
+			row
+		};
+
+		match self.mode {
+			Mode::Select(ref selection) => {
+				let mut out_mask = Some(0);
+				let mut matrix = Vec::new();
+				for channel in selection {
+					let (index, speaker) = match *channel {
+						Channel::Index(index) => (index, layout.iter().find(|(_, i)| *i == index).map(|(s, _)| *s)),
+						Channel::Speaker(speaker) => match layout.iter().find(|(s, _)| *s == speaker) {
+							Some(&(_, index)) => (index, Some(speaker)),
+							None => return Err(format!("There is no {} channel", SPEAKER_NAMES[speaker.trailing_zeros() as usize])),
+						},
+					};
+					if index >= channels {
+						return Err(format!("There is no channel {} in {}", index, channels));
+					}
+					// A mask can only describe speakers in ascending order
+					out_mask = match (out_mask, speaker) {
+						(Some(m), Some(s)) if s > m => Some(m | s),
+						_ => None,
+					};
+					matrix.push(unit(index));
+				}
+				Ok((matrix, out_mask))
+			},
+			Mode::Mono => {
+				let stereo = stereo_matrix(mask, channels);
+				let row = (0..channels).map(|i| 0.5 * (stereo[0][i] + stereo[1][i])).collect();
+				Ok((vec![row], Some(FRONT_CENTER)))
+			},
+			Mode::Stereo => Ok((stereo_matrix(mask, channels), Some(FRONT_LEFT | FRONT_RIGHT))),
+			Mode::Duplicate(n) => Ok(((0..n).map(|i| unit(i % channels)).collect(), Some(default_channel_mask(n)))),
+			Mode::Matrix(ref matrix) => {
+				if matrix.iter().any(|row| row.len() != channels) {
+					return Err(format!("The matrix needs a column for each of the {} channels", channels));
+				}
+				Ok((matrix.clone(), None))
+			},
+		}
+	}
+
+	// The channel count and layout that come out for an input with this
+	// layout, or why the input cannot be remixed
+	pub fn output(&self, mask: u32, channels: usize) -> Result<(usize, u32), String> {
+		let (matrix, out_mask) = self.resolve(mask, channels)?;
+		Ok((matrix.len(), out_mask.unwrap_or_else(|| default_channel_mask(matrix.len()))))
+	}
+}
+
+impl Stage for Remix {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		let channels = frame.channels;
+		if let Some(ref mut metadata) = frame.metadata {
+			let mask = metadata.channel_mask.unwrap_or_else(|| default_channel_mask(channels));
+			// Graph::check has already tried the layout
+			let (mut matrix, out_mask) = self.resolve(mask, channels).unwrap_or_else(|e| panic!("{}", e));
+			if self.normalize {
+				let peak = matrix.iter().map(|row| row.iter().map(|g| g.abs()).sum::<f64>()).fold(0.0, f64::max);
+				if peak > 1.0 {
+					for gain in matrix.iter_mut().flat_map(|row| row.iter_mut()) {
+						*gain /= peak;
+					}
+				}
+			}
+
+			// Only record a layout when FLAC would not assume it anyway
+			metadata.channel_mask = out_mask.filter(|&m| metadata.channel_mask.is_some() || m != default_channel_mask(matrix.len()));
+			metadata.audio_md5 = None;
+			self.in_channels = channels;
+			self.matrix = matrix;
+		}
+
+		let input = stage::to_f64(&frame.samples, frame.bits_per_sample);
+		let mut output = Vec::with_capacity(input.len() / self.in_channels * self.matrix.len());
+		for sample in input.chunks_exact(self.in_channels) {
+			for row in &self.matrix {
+				output.push(row.iter().zip(sample).map(|(g, x)| g * x).sum());
+			}
+		}
+		frame.samples = stage::from_f64(&output, frame.bits_per_sample);
+		frame.channels = self.matrix.len();
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::codec::Metadata;
+
+	const SURROUND : u32 = FRONT_LEFT | FRONT_RIGHT | FRONT_CENTER | LOW_FREQUENCY | BACK_LEFT | BACK_RIGHT;
+
+	// Runs one block through a remix, returning the output samples and layout
+	fn remix(mode: Mode, normalize: bool, channel_mask: Option<u32>, channels: usize, samples: Vec<i32>) -> (Vec<i32>, usize, Option<u32>) {
+		let mut stage = Remix::new(mode, normalize);
+		let metadata = Metadata { channel_mask, ..Metadata::default() };
+		let header = stage.process(Frame { channels, sample_rate: 48000, bits_per_sample: 16, samples: Vec::new(), metadata: Some(metadata), eof: false });
+		let frame = stage.process(Frame { channels, sample_rate: 48000, bits_per_sample: 16, samples, metadata: None, eof: true });
+		(frame.samples, frame.channels, header.metadata.unwrap().channel_mask)
+	}
+
+	#[test]
+	fn modes() {
+		assert_eq!(parse_mode("mono"), Ok(Mode::Mono));
+		assert_eq!(parse_mode("stereo"), Ok(Mode::Stereo));
+		assert_eq!(parse_mode("duplicate:4"), Ok(Mode::Duplicate(4)));
+		assert_eq!(parse_mode("select:FL,lfe,2"), Ok(Mode::Select(vec![Channel::Speaker(FRONT_LEFT), Channel::Speaker(LOW_FREQUENCY), Channel::Index(2)])));
+		assert_eq!(parse_mode("matrix:1,0.5;0, -1"), Ok(Mode::Matrix(vec![vec![1.0, 0.5], vec![0.0, -1.0]])));
+		for spec in ["duplicate:0", "duplicate", "select:XX", "matrix:1,a", "mono:1", "quad"] {
+			assert!(parse_mode(spec).is_err(), "{}", spec);
+		}
+	}
+
+	#[test]
+	fn bs775() {
+		let matrix = stereo_matrix(SURROUND, 6);
+		let h = FRAC_1_SQRT_2;
+		assert_eq!(matrix, vec![
+			vec![1.0, 0.0, h, 0.0, h, 0.0],
+			vec![0.0, 1.0, h, 0.0, 0.0, h],
+		]);
+		// Mono is duplicated, and stereo is the sum of the two at -6 dB
+		assert_eq!(stereo_matrix(FRONT_CENTER, 1), vec![vec![1.0], vec![1.0]]);
+		let (samples, channels, mask) = remix(Mode::Mono, false, None, 2, vec![1000, 3000, -2000, 0]);
+		assert_eq!((samples, channels, mask), (vec![2000, -1000], 1, None));
+	}
+
+	#[test]
+	fn select_masks() {
+		// Ascending speakers keep a layout
+		let (samples, channels, mask) = remix(parse_mode("select:FC,LFE").unwrap(), false, Some(SURROUND), 6, vec![1, 2, 3, 4, 5, 6]);
+		assert_eq!((samples, channels, mask), (vec![3, 4], 2, Some(FRONT_CENTER | LOW_FREQUENCY)));
+		// Swapping channels leaves no layout to record
+		let (samples, _, mask) = remix(parse_mode("select:1,0").unwrap(), false, None, 2, vec![1, 2]);
+		assert_eq!((samples, mask), (vec![2, 1], None));
+		// FLAC's own layout for two channels is not written out
+		let (_, _, mask) = remix(parse_mode("select:FL,FR").unwrap(), false, None, 6, vec![0; 6]);
+		assert_eq!(mask, None);
+
+		let stage = Remix::new(parse_mode("select:BL").unwrap(), false);
+		assert_eq!(stage.output(SURROUND, 6), Ok((1, BACK_LEFT)));
+		assert!(stage.output(FRONT_LEFT | FRONT_RIGHT, 2).is_err());
+		assert!(Remix::new(parse_mode("select:2").unwrap(), false).output(0x3, 2).is_err());
+		assert!(Remix::new(parse_mode("matrix:1,1").unwrap(), false).output(0x7, 3).is_err());
+	}
+
+	#[test]
+	fn normalize() {
+		let matrix = Mode::Matrix(vec![vec![1.0, 1.0], vec![0.5, -0.5]]);
+		let (samples, _, _) = remix(matrix.clone(), false, None, 2, vec![20000, 20000]);
+		assert_eq!(samples, vec![32767, 0]);
+		// Scaled so that the loudest row sums to unity
+		let (samples, _, _) = remix(matrix, true, None, 2, vec![20000, 20000]);
+		assert_eq!(samples, vec![20000, 0]);
+		let (samples, _, _) = remix(Mode::Matrix(vec![vec![0.5, 0.25]]), true, None, 2, vec![8000, 8000]);
+		assert_eq!(samples, vec![6000]);
+	}
+}
@@ -0,0 +1,351 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Sample-rate conversion by a rational factor L/M with a polyphase bank of
+// Kaiser-windowed sinc filters. The kernel is symmetric, so phase stays
+// linear, and output sample n is centred exactly on input time n*M/L: the
+// filter's look-ahead is compensated here rather than passed on as delay,
+// and n input samples always come out as ceil(n*L/M).
+
+use std::f64::consts::PI;
+
+use crate::codec::{Frame, Metadata};
+use crate::stage::{self, Stage};
+
+// More phases than this are interpolated from a table of this many
+const MAX_PHASES : usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+	Low,
+	Medium,
+	High,
+	Best,
+}
+
+impl Quality {
+	pub fn parse(name: &str) -> Option<Quality> {
+		match name {
+			"low" => Some(Quality::Low),
+			"medium" => Some(Quality::Medium),
+			"high" => Some(Quality::High),
+			"best" => Some(Quality::Best),
+			_ => None,
+		}
+	}
+
+	// Zero crossings each side of the kernel, Kaiser beta, and the cutoff as
+	// a fraction of the lower Nyquist frequency. The cutoff puts the end of
+	// the transition band at Nyquist, so nothing aliases.
+	fn params(self) -> (usize, f64, f64) {
+		match self {
+			Quality::Low => (8, 5.0, 0.80),
+			Quality::Medium => (16, 7.0, 0.86),
+			Quality::High => (48, 10.0, 0.93),
+			Quality::Best => (96, 13.0, 0.955),
+		}
+	}
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+	if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// Zeroth-order modified Bessel function of the first kind
+pub(crate) fn bessel_i0(x: f64) -> f64 {
+	let mut sum = 1.0;
+	let mut term = 1.0;
+	let mut k = 1.0;
+	while term > sum * 1e-16 {
+		term *= (x / (2.0 * k)) * (x / (2.0 * k));
+		sum += term;
+		k += 1.0;
+	}
+	sum
+}
+
+fn sinc(x: f64) -> f64 {
+	if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+struct State {
+	channels : usize,
+	bits_per_sample : usize,
+	up : u64,
+	down : u64,
+	// Kernel half-width in input samples
+	half : usize,
+	phases : usize,
+	// phases + 1 rows of 2 * half taps, the last row closing the interpolation
+	table : Vec<f64>,
+	// Interleaved input from sample input_start on
+	input : Vec<f64>,
+	input_start : u64,
+	received : u64,
+	produced : u64,
+}
+
+impl State {
+	fn new(channels: usize, bits_per_sample: usize, from: usize, to: usize, quality: Quality) -> State {
+		let g = gcd(from as u64, to as u64);
+		let (up, down) = (to as u64 / g, from as u64 / g);
+		let (zero_crossings, beta, cutoff) = quality.params();
+
+		// Cutoff relative to the input Nyquist frequency
+		let c = cutoff * (up as f64 / down as f64).min(1.0);
+		let half = (zero_crossings as f64 / c).ceil() as usize;
+		let taps = 2 * half;
+		let phases = (up as usize).min(MAX_PHASES);
+
+		let window_norm = bessel_i0(beta);
+		let mut table = Vec::with_capacity((phases + 1) * taps);
+		for p in 0..=phases {
+			let frac = p as f64 / phases as f64;
+			let row : Vec<f64> = (0..taps).map(|j| {
+				let t = frac + half as f64 - 1.0 - j as f64;
+				let w = t / half as f64;
+				if w.abs() >= 1.0 {
+					0.0
+				} else {
+					c * sinc(c * t) * bessel_i0(beta * (1.0 - w * w).sqrt()) / window_norm
+				}
+			}).collect();
+			// Unity gain at DC for every phase
+			let sum : f64 = row.iter().sum();
+			table.extend(row.iter().map(|h| h / sum));
+		}
+
+		State {
+			channels,
+			bits_per_sample,
+			up,
+			down,
+			half,
+			phases,
+			table,
+			input: Vec::new(),
+			input_start: 0,
+			received: 0,
+			produced: 0,
+		}
+	}
+
+	fn output_len(&self, input_len: u64) -> u64 {
+		(input_len * self.up).div_ceil(self.down)
+	}
+
+	// Produces every output sample the input so far allows, or all of the
+	// rest once the input has ended
+	fn drain(&mut self, eof: bool) -> Vec<f64> {
+		let taps = 2 * self.half;
+		let limit = if eof {
+			self.output_len(self.received)
+		} else {
+			// Output n needs input up to floor(n*M/L) + half
+			self.output_len(self.received.saturating_sub(self.half as u64))
+		};
+
+		let mut out = Vec::new();
+		let mut coeffs = vec![0.0; taps];
+		while self.produced < limit {
+			let pos = self.produced * self.down;
+			let base = pos / self.up;
+			let phase = (pos % self.up) as f64 * self.phases as f64 / self.up as f64;
+			let p = phase as usize;
+			let t = phase - p as f64;
+
+			let row = &self.table[p * taps..(p + 1) * taps];
+			if t == 0.0 {
+				coeffs.copy_from_slice(row);
+			} else {
+				let next = &self.table[(p + 1) * taps..(p + 2) * taps];
+				for (c, (a, b)) in coeffs.iter_mut().zip(row.iter().zip(next)) {
+					*c = a + (b - a) * t;
+				}
+			}
+
+			let first = base as i64 - self.half as i64 + 1;
+			for ch in 0..self.channels {
+				let mut acc = 0.0;
+				for (j, c) in coeffs.iter().enumerate() {
+					let k = first + j as i64;
+					if k >= self.input_start as i64 && (k as u64) < self.received {
+						acc += c * self.input[(k as u64 - self.input_start) as usize * self.channels + ch];
+					}
+				}
+				out.push(acc);
+			}
+			self.produced += 1;
+		}
+
+		// Drop input no later output can reach
+		let keep_from = ((self.produced * self.down / self.up) as i64 - self.half as i64 + 1).max(0) as u64;
+		if keep_from > self.input_start {
+			let n = ((keep_from - self.input_start) as usize).min(self.input.len() / self.channels);
+			self.input.drain(..n * self.channels);
+			self.input_start += n as u64;
+		}
+		out
+	}
+}
+
+pub struct Resample {
+	target_rate : usize,
+	quality : Quality,
+	state : Option<State>,
+}
+
+impl Resample {
+	pub fn new(target_rate: usize, quality: Quality) -> Resample {
+		Resample {
+			target_rate,
+			quality,
+			state: None,
+		}
+	}
+
+	// Input samples the filter looks ahead, already compensated for
+	pub fn latency(&self) -> usize {
+		self.state.as_ref().map(|s| s.half).unwrap_or(0)
+	}
+}
+
+// Moves every sample position in the metadata onto the new rate
+fn rescale(metadata: &mut Metadata, up: u64, down: u64, to: usize) {
+	let scale = |n: u64| ((n as u128 * up as u128 + down as u128 / 2) / down as u128) as u64;
+	metadata.total_samples = metadata.total_samples.map(|n| (n * up).div_ceil(down));
+	metadata.time_reference = metadata.time_reference.map(scale);
+	for marker in &mut metadata.markers {
+		marker.position = scale(marker.position);
+		marker.length = scale(marker.length);
+	}
+	if let Some(ref mut sampler) = metadata.sampler {
+		sampler.sample_period = (1e9 / to as f64).round() as u32;
+		for l in &mut sampler.loops {
+			l.start = scale(l.start);
+			l.end = scale(l.end);
+		}
+	}
+	if let Some(ref mut sheet) = metadata.cue_sheet {
+		for track in &mut sheet.tracks {
+			track.sample_start = track.sample_start.map(scale);
+		}
+	}
+	// Everything else, the rest of cue sheets included, is kept in time
+	// rather than samples
+}
+
+impl Stage for Resample {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		if let Some(ref mut metadata) = frame.metadata {
+			if frame.sample_rate != self.target_rate {
+				let state = State::new(frame.channels, frame.bits_per_sample, frame.sample_rate, self.target_rate, self.quality);
+				rescale(metadata, state.up, state.down, self.target_rate);
+				metadata.audio_md5 = None;
+				self.state = Some(state);
+			}
+		}
+
+		let state = match self.state {
+			Some(ref mut state) => state,
+			None => return frame,
+		};
+		state.input.extend(stage::to_f64(&frame.samples, state.bits_per_sample));
+		state.received += (frame.samples.len() / state.channels) as u64;
+		let out = state.drain(frame.eof);
+
+		frame.samples = stage::from_f64(&out, state.bits_per_sample);
+		frame.sample_rate = self.target_rate;
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Feeds interleaved samples through in frames of the given length and
+	// gathers what comes out
+	fn run(from: usize, to: usize, channels: usize, samples: &[i32], frame_length: usize) -> (Frame, Vec<i32>) {
+		let mut resample = Resample::new(to, Quality::High);
+		let metadata = Metadata { total_samples: Some((samples.len() / channels) as u64), time_reference: Some(from as u64), ..Metadata::default() };
+		let frame = |samples: Vec<i32>, metadata, eof| Frame { channels, sample_rate: from, bits_per_sample: 16, samples, metadata, eof };
+		let header = resample.process(frame(Vec::new(), Some(metadata), false));
+		let mut out = Vec::new();
+		let chunks : Vec<&[i32]> = samples.chunks(frame_length * channels).collect();
+		for (i, chunk) in chunks.iter().enumerate() {
+			let frame = resample.process(frame(chunk.to_vec(), None, i + 1 == chunks.len()));
+			assert_eq!(frame.sample_rate, to);
+			out.extend(frame.samples);
+		}
+		(header, out)
+	}
+
+	#[test]
+	fn lengths() {
+		for &(from, to) in &[(44100, 48000), (48000, 44100), (44100, 22050), (8000, 44100)] {
+			for &n in &[1, 1000, 4097] {
+				let samples = vec![0; n * 2];
+				let (header, out) = run(from, to, 2, &samples, 1024);
+				let expected = (n as u64 * to as u64).div_ceil(from as u64);
+				assert_eq!(out.len() as u64, expected * 2, "{} to {}, {} samples", from, to, n);
+				assert_eq!(header.sample_rate, to);
+				let metadata = header.metadata.unwrap();
+				assert_eq!(metadata.total_samples, Some(expected));
+				assert_eq!(metadata.time_reference, Some(to as u64));
+			}
+		}
+	}
+
+	#[test]
+	fn unity_gain() {
+		let samples : Vec<i32> = (0..20000).map(|n| if n % 2 == 0 { 10000 } else { -20000 }).collect();
+		for &(from, to) in &[(44100, 48000), (48000, 44100), (96000, 44100)] {
+			let (_, out) = run(from, to, 2, &samples, 777);
+			// Away from the edges, where the kernel runs off the input
+			let quarter = out.len() / 8 * 2;
+			let middle = &out[quarter..out.len() - quarter];
+			for frame in middle.chunks_exact(2) {
+				assert!((frame[0] - 10000).abs() <= 2, "{} to {}: {}", from, to, frame[0]);
+				assert!((frame[1] + 20000).abs() <= 2, "{} to {}: {}", from, to, frame[1]);
+			}
+		}
+	}
+
+	#[test]
+	fn sine_keeps_its_level() {
+		let samples : Vec<i32> = (0..48000).map(|n| (16000.0 * (2.0 * PI * 1000.0 * n as f64 / 48000.0).sin()).round() as i32).collect();
+		let (_, out) = run(48000, 44100, 1, &samples, 4096);
+		let middle = &out[out.len() / 4..out.len() * 3 / 4];
+		let peak = middle.iter().map(|x| x.abs()).max().unwrap();
+		assert!((15990..=16010).contains(&peak), "{}", peak);
+		// Centred on the same instants as the input
+		for (n, &y) in middle.iter().enumerate().step_by(101) {
+			let t = (n + out.len() / 4) as f64 / 44100.0;
+			let x = 16000.0 * (2.0 * PI * 1000.0 * t).sin();
+			assert!((y as f64 - x).abs() <= 4.0, "{} at {}", y, n);
+		}
+	}
+
+	#[test]
+	fn same_rate_passes_through() {
+		let samples : Vec<i32> = (0..3000).collect();
+		let (header, out) = run(44100, 44100, 1, &samples, 1000);
+		assert_eq!(header.metadata.unwrap().total_samples, Some(3000));
+		assert_eq!(out, samples);
+	}
+}
@@ -0,0 +1,180 @@
+//
+// Copyright (C) 2021 Christopher Atherton <atherchris@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+// Keeping part of a stream. Positions are given as a sample count
+// ("441000"), seconds ("10s", "2.5", "1:02.5", "1:00:00") or, at a frame
+// rate, timecode ("01:00:00:12", or "01:00:00.12").
+
+use crate::codec::Frame;
+use crate::stage::Stage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Position {
+	Samples(u64),
+	Seconds(f64),
+	Timecode { seconds : u64, frames : u64, frame_rate : f64 },
+}
+
+fn whole(part: &str) -> Option<u64> {
+	if !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) { part.parse().ok() } else { None }
+}
+
+fn seconds(part: &str) -> Option<f64> {
+	part.parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0)
+}
+
+impl Position {
+	pub fn parse(spec: &str, frame_rate: Option<f64>) -> Result<Position, String> {
+		let bad = || format!("Bad position \"{}\"", spec);
+		if let Some(samples) = whole(spec) {
+			return Ok(Position::Samples(samples));
+		}
+		if let Some(s) = spec.strip_suffix('s') {
+			return seconds(s).map(Position::Seconds).ok_or_else(bad);
+		}
+
+		let parts : Vec<&str> = spec.split(':').collect();
+		let (fields, last) = parts.split_at(parts.len() - 1);
+		let last = last[0];
+		let mut whole_seconds = 0;
+		for field in fields {
+			whole_seconds = whole_seconds * 60 + whole(field).ok_or_else(bad)?;
+		}
+
+		// hh:mm:ss:ff, or hh:mm:ss.ff when there is a frame rate to count in
+		let timecode = match (parts.len(), frame_rate) {
+			(4, Some(frame_rate)) => Some((whole_seconds, last, frame_rate)),
+			(4, None) => return Err(format!("Timecode \"{}\" needs a frame rate", spec)),
+			(3, Some(frame_rate)) if last.contains('.') => {
+				let (s, frames) = last.split_once('.').unwrap();
+				Some((whole_seconds * 60 + whole(s).ok_or_else(bad)?, frames, frame_rate))
+			},
+			_ => None,
+		};
+		match timecode {
+			Some((seconds, frames, frame_rate)) => {
+				let frames = whole(frames).filter(|&f| (f as f64) < frame_rate).ok_or_else(bad)?;
+				Ok(Position::Timecode { seconds, frames, frame_rate })
+			},
+			None if parts.len() <= 3 => {
+				let s = seconds(last).filter(|&s| parts.len() == 1 || s < 60.0).ok_or_else(bad)?;
+				Ok(Position::Seconds((whole_seconds * 60) as f64 + s))
+			},
+			None => Err(bad()),
+		}
+	}
+
+	pub fn to_samples(self, sample_rate: usize) -> u64 {
+		let rate = sample_rate as f64;
+		match self {
+			Position::Samples(samples) => samples,
+			Position::Seconds(seconds) => (seconds * rate).round() as u64,
+			Position::Timecode { seconds, frames, frame_rate } => seconds * sample_rate as u64 + (frames as f64 * rate / frame_rate).round() as u64,
+		}
+	}
+}
+
+// The samples kept, end exclusive; None runs to the end of the stream
+pub fn range(start: Option<Position>, end: Option<Position>, duration: Option<Position>, sample_rate: usize) -> Result<(u64, Option<u64>), String> {
+	let start = start.map_or(0, |p| p.to_samples(sample_rate));
+	let end = match (end, duration) {
+		(Some(_), Some(_)) => return Err("Give an end or a duration, not both".to_string()),
+		(Some(end), None) => Some(end.to_samples(sample_rate)),
+		(None, Some(duration)) => Some(start + duration.to_samples(sample_rate)),
+		(None, None) => None,
+	};
+	if end.is_some_and(|end| end < start) {
+		return Err("The range ends before it starts".to_string());
+	}
+	Ok((start, end))
+}
+
+// Trimming as a stage, for streams that cannot seek; everything up to the
+// start is still decoded, then dropped
+pub struct Trim {
+	start : Option<Position>,
+	end : Option<Position>,
+	duration : Option<Position>,
+	range : (u64, Option<u64>),
+	position : u64,
+}
+
+impl Trim {
+	pub fn new(start: Option<Position>, end: Option<Position>, duration: Option<Position>) -> Trim {
+		Trim {
+			start,
+			end,
+			duration,
+			range: (0, None),
+			position: 0,
+		}
+	}
+}
+
+impl Stage for Trim {
+	fn process(&mut self, mut frame: Frame) -> Frame {
+		if let Some(ref mut metadata) = frame.metadata {
+			self.range = range(self.start, self.end, self.duration, frame.sample_rate).unwrap();
+			metadata.trim(self.range.0, self.range.1);
+		}
+
+		let channels = frame.channels;
+		let length = (frame.samples.len() / channels) as u64;
+		let (start, end) = self.range;
+		let from = start.saturating_sub(self.position).min(length);
+		let to = end.map_or(length, |end| end.saturating_sub(self.position).min(length));
+		frame.samples.truncate(to.max(from) as usize * channels);
+		frame.samples.drain(..from as usize * channels);
+		self.position += length;
+		frame
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn positions() {
+		assert_eq!(Position::parse("441000", None), Ok(Position::Samples(441000)));
+		assert_eq!(Position::parse("10s", None), Ok(Position::Seconds(10.0)));
+		assert_eq!(Position::parse("2.5", None), Ok(Position::Seconds(2.5)));
+		assert_eq!(Position::parse("1:02.5", None), Ok(Position::Seconds(62.5)));
+		assert_eq!(Position::parse("1:00:00", None), Ok(Position::Seconds(3600.0)));
+		assert_eq!(Position::parse("01:00:00:12", Some(25.0)), Ok(Position::Timecode { seconds: 3600, frames: 12, frame_rate: 25.0 }));
+		assert_eq!(Position::parse("01:00:00.12", Some(25.0)), Ok(Position::Timecode { seconds: 3600, frames: 12, frame_rate: 25.0 }));
+		// Without a frame rate a fraction is still seconds
+		assert_eq!(Position::parse("00:01:00.5", None), Ok(Position::Seconds(60.5)));
+	}
+
+	#[test]
+	fn bad_positions() {
+		for spec in ["", "s", "-1s", "1:60", "1:xx", "a:00", "1:2:3:4:5", "01:00:00:25"] {
+			assert!(Position::parse(spec, Some(25.0)).is_err(), "{}", spec);
+		}
+		assert!(Position::parse("01:00:00:12", None).is_err());
+	}
+
+	#[test]
+	fn samples() {
+		assert_eq!(Position::Seconds(1.5).to_samples(44100), 66150);
+		assert_eq!(Position::Timecode { seconds: 2, frames: 15, frame_rate: 30.0 }.to_samples(48000), 2 * 48000 + 24000);
+		assert_eq!(range(Some(Position::Samples(100)), None, Some(Position::Samples(50)), 44100), Ok((100, Some(150))));
+		assert!(range(Some(Position::Samples(100)), Some(Position::Samples(50)), None, 44100).is_err());
+		assert!(range(None, Some(Position::Samples(1)), Some(Position::Samples(1)), 44100).is_err());
+	}
+}